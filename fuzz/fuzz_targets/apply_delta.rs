@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The first byte is the length of the base, the rest of it is the delta
+fuzz_target!(|data: &[u8]| {
+    if let Some((len, rest)) = data.split_first() {
+        let (base, delta) = rest.split_at((*len as usize).min(rest.len()));
+        let _ = git_rust::packs::resolve_delta(delta, base);
+    }
+});
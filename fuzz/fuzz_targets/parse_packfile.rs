@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sha1::Digest;
+
+// The input gets a valid trailer, otherwise nearly every input would stop
+// at the checksum
+fuzz_target!(|data: &[u8]| {
+    let mut pack = data.to_vec();
+    pack.extend_from_slice(&sha1::Sha1::digest(data));
+    let _ = git_rust::packs::parse_packfile(&pack);
+});
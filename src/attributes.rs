@@ -0,0 +1,307 @@
+use anyhow::Result;
+use std::fs;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::repo_path;
+
+/// State of an attribute for a path, as described in gitattributes(5)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttrValue {
+    Set,
+    Unset,
+    Value(String),
+    Unspecified,
+}
+
+/// A line of a `.gitattributes` file
+#[derive(Debug)]
+struct AttrRule {
+    base: String,
+    pattern: String,
+    attrs: Vec<(String, AttrValue)>,
+}
+
+/// Matches `text` against a glob pattern with `*`, `**`, `?` and `[...]`.
+/// Single stars never match a `/`, double stars match any number of dirs.
+pub fn wildmatch(pattern: &[u8], text: &[u8]) -> bool {
+    if pattern.is_empty() {
+        return text.is_empty();
+    }
+    match pattern[0] {
+        b'*' if pattern.starts_with(b"**") => {
+            let mut rest = &pattern[2..];
+            if rest.first() == Some(&b'/') {
+                // `**/` also matches zero directories
+                if wildmatch(&rest[1..], text) {
+                    return true;
+                }
+                rest = &rest[1..];
+                return (0..text.len())
+                    .filter(|i| text[*i] == b'/')
+                    .any(|i| wildmatch(rest, &text[i + 1..]));
+            }
+            return (0..=text.len()).any(|i| wildmatch(rest, &text[i..]));
+        }
+        b'*' => {
+            for i in 0..=text.len() {
+                if wildmatch(&pattern[1..], &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == b'/' {
+                    return false;
+                }
+            }
+            return false;
+        }
+        b'?' => {
+            return !text.is_empty() && text[0] != b'/' && wildmatch(&pattern[1..], &text[1..]);
+        }
+        b'[' => {
+            let end = match pattern.iter().skip(2).position(|c| *c == b']') {
+                Some(pos) => pos + 2,
+                None => {
+                    return !text.is_empty()
+                        && text[0] == b'['
+                        && wildmatch(&pattern[1..], &text[1..])
+                }
+            };
+            if text.is_empty() || text[0] == b'/' {
+                return false;
+            }
+            let mut class = &pattern[1..end];
+            let negated = matches!(class.first(), Some(b'!') | Some(b'^'));
+            if negated {
+                class = &class[1..];
+            }
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    matched |= class[i] <= text[0] && text[0] <= class[i + 2];
+                    i += 3;
+                } else {
+                    matched |= class[i] == text[0];
+                    i += 1;
+                }
+            }
+            return matched != negated && wildmatch(&pattern[end + 1..], &text[1..]);
+        }
+        b'\\' if pattern.len() > 1 => {
+            return !text.is_empty()
+                && text[0] == pattern[1]
+                && wildmatch(&pattern[2..], &text[1..]);
+        }
+        c => {
+            return !text.is_empty() && text[0] == c && wildmatch(&pattern[1..], &text[1..]);
+        }
+    }
+}
+
+/// Parses the contents of an attributes file whose patterns are relative to `base`
+fn parse_rules(content: &str, base: &str) -> Vec<AttrRule> {
+    let mut rules = Vec::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        let pattern = parts.next().unwrap().to_string();
+        let mut attrs = Vec::new();
+        for attr in parts {
+            let (name, value) = if let Some(name) = attr.strip_prefix('-') {
+                (name.to_string(), AttrValue::Unset)
+            } else if let Some(name) = attr.strip_prefix('!') {
+                (name.to_string(), AttrValue::Unspecified)
+            } else if let Some((name, value)) = attr.split_once('=') {
+                (name.to_string(), AttrValue::Value(value.to_string()))
+            } else {
+                (attr.to_string(), AttrValue::Set)
+            };
+            // `binary` is a builtin macro for `-diff -merge -text`
+            if name == "binary" && value == AttrValue::Set {
+                for macro_attr in ["diff", "merge", "text"] {
+                    attrs.push((macro_attr.to_string(), AttrValue::Unset));
+                }
+            }
+            attrs.push((name, value));
+        }
+        rules.push(AttrRule {
+            base: base.to_string(),
+            pattern,
+            attrs,
+        });
+    }
+    return rules;
+}
+
+impl AttrRule {
+    fn matches(&self, path: &str) -> bool {
+        let relative = match self.base.as_str() {
+            "" => path,
+            base => match path.strip_prefix(base).and_then(|p| p.strip_prefix('/')) {
+                Some(relative) => relative,
+                None => return false,
+            },
+        };
+        let pattern = self.pattern.strip_prefix('/').unwrap_or(&self.pattern);
+        if !self.pattern.contains('/') {
+            let basename = relative.rsplit('/').next().unwrap_or(relative);
+            return wildmatch(pattern.as_bytes(), basename.as_bytes());
+        }
+        return wildmatch(pattern.as_bytes(), relative.as_bytes());
+    }
+}
+
+/// Attributes that apply to a path of the working tree (relative to the cwd)
+#[derive(Debug)]
+pub struct Attributes {
+    path: String,
+    rules: Vec<AttrRule>,
+}
+
+impl Attributes {
+    /// Loads the attribute files that can affect `path`, in increasing precedence
+    pub fn for_path(path: &str) -> Result<Attributes> {
+        let path = path.trim_start_matches("./").to_string();
+        let mut rules = Vec::new();
+        let mut dirs = vec![String::new()];
+        let components: Vec<&str> = path.split('/').collect();
+        for i in 1..components.len() {
+            dirs.push(components[..i].join("/"));
+        }
+        for dir in dirs {
+            let file = Path::new(&dir).join(".gitattributes");
+            if file.is_file() {
+                rules.extend(parse_rules(&fs::read_to_string(file)?, &dir));
+            }
+        }
+        let info = &repo_path(".git/info/attributes");
+        if info.is_file() {
+            rules.extend(parse_rules(&fs::read_to_string(info)?, ""));
+        }
+        return Ok(Attributes { path, rules });
+    }
+
+    /// Returns the value of an attribute, the last matching rule wins
+    pub fn get(&self, name: &str) -> AttrValue {
+        for rule in self.rules.iter().rev() {
+            if !rule.matches(&self.path) {
+                continue;
+            }
+            if let Some((_, value)) = rule.attrs.iter().rev().find(|(n, _)| n == name) {
+                return value.clone();
+            }
+        }
+        return AttrValue::Unspecified;
+    }
+}
+
+/// Returns whether the content looks binary, as git does with a NUL check
+fn is_binary(data: &[u8]) -> bool {
+    return data.iter().take(8000).any(|b| *b == 0);
+}
+
+/// How the line endings of a path are normalized when stored
+enum TextMode {
+    Binary,
+    Auto,
+    Text,
+}
+
+/// Returns the line ending normalization that applies to `path`
+fn text_mode(path: &str) -> Result<TextMode> {
+    let attributes = Attributes::for_path(path)?;
+    return match attributes.get("text") {
+        AttrValue::Set => Ok(TextMode::Text),
+        AttrValue::Unset => Ok(TextMode::Binary),
+        AttrValue::Value(_) => Ok(TextMode::Auto),
+        AttrValue::Unspecified if attributes.get("eol") != AttrValue::Unspecified => {
+            Ok(TextMode::Text)
+        }
+        AttrValue::Unspecified => {
+            let autocrlf = Config::load()?
+                .get("core.autocrlf")
+                .map(|v| v.to_lowercase());
+            match autocrlf.as_deref() {
+                Some("true") | Some("input") => Ok(TextMode::Auto),
+                _ => Ok(TextMode::Binary),
+            }
+        }
+    };
+}
+
+/// Returns whether content at `path` may be changed when stored
+pub fn needs_conversion(path: &str) -> Result<bool> {
+    return Ok(!matches!(text_mode(path)?, TextMode::Binary));
+}
+
+/// Converts working tree content to the form stored in the repository,
+/// turning CRLF line endings into LF for text files
+pub fn convert_to_git(path: &str, data: Vec<u8>) -> Result<Vec<u8>> {
+    match text_mode(path)? {
+        TextMode::Binary => return Ok(data),
+        TextMode::Auto if is_binary(&data) => return Ok(data),
+        TextMode::Auto | TextMode::Text => {}
+    }
+    let mut converted = Vec::with_capacity(data.len());
+    let mut iter = data.iter().peekable();
+    while let Some(&b) = iter.next() {
+        if b == b'\r' && iter.peek() == Some(&&b'\n') {
+            continue;
+        }
+        converted.push(b);
+    }
+    return Ok(converted);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{convert_to_git, wildmatch, AttrValue, Attributes};
+    use crate::testutils;
+
+    #[test]
+    fn test_attributes() {
+        assert!(wildmatch(b"*.txt", b"file.txt"));
+        assert!(!wildmatch(b"*.txt", b"dir/file.txt"));
+        assert!(wildmatch(b"**/*.txt", b"dir/sub/file.txt"));
+        assert!(wildmatch(b"**/*.txt", b"file.txt"));
+        assert!(wildmatch(b"docs/**", b"docs/a/b"));
+        assert!(wildmatch(b"f?le.[ch]", b"file.c"));
+        assert!(!wildmatch(b"f?le.[!ch]", b"file.c"));
+
+        testutils::in_tmp_git(|| {
+            fs::create_dir_all("sub/deep").unwrap();
+            fs::write(
+                ".gitattributes",
+                "* text=auto\n*.bat eol=crlf\n*.png binary\n/root.txt -text\n",
+            )
+            .unwrap();
+            fs::write("sub/.gitattributes", "*.txt -text\ndeep/*.md text\n").unwrap();
+
+            let attrs = Attributes::for_path("sub/deep/file.md").unwrap();
+            assert_eq!(attrs.get("text"), AttrValue::Set);
+            let attrs = Attributes::for_path("sub/file.txt").unwrap();
+            assert_eq!(attrs.get("text"), AttrValue::Unset);
+            let attrs = Attributes::for_path("a.bat").unwrap();
+            assert_eq!(attrs.get("eol"), AttrValue::Value("crlf".to_string()));
+            assert_eq!(attrs.get("text"), AttrValue::Value("auto".to_string()));
+            let attrs = Attributes::for_path("img.png").unwrap();
+            assert_eq!(attrs.get("text"), AttrValue::Unset);
+            let attrs = Attributes::for_path("root.txt").unwrap();
+            assert_eq!(attrs.get("text"), AttrValue::Unset);
+            let attrs = Attributes::for_path("sub/root.txt").unwrap();
+            assert_eq!(attrs.get("text"), AttrValue::Unset);
+            assert_eq!(attrs.get("missing"), AttrValue::Unspecified);
+
+            let crlf = b"a\r\nb\r\n".to_vec();
+            assert_eq!(convert_to_git("a.bat", crlf.clone()).unwrap(), b"a\nb\n");
+            assert_eq!(convert_to_git("sub/a.txt", crlf.clone()).unwrap(), crlf);
+            let binary = b"\0\r\n".to_vec();
+            assert_eq!(convert_to_git("a.dat", binary.clone()).unwrap(), binary);
+        });
+    }
+}
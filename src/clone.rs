@@ -1,101 +1,372 @@
-use bytes::Bytes;
-
+use crate::config;
+use crate::dumb::DumbRemote;
+use crate::fetch::{fetch_promised_objects, store_fetched};
 use crate::init;
-use crate::objects::{load_object, store_object, GitObjectType};
-use crate::packs::{self, ObjectType, Packfile};
-use crate::tree::checkout_tree;
+use crate::objects::{load_object, object_exists, GitObjectType};
+use crate::protocol::{check_filter_spec, Connection, FetchRequest, FetchResponse, RemoteRef};
+use crate::refs::{check_ref_format, write_ref, write_symref};
+use crate::shallow::parse_since;
+use crate::transport::{local_path, transport_for_url, NotSmartServer, Service};
+use crate::tree::{checkout_tree, lstree};
+use crate::{repo_path, with_repository};
 use anyhow::{bail, Result};
-use std::env::set_current_dir;
 use std::fs;
-use std::str;
-
-/// Perform a blocking HTTP request to the given URL and download a list of refs
-fn discover_refs(url: &String) -> Vec<String> {
-    let formatted_url = format!("{}/info/refs?service=git-upload-pack", url);
-    let resp = reqwest::blocking::get(formatted_url)
-        .unwrap()
-        .bytes()
-        .unwrap();
-    let lines = str::from_utf8(&resp).unwrap().split("\n");
-    let iter = lines.skip(2);
-    let mut hashes = Vec::new();
-    for line in iter {
-        if line == "0000" {
-            break;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Name given to the remote a repository is cloned from
+const REMOTE_NAME: &str = "origin";
+
+/// Options of `clone`
+#[derive(Debug, Default, Clone)]
+pub struct CloneOptions {
+    /// Branch (or tag) to check out instead of the remote HEAD
+    pub branch: Option<String>,
+    /// Only fetch the history of the checked out branch
+    pub single_branch: bool,
+    /// Don't check out the working tree
+    pub no_checkout: bool,
+    /// Don't show the progress sent by the server
+    pub quiet: bool,
+    /// Only fetch this number of commits of history from each ref, like the
+    /// other shallow options it implies `single_branch`
+    pub depth: Option<u32>,
+    /// Only fetch the history after a date
+    pub shallow_since: Option<String>,
+    /// Don't fetch the history reachable from these remote refs
+    pub shallow_exclude: Vec<String>,
+    /// Leave out objects as this filter-spec says, fetching them on demand
+    pub filter: Option<String>,
+    /// Fetch from local paths like from any other remote, instead of
+    /// copying the objects of the source repository
+    pub no_local: bool,
+    /// Copy the objects of a local source instead of hardlinking them
+    pub no_hardlinks: bool,
+}
+
+/// What HEAD of the new repository points to
+#[derive(Debug)]
+enum CloneHead {
+    /// A local branch created from the remote branch of the same name
+    Branch(String, String),
+    /// A detached HEAD, when cloning a tag
+    Detached(String),
+    /// An unborn branch, when the remote is empty
+    Unborn(String),
+}
+
+/// Picks what the new HEAD points to, following the remote HEAD unless a
+/// branch is requested
+fn choose_head(refs: &[RemoteRef], branch: &Option<String>) -> Result<CloneHead> {
+    let find = |name: &str| refs.iter().find(|r| r.name == name);
+    if let Some(branch) = branch {
+        if let Some(r) = find(&format!("refs/heads/{}", branch)) {
+            return Ok(CloneHead::Branch(branch.clone(), r.sha1.clone()));
         }
-        let h: String = line.chars().skip(4).take(40).collect();
-        hashes.push(h);
+        if let Some(r) = find(&format!("refs/tags/{}", branch)) {
+            let commit = r.peeled.as_ref().unwrap_or(&r.sha1);
+            return Ok(CloneHead::Detached(commit.clone()));
+        }
+        bail!("remote branch {} not found in upstream origin", branch);
     }
-    return hashes;
+
+    let head = match find("HEAD") {
+        Some(head) => head,
+        None if refs.is_empty() => return Ok(CloneHead::Unborn("master".to_string())),
+        None => bail!("remote HEAD not found"),
+    };
+    // old servers don't advertise the target of HEAD, git then guesses it
+    // from the branches pointing to the same commit, preferring master
+    let target = head.symref_target.clone().or_else(|| {
+        let mut candidates = refs
+            .iter()
+            .filter(|r| r.name.starts_with("refs/heads/") && r.sha1 == head.sha1);
+        let first = candidates.next().map(|r| r.name.clone());
+        match find("refs/heads/master").filter(|r| r.sha1 == head.sha1) {
+            Some(master) => Some(master.name.clone()),
+            None => first,
+        }
+    });
+    return match target
+        .as_deref()
+        .and_then(|t| t.strip_prefix("refs/heads/"))
+    {
+        Some(branch) => Ok(CloneHead::Branch(branch.to_string(), head.sha1.clone())),
+        None => Ok(CloneHead::Detached(head.sha1.clone())),
+    };
 }
 
-/// Perform a blocking HTTP request to the given URL and download packfile data
-fn request_packfile(url: &String, refs: &Vec<String>) -> Bytes {
-    let client = reqwest::blocking::Client::new();
-    let formatted_url = format!("{}/git-upload-pack", url);
-    let req_body = format!("0032want {}\n00000009done\n", refs.get(0).unwrap());
-    let resp = client
-        .post(&formatted_url)
-        .header("Content-Type", "application/x-git-upload-pack-request")
-        .body(req_body)
-        .send()
-        .unwrap()
-        .bytes()
-        .unwrap();
-    return resp;
+/// Returns the local name of a fetched remote ref, None for refs not cloned
+/// and for names a hostile remote could use to write outside of refs/
+fn local_ref_name(name: &str) -> Option<String> {
+    if check_ref_format(name).is_err() {
+        return None;
+    }
+    if let Some(branch) = name.strip_prefix("refs/heads/") {
+        return Some(format!("refs/remotes/{}/{}", REMOTE_NAME, branch));
+    }
+    if name.starts_with("refs/tags/") {
+        return Some(name.to_string());
+    }
+    return None;
 }
 
-/// Store all the objects of the given packfile in the local git object store
-fn store_pack_objects(packfile: Packfile) -> Result<()> {
-    for entry in packfile.entries {
-        match entry.type_ {
-            ObjectType::Tree | ObjectType::Blob | ObjectType::Commit => {
-                store_object(&entry.type_.to_string(), &entry.data.to_vec())?;
+/// Returns the object directory of a local repository, bare or not
+fn objects_dir(repository: &Path) -> PathBuf {
+    let git_dir = repository.join(".git");
+    return match git_dir.is_dir() {
+        true => git_dir.join("objects"),
+        false => repository.join("objects"),
+    };
+}
+
+/// Copies the object files of a local repository into `dest`, hardlinking
+/// them when possible unless `hardlinks` is false
+fn copy_objects(source: &Path, dest: &Path, hardlinks: bool) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        // objects still being written by another process
+        if name.to_string_lossy().starts_with("tmp") {
+            continue;
+        }
+        let target = dest.join(&name);
+        if entry.file_type()?.is_dir() {
+            copy_objects(&entry.path(), &target, hardlinks)?;
+        } else if !target.exists() {
+            // hardlinks fail across filesystems, the files are copied then
+            if !hardlinks || fs::hard_link(entry.path(), &target).is_err() {
+                fs::copy(entry.path(), &target)?;
             }
-            _ => bail!("storing {} is not supported", entry.type_),
         }
     }
-    Ok(())
+    return Ok(());
 }
 
 /// Clone a remote repository from the given URL
-pub fn clone(url: &String, dest: &String) -> Result<()> {
-    let mut base_url = url.clone();
-    println!("Cloning '{}' into '{}'", base_url, dest);
-    if !base_url.ends_with(".git") {
-        base_url.push_str(".git");
+pub fn clone(url: &String, dest: &String, options: &CloneOptions) -> Result<()> {
+    if let Some(filter) = options.filter.as_ref() {
+        check_filter_spec(filter)?;
     }
+    let deepen_since = match options.shallow_since.as_ref() {
+        Some(date) => Some(parse_since(date)?),
+        None => None,
+    };
+    let shallow =
+        options.depth.is_some() || deepen_since.is_some() || !options.shallow_exclude.is_empty();
+    let mut options = options.clone();
+    options.single_branch |= shallow;
+    let options = &options;
 
-    let discovered_refs = discover_refs(&base_url);
-    let packfile_data = request_packfile(&base_url, &discovered_refs);
-    let packfile = packs::parse_packfile(&packfile_data[8..])?;
+    // plain paths are recorded as absolute ones, and their objects are
+    // copied directly unless the clone must be shallow or partial
+    let mut url = url.clone();
+    let mut local_objects = None;
+    if let Some(path) = local_path(&url).filter(|_| !url.starts_with("file://")) {
+        let path = match fs::canonicalize(&path) {
+            Ok(path) => path,
+            Err(_) => bail!("repository '{}' does not exist", url),
+        };
+        url = path.to_string_lossy().to_string();
+        if !options.no_local && !shallow && options.filter.is_none() {
+            local_objects = Some(objects_dir(&path));
+        }
+    }
+    let url = &url;
+    println!("Cloning '{}' into '{}'", url, dest);
+    // servers of plain files are read with the dumb protocol
+    let (mut connection, mut dumb) =
+        match Connection::open(transport_for_url(url)?, Service::UploadPack) {
+            Ok(connection) => (Some(connection), None),
+            Err(e) if e.is::<NotSmartServer>() => (None, Some(DumbRemote::new(url)?)),
+            Err(e) => return Err(e),
+        };
+    if dumb.is_some() && (shallow || options.filter.is_some()) {
+        bail!("shallow and partial clones need a smart server");
+    }
+    let mut advertised = match (connection.as_mut(), dumb.as_mut()) {
+        (Some(connection), _) => connection.ls_refs(&["HEAD", "refs/heads/", "refs/tags/"])?,
+        (_, Some(dumb)) => dumb.list_refs()?,
+        _ => unreachable!(),
+    };
+    advertised.retain(|r| r.name == "HEAD" || check_ref_format(&r.name).is_ok());
+    for r in advertised.iter_mut() {
+        if r.symref_target
+            .as_deref()
+            .is_some_and(|t| check_ref_format(t).is_err())
+        {
+            r.symref_target = None;
+        }
+    }
+    let head = choose_head(&advertised, &options.branch)?;
+    let remote_head = choose_head(&advertised, &None).ok();
 
-    {
-        fs::create_dir(&dest)?;
-        set_current_dir(&dest)?;
+    let refs: Vec<&RemoteRef> = advertised
+        .iter()
+        .filter(|r| match (&head, options.single_branch) {
+            (_, false) => r.name != "HEAD",
+            (CloneHead::Branch(branch, _), true) => r.name == format!("refs/heads/{}", branch),
+            (_, true) => {
+                options.branch.as_ref().map(|b| format!("refs/tags/{}", b)) == Some(r.name.clone())
+            }
+        })
+        .collect();
+    let mut wants: Vec<String> = refs.iter().map(|r| r.sha1.clone()).collect();
+    wants.sort();
+    wants.dedup();
 
-        if let Err(e) = init() {
-            set_current_dir("..")?;
-            bail!(e);
+    // the pack is read once the repository is set up, while the progress of
+    // the server is still reported
+    let mut stderr = io::stderr();
+    let source = if wants.is_empty() {
+        ObjectSource::Nothing
+    } else if let Some(path) = local_objects {
+        ObjectSource::Local(path)
+    } else if let Some(dumb) = dumb {
+        ObjectSource::Dumb(Box::new(dumb), wants)
+    } else {
+        let request = FetchRequest {
+            wants,
+            depth: options.depth,
+            deepen_since,
+            deepen_not: options.shallow_exclude.clone(),
+            filter: options.filter.clone(),
+            ..Default::default()
         };
-        if let Err(e) = store_pack_objects(packfile) {
-            set_current_dir("..")?;
-            bail!(e);
+        let progress: Option<&mut dyn Write> = match options.quiet {
+            true => None,
+            false => Some(&mut stderr),
         };
+        let connection = connection.as_mut().unwrap();
+        ObjectSource::Fetched(connection.fetch(request, progress)?)
+    };
 
-        let head_commit = discovered_refs.get(0).unwrap();
-        let checkout_res = checkout_commit(head_commit);
-        set_current_dir("..").unwrap();
-        if let Err(e) = checkout_res {
-            bail!(e);
+    fs::create_dir(dest)?;
+    let result = with_repository(&Path::new(dest).join(".git"), || {
+        return setup_repository(url, dest, source, &refs, &head, remote_head, options);
+    });
+    if result.is_err() {
+        fs::remove_dir_all(dest)?;
+    }
+    return result;
+}
+
+/// Where the objects of a clone come from
+enum ObjectSource<'a> {
+    /// The remote has no refs
+    Nothing,
+    /// Sent by the server
+    Fetched(FetchResponse<'a>),
+    /// The objects directory of a local repository, copied as is
+    Local(PathBuf),
+    /// Downloaded from a dumb HTTP server, reachable from these objects
+    Dumb(Box<DumbRemote>, Vec<String>),
+}
+
+/// Initializes the repository the thread works on with the fetched objects
+/// and refs, its working tree being `dest`
+fn setup_repository(
+    url: &str,
+    dest: &String,
+    source: ObjectSource<'_>,
+    refs: &[&RemoteRef],
+    head: &CloneHead,
+    remote_head: Option<CloneHead>,
+    options: &CloneOptions,
+) -> Result<()> {
+    init()?;
+    match source {
+        ObjectSource::Nothing => {}
+        ObjectSource::Fetched(response) => store_fetched(response, options.filter.is_some())?,
+        ObjectSource::Local(path) => {
+            copy_objects(&path, &repo_path(".git/objects"), !options.no_hardlinks)?
+        }
+        ObjectSource::Dumb(mut dumb, wants) => dumb.fetch(&wants)?,
+    }
+
+    let remote = format!("remote.{}", REMOTE_NAME);
+    config::add_value(&format!("{}.url", remote), url)?;
+    if let Some(filter) = options.filter.as_ref() {
+        config::add_value(&format!("{}.promisor", remote), "true")?;
+        config::add_value(&format!("{}.partialclonefilter", remote), filter)?;
+        config::add_value("extensions.partialclone", REMOTE_NAME)?;
+    }
+    let fetch_refspec = match (head, options.single_branch) {
+        (CloneHead::Branch(branch, _), true) => format!(
+            "+refs/heads/{}:refs/remotes/{}/{}",
+            branch, REMOTE_NAME, branch
+        ),
+        (CloneHead::Detached(_), true) => match &options.branch {
+            Some(tag) => format!("+refs/tags/{}:refs/tags/{}", tag, tag),
+            None => String::new(),
+        },
+        (_, true) => String::new(),
+        (_, false) => format!("+refs/heads/*:refs/remotes/{}/*", REMOTE_NAME),
+    };
+    if !fetch_refspec.is_empty() {
+        config::add_value(&format!("{}.fetch", remote), &fetch_refspec)?;
+    }
+
+    for r in refs {
+        if let Some(local) = local_ref_name(&r.name) {
+            write_ref(&local, &r.sha1)?;
+        }
+    }
+    // origin/HEAD follows the remote HEAD, when its branch was fetched
+    if let Some(CloneHead::Branch(branch, _)) = remote_head {
+        let remote_branch = format!("refs/heads/{}", branch);
+        if refs.iter().any(|r| r.name == remote_branch) {
+            write_symref(
+                &format!("refs/remotes/{}/HEAD", REMOTE_NAME),
+                &format!("refs/remotes/{}/{}", REMOTE_NAME, branch),
+            )?;
         }
-        return Ok(());
     }
+
+    match head {
+        CloneHead::Branch(branch, sha1) => {
+            write_ref(&format!("refs/heads/{}", branch), sha1)?;
+            write_symref("HEAD", &format!("refs/heads/{}", branch))?;
+            config::add_value(&format!("branch.{}.remote", branch), REMOTE_NAME)?;
+            config::add_value(
+                &format!("branch.{}.merge", branch),
+                &format!("refs/heads/{}", branch),
+            )?;
+            if !options.no_checkout {
+                checkout_commit(sha1, dest)?;
+            }
+        }
+        CloneHead::Detached(sha1) => {
+            fs::write(repo_path(".git/HEAD"), format!("{}\n", sha1))?;
+            if !options.no_checkout {
+                checkout_commit(sha1, dest)?;
+            }
+        }
+        CloneHead::Unborn(branch) => {
+            eprintln!("warning: You appear to have cloned an empty repository.");
+            write_symref("HEAD", &format!("refs/heads/{}", branch))?;
+        }
+    }
+    return Ok(());
+}
+
+/// Collects the blobs of a tree missing from the local object store. Missing
+/// trees are fetched on demand while walking.
+fn collect_missing_blobs(tree: &String, missing: &mut Vec<String>) -> Result<()> {
+    for node in lstree(tree)?.iter() {
+        match node.object_type() {
+            GitObjectType::Tree => collect_missing_blobs(&node.hash, missing)?,
+            GitObjectType::Blob if !object_exists(&node.hash)? => missing.push(node.hash.clone()),
+            _ => {}
+        }
+    }
+    return Ok(());
 }
 
 /// Creates files and directories in `base` directory
 /// to match those of the tree in the given commit.
-fn checkout_commit(sha1: &String) -> Result<()> {
+fn checkout_commit(sha1: &String, base: &String) -> Result<()> {
     println!("Checking out at {}", sha1);
     let commit = load_object(sha1)?;
     if let GitObjectType::Commit = commit.type_ {
@@ -104,12 +375,568 @@ fn checkout_commit(sha1: &String) -> Result<()> {
             .lines()
             .next()
             .unwrap()
-            .split(" ")
-            .skip(1)
-            .next()
-            .unwrap();
-        return checkout_tree(&head_tree.to_string(), &String::new());
+            .split(' ')
+            .nth(1)
+            .unwrap()
+            .to_string();
+        // partial clones get the blobs left out in a single request
+        if config::Config::load()?
+            .get("extensions.partialclone")
+            .is_some()
+        {
+            let mut missing = Vec::new();
+            collect_missing_blobs(&head_tree, &mut missing)?;
+            fetch_promised_objects(&missing)?;
+        }
+        return checkout_tree(&head_tree, base);
     } else {
         bail!("head is not a commit object");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env::current_dir;
+    use std::fs;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::Path;
+
+    use crate::clone::{clone, CloneOptions};
+    use crate::fetch::{fetch, FetchOptions};
+    use crate::objects::{object_exists, read_object};
+    use crate::push::{push, PushOptions};
+    use crate::testutils::{self, git_in};
+
+    #[test]
+    fn test_clone_over_http() {
+        testutils::in_tmp_dir(|| {
+            testutils::create_repo(
+                "remote.git",
+                &[("README.md", "readme\n"), ("src/main.rs", "fn main() {}\n")],
+            );
+            testutils::commit_files("remote.git", &[("src/lib.rs", "// lib\n")], "second");
+            let url = testutils::start_http_server(&current_dir().unwrap(), true);
+            let options = CloneOptions {
+                quiet: true,
+                ..Default::default()
+            };
+
+            clone(&format!("{}/remote", url), &"local".to_string(), &options).unwrap();
+            assert_eq!(fs::read_to_string("local/README.md").unwrap(), "readme\n");
+            assert_eq!(fs::read_to_string("local/src/lib.rs").unwrap(), "// lib\n");
+            assert_eq!(
+                testutils::get_git_output_in("local", &["fsck", "--no-dangling"]),
+                ""
+            );
+
+            assert!(clone(&format!("{}/missing", url), &"other".to_string(), &options).is_err());
+        });
+    }
+
+    #[test]
+    fn test_clone_branches_and_tags() {
+        testutils::in_tmp_dir(|| {
+            let master = testutils::create_repo("remote.git", &[("file.txt", "master\n")]);
+            testutils::get_git_output_in("remote.git", &["checkout", "-q", "-b", "feature"]);
+            let feature =
+                testutils::commit_files("remote.git", &[("file.txt", "feature\n")], "feature");
+            testutils::tag_in("remote.git", "v1");
+            testutils::get_git_output_in("remote.git", &["checkout", "-q", "master"]);
+            testutils::get_git_output(&["init", "-q", "empty.git"]);
+            let url = testutils::start_http_server(&current_dir().unwrap(), true);
+            let url = format!("{}/remote.git", url);
+
+            let options = CloneOptions {
+                quiet: true,
+                ..Default::default()
+            };
+            clone(&url, &"full".to_string(), &options).unwrap();
+            assert_eq!(
+                git_in("full", &["symbolic-ref", "HEAD"]),
+                "refs/heads/master"
+            );
+            assert_eq!(git_in("full", &["rev-parse", "origin/feature"]), feature);
+            assert_eq!(git_in("full", &["rev-parse", "origin/HEAD"]), master);
+            assert_eq!(
+                git_in("full", &["symbolic-ref", "refs/remotes/origin/HEAD"]),
+                "refs/remotes/origin/master"
+            );
+            assert_eq!(git_in("full", &["rev-parse", "v1^{commit}"]), feature);
+            assert_eq!(git_in("full", &["rev-parse", "master@{upstream}"]), master);
+            assert_eq!(git_in("full", &["config", "remote.origin.url"]), url);
+            assert_eq!(fs::read_to_string("full/file.txt").unwrap(), "master\n");
+            assert_eq!(git_in("full", &["fsck", "--no-dangling"]), "");
+
+            let options = CloneOptions {
+                branch: Some("feature".to_string()),
+                single_branch: true,
+                quiet: true,
+                ..Default::default()
+            };
+            clone(&url, &"single".to_string(), &options).unwrap();
+            assert_eq!(
+                git_in("single", &["for-each-ref", "--format=%(refname)"]),
+                "refs/heads/feature\nrefs/remotes/origin/feature"
+            );
+            assert_eq!(
+                git_in("single", &["config", "remote.origin.fetch"]),
+                "+refs/heads/feature:refs/remotes/origin/feature"
+            );
+            assert_eq!(fs::read_to_string("single/file.txt").unwrap(), "feature\n");
+
+            let options = CloneOptions {
+                branch: Some("v1".to_string()),
+                no_checkout: true,
+                quiet: true,
+                ..Default::default()
+            };
+            clone(&url, &"tag".to_string(), &options).unwrap();
+            assert_eq!(git_in("tag", &["rev-parse", "HEAD"]), feature);
+            assert!(!Path::new("tag/file.txt").exists());
+
+            let options = CloneOptions {
+                branch: Some("missing".to_string()),
+                quiet: true,
+                ..Default::default()
+            };
+            assert!(clone(&url, &"missing".to_string(), &options).is_err());
+            assert!(!Path::new("missing").exists());
+
+            let options = CloneOptions {
+                quiet: true,
+                ..Default::default()
+            };
+            let empty_url = url.replace("remote.git", "empty.git");
+            clone(&empty_url, &"empty".to_string(), &options).unwrap();
+            assert_eq!(
+                git_in("empty", &["symbolic-ref", "HEAD"]),
+                "refs/heads/master"
+            );
+        });
+    }
+
+    #[test]
+    fn test_shallow_and_partial_clone() {
+        testutils::in_tmp_dir(|| {
+            let first = testutils::create_repo("remote.git", &[("file.txt", "first\n")]);
+            testutils::get_git_output_in("remote.git", &["tag", "old"]);
+            testutils::commit_files("remote.git", &[("file.txt", "second\n")], "second");
+            let third = testutils::commit_files("remote.git", &[("file.txt", "third\n")], "third");
+            testutils::get_git_output_in("remote.git", &["config", "uploadpack.allowFilter", "1"]);
+            testutils::get_git_output_in(
+                "remote.git",
+                &["config", "uploadpack.allowAnySHA1InWant", "1"],
+            );
+            let url = testutils::start_http_server(&current_dir().unwrap(), true);
+            let url = format!("{}/remote.git", url);
+
+            let options = CloneOptions {
+                quiet: true,
+                depth: Some(1),
+                ..Default::default()
+            };
+            clone(&url, &"shallow".to_string(), &options).unwrap();
+            assert_eq!(
+                fs::read_to_string("shallow/.git/shallow").unwrap(),
+                format!("{}\n", third)
+            );
+            assert_eq!(git_in("shallow", &["rev-list", "--count", "HEAD"]), "1");
+            assert_eq!(git_in("shallow", &["fsck", "--no-dangling"]), "");
+
+            std::env::set_current_dir("shallow").unwrap();
+            let fetch_options = FetchOptions {
+                quiet: true,
+                depth: Some(2),
+                ..Default::default()
+            };
+            fetch("origin", &[], &fetch_options).unwrap();
+            assert_eq!(git_in(".", &["rev-list", "--count", "HEAD"]), "2");
+            let fetch_options = FetchOptions {
+                quiet: true,
+                unshallow: true,
+                ..Default::default()
+            };
+            fetch("origin", &[], &fetch_options).unwrap();
+            assert_eq!(git_in(".", &["rev-list", "--count", "HEAD"]), "3");
+            assert!(!Path::new(".git/shallow").exists());
+            std::env::set_current_dir("..").unwrap();
+
+            let options = CloneOptions {
+                quiet: true,
+                shallow_exclude: vec!["old".to_string()],
+                ..Default::default()
+            };
+            clone(&url, &"exclude".to_string(), &options).unwrap();
+            assert_eq!(git_in("exclude", &["rev-list", "--count", "HEAD"]), "2");
+
+            let options = CloneOptions {
+                quiet: true,
+                filter: Some("blob:none".to_string()),
+                ..Default::default()
+            };
+            clone(&url, &"partial".to_string(), &options).unwrap();
+            assert_eq!(fs::read_to_string("partial/file.txt").unwrap(), "third\n");
+            assert_eq!(
+                git_in("partial", &["config", "extensions.partialclone"]),
+                "origin"
+            );
+            assert_eq!(git_in("partial", &["fsck", "--no-dangling"]), "");
+            std::env::set_current_dir("partial").unwrap();
+            let old_blob = git_in(".", &["rev-parse", &format!("{}:file.txt", first)]);
+            assert!(!object_exists(&old_blob).unwrap());
+            assert_eq!(read_object(&old_blob).unwrap().1, b"first\n");
+            assert!(object_exists(&old_blob).unwrap());
+            std::env::set_current_dir("..").unwrap();
+
+            let options = CloneOptions {
+                filter: Some("blob:some".to_string()),
+                ..Default::default()
+            };
+            assert!(clone(&url, &"invalid".to_string(), &options).is_err());
+        });
+    }
+
+    #[test]
+    fn test_clone_local() {
+        testutils::in_tmp_dir(|| {
+            let first = testutils::create_repo("source", &[("file.txt", "first\n")]);
+            testutils::get_git_output(&["clone", "-q", "--bare", "source", "bare.git"]);
+            let links = |dir: &str, sha1: &str| {
+                let path = format!("{}/.git/objects/{}/{}", dir, &sha1[..2], &sha1[2..]);
+                fs::metadata(path).unwrap().nlink()
+            };
+            let options = CloneOptions {
+                quiet: true,
+                ..Default::default()
+            };
+
+            // plain paths copy the objects of the source, hardlinked
+            clone(&"source".to_string(), &"linked".to_string(), &options).unwrap();
+            assert_eq!(fs::read_to_string("linked/file.txt").unwrap(), "first\n");
+            assert_eq!(
+                git_in("linked", &["config", "remote.origin.url"]),
+                fs::canonicalize("source").unwrap().to_string_lossy()
+            );
+            assert!(links("linked", &first) > 1);
+            assert_eq!(git_in("linked", &["fsck", "--no-dangling"]), "");
+            let copy_options = CloneOptions {
+                no_hardlinks: true,
+                ..options.clone()
+            };
+            clone(
+                &"bare.git".to_string(),
+                &"copied".to_string(),
+                &copy_options,
+            )
+            .unwrap();
+            assert_eq!(links("copied", &first), 1);
+            assert_eq!(git_in("copied", &["rev-parse", "origin/master"]), first);
+
+            // file:// URLs and --no-local go through upload-pack
+            let url = format!("file://{}", fs::canonicalize("bare.git").unwrap().display());
+            clone(&url, &"fetched".to_string(), &options).unwrap();
+            assert_eq!(git_in("fetched", &["config", "remote.origin.url"]), url);
+            assert!(!Path::new(&format!("fetched/.git/objects/{}", &first[..2])).exists());
+            assert_eq!(git_in("fetched", &["fsck", "--no-dangling"]), "");
+            let no_local = CloneOptions {
+                no_local: true,
+                ..options.clone()
+            };
+            clone(&"source".to_string(), &"no-local".to_string(), &no_local).unwrap();
+            assert_eq!(
+                git_in("no-local", &["count-objects"]),
+                "0 objects, 0 kilobytes"
+            );
+
+            // fetches and pushes work with local remotes too
+            let second = testutils::commit_files("source", &[("file.txt", "second\n")], "second");
+            std::env::set_current_dir("linked").unwrap();
+            let fetch_options = FetchOptions {
+                quiet: true,
+                ..Default::default()
+            };
+            fetch("origin", &[], &fetch_options).unwrap();
+            assert_eq!(git_in(".", &["rev-parse", "origin/master"]), second);
+            std::env::set_current_dir("../fetched").unwrap();
+            let third = testutils::commit_files(".", &[("file.txt", "third\n")], "third");
+            let push_options = PushOptions {
+                quiet: true,
+                ..Default::default()
+            };
+            push("origin", &[], &push_options).unwrap();
+            std::env::set_current_dir("..").unwrap();
+            assert_eq!(git_in("bare.git", &["rev-parse", "master"]), third);
+
+            assert!(clone(&"missing".to_string(), &"none".to_string(), &options).is_err());
+            assert!(!Path::new("none").exists());
+
+            // other directories are cloned into without leaving the cwd, and
+            // only the failed clone is removed
+            let cwd = current_dir().unwrap();
+            fs::create_dir("nested").unwrap();
+            clone(&"source".to_string(), &"nested/ok".to_string(), &options).unwrap();
+            assert_eq!(
+                fs::read_to_string("nested/ok/file.txt").unwrap(),
+                "second\n"
+            );
+            let blob = git_in("source", &["rev-parse", "HEAD:file.txt"]);
+            fs::remove_file(format!("source/.git/objects/{}/{}", &blob[..2], &blob[2..])).unwrap();
+            let error = clone(
+                &"source".to_string(),
+                &"nested/broken".to_string(),
+                &options,
+            );
+            assert!(error.is_err());
+            assert_eq!(current_dir().unwrap(), cwd);
+            assert!(!Path::new("nested/broken").exists());
+            assert!(Path::new("nested/ok/.git").is_dir());
+        });
+    }
+
+    #[test]
+    fn test_clone_over_dumb_http() {
+        testutils::in_tmp_dir(|| {
+            testutils::create_repo("source", &[("file.txt", "first\n"), ("dir/a.txt", "a\n")]);
+            git_in(".", &["init", "-q", "--bare", "remote.git"]);
+            git_in("source", &["push", "-q", "../remote.git", "master"]);
+            // the first commits are packed, the last one stays loose
+            git_in("remote.git", &["repack", "-q", "-a", "-d"]);
+            testutils::commit_files("source", &[("dir/b.txt", "b\n")], "second");
+            testutils::tag_in("source", "v1");
+            git_in("source", &["push", "-q", "../remote.git", "master", "v1"]);
+            git_in("remote.git", &["update-server-info"]);
+            assert!(Path::new("remote.git/objects/info/packs").exists());
+            // refs escaping refs/ are dropped
+            let master = git_in("remote.git", &["rev-parse", "master"]);
+            let mut info_refs = fs::read_to_string("remote.git/info/refs").unwrap();
+            info_refs.push_str(&format!(
+                "{}\trefs/heads/../../hooks/post-checkout\n",
+                master
+            ));
+            info_refs.push_str(&format!("{}\trefs/tags/../../../../escaped\n", master));
+            fs::write("remote.git/info/refs", info_refs).unwrap();
+
+            let url = testutils::start_static_http_server(&current_dir().unwrap());
+            let options = CloneOptions {
+                quiet: true,
+                ..Default::default()
+            };
+            clone(
+                &format!("{}/remote.git", url),
+                &"local".to_string(),
+                &options,
+            )
+            .unwrap();
+            assert_eq!(fs::read_to_string("local/dir/b.txt").unwrap(), "b\n");
+            assert_eq!(
+                git_in("local", &["rev-parse", "origin/master", "v1"]),
+                git_in("remote.git", &["rev-parse", "master", "v1"])
+            );
+            assert_eq!(git_in("local", &["fsck", "--no-dangling"]), "");
+            assert!(!Path::new("local/.git/hooks/post-checkout").exists());
+            assert!(!Path::new("escaped").exists());
+            assert_eq!(
+                git_in("local", &["for-each-ref", "--format=%(refname)"]),
+                "refs/heads/master\nrefs/remotes/origin/HEAD\nrefs/remotes/origin/master\nrefs/tags/v1"
+            );
+
+            let shallow = CloneOptions {
+                depth: Some(1),
+                ..options.clone()
+            };
+            let error = clone(
+                &format!("{}/remote.git", url),
+                &"none".to_string(),
+                &shallow,
+            );
+            assert!(error.unwrap_err().to_string().contains("smart server"));
+        });
+    }
+
+    #[test]
+    fn test_http_authentication() {
+        testutils::in_tmp_dir(|| {
+            testutils::create_repo("remote.git", &[("file.txt", "first\n")]);
+            // `user:secret` in base64
+            let basic = "Basic dXNlcjpzZWNyZXQ=";
+            let server = testutils::start_auth_http_server(
+                &current_dir().unwrap(),
+                true,
+                &[basic, "Bearer token"],
+            );
+            let options = CloneOptions {
+                quiet: true,
+                ..Default::default()
+            };
+            let url = format!("{}/remote", server);
+            let error = clone(&url, &"none".to_string(), &options).unwrap_err();
+            assert!(error.to_string().contains("could not read Username"));
+
+            let with_password = url.replace("http://", "http://user:secret@");
+            clone(&with_password, &"local".to_string(), &options).unwrap();
+            assert_eq!(fs::read_to_string("local/file.txt").unwrap(), "first\n");
+
+            std::env::set_current_dir("local").unwrap();
+            let git = |args: &[&str]| testutils::get_git_output(args);
+            let fetch_options = FetchOptions {
+                quiet: true,
+                ..Default::default()
+            };
+            git(&["config", "remote.origin.url", &url]);
+            let helper = |password: &str| {
+                format!(
+                    "!f() {{ echo $1 >> ../helper.log; test $1 = get && echo username=user && echo password={}; true; }}; f",
+                    password
+                )
+            };
+            git(&["config", "credential.helper", &helper("secret")]);
+            fetch("origin", &[], &fetch_options).unwrap();
+            assert_eq!(fs::read_to_string("../helper.log").unwrap(), "get\nstore\n");
+            git(&["config", "credential.helper", &helper("wrong")]);
+            let error = fetch("origin", &[], &fetch_options).unwrap_err();
+            assert!(error.to_string().contains("Authentication failed"));
+            assert!(fs::read_to_string("../helper.log")
+                .unwrap()
+                .ends_with("get\nerase\n"));
+
+            git(&["config", "--unset", "credential.helper"]);
+            git(&["config", "http.extraHeader", "Authorization: Bearer token"]);
+            fetch("origin", &[], &fetch_options).unwrap();
+            // the server also proxies requests for other hosts
+            git(&["config", "remote.origin.url", "http://git.invalid/remote"]);
+            git(&["config", "http.proxy", server.trim_start_matches("http://")]);
+            fetch("origin", &[], &fetch_options).unwrap();
+            std::env::set_current_dir("..").unwrap();
+        });
+    }
+
+    #[test]
+    fn test_clone_over_ssh() {
+        testutils::in_tmp_dir(|| {
+            testutils::create_repo("source", &[("file.txt", "first\n")]);
+            testutils::get_git_output(&["clone", "-q", "--bare", "source", "remote.git"]);
+            let root = current_dir().unwrap();
+            // stand-ins for ssh logging their arguments and running the
+            // command locally, one of them dropping the protocol version
+            for (name, extra) in [("ssh-v2", ""), ("ssh-v0", "unset GIT_PROTOCOL\n")] {
+                let script = format!(
+                    "#!/bin/sh\necho \"$@\" >> {}/ssh.log\n{}while [ $# -gt 1 ]; do shift; done\n\
+                     PATH=\"$(git --exec-path):$PATH\" exec sh -c \"$1\"\n",
+                    root.display(),
+                    extra
+                );
+                fs::write(name, script).unwrap();
+                fs::set_permissions(name, fs::Permissions::from_mode(0o755)).unwrap();
+            }
+            let options = CloneOptions {
+                quiet: true,
+                ..Default::default()
+            };
+
+            std::env::set_var("GIT_SSH_COMMAND", root.join("ssh-v2"));
+            let url = format!("me@example.com:{}/remote.git", root.display());
+            clone(&url, &"v2".to_string(), &options).unwrap();
+            assert_eq!(fs::read_to_string("v2/file.txt").unwrap(), "first\n");
+            assert_eq!(git_in("v2", &["fsck", "--no-dangling"]), "");
+            // programs of unknown variants only get the host and command
+            let log = fs::read_to_string("ssh.log").unwrap();
+            assert!(log.starts_with(&format!(
+                "me@example.com git-upload-pack '{}/remote.git'",
+                root.display()
+            )));
+
+            std::env::set_var("GIT_SSH_COMMAND", root.join("ssh-v0"));
+            let url = format!("ssh://example.com:2222{}/remote.git", root.display());
+            let error = clone(&url, &"v0".to_string(), &options).unwrap_err();
+            assert!(error
+                .to_string()
+                .contains("does not support setting a port"));
+            std::env::set_var("GIT_SSH_VARIANT", "plink");
+            clone(&url, &"plink".to_string(), &options).unwrap();
+            assert!(fs::read_to_string("ssh.log")
+                .unwrap()
+                .contains("\n-P 2222 example.com git-upload-pack"));
+            std::env::set_var("GIT_SSH_VARIANT", "ssh");
+            clone(&url, &"v0".to_string(), &options).unwrap();
+            assert_eq!(fs::read_to_string("v0/file.txt").unwrap(), "first\n");
+            assert!(fs::read_to_string("ssh.log")
+                .unwrap()
+                .contains("\n-o SendEnv=GIT_PROTOCOL -p 2222 example.com git-upload-pack"));
+            let shallow = CloneOptions {
+                depth: Some(1),
+                ..options.clone()
+            };
+            testutils::commit_files("source", &[("file.txt", "second\n")], "second");
+            git_in("source", &["push", "-q", "../remote.git", "master"]);
+            clone(&url, &"shallow".to_string(), &shallow).unwrap();
+            assert_eq!(git_in("shallow", &["rev-list", "--count", "HEAD"]), "1");
+
+            // pushes and negotiated fetches over a single connection
+            std::env::set_current_dir("v2").unwrap();
+            let third = testutils::commit_files(".", &[("other.txt", "third\n")], "third");
+            let pull = ["pull", "-q", "--no-rebase", "--no-edit", "origin", "master"];
+            testutils::git_as_user_in(".", &pull);
+            let merge = git_in(".", &["rev-parse", "HEAD"]);
+            let push_options = PushOptions {
+                quiet: true,
+                ..Default::default()
+            };
+            push("origin", &[], &push_options).unwrap();
+            std::env::set_current_dir("../v0").unwrap();
+            let fetch_options = FetchOptions {
+                quiet: true,
+                ..Default::default()
+            };
+            fetch("origin", &[], &fetch_options).unwrap();
+            assert_eq!(git_in(".", &["rev-parse", "origin/master"]), merge);
+            assert_eq!(git_in(".", &["rev-parse", "origin/master^1"]), third);
+            assert_eq!(git_in(".", &["fsck", "--no-dangling"]), "");
+            std::env::set_current_dir("..").unwrap();
+            std::env::remove_var("GIT_SSH_COMMAND");
+            std::env::remove_var("GIT_SSH_VARIANT");
+        });
+    }
+
+    #[test]
+    fn test_clone_from_daemon() {
+        testutils::in_tmp_dir(|| {
+            let first = testutils::create_repo("source", &[("file.txt", "first\n")]);
+            testutils::get_git_output(&["clone", "-q", "--bare", "source", "remote.git"]);
+            let daemon = testutils::start_git_daemon(&current_dir().unwrap());
+            let options = CloneOptions {
+                quiet: true,
+                ..Default::default()
+            };
+
+            let url = format!("{}/remote.git", daemon.url);
+            clone(&url, &"cloned".to_string(), &options).unwrap();
+            assert_eq!(fs::read_to_string("cloned/file.txt").unwrap(), "first\n");
+            assert_eq!(git_in("cloned", &["rev-parse", "origin/master"]), first);
+            assert_eq!(git_in("cloned", &["config", "remote.origin.url"]), url);
+            assert_eq!(git_in("cloned", &["fsck", "--no-dangling"]), "");
+
+            let second = testutils::commit_files("source", &[("file.txt", "second\n")], "second");
+            git_in("source", &["push", "-q", "../remote.git", "master"]);
+            let shallow = CloneOptions {
+                depth: Some(1),
+                ..options.clone()
+            };
+            clone(&url, &"shallow".to_string(), &shallow).unwrap();
+            assert_eq!(git_in("shallow", &["rev-list", "--count", "HEAD"]), "1");
+            assert_eq!(fs::read_to_string("shallow/file.txt").unwrap(), "second\n");
+
+            std::env::set_current_dir("cloned").unwrap();
+            let fetch_options = FetchOptions {
+                quiet: true,
+                ..Default::default()
+            };
+            fetch("origin", &[], &fetch_options).unwrap();
+            std::env::set_current_dir("..").unwrap();
+            assert_eq!(git_in("cloned", &["rev-parse", "origin/master"]), second);
+
+            let missing = format!("{}/missing.git", daemon.url);
+            let error = clone(&missing, &"none".to_string(), &options).unwrap_err();
+            assert!(error.to_string().contains("not exported"));
+        });
+    }
+}
@@ -0,0 +1,780 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+};
+
+use anyhow::{bail, Result};
+use sha1::Digest;
+
+use crate::midx::{
+    parse_chunk_table, parse_fanout, search_fanout, write_chunk_file, write_fanout, SHA1_VERSION,
+};
+use crate::packs::{self, write_pack_file, PackIndex};
+use crate::refs::{list_refs, read_ref};
+use crate::repo_path;
+use crate::revwalk::{peel_to_commit, read_commit, Commit};
+use crate::shallow::read_shallow;
+use crate::tree::diff_tree_paths;
+
+/// Path of the commit-graph of the local object store
+pub const COMMIT_GRAPH_PATH: &str = ".git/objects/info/commit-graph";
+
+const GRAPH_MAGIC: &[u8] = b"CGPH";
+const GRAPH_VERSION: u8 = 1;
+
+const CHUNK_FANOUT: &[u8; 4] = b"OIDF";
+const CHUNK_OIDS: &[u8; 4] = b"OIDL";
+const CHUNK_COMMITS: &[u8; 4] = b"CDAT";
+/// Corrected commit dates, the GDAT and GDOV chunks of older gits are
+/// ignored as they could be wrong
+const CHUNK_GENERATIONS: &[u8; 4] = b"GDA2";
+const CHUNK_GENERATION_OVERFLOWS: &[u8; 4] = b"GDO2";
+const CHUNK_EXTRA_EDGES: &[u8; 4] = b"EDGE";
+const CHUNK_BLOOM_INDEXES: &[u8; 4] = b"BIDX";
+const CHUNK_BLOOM_DATA: &[u8; 4] = b"BDAT";
+
+/// Bytes of a commit in the CDAT chunk: tree, two parents, generation and
+/// date
+const COMMIT_DATA_SIZE: usize = 36;
+
+/// Parent position of commits with fewer parents
+const PARENT_NONE: u32 = 0x70000000;
+/// Second parent position pointing to the EDGE list of octopus merges, and
+/// flag of the last parent of that list
+const PARENT_EXTRA: u32 = 0x80000000;
+
+/// Largest topological level stored in CDAT
+const GENERATION_V1_MAX: u64 = 0x3fffffff;
+/// GDA2 offsets with this bit set index the GDO2 chunk
+const GENERATION_OFFSET_OVERFLOW: u32 = 0x80000000;
+
+/// Generation of the commits missing from the commit-graph: they can be
+/// ancestors of no commit of the graph
+pub const GENERATION_INFINITY: u64 = u64::MAX;
+
+/// Changed paths Bloom filters: hash version, number of hashes per path,
+/// bits of filter per changed path, and the number of changed paths past
+/// which a commit gets a filter matching everything
+const BLOOM_VERSION: u32 = 1;
+const BLOOM_HASHES: u32 = 7;
+const BLOOM_BITS_PER_ENTRY: u32 = 10;
+const BLOOM_MAX_CHANGED_PATHS: usize = 512;
+const BLOOM_SEEDS: [u32; 2] = [0x293ae76f, 0x7e646e2c];
+
+/// 32 bit murmur3 hash of `data`. Version 1 of the Bloom filters, the one
+/// git writes, reads the bytes as signed chars.
+fn murmur3(seed: u32, data: &[u8], version: u32) -> u32 {
+    let byte = |b: u8| match version {
+        1 => b as i8 as u32,
+        _ => b as u32,
+    };
+    let (c1, c2) = (0xcc9e2d51u32, 0x1b873593u32);
+    let mut hash = seed;
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k = byte(chunk[0])
+            | (byte(chunk[1]) << 8)
+            | (byte(chunk[2]) << 16)
+            | (byte(chunk[3]) << 24);
+        k = k.wrapping_mul(c1).rotate_left(15).wrapping_mul(c2);
+        hash ^= k;
+        hash = hash
+            .rotate_left(13)
+            .wrapping_mul(5)
+            .wrapping_add(0xe6546b64);
+    }
+    if !tail.is_empty() {
+        let mut k = 0u32;
+        for (i, b) in tail.iter().enumerate() {
+            k ^= byte(*b) << (8 * i);
+        }
+        k = k.wrapping_mul(c1).rotate_left(15).wrapping_mul(c2);
+        hash ^= k;
+    }
+    hash ^= data.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85ebca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2ae35);
+    hash ^= hash >> 16;
+    return hash;
+}
+
+/// Bit positions of a path in Bloom filters, before being reduced modulo
+/// the size of a filter
+fn bloom_key(path: &str, hashes: u32, version: u32) -> Vec<u32> {
+    let first = murmur3(BLOOM_SEEDS[0], path.as_bytes(), version);
+    let second = murmur3(BLOOM_SEEDS[1], path.as_bytes(), version);
+    return (0..hashes)
+        .map(|i| first.wrapping_add(i.wrapping_mul(second)))
+        .collect();
+}
+
+/// Whether a filter may contain a key; filters are little endian bit sets
+fn bloom_contains(filter: &[u8], key: &[u32]) -> bool {
+    let bits = filter.len() as u64 * 8;
+    return key.iter().all(|hash| {
+        let bit = *hash as u64 % bits;
+        filter[(bit / 8) as usize] & (1 << (bit % 8)) != 0
+    });
+}
+
+/// Builds the Bloom filter of the paths a commit changes, and of their
+/// leading directories
+fn bloom_filter(changed: &[String]) -> Vec<u8> {
+    if changed.len() > BLOOM_MAX_CHANGED_PATHS {
+        return vec![0xff];
+    }
+    let mut paths = HashSet::new();
+    for path in changed {
+        let mut path = path.as_str();
+        paths.insert(path);
+        while let Some((parent, _)) = path.rsplit_once('/') {
+            paths.insert(parent);
+            path = parent;
+        }
+    }
+    if paths.len() > BLOOM_MAX_CHANGED_PATHS {
+        return vec![0xff];
+    }
+    let len = (paths.len() * BLOOM_BITS_PER_ENTRY as usize)
+        .div_ceil(8)
+        .max(1);
+    let mut filter = vec![0u8; len];
+    let bits = len as u64 * 8;
+    for path in paths {
+        for hash in bloom_key(path, BLOOM_HASHES, BLOOM_VERSION) {
+            let bit = hash as u64 % bits;
+            filter[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+    return filter;
+}
+
+/// Position and settings of the changed paths Bloom filters of a graph
+#[derive(Debug)]
+struct BloomFilters {
+    indexes: usize,
+    data: usize,
+    data_len: usize,
+    version: u32,
+    hashes: u32,
+}
+
+/// A commit-graph file: the parents, tree, date and generation of commits,
+/// with Bloom filters of the paths they change, so that history can be
+/// walked without reading and inflating commit objects. The whole file is
+/// held in memory.
+#[derive(Debug)]
+pub struct CommitGraph {
+    data: Vec<u8>,
+    fanout: [u32; 256],
+    oids: usize,
+    commits: usize,
+    generations: Option<usize>,
+    generation_overflows: Option<(usize, usize)>,
+    extra_edges: Option<(usize, usize)>,
+    bloom: Option<BloomFilters>,
+}
+
+/// A commit as stored in the commit-graph, its parents given by position
+#[derive(Debug, Clone)]
+pub struct GraphCommit {
+    pub sha1: String,
+    pub tree: String,
+    pub parents: Vec<usize>,
+    pub time: i64,
+    /// Topological level: 1 for root commits, one more than the highest
+    /// level of the parents otherwise
+    pub level: u64,
+    /// Corrected commit date, the date of the commit or one more than that
+    /// of its parents if later, or the level when the graph has no GDA2 chunk
+    pub generation: u64,
+}
+
+impl CommitGraph {
+    /// Parses the contents of a commit-graph file, checking its structure
+    /// but not its checksum
+    pub fn parse(data: Vec<u8>) -> Result<CommitGraph> {
+        if data.len() < 8 + 12 + 20 || &data[..4] != GRAPH_MAGIC {
+            bail!("not a commit-graph");
+        }
+        if data[4] != GRAPH_VERSION || data[5] != SHA1_VERSION {
+            bail!(
+                "commit-graph version {} with hash version {} is not supported",
+                data[4],
+                data[5]
+            );
+        }
+        if data[7] != 0 {
+            bail!("split commit-graph chains are not supported");
+        }
+        let chunks = parse_chunk_table(&data, 8, data[6] as usize, data.len())?;
+        let chunk = |id: &[u8; 4]| match chunks.get(id) {
+            Some(chunk) => Ok(*chunk),
+            None => bail!("commit-graph has no {} chunk", String::from_utf8_lossy(id)),
+        };
+        let (oids, oids_len) = chunk(CHUNK_OIDS)?;
+        let count = oids_len / 20;
+        let (fanout_start, fanout_len) = chunk(CHUNK_FANOUT)?;
+        let fanout = parse_fanout(&data[fanout_start..fanout_start + fanout_len], count)?;
+        let (commits, commits_len) = chunk(CHUNK_COMMITS)?;
+        if oids_len != count * 20 || commits_len != count * COMMIT_DATA_SIZE {
+            bail!("commit-graph chunks don't match its {} commits", count);
+        }
+        let generations = match chunks.get(CHUNK_GENERATIONS) {
+            Some((start, len)) if *len == count * 4 => Some(*start),
+            Some(_) => bail!(
+                "commit-graph GDA2 chunk doesn't match its {} commits",
+                count
+            ),
+            None => None,
+        };
+        let bloom = match (
+            chunks.get(CHUNK_BLOOM_INDEXES),
+            chunks.get(CHUNK_BLOOM_DATA),
+        ) {
+            (Some((indexes, indexes_len)), Some((data_start, data_len)))
+                if *indexes_len == count * 4 && *data_len >= 12 =>
+            {
+                let header = |i: usize| {
+                    u32::from_be_bytes(
+                        data[data_start + i * 4..data_start + i * 4 + 4]
+                            .try_into()
+                            .unwrap(),
+                    )
+                };
+                Some(BloomFilters {
+                    indexes: *indexes,
+                    data: data_start + 12,
+                    data_len: data_len - 12,
+                    version: header(0),
+                    hashes: header(1),
+                })
+            }
+            // filters that can't be read are ignored, like git does
+            _ => None,
+        };
+        let bloom = bloom.filter(|bloom| [1, 2].contains(&bloom.version) && bloom.hashes > 0);
+        return Ok(CommitGraph {
+            fanout,
+            oids,
+            commits,
+            generations,
+            generation_overflows: chunks.get(CHUNK_GENERATION_OVERFLOWS).copied(),
+            extra_edges: chunks.get(CHUNK_EXTRA_EDGES).copied(),
+            bloom,
+            data,
+        });
+    }
+
+    /// Opens the commit-graph of the local object store, if any. Shallow
+    /// repositories don't use it, their history is cut.
+    pub fn open_local() -> Result<Option<CommitGraph>> {
+        if !repo_path(COMMIT_GRAPH_PATH).exists() || !read_shallow()?.is_empty() {
+            return Ok(None);
+        }
+        return Ok(Some(CommitGraph::parse(fs::read(repo_path(
+            COMMIT_GRAPH_PATH,
+        ))?)?));
+    }
+
+    /// Number of commits in the graph
+    pub fn len(&self) -> usize {
+        return self.fanout[255] as usize;
+    }
+
+    /// Whether the graph has no commits
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    /// Whether the graph has changed paths Bloom filters
+    pub fn has_changed_paths(&self) -> bool {
+        return self.bloom.is_some();
+    }
+
+    fn u32_at(&self, position: usize) -> u32 {
+        return u32::from_be_bytes(self.data[position..position + 4].try_into().unwrap());
+    }
+
+    fn hash_at(&self, i: usize) -> [u8; 20] {
+        let start = self.oids + i * 20;
+        return self.data[start..start + 20].try_into().unwrap();
+    }
+
+    /// Returns the position of a commit in the graph
+    pub fn position(&self, sha1: &str) -> Option<usize> {
+        let hash = hex::decode(sha1).ok().filter(|hash| hash.len() == 20)?;
+        return search_fanout(&self.fanout, &hash, |i| Ok(self.hash_at(i))).unwrap_or(None);
+    }
+
+    fn parent_position(&self, position: u32) -> Result<usize> {
+        if position as usize >= self.len() {
+            bail!(
+                "commit-graph has a parent at position {} of {}",
+                position,
+                self.len()
+            );
+        }
+        return Ok(position as usize);
+    }
+
+    /// Returns the commit at position `i` of the graph
+    pub fn commit_at(&self, i: usize) -> Result<GraphCommit> {
+        let start = self.commits + i * COMMIT_DATA_SIZE;
+        let mut parents = Vec::new();
+        let first = self.u32_at(start + 20);
+        let second = self.u32_at(start + 24);
+        if first != PARENT_NONE {
+            parents.push(self.parent_position(first)?);
+        }
+        if second & PARENT_EXTRA != 0 {
+            let (edges, edges_len) = match self.extra_edges {
+                Some(edges) => edges,
+                None => bail!("commit-graph has octopus merges but no EDGE chunk"),
+            };
+            let mut edge = (second & !PARENT_EXTRA) as usize;
+            loop {
+                if (edge + 1) * 4 > edges_len {
+                    bail!("commit-graph EDGE list runs past its chunk");
+                }
+                let parent = self.u32_at(edges + edge * 4);
+                parents.push(self.parent_position(parent & !PARENT_EXTRA)?);
+                if parent & PARENT_EXTRA != 0 {
+                    break;
+                }
+                edge += 1;
+            }
+        } else if second != PARENT_NONE {
+            parents.push(self.parent_position(second)?);
+        }
+        let high = self.u32_at(start + 28);
+        let time = (((high & 0x3) as u64) << 32 | self.u32_at(start + 32) as u64) as i64;
+        let level = (high >> 2) as u64;
+        let generation = match self.generations {
+            Some(generations) => {
+                let offset = self.u32_at(generations + i * 4);
+                if offset & GENERATION_OFFSET_OVERFLOW == 0 {
+                    time as u64 + offset as u64
+                } else {
+                    let overflow = (offset & !GENERATION_OFFSET_OVERFLOW) as usize;
+                    match self.generation_overflows {
+                        Some((start, len)) if (overflow + 1) * 8 <= len => {
+                            let at = start + overflow * 8;
+                            time as u64 + u64::from_be_bytes(self.data[at..at + 8].try_into()?)
+                        }
+                        _ => bail!("commit-graph has no generation overflow {}", overflow),
+                    }
+                }
+            }
+            None => level,
+        };
+        return Ok(GraphCommit {
+            sha1: hex::encode(self.hash_at(i)),
+            tree: hex::encode(&self.data[start..start + 20]),
+            parents,
+            time,
+            level,
+            generation,
+        });
+    }
+
+    /// Reads a commit from the graph, None when the graph doesn't have it
+    pub fn read_commit(&self, sha1: &str) -> Result<Option<Commit>> {
+        let position = match self.position(sha1) {
+            Some(position) => position,
+            None => return Ok(None),
+        };
+        let commit = self.commit_at(position)?;
+        let parents = commit
+            .parents
+            .iter()
+            .map(|parent| hex::encode(self.hash_at(*parent)))
+            .collect();
+        return Ok(Some(Commit {
+            sha1: commit.sha1,
+            tree: commit.tree,
+            parents,
+            time: commit.time,
+            generation: commit.generation,
+        }));
+    }
+
+    /// Returns the generation of a commit, `GENERATION_INFINITY` when the
+    /// graph doesn't have it
+    pub fn generation(&self, sha1: &str) -> Result<u64> {
+        return match self.position(sha1) {
+            Some(position) => Ok(self.commit_at(position)?.generation),
+            None => Ok(GENERATION_INFINITY),
+        };
+    }
+
+    /// Tells from the Bloom filter of a commit whether it may change one of
+    /// `paths` compared to its first parent: `Some(false)` means it surely
+    /// doesn't, `None` that the graph can't tell
+    pub fn maybe_changes(&self, sha1: &str, paths: &[String]) -> Result<Option<bool>> {
+        let (bloom, position) = match (&self.bloom, self.position(sha1)) {
+            (Some(bloom), Some(position)) => (bloom, position),
+            _ => return Ok(None),
+        };
+        let end = self.u32_at(bloom.indexes + position * 4) as usize;
+        let start = match position {
+            0 => 0,
+            _ => self.u32_at(bloom.indexes + (position - 1) * 4) as usize,
+        };
+        if start > end || end > bloom.data_len {
+            bail!("commit-graph has a Bloom filter out of bounds");
+        }
+        let filter = &self.data[bloom.data + start..bloom.data + end];
+        if filter.is_empty() {
+            return Ok(None);
+        }
+        // a path may change if it and all its leading directories may
+        for path in paths {
+            let mut path = path.trim_matches('/');
+            let mut maybe = true;
+            loop {
+                maybe &= bloom_contains(filter, &bloom_key(path, bloom.hashes, bloom.version));
+                match path.rsplit_once('/') {
+                    Some((parent, _)) if maybe => path = parent,
+                    _ => break,
+                }
+            }
+            if maybe {
+                return Ok(Some(true));
+            }
+        }
+        return Ok(Some(false));
+    }
+}
+
+/// Options of `write_commit_graph`
+#[derive(Debug, Default)]
+pub struct CommitGraphOptions {
+    /// Start from the commits of HEAD and every ref rather than from the
+    /// commits of the packs
+    pub reachable: bool,
+    /// Compute the changed paths Bloom filters, which are also computed when
+    /// the current graph has them
+    pub changed_paths: bool,
+}
+
+/// Lists the commits to write in the commit-graph, the ones reachable from
+/// the starting commits
+fn graph_commits(options: &CommitGraphOptions) -> Result<HashMap<String, Commit>> {
+    let mut starts = Vec::new();
+    if options.reachable {
+        let mut tips: Vec<String> = read_ref("HEAD")?.into_iter().collect();
+        tips.extend(list_refs("refs/")?.into_iter().map(|(_, sha1)| sha1));
+        for tip in tips {
+            if let Some(commit) = peel_to_commit(&tip)? {
+                starts.push(commit);
+            }
+        }
+    } else {
+        for path in packs::list_pack_indexes()? {
+            starts.extend(PackIndex::open(&path)?.commits()?);
+        }
+    }
+    let mut commits = HashMap::new();
+    while let Some(sha1) = starts.pop() {
+        if commits.contains_key(&sha1) {
+            continue;
+        }
+        let commit = read_commit(&sha1)?;
+        starts.extend(commit.parents.iter().cloned());
+        commits.insert(sha1, commit);
+    }
+    return Ok(commits);
+}
+
+/// Writes the commit-graph of the local object store, with the commits
+/// reachable from the packs or, with `options.reachable`, from the refs.
+/// Returns the number of commits written; shallow repositories get no
+/// commit-graph.
+pub fn write_commit_graph(options: &CommitGraphOptions) -> Result<usize> {
+    if !read_shallow()?.is_empty() {
+        return Ok(0);
+    }
+    let changed_paths = options.changed_paths
+        || CommitGraph::open_local()?.is_some_and(|graph| graph.has_changed_paths());
+    let commits = graph_commits(options)?;
+    let mut sorted: Vec<&Commit> = commits.values().collect();
+    sorted.sort_by(|a, b| a.sha1.cmp(&b.sha1));
+    let positions: HashMap<&str, usize> = sorted
+        .iter()
+        .enumerate()
+        .map(|(i, commit)| (commit.sha1.as_str(), i))
+        .collect();
+
+    // levels and corrected dates, parents first
+    let mut levels = vec![0u64; sorted.len()];
+    let mut generations = vec![0u64; sorted.len()];
+    for start in 0..sorted.len() {
+        let mut stack = vec![start];
+        while let Some(&i) = stack.last() {
+            if levels[i] != 0 {
+                stack.pop();
+                continue;
+            }
+            let parents: Vec<usize> = sorted[i]
+                .parents
+                .iter()
+                .map(|p| positions[p.as_str()])
+                .collect();
+            let pending: Vec<usize> = parents
+                .iter()
+                .copied()
+                .filter(|p| levels[*p] == 0)
+                .collect();
+            if !pending.is_empty() {
+                stack.extend(pending);
+                continue;
+            }
+            let time = sorted[i].time.max(0) as u64;
+            levels[i] = 1 + parents.iter().map(|p| levels[*p]).max().unwrap_or(0);
+            let parents_generation = parents.iter().map(|p| generations[*p]).max().unwrap_or(0);
+            generations[i] = time.max(parents_generation + 1);
+            stack.pop();
+        }
+    }
+
+    let hashes: Vec<[u8; 20]> = sorted
+        .iter()
+        .map(|commit| hex::decode(&commit.sha1).unwrap().try_into().unwrap())
+        .collect();
+    let mut commit_data = Vec::with_capacity(sorted.len() * COMMIT_DATA_SIZE);
+    let mut generation_data = Vec::with_capacity(sorted.len() * 4);
+    let mut overflows = Vec::new();
+    let mut edges: Vec<u32> = Vec::new();
+    let mut bloom_indexes = Vec::new();
+    let mut bloom_data = Vec::new();
+    if changed_paths {
+        for value in [BLOOM_VERSION, BLOOM_HASHES, BLOOM_BITS_PER_ENTRY] {
+            bloom_data.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+    for (i, commit) in sorted.iter().enumerate() {
+        commit_data.extend_from_slice(&hex::decode(&commit.tree)?);
+        let parents: Vec<u32> = commit
+            .parents
+            .iter()
+            .map(|p| positions[p.as_str()] as u32)
+            .collect();
+        let first = parents.first().copied().unwrap_or(PARENT_NONE);
+        let second = match parents.len() {
+            0 | 1 => PARENT_NONE,
+            2 => parents[1],
+            _ => {
+                let start = PARENT_EXTRA | edges.len() as u32;
+                edges.extend_from_slice(&parents[1..]);
+                *edges.last_mut().unwrap() |= PARENT_EXTRA;
+                start
+            }
+        };
+        commit_data.extend_from_slice(&first.to_be_bytes());
+        commit_data.extend_from_slice(&second.to_be_bytes());
+        let time = commit.time.max(0) as u64;
+        let high = ((levels[i].min(GENERATION_V1_MAX) << 2) as u32) | ((time >> 32) & 0x3) as u32;
+        commit_data.extend_from_slice(&high.to_be_bytes());
+        commit_data.extend_from_slice(&(time as u32).to_be_bytes());
+
+        let offset = generations[i] - time;
+        if offset > (!GENERATION_OFFSET_OVERFLOW) as u64 {
+            let overflow = GENERATION_OFFSET_OVERFLOW | (overflows.len() / 8) as u32;
+            generation_data.extend_from_slice(&overflow.to_be_bytes());
+            overflows.extend_from_slice(&offset.to_be_bytes());
+        } else {
+            generation_data.extend_from_slice(&(offset as u32).to_be_bytes());
+        }
+
+        if changed_paths {
+            let parent_tree = commit.parents.first().map(|parent| &commits[parent].tree);
+            let changed = diff_tree_paths(parent_tree, Some(&commit.tree), "")?;
+            bloom_data.extend(bloom_filter(&changed));
+            bloom_indexes.extend_from_slice(&((bloom_data.len() - 12) as u32).to_be_bytes());
+        }
+    }
+
+    let mut chunks = vec![
+        (CHUNK_FANOUT, write_fanout(&hashes)),
+        (CHUNK_OIDS, hashes.concat()),
+        (CHUNK_COMMITS, commit_data),
+        (CHUNK_GENERATIONS, generation_data),
+    ];
+    if !overflows.is_empty() {
+        chunks.push((CHUNK_GENERATION_OVERFLOWS, overflows));
+    }
+    if !edges.is_empty() {
+        let edges = edges.iter().flat_map(|edge| edge.to_be_bytes()).collect();
+        chunks.push((CHUNK_EXTRA_EDGES, edges));
+    }
+    if changed_paths {
+        chunks.push((CHUNK_BLOOM_INDEXES, bloom_indexes));
+        chunks.push((CHUNK_BLOOM_DATA, bloom_data));
+    }
+    let mut header = GRAPH_MAGIC.to_vec();
+    header.extend_from_slice(&[GRAPH_VERSION, SHA1_VERSION, chunks.len() as u8, 0]);
+    let path = &repo_path(COMMIT_GRAPH_PATH);
+    fs::create_dir_all(path.parent().unwrap())?;
+    if path.exists() {
+        // written read-only
+        fs::remove_file(path)?;
+    }
+    write_pack_file(path, &write_chunk_file(&header, &chunks))?;
+    return Ok(sorted.len());
+}
+
+/// Checks the commit-graph of the local object store: its checksum, the
+/// order of its commits, and that each one matches the commit object and
+/// has the generation numbers its parents give
+pub fn verify_commit_graph() -> Result<usize> {
+    let data = fs::read(repo_path(COMMIT_GRAPH_PATH))?;
+    if data.len() < 20
+        || sha1::Sha1::digest(&data[..data.len() - 20]).as_slice() != &data[data.len() - 20..]
+    {
+        bail!("commit-graph checksum mismatch");
+    }
+    let graph = CommitGraph::parse(data)?;
+    for i in 1..graph.len() {
+        if graph.hash_at(i - 1) >= graph.hash_at(i) {
+            bail!("commit-graph is not sorted at commit {}", i);
+        }
+    }
+    for i in 0..graph.len() {
+        let commit = graph.commit_at(i)?;
+        let object = match read_commit(&commit.sha1) {
+            Ok(object) => object,
+            Err(e) => bail!("commit-graph has commit {}: {}", commit.sha1, e),
+        };
+        let parents: Vec<String> = commit
+            .parents
+            .iter()
+            .map(|p| hex::encode(graph.hash_at(*p)))
+            .collect();
+        if object.tree != commit.tree || object.parents != parents {
+            bail!(
+                "commit-graph has the wrong tree or parents for {}",
+                commit.sha1
+            );
+        }
+        if object.time.max(0) != commit.time {
+            bail!("commit-graph has the wrong date for {}", commit.sha1);
+        }
+        let mut level = 0;
+        let mut generation = 0;
+        for parent in commit.parents.iter() {
+            let parent = graph.commit_at(*parent)?;
+            level = level.max(parent.level);
+            generation = generation.max(parent.generation);
+        }
+        let level = (level + 1).min(GENERATION_V1_MAX);
+        let generation = match graph.generations {
+            Some(_) => (commit.time as u64).max(generation + 1),
+            None => level,
+        };
+        if commit.level != level || commit.generation != generation {
+            bail!("commit-graph has the wrong generation for {}", commit.sha1);
+        }
+    }
+    return Ok(graph.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commitgraph::{
+        verify_commit_graph, write_commit_graph, CommitGraph, CommitGraphOptions, COMMIT_GRAPH_PATH,
+    };
+    use crate::testutils;
+    use std::fs;
+
+    /// Creates a history with branches, a merge and nested directories
+    fn create_history() -> Vec<String> {
+        let mut commits = vec![testutils::create_repo(
+            ".",
+            &[("a.txt", "a\n"), ("dir/b.txt", "b\n")],
+        )];
+        testutils::get_git_output(&["checkout", "-q", "-b", "side"]);
+        commits.push(testutils::commit_files(
+            ".",
+            &[("dir/sub/c.txt", "c\n")],
+            "side",
+        ));
+        testutils::get_git_output(&["checkout", "-q", "master"]);
+        commits.push(testutils::commit_files(
+            ".",
+            &[("a.txt", "a\na\n")],
+            "second",
+        ));
+        testutils::git_as_user_in(".", &["merge", "-q", "-m", "merge", "side"]);
+        commits.push(testutils::git_in(".", &["rev-parse", "HEAD"]));
+        return commits;
+    }
+
+    #[test]
+    fn test_write_commit_graph() {
+        testutils::in_tmp_dir(|| {
+            let commits = create_history();
+            testutils::get_git_output(&["repack", "-q", "-a", "-d"]);
+
+            // from the commits of the packs
+            let options = CommitGraphOptions::default();
+            assert_eq!(write_commit_graph(&options).unwrap(), commits.len());
+            let ours = fs::read(COMMIT_GRAPH_PATH).unwrap();
+            testutils::get_git_output(&["commit-graph", "write"]);
+            assert_eq!(ours, fs::read(COMMIT_GRAPH_PATH).unwrap());
+
+            // from the refs, with changed paths
+            let options = CommitGraphOptions {
+                reachable: true,
+                changed_paths: true,
+            };
+            assert_eq!(write_commit_graph(&options).unwrap(), commits.len());
+            let ours = fs::read(COMMIT_GRAPH_PATH).unwrap();
+            testutils::get_git_output(&["commit-graph", "write", "--reachable", "--changed-paths"]);
+            assert_eq!(ours, fs::read(COMMIT_GRAPH_PATH).unwrap());
+            assert_eq!(verify_commit_graph().unwrap(), commits.len());
+        });
+    }
+
+    #[test]
+    fn test_read_commit_graph() {
+        testutils::in_tmp_dir(|| {
+            let commits = create_history();
+            testutils::get_git_output(&["commit-graph", "write", "--reachable", "--changed-paths"]);
+            let graph = CommitGraph::open_local().unwrap().unwrap();
+            assert_eq!(graph.len(), commits.len());
+            assert!(graph.has_changed_paths());
+
+            let merge = graph.read_commit(&commits[3]).unwrap().unwrap();
+            assert_eq!(merge.parents, vec![commits[2].clone(), commits[1].clone()]);
+            let tree = testutils::get_git_output(&["rev-parse", "HEAD^{tree}"]);
+            assert_eq!(merge.tree, tree.trim());
+            let root = graph.commit_at(graph.position(&commits[0]).unwrap());
+            assert_eq!(root.unwrap().level, 1);
+            let position = graph.position(&commits[3]).unwrap();
+            assert_eq!(graph.commit_at(position).unwrap().level, 3);
+            // corrected commit dates grow along the parents
+            assert!(
+                graph.generation(&commits[3]).unwrap() > graph.generation(&commits[1]).unwrap()
+            );
+            assert!(graph.read_commit(&"0".repeat(40)).unwrap().is_none());
+
+            let paths = |paths: &[&str]| -> Vec<String> {
+                return paths.iter().map(|p| p.to_string()).collect();
+            };
+            // the filters have no false negatives
+            let changes = graph.maybe_changes(&commits[1], &paths(&["dir/sub/c.txt"]));
+            assert_eq!(changes.unwrap(), Some(true));
+            let changes = graph.maybe_changes(&commits[2], &paths(&["dir", "a.txt"]));
+            assert_eq!(changes.unwrap(), Some(true));
+            let changes = graph.maybe_changes(&commits[2], &paths(&["dir/sub/c.txt"]));
+            assert_eq!(changes.unwrap(), Some(false));
+            // a root commit has no first parent to compare with
+            let changes = graph.maybe_changes(&commits[0], &paths(&["a.txt"]));
+            assert_eq!(changes.unwrap(), Some(true));
+        });
+    }
+}
@@ -0,0 +1,363 @@
+use crate::repo_path;
+use anyhow::{bail, Result};
+use std::fs;
+use std::io::{self, Write};
+
+/// A single `key = value` line of a git config file.
+#[derive(Debug, Clone)]
+struct ConfigEntry {
+    section: String,
+    subsection: Option<String>,
+    key: String,
+    value: String,
+}
+
+impl ConfigEntry {
+    fn matches(&self, section: &str, subsection: Option<&str>, key: &str) -> bool {
+        return self.section.eq_ignore_ascii_case(section)
+            && self.subsection.as_deref() == subsection
+            && self.key.eq_ignore_ascii_case(key);
+    }
+}
+
+/// Parsed contents of a git config file.
+#[derive(Debug, Default)]
+pub struct Config {
+    entries: Vec<ConfigEntry>,
+}
+
+/// Splits a `section.subsection.key` name into its parts
+fn split_name(name: &str) -> Result<(&str, Option<&str>, &str)> {
+    let (section, rest) = match name.split_once('.') {
+        Some(parts) => parts,
+        None => bail!("config key '{}' does not contain a section", name),
+    };
+    return match rest.rsplit_once('.') {
+        Some((subsection, key)) => Ok((section, Some(subsection), key)),
+        None => Ok((section, None, rest)),
+    };
+}
+
+/// Parses a section header line such as `[core]` or `[remote "origin"]`
+fn parse_section(line: &str) -> Result<(String, Option<String>)> {
+    let inner = match line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+        Some(inner) => inner.trim(),
+        None => bail!("malformed config section '{}'", line),
+    };
+    return match inner.split_once(' ') {
+        Some((section, subsection)) => {
+            let subsection = subsection.trim();
+            let subsection = match subsection
+                .strip_prefix('"')
+                .and_then(|s| s.strip_suffix('"'))
+            {
+                Some(s) => s.replace("\\\"", "\"").replace("\\\\", "\\"),
+                None => bail!("malformed config subsection '{}'", subsection),
+            };
+            Ok((section.to_string(), Some(subsection)))
+        }
+        None => match inner.split_once('.') {
+            // deprecated `[section.subsection]` syntax
+            Some((section, subsection)) => {
+                Ok((section.to_string(), Some(subsection.to_lowercase())))
+            }
+            None => Ok((inner.to_string(), None)),
+        },
+    };
+}
+
+/// Parses a value removing quotes, escapes and trailing comments
+fn parse_value(raw: &str) -> String {
+    let mut value = String::new();
+    let mut quoted = false;
+    let mut chars = raw.trim().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some(other) => value.push(other),
+                None => {}
+            },
+            '#' | ';' if !quoted => break,
+            _ => value.push(c),
+        }
+    }
+    return value.trim_end().to_string();
+}
+
+/// Formats a value so that `parse_value` reads it back unchanged
+fn format_value(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+    if escaped.contains(['#', ';']) || escaped.trim() != escaped {
+        return format!("\"{}\"", escaped);
+    }
+    return escaped;
+}
+
+/// Formats the header line of a section
+fn format_section(section: &str, subsection: Option<&str>) -> String {
+    return match subsection {
+        Some(subsection) => format!(
+            "[{} \"{}\"]",
+            section,
+            subsection.replace('\\', "\\\\").replace('"', "\\\"")
+        ),
+        None => format!("[{}]", section),
+    };
+}
+
+/// Adds a `section[.subsection].key` entry to the config of the repository
+/// in the cwd, after the other entries of its section. Existing values are
+/// kept, like `git config --add` does.
+pub fn add_value(name: &str, value: &str) -> Result<()> {
+    let (section, subsection, key) = split_name(name)?;
+    let path = &repo_path(".git/config");
+    let content = match path.exists() {
+        true => fs::read_to_string(path)?,
+        false => String::new(),
+    };
+
+    let mut lines: Vec<String> = content.lines().map(|l| l.to_string()).collect();
+    let mut insert_at = None;
+    let mut in_section = false;
+    for (i, line) in lines.iter().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            let (s, sub) = parse_section(line)?;
+            in_section = s.eq_ignore_ascii_case(section) && sub.as_deref() == subsection;
+        }
+        if in_section && !line.is_empty() && !line.starts_with('#') && !line.starts_with(';') {
+            insert_at = Some(i + 1);
+        }
+    }
+    let entry = format!("\t{} = {}", key, format_value(value));
+    match insert_at {
+        Some(i) => lines.insert(i, entry),
+        None => {
+            lines.push(format_section(section, subsection));
+            lines.push(entry);
+        }
+    }
+
+    let lock = &repo_path(".git/config.lock");
+    let mut file = match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(lock)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            bail!("could not lock config file, '{}' exists", lock.display())
+        }
+        Err(e) => bail!(e),
+    };
+    if let Err(e) = file.write_all(format!("{}\n", lines.join("\n")).as_bytes()) {
+        let _ = fs::remove_file(lock);
+        bail!(e);
+    }
+    fs::rename(lock, path)?;
+    return Ok(());
+}
+
+/// Returns the boolean meaning of a config value
+pub fn parse_bool(value: &str) -> Result<bool> {
+    return match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" | "" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => bail!("bad boolean config value '{}'", value),
+    };
+}
+
+/// Returns the meaning of a config value giving a size, a number of bytes
+/// optionally followed by `k`, `m` or `g`
+pub fn parse_size(value: &str) -> Result<usize> {
+    let lower = value.trim().to_lowercase();
+    let (number, unit) = match lower.char_indices().last() {
+        Some((i, 'k')) => (&lower[..i], 1 << 10),
+        Some((i, 'm')) => (&lower[..i], 1 << 20),
+        Some((i, 'g')) => (&lower[..i], 1 << 30),
+        _ => (lower.as_str(), 1),
+    };
+    return match number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+    {
+        Some(size) => Ok(size),
+        None => bail!("bad size config value '{}'", value),
+    };
+}
+
+impl Config {
+    /// Parses the contents of a config file
+    pub fn parse(content: &str) -> Result<Config> {
+        let mut config = Config::default();
+        let mut section: Option<(String, Option<String>)> = None;
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') {
+                section = Some(parse_section(line)?);
+                continue;
+            }
+            let (section, subsection) = match &section {
+                Some(s) => s.clone(),
+                None => bail!("config key '{}' outside of a section", line),
+            };
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), parse_value(value)),
+                // a key without value is a true boolean
+                None => (line, String::new()),
+            };
+            config.entries.push(ConfigEntry {
+                section,
+                subsection,
+                key: key.to_string(),
+                value,
+            });
+        }
+        return Ok(config);
+    }
+
+    /// Loads the config of the repository in the cwd, empty if it doesn't exist
+    pub fn load() -> Result<Config> {
+        let path = &repo_path(".git/config");
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        return Config::parse(&fs::read_to_string(path)?);
+    }
+
+    /// Returns the last value set for a `section[.subsection].key` name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        return self.get_all(name).last().copied();
+    }
+
+    /// Returns all the values set for a `section[.subsection].key` name
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        let (section, subsection, key) = match split_name(name) {
+            Ok(parts) => parts,
+            Err(_) => return Vec::new(),
+        };
+        return self
+            .entries
+            .iter()
+            .filter(|e| e.matches(section, subsection, key))
+            .map(|e| e.value.as_str())
+            .collect();
+    }
+
+    /// Returns a value interpreted as a boolean
+    pub fn get_bool(&self, name: &str) -> Result<Option<bool>> {
+        return match self.get(name) {
+            Some(value) => Ok(Some(parse_bool(value)?)),
+            None => Ok(None),
+        };
+    }
+
+    /// Returns a value interpreted as a size in bytes
+    pub fn get_size(&self, name: &str) -> Result<Option<usize>> {
+        return match self.get(name) {
+            Some(value) => Ok(Some(parse_size(value)?)),
+            None => Ok(None),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{add_value, parse_size, Config};
+    use crate::testutils;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            "# comment\n\
+             [core]\n\
+             \trepositoryformatversion = 0\n\
+             \tbare = false ; inline comment\n\
+             \tFsync = \"loose-object,pack\"\n\
+             \tlogallrefupdates\n\
+             [remote \"origin\"]\n\
+             \turl = https://example.com/repo.git\n\
+             \tfetch = +refs/heads/*:refs/remotes/origin/*\n\
+             \tfetch = +refs/tags/*:refs/tags/*\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.get("core.repositoryformatversion"), Some("0"));
+        assert_eq!(config.get_bool("core.bare").unwrap(), Some(false));
+        assert_eq!(config.get("CORE.fsync"), Some("loose-object,pack"));
+        assert_eq!(
+            config.get_bool("core.logallrefupdates").unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            config.get("remote.origin.url"),
+            Some("https://example.com/repo.git")
+        );
+        assert_eq!(config.get_all("remote.origin.fetch").len(), 2);
+        assert_eq!(config.get("remote.Origin.url"), None);
+        assert_eq!(config.get("core.missing"), None);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("2k").unwrap(), 2048);
+        assert_eq!(parse_size("3M").unwrap(), 3 << 20);
+        assert_eq!(parse_size(" 1g ").unwrap(), 1 << 30);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("12x").is_err());
+    }
+
+    #[test]
+    fn test_add_value() {
+        testutils::in_tmp_git(|| {
+            fs::write(
+                ".git/config",
+                "[core]\n\tbare = false\n# trailing comment\n",
+            )
+            .unwrap();
+            add_value("remote.origin.url", "https://example.com/repo.git").unwrap();
+            add_value("remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*").unwrap();
+            add_value("remote.origin.fetch", "+refs/tags/*:refs/tags/*").unwrap();
+            add_value("core.logallrefupdates", "true").unwrap();
+            add_value("branch.a\"b.description", " spaced # \\ \"value\"").unwrap();
+
+            let config = Config::load().unwrap();
+            assert_eq!(
+                config.get_bool("core.logallrefupdates").unwrap(),
+                Some(true)
+            );
+            assert_eq!(config.get_all("remote.origin.fetch").len(), 2);
+            assert_eq!(
+                config.get("branch.a\"b.description"),
+                Some(" spaced # \\ \"value\"")
+            );
+            assert!(fs::read_to_string(".git/config")
+                .unwrap()
+                .starts_with("[core]\n\tbare = false\n\tlogallrefupdates = true\n# trailing"));
+            assert_eq!(
+                testutils::get_git_output(&["config", "--get-all", "remote.origin.fetch"]),
+                "+refs/heads/*:refs/remotes/origin/*\n+refs/tags/*:refs/tags/*\n"
+            );
+            assert_eq!(
+                testutils::get_git_output(&["config", "branch.a\"b.description"]),
+                " spaced # \\ \"value\"\n"
+            );
+        });
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::objects::{self, GitObjectType, ObjectReader};
+
+/// Bytes of the blocks of the base looked up in the target to find copies
+const BLOCK: usize = 16;
+
+/// Most positions of the base remembered for one block, so long runs of the
+/// same bytes don't make the search quadratic
+const MAX_BLOCK_POSITIONS: usize = 16;
+
+/// Largest copy instruction written, as git does for compatibility with
+/// version 2 packs
+const MAX_COPY: usize = 0x10000;
+
+/// Largest insert instruction
+const MAX_INSERT: usize = 0x7f;
+
+/// Objects smaller than this are always stored whole
+const MIN_DELTA_TARGET: usize = 64;
+
+/// Appends a size of a delta header: 7 bits per byte, least significant
+/// first
+fn encode_size(delta: &mut Vec<u8>, size: usize) {
+    let mut size = size;
+    while size >= 0x80 {
+        delta.push(0x80 | (size & 0x7f) as u8);
+        size >>= 7;
+    }
+    delta.push(size as u8);
+}
+
+/// Appends instructions copying `size` bytes at `offset` of the base: the
+/// opcode flags which bytes of the offset and of the size follow
+fn encode_copy(delta: &mut Vec<u8>, offset: usize, size: usize) {
+    let mut offset = offset;
+    let mut size = size;
+    while size > 0 {
+        let chunk = size.min(MAX_COPY);
+        let op = delta.len();
+        delta.push(0x80);
+        for i in 0..4 {
+            let byte = (offset >> (8 * i)) as u8;
+            if byte != 0 {
+                delta[op] |= 1 << i;
+                delta.push(byte);
+            }
+        }
+        // a size of 0x10000 is written with no size byte
+        for i in 0..3 {
+            let byte = ((chunk & 0xffff) >> (8 * i)) as u8;
+            if byte != 0 {
+                delta[op] |= 0x10 << i;
+                delta.push(byte);
+            }
+        }
+        offset += chunk;
+        size -= chunk;
+    }
+}
+
+/// Appends instructions inserting `data` in the target
+fn encode_insert(delta: &mut Vec<u8>, data: &[u8]) {
+    for chunk in data.chunks(MAX_INSERT) {
+        delta.push(chunk.len() as u8);
+        delta.extend_from_slice(chunk);
+    }
+}
+
+/// Computes a delta rebuilding `target` from `base`, in the format read by
+/// `packs::resolve_delta`, or None if it would be larger than `max_size`.
+///
+/// The base is indexed by blocks of 16 bytes, then the target is scanned
+/// for these blocks: each match is extended both ways into the longest copy
+/// found and the bytes between copies are inserted.
+pub fn create_delta(base: &[u8], target: &[u8], max_size: usize) -> Option<Vec<u8>> {
+    let mut delta = Vec::new();
+    encode_size(&mut delta, base.len());
+    encode_size(&mut delta, target.len());
+
+    let mut index: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    let mut start = 0;
+    while start + BLOCK <= base.len() {
+        let positions = index.entry(&base[start..start + BLOCK]).or_default();
+        if positions.len() < MAX_BLOCK_POSITIONS {
+            positions.push(start);
+        }
+        start += BLOCK;
+    }
+
+    // start of the target bytes not covered by an instruction yet
+    let mut pending = 0;
+    let mut ix = 0;
+    while ix + BLOCK <= target.len() {
+        let positions = match index.get(&target[ix..ix + BLOCK]) {
+            Some(positions) => positions,
+            None => {
+                ix += 1;
+                continue;
+            }
+        };
+        let (mut offset, mut size) = positions
+            .iter()
+            .map(|&position| {
+                let size = base[position..]
+                    .iter()
+                    .zip(&target[ix..])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                (position, size)
+            })
+            .max_by_key(|(_, size)| *size)
+            .unwrap();
+        let mut copy_start = ix;
+        while copy_start > pending && offset > 0 && base[offset - 1] == target[copy_start - 1] {
+            copy_start -= 1;
+            offset -= 1;
+            size += 1;
+        }
+        encode_insert(&mut delta, &target[pending..copy_start]);
+        encode_copy(&mut delta, offset, size);
+        ix = copy_start + size;
+        pending = ix;
+        if delta.len() > max_size {
+            return None;
+        }
+    }
+    encode_insert(&mut delta, &target[pending..]);
+    if delta.len() > max_size {
+        return None;
+    }
+    return Some(delta);
+}
+
+/// Hash of a path grouping the versions of a file, and files with the same
+/// extension, when candidates are sorted: the last characters count most
+pub fn name_hash(path: &str) -> u32 {
+    let mut hash: u32 = 0;
+    for c in path.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        hash = (hash >> 2).wrapping_add((c as u32) << 24);
+    }
+    return hash;
+}
+
+/// An object stored as a delta in a generated pack
+#[derive(Debug)]
+pub struct Delta {
+    /// Index of the base among the packed objects
+    pub base: usize,
+    /// The delta, with its size header
+    pub data: Vec<u8>,
+}
+
+/// An object of the delta search window
+struct Candidate {
+    index: usize,
+    type_: GitObjectType,
+    data: Vec<u8>,
+    depth: usize,
+}
+
+/// Chooses which of the objects (hashes with the path they were found at)
+/// to store as deltas, and against which base.
+///
+/// Like `git pack-objects`, the objects are sorted by type, hash of their
+/// path and decreasing size, so that versions of the same file end up next
+/// to each other, and each one is compared to the `window` objects before
+/// it. The smallest delta is kept if it's small enough to be worth it and
+/// its chain of deltas stays within `depth`. Bases always come before their
+/// deltas in the sorted order, so there are no cycles.
+pub fn search_deltas(
+    objects: &[(String, String)],
+    window: usize,
+    depth: usize,
+) -> Result<Vec<Option<Delta>>> {
+    let mut deltas: Vec<Option<Delta>> = objects.iter().map(|_| None).collect();
+    if window == 0 || depth == 0 {
+        return Ok(deltas);
+    }
+    let mut headers = Vec::new();
+    for (index, (sha1, path)) in objects.iter().enumerate() {
+        let reader = ObjectReader::open(sha1)?;
+        headers.push((reader.type_, name_hash(path), reader.size, index));
+    }
+    headers.sort_by(|a, b| {
+        (a.0 as u8, a.1)
+            .cmp(&(b.0 as u8, b.1))
+            .then(b.2.cmp(&a.2))
+            .then(a.3.cmp(&b.3))
+    });
+
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for (type_, _, size, index) in headers {
+        let (_, data) = objects::read_object(&objects[index].0)?;
+        let mut best: Option<(usize, Vec<u8>, usize)> = None;
+        if size >= MIN_DELTA_TARGET {
+            for candidate in candidates.iter().rev() {
+                if candidate.type_ != type_ || candidate.depth >= depth {
+                    continue;
+                }
+                // deeper bases must save more to be worth their longer chain
+                let mut max_size =
+                    (size / 2).saturating_sub(20) * (depth - candidate.depth) / depth;
+                if let Some((_, delta, _)) = &best {
+                    max_size = max_size.min(delta.len() - 1);
+                }
+                // the target bytes missing from the base must all be inserted
+                let base_size = candidate.data.len();
+                if size.saturating_sub(base_size) >= max_size || size < base_size / 32 {
+                    continue;
+                }
+                if let Some(delta) = create_delta(&candidate.data, &data, max_size) {
+                    best = Some((candidate.index, delta, candidate.depth + 1));
+                }
+            }
+        }
+        let depth = match best {
+            Some((base, delta, depth)) => {
+                deltas[index] = Some(Delta { base, data: delta });
+                depth
+            }
+            None => 0,
+        };
+        if candidates.len() == window {
+            candidates.remove(0);
+        }
+        candidates.push(Candidate {
+            index,
+            type_,
+            data,
+            depth,
+        });
+    }
+    return Ok(deltas);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::delta::{create_delta, name_hash};
+    use crate::packs::resolve_delta;
+
+    #[test]
+    fn test_create_delta() {
+        let base: Vec<u8> = (0..20000u32)
+            .flat_map(|n| format!("line {}\n", n).into_bytes())
+            .collect();
+        let mut target = b"a new first line\n".to_vec();
+        target.extend_from_slice(&base[..50000]);
+        target.extend_from_slice(b"inserted in the middle");
+        target.extend_from_slice(&base[60000..]);
+        target.extend_from_slice(&base[..100]);
+
+        let delta = create_delta(&base, &target, usize::MAX).unwrap();
+        assert!(delta.len() < 200, "delta of {} bytes", delta.len());
+        assert_eq!(resolve_delta(&delta, &base).unwrap(), target);
+        assert!(create_delta(&base, &target, 50).is_none());
+
+        // nothing in common, and empty sides
+        let other: Vec<u8> = (0..1000u32).map(|n| (n * 7 % 251) as u8).collect();
+        let delta = create_delta(&base, &other, usize::MAX).unwrap();
+        assert_eq!(resolve_delta(&delta, &base).unwrap(), other);
+        let delta = create_delta(&[], &other, usize::MAX).unwrap();
+        assert_eq!(resolve_delta(&delta, &[]).unwrap(), other);
+        let delta = create_delta(&base, &[], usize::MAX).unwrap();
+        assert_eq!(resolve_delta(&delta, &base).unwrap(), b"");
+
+        // repeated bytes
+        let zeros = vec![0u8; 200000];
+        let delta = create_delta(&zeros, &zeros[1..], usize::MAX).unwrap();
+        assert!(delta.len() < 50, "delta of {} bytes", delta.len());
+        assert_eq!(resolve_delta(&delta, &zeros).unwrap(), &zeros[1..]);
+    }
+
+    #[test]
+    fn test_name_hash() {
+        assert_eq!(name_hash(""), 0);
+        assert_eq!(name_hash("src/main.rs"), name_hash("src/main .rs"));
+        // files with the same extension sort close together
+        let (a, b, c) = (name_hash("a.rs"), name_hash("b.rs"), name_hash("a.txt"));
+        assert!(a.abs_diff(b) < a.abs_diff(c));
+    }
+}
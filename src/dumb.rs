@@ -0,0 +1,311 @@
+use anyhow::{bail, Result};
+use flate2::read::ZlibDecoder;
+use std::collections::HashSet;
+use std::io::Read;
+
+use crate::objects::{object_exists, read_object, store_object, GitObjectType};
+use crate::packs::{parse_index_hashes, store_pack};
+use crate::protocol::RemoteRef;
+use crate::refs::is_sha1;
+use crate::revwalk::read_commit;
+use crate::transport::{http_transport, HttpTransport};
+use crate::tree::lstree;
+
+/// A pack of the remote, known by its index
+struct RemotePack {
+    name: String,
+    hashes: HashSet<String>,
+}
+
+/// A repository served as plain files over HTTP, read with the dumb
+/// protocol described in gitprotocol-http(5): objects are downloaded one at
+/// a time, or with the whole pack holding them, while walking the history.
+pub struct DumbRemote {
+    transport: HttpTransport,
+    /// Packs of the remote not downloaded yet, listed the first time an
+    /// object isn't found loose
+    packs: Option<Vec<RemotePack>>,
+}
+
+impl DumbRemote {
+    pub fn new(url: &str) -> Result<DumbRemote> {
+        return Ok(DumbRemote {
+            transport: http_transport(url)?,
+            packs: None,
+        });
+    }
+
+    /// Downloads a file that must exist
+    fn get_required(&mut self, path: &str) -> Result<Vec<u8>> {
+        return match self.transport.get_file(path)? {
+            Some(data) => Ok(data),
+            None => bail!("the remote has no '{}'", path),
+        };
+    }
+
+    /// Lists the refs of `info/refs` (written by `git update-server-info`)
+    /// along with HEAD
+    pub fn list_refs(&mut self) -> Result<Vec<RemoteRef>> {
+        let info = self.get_required("info/refs")?;
+        let mut refs: Vec<RemoteRef> = Vec::new();
+        for line in String::from_utf8_lossy(&info).lines() {
+            let (sha1, name) = match line.split_once('\t') {
+                Some((sha1, name)) if is_sha1(sha1) => (sha1, name),
+                _ => bail!("malformed info/refs line '{}'", line),
+            };
+            if let Some(peeled_name) = name.strip_suffix("^{}") {
+                if let Some(last) = refs.last_mut().filter(|r| r.name == peeled_name) {
+                    last.peeled = Some(sha1.to_string());
+                }
+                continue;
+            }
+            refs.push(RemoteRef {
+                name: name.to_string(),
+                sha1: sha1.to_string(),
+                symref_target: None,
+                peeled: None,
+            });
+        }
+
+        let head = match self.transport.get_file("HEAD")? {
+            Some(head) => String::from_utf8_lossy(&head).trim().to_string(),
+            None => return Ok(refs),
+        };
+        let head_ref = match head.strip_prefix("ref: ") {
+            Some(target) => refs
+                .iter()
+                .find(|r| r.name == target)
+                .map(|r| (r.sha1.clone(), Some(target.to_string()))),
+            None if is_sha1(&head) => Some((head.clone(), None)),
+            None => bail!("malformed remote HEAD '{}'", head),
+        };
+        if let Some((sha1, symref_target)) = head_ref {
+            refs.insert(
+                0,
+                RemoteRef {
+                    name: "HEAD".to_string(),
+                    sha1,
+                    symref_target,
+                    peeled: None,
+                },
+            );
+        }
+        return Ok(refs);
+    }
+
+    /// Downloads a loose object, returns false if the remote doesn't have it
+    fn download_loose(&mut self, sha1: &String) -> Result<bool> {
+        if !is_sha1(sha1) {
+            bail!("invalid object id '{}'", sha1);
+        }
+        let path = format!("objects/{}/{}", &sha1[..2], &sha1[2..]);
+        let compressed = match self.transport.get_file(&path)? {
+            Some(compressed) => compressed,
+            None => return Ok(false),
+        };
+        let mut raw = Vec::new();
+        if let Err(e) = ZlibDecoder::new(compressed.as_slice()).read_to_end(&mut raw) {
+            bail!("object {} is corrupt on the remote: {}", sha1, e);
+        }
+        let (type_, data) = match raw.iter().position(|b| *b == 0) {
+            Some(nul) => match std::str::from_utf8(&raw[..nul])?.split_once(' ') {
+                Some((type_, size)) if size.parse::<usize>().ok() == Some(raw.len() - nul - 1) => {
+                    (type_.to_string(), raw[nul + 1..].to_vec())
+                }
+                _ => bail!("object {} has a malformed header", sha1),
+            },
+            None => bail!("object {} has a malformed header", sha1),
+        };
+        GitObjectType::from_string(&type_)?;
+        if store_object(&type_, &data)? != *sha1 {
+            bail!(
+                "object {} downloaded from the remote has another hash",
+                sha1
+            );
+        }
+        return Ok(true);
+    }
+
+    /// Lists the packs of the remote from `objects/info/packs` and downloads
+    /// their indexes
+    fn list_packs(&mut self) -> Result<Vec<RemotePack>> {
+        let info = match self.transport.get_file("objects/info/packs")? {
+            Some(info) => info,
+            None => return Ok(Vec::new()),
+        };
+        let mut packs = Vec::new();
+        for line in String::from_utf8_lossy(&info).lines() {
+            let name = match line
+                .strip_prefix("P ")
+                .and_then(|p| p.strip_suffix(".pack"))
+            {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            let index = self.get_required(&format!("objects/pack/{}.idx", name))?;
+            let hashes = match parse_index_hashes(&index) {
+                Ok(hashes) => hashes.into_iter().collect(),
+                Err(e) => bail!("bad index of remote pack {}: {}", name, e),
+            };
+            packs.push(RemotePack { name, hashes });
+        }
+        return Ok(packs);
+    }
+
+    /// Downloads an object, on its own or with the pack holding it, and
+    /// adds what was downloaded to `fetched`
+    fn download(&mut self, sha1: &String, fetched: &mut HashSet<String>) -> Result<()> {
+        if self.download_loose(sha1)? {
+            fetched.insert(sha1.clone());
+            return Ok(());
+        }
+        if self.packs.is_none() {
+            self.packs = Some(self.list_packs()?);
+        }
+        let packs = self.packs.as_mut().unwrap();
+        let pack = match packs.iter().position(|p| p.hashes.contains(sha1)) {
+            Some(i) => packs.remove(i),
+            None => bail!("unable to find {} on the remote", sha1),
+        };
+        let data = self.get_required(&format!("objects/pack/{}.pack", pack.name))?;
+        store_pack(data.as_slice())?;
+        fetched.extend(pack.hashes);
+        return Ok(());
+    }
+
+    /// Downloads the objects reachable from `wants` that the local object
+    /// store is missing. Objects already there are taken as complete, with
+    /// everything they refer to.
+    pub fn fetch(&mut self, wants: &[String]) -> Result<()> {
+        let mut fetched = HashSet::new();
+        let mut seen = HashSet::new();
+        let mut queue = wants.to_vec();
+        while let Some(sha1) = queue.pop() {
+            // ids come from objects of the remote, which end up in URLs
+            if !is_sha1(&sha1) {
+                bail!("invalid object id '{}' on the remote", sha1);
+            }
+            if !seen.insert(sha1.clone()) {
+                continue;
+            }
+            if !fetched.contains(&sha1) {
+                if object_exists(&sha1)? {
+                    continue;
+                }
+                self.download(&sha1, &mut fetched)?;
+            }
+            let (type_, data) = read_object(&sha1)?;
+            match type_ {
+                GitObjectType::Commit => {
+                    let commit = read_commit(&sha1)?;
+                    queue.push(commit.tree);
+                    queue.extend(commit.parents);
+                }
+                GitObjectType::Tree => {
+                    for node in lstree(&sha1)?.iter() {
+                        // submodule commits live in another repository
+                        if node.object_type() != GitObjectType::Commit {
+                            queue.push(node.hash.clone());
+                        }
+                    }
+                }
+                GitObjectType::Tag => {
+                    let content = String::from_utf8_lossy(&data);
+                    match content
+                        .lines()
+                        .next()
+                        .and_then(|l| l.strip_prefix("object "))
+                    {
+                        Some(target) => queue.push(target.to_string()),
+                        None => bail!("tag {} has no object line", sha1),
+                    }
+                }
+                GitObjectType::Blob => {}
+            }
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::{current_dir, set_current_dir};
+    use std::fs;
+
+    use crate::dumb::DumbRemote;
+    use crate::testutils::{self, git_in};
+
+    #[test]
+    fn test_dumb_fetch() {
+        testutils::in_tmp_dir(|| {
+            let first = testutils::create_repo("source", &[("a.txt", "a\n")]);
+            git_in(".", &["clone", "-q", "--bare", "source", "remote.git"]);
+            // the first commit is packed, the second one loose
+            git_in("remote.git", &["repack", "-q", "-a", "-d"]);
+            let second = testutils::commit_files("source", &[("b.txt", "b\n")], "second");
+            git_in("source", &["push", "-q", "../remote.git", "master"]);
+            git_in("remote.git", &["update-server-info"]);
+            let url = testutils::start_static_http_server(&current_dir().unwrap());
+
+            fs::create_dir("local").unwrap();
+            set_current_dir("local").unwrap();
+            crate::init().unwrap();
+            let mut remote = DumbRemote::new(&format!("{}/remote.git", url)).unwrap();
+            let refs = remote.list_refs().unwrap();
+            assert_eq!(refs[0].name, "HEAD");
+            assert_eq!(refs[0].symref_target.as_deref(), Some("refs/heads/master"));
+            assert_eq!(refs[0].sha1, second);
+            remote.fetch(std::slice::from_ref(&second)).unwrap();
+            assert_eq!(git_in(".", &["cat-file", "-t", &first]), "commit");
+            git_in(".", &["update-ref", "refs/heads/master", &second]);
+            assert_eq!(git_in(".", &["fsck", "--no-dangling"]), "");
+            set_current_dir("..").unwrap();
+        });
+    }
+
+    #[test]
+    fn test_dumb_fetch_invalid_ids() {
+        testutils::in_tmp_dir(|| {
+            testutils::create_repo("source", &[("a.txt", "a\n")]);
+            git_in(".", &["clone", "-q", "--bare", "source", "remote.git"]);
+            let tree = git_in("remote.git", &["rev-parse", "master^{tree}"]);
+            // objects pointing outside of the object directory of the remote
+            let commit = format!(
+                "tree {}\nparent ../../../info/refs\nauthor t <t@t> 0 +0000\n\
+                 committer t <t@t> 0 +0000\n\nhostile\n",
+                tree
+            );
+            let tag =
+                "object ../../config\ntype commit\ntag t\ntagger t <t@t> 0 +0000\n\nhostile\n";
+            let mut hostile = Vec::new();
+            for (type_, content) in [("commit", commit.as_str()), ("tag", tag)] {
+                let args = [
+                    "-C",
+                    "remote.git",
+                    "hash-object",
+                    "--literally",
+                    "-w",
+                    "-t",
+                    type_,
+                    "--stdin",
+                ];
+                let output = testutils::get_git_output_with_stdin(&args, content);
+                hostile.push(String::from_utf8(output).unwrap().trim().to_string());
+            }
+            git_in("remote.git", &["update-server-info"]);
+            let url = testutils::start_static_http_server(&current_dir().unwrap());
+
+            fs::create_dir("local").unwrap();
+            set_current_dir("local").unwrap();
+            crate::init().unwrap();
+            let mut remote = DumbRemote::new(&format!("{}/remote.git", url)).unwrap();
+            for sha1 in hostile {
+                let error = remote.fetch(&[sha1]).unwrap_err();
+                assert!(error.to_string().contains("invalid object id"));
+            }
+            let error = remote.fetch(&["../../config".to_string()]).unwrap_err();
+            assert!(error.to_string().contains("invalid object id"));
+            set_current_dir("..").unwrap();
+        });
+    }
+}
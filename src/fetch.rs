@@ -0,0 +1,623 @@
+use anyhow::{bail, Result};
+use std::cell::Cell;
+use std::fs;
+use std::io::{self, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::config::Config;
+use crate::objects::object_exists;
+use crate::packs;
+use crate::protocol::{Connection, FetchRequest, FetchResponse, Haves, RemoteRef};
+use crate::refs::{check_ref_format, list_refs, read_ref, shorten_ref, write_ref};
+use crate::refspec::Refspec;
+use crate::revwalk::{is_ancestor, peel_to_commit, RevWalk};
+use crate::shallow::{parse_since, read_shallow, update_shallow};
+use crate::transport::{transport_for_url, Service};
+use crate::{repo_path, repository_dir};
+
+/// Width of the summary column of the fetch output, fits `abcdef0...1234567`
+pub const SUMMARY_WIDTH: usize = 17;
+
+/// Depth git asks for to fetch the whole history of a shallow repository
+const INFINITE_DEPTH: u32 = 0x7fffffff;
+
+thread_local! {
+    /// Set while the thread fetches promised objects, objects missing during
+    /// that fetch must not start another one
+    static FETCHING_PROMISED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Options of `fetch`
+#[derive(Debug, Default)]
+pub struct FetchOptions {
+    /// Don't show the progress sent by the server nor the updated refs
+    pub quiet: bool,
+    /// Limit the history fetched to this number of commits from the tips
+    pub depth: Option<u32>,
+    /// Limit the history fetched to the commits after a date
+    pub shallow_since: Option<String>,
+    /// Don't fetch the history reachable from these remote refs
+    pub shallow_exclude: Vec<String>,
+    /// Fetch the whole history of a shallow repository
+    pub unshallow: bool,
+}
+
+/// A local ref to point at a fetched remote ref
+#[derive(Debug)]
+struct RefUpdate {
+    remote: String,
+    /// Empty when the ref is only recorded in FETCH_HEAD
+    local: String,
+    sha1: String,
+    force: bool,
+    /// Whether `git merge FETCH_HEAD` would merge this ref
+    for_merge: bool,
+}
+
+/// What happened to a ref, as printed in the fetch summary
+enum UpdateStatus {
+    UpToDate,
+    FetchHeadOnly,
+    New,
+    FastForward(String),
+    Forced(String),
+    Rejected(&'static str),
+}
+
+/// Returns the kind of ref git names in the summary and in FETCH_HEAD
+pub fn ref_kind(name: &str) -> &'static str {
+    if name.starts_with("refs/heads/") {
+        return "branch";
+    }
+    if name.starts_with("refs/tags/") {
+        return "tag";
+    }
+    return "ref";
+}
+
+/// Returns the commits that can be offered as `have`s, newest first, walked
+/// from the local refs as negotiation asks for them
+fn local_haves() -> Result<Haves> {
+    let mut tips = Vec::new();
+    for (_, sha1) in list_refs("refs/")? {
+        if let Some(commit) = peel_to_commit(&sha1)? {
+            tips.push(commit);
+        }
+    }
+    if let Some(head) = read_ref("HEAD")? {
+        tips.push(head);
+    }
+    tips.sort();
+    tips.dedup();
+
+    let mut walk = RevWalk::new(&tips)?;
+    let haves = std::iter::from_fn(move || walk.next_commit().transpose());
+    return Ok(Haves::new(haves.map(|commit| Ok(commit?.sha1))));
+}
+
+/// Matches the advertised refs against the refspecs. Refs whose remote or
+/// local name isn't a valid ref name are left out, a hostile remote could
+/// otherwise write outside of refs/
+fn match_refspecs(
+    advertised: &[RemoteRef],
+    refspecs: &[Refspec],
+    merge_ref: Option<&str>,
+    explicit: bool,
+) -> Result<Vec<RefUpdate>> {
+    let mut updates = Vec::new();
+    for refspec in refspecs {
+        let mut matched = false;
+        for r in advertised {
+            if r.name != "HEAD" && check_ref_format(&r.name).is_err() {
+                continue;
+            }
+            let local = match refspec.map(&r.name) {
+                Some(local) if local.is_empty() || check_ref_format(&local).is_ok() => local,
+                _ => continue,
+            };
+            matched = true;
+            if updates.iter().any(|u: &RefUpdate| u.remote == r.name) {
+                continue;
+            }
+            updates.push(RefUpdate {
+                remote: r.name.clone(),
+                local,
+                sha1: r.sha1.clone(),
+                force: refspec.force,
+                for_merge: explicit || merge_ref == Some(r.name.as_str()),
+            });
+            if !refspec.is_glob() {
+                break;
+            }
+        }
+        if !matched && !refspec.is_glob() {
+            bail!("couldn't find remote ref {}", refspec.src);
+        }
+    }
+    return Ok(updates);
+}
+
+/// Decides how a ref is updated, without touching it yet
+fn classify_update(update: &RefUpdate) -> Result<UpdateStatus> {
+    if update.local.is_empty() {
+        return Ok(UpdateStatus::FetchHeadOnly);
+    }
+    let old = match read_ref(&update.local)? {
+        Some(old) if old == update.sha1 => return Ok(UpdateStatus::UpToDate),
+        Some(old) => old,
+        None => return Ok(UpdateStatus::New),
+    };
+    if update.local.starts_with("refs/tags/") && !update.force {
+        return Ok(UpdateStatus::Rejected("would clobber existing tag"));
+    }
+    let fast_forward = match (peel_to_commit(&old)?, peel_to_commit(&update.sha1)?) {
+        (Some(old_commit), Some(new_commit)) => is_ancestor(&old_commit, &new_commit)?,
+        _ => false,
+    };
+    if fast_forward {
+        return Ok(UpdateStatus::FastForward(old));
+    }
+    if update.force {
+        return Ok(UpdateStatus::Forced(old));
+    }
+    return Ok(UpdateStatus::Rejected("non-fast-forward"));
+}
+
+/// Formats a line of the summary git prints for every updated ref
+fn format_update(update: &RefUpdate, status: &UpdateStatus, width: usize) -> Option<String> {
+    let (flag, summary, suffix) = match status {
+        UpdateStatus::UpToDate => return None,
+        UpdateStatus::FetchHeadOnly => ('*', ref_kind(&update.remote).to_string(), String::new()),
+        UpdateStatus::New => {
+            let kind = match ref_kind(&update.local) {
+                "branch" => "branch",
+                "tag" => "tag",
+                _ if update.local.starts_with("refs/remotes/") => "branch",
+                _ => "ref",
+            };
+            ('*', format!("[new {}]", kind), String::new())
+        }
+        UpdateStatus::FastForward(old) => (
+            ' ',
+            format!("{}..{}", &old[..7], &update.sha1[..7]),
+            String::new(),
+        ),
+        UpdateStatus::Forced(old) => (
+            '+',
+            format!("{}...{}", &old[..7], &update.sha1[..7]),
+            "  (forced update)".to_string(),
+        ),
+        UpdateStatus::Rejected(reason) => {
+            ('!', "[rejected]".to_string(), format!("  ({})", reason))
+        }
+    };
+    let local = match update.local.as_str() {
+        "" => "FETCH_HEAD",
+        local => shorten_ref(local),
+    };
+    return Some(format!(
+        " {} {:<sw$} {:<w$} -> {}{}",
+        flag,
+        summary,
+        shorten_ref(&update.remote),
+        local,
+        suffix,
+        sw = SUMMARY_WIDTH,
+        w = width
+    ));
+}
+
+/// Records the fetched refs in `.git/FETCH_HEAD` for later merges
+fn write_fetch_head(updates: &[RefUpdate], url: &str) -> Result<()> {
+    let mut content = String::new();
+    for update in updates.iter().filter(|u| u.for_merge) {
+        content.push_str(&format!(
+            "{}\t\t{} '{}' of {}\n",
+            update.sha1,
+            ref_kind(&update.remote),
+            shorten_ref(&update.remote),
+            url
+        ));
+    }
+    for update in updates.iter().filter(|u| !u.for_merge) {
+        content.push_str(&format!(
+            "{}\tnot-for-merge\t{} '{}' of {}\n",
+            update.sha1,
+            ref_kind(&update.remote),
+            shorten_ref(&update.remote),
+            url
+        ));
+    }
+    fs::write(repo_path(".git/FETCH_HEAD"), content)?;
+    return Ok(());
+}
+
+/// Stores a fetched pack as it is received and records the shallow commits
+/// the server sent
+pub fn store_fetched(response: FetchResponse, promisor: bool) -> Result<()> {
+    let packfile = packs::store_pack(BufReader::new(response.pack))?;
+    if promisor {
+        packs::mark_promisor_pack(&packfile)?;
+    }
+    return update_shallow(&response.shallow, &response.unshallow);
+}
+
+/// Fetches objects missing from a partial clone from its promisor remote,
+/// failing when the remote doesn't send them all. Returns false, without
+/// fetching anything, when the repository has no promisor remote.
+pub fn fetch_promised_objects(objects: &[String]) -> Result<bool> {
+    let url = match promisor_url()? {
+        Some(url) => url,
+        None => return Ok(false),
+    };
+    if objects.is_empty() || FETCHING_PROMISED.with(|fetching| fetching.replace(true)) {
+        return Ok(false);
+    }
+    let result = (|| {
+        let mut connection = Connection::open(transport_for_url(&url)?, Service::UploadPack)?;
+        let request = FetchRequest {
+            wants: objects.to_vec(),
+            ..Default::default()
+        };
+        let response = connection.fetch(request, None)?;
+        return store_fetched(response, true);
+    })();
+    FETCHING_PROMISED.with(|fetching| fetching.set(false));
+    result?;
+    for sha1 in objects {
+        if !object_exists(sha1)? {
+            bail!("promised object {} missing from {}", sha1, url);
+        }
+    }
+    return Ok(true);
+}
+
+/// URL of the promisor remote of a partial clone. The config is loaded once
+/// per repository rather than for each missing object. Only a found remote
+/// is kept, a clone configures it after creating the repository.
+fn promisor_url() -> Result<Option<String>> {
+    static PROMISOR: Mutex<Option<(PathBuf, String)>> = Mutex::new(None);
+    let repository = repository_dir()?;
+    let mut cached = match PROMISOR.lock() {
+        Ok(guard) => guard,
+        Err(poison) => poison.into_inner(),
+    };
+    if let Some((path, url)) = cached.as_ref() {
+        if *path == repository {
+            return Ok(Some(url.clone()));
+        }
+    }
+    let config = Config::load()?;
+    let url = match config
+        .get("extensions.partialclone")
+        .and_then(|remote| config.get(&format!("remote.{}.url", remote)))
+    {
+        Some(url) => url.to_string(),
+        None => return Ok(None),
+    };
+    *cached = Some((repository, url.clone()));
+    return Ok(Some(url));
+}
+
+/// Fetches objects and refs from a remote (a configured remote name or a
+/// URL), updating the local refs as the refspecs say
+pub fn fetch(remote: &str, refspecs: &[String], options: &FetchOptions) -> Result<()> {
+    let config = Config::load()?;
+    let configured_url = config.get(&format!("remote.{}.url", remote));
+    let url = configured_url.unwrap_or(remote).to_string();
+    let explicit = !refspecs.is_empty();
+    let refspecs: Vec<Refspec> = if explicit {
+        refspecs
+            .iter()
+            .map(|r| Refspec::parse(r))
+            .collect::<Result<_>>()?
+    } else if configured_url.is_some() {
+        config
+            .get_all(&format!("remote.{}.fetch", remote))
+            .into_iter()
+            .map(Refspec::parse)
+            .collect::<Result<_>>()?
+    } else {
+        vec![Refspec::parse("HEAD")?]
+    };
+
+    // the upstream of the current branch is what `git pull` merges
+    let merge_ref = fs::read_to_string(repo_path(".git/HEAD"))
+        .ok()
+        .and_then(|head| {
+            let branch = head.trim().strip_prefix("ref: refs/heads/")?.to_string();
+            match config.get(&format!("branch.{}.remote", branch)) {
+                Some(r) if r == remote => config
+                    .get(&format!("branch.{}.merge", branch))
+                    .map(|m| m.to_string()),
+                _ => None,
+            }
+        });
+
+    let mut connection = Connection::open(transport_for_url(&url)?, Service::UploadPack)?;
+    let mut prefixes: Vec<String> = refspecs.iter().flat_map(|r| r.prefixes()).collect();
+    prefixes.push("refs/tags/".to_string());
+    let prefixes: Vec<&str> = prefixes.iter().map(|p| p.as_str()).collect();
+    let advertised = connection.ls_refs(&prefixes)?;
+    let mut updates = match_refspecs(&advertised, &refspecs, merge_ref.as_deref(), explicit)?;
+
+    // tags pointing into the fetched history follow automatically
+    let mut followed = Vec::new();
+    for r in advertised
+        .iter()
+        .filter(|r| r.name.starts_with("refs/tags/") && check_ref_format(&r.name).is_ok())
+    {
+        if updates.iter().any(|u| u.remote == r.name) || read_ref(&r.name)?.is_some() {
+            continue;
+        }
+        followed.push(r);
+    }
+
+    let depth = match options.unshallow {
+        true => Some(INFINITE_DEPTH),
+        false => options.depth,
+    };
+    let deepen_since = match options.shallow_since.as_ref() {
+        Some(date) => Some(parse_since(date)?),
+        None => None,
+    };
+    let deepens = depth.is_some() || deepen_since.is_some() || !options.shallow_exclude.is_empty();
+    let shallows: Vec<String> = read_shallow()?.into_iter().collect();
+    if options.unshallow && shallows.is_empty() {
+        bail!("--unshallow on a complete repository does not make sense");
+    }
+
+    let mut wants = Vec::new();
+    for update in updates.iter() {
+        // deepening asks for the tips again to get more of their history
+        if deepens || !object_exists(&update.sha1)? {
+            wants.push(update.sha1.clone());
+        }
+    }
+    // `include-tag` only sends tags whose target is in the pack, ask for
+    // those pointing to objects we already have
+    for r in followed.iter() {
+        let target = r.peeled.as_ref().unwrap_or(&r.sha1);
+        if object_exists(target)? && !object_exists(&r.sha1)? {
+            wants.push(r.sha1.clone());
+        }
+    }
+    wants.sort();
+    wants.dedup();
+
+    // a partial clone keeps leaving out the objects its filter excludes
+    let promisor = configured_url.is_some()
+        && config
+            .get_bool(&format!("remote.{}.promisor", remote))?
+            .unwrap_or(false);
+    let filter = config
+        .get(&format!("remote.{}.partialclonefilter", remote))
+        .filter(|_| promisor)
+        .map(|f| f.to_string());
+
+    if !wants.is_empty() {
+        let request = FetchRequest {
+            wants,
+            haves: local_haves()?,
+            shallows,
+            depth,
+            deepen_since,
+            deepen_not: options.shallow_exclude.clone(),
+            filter,
+        };
+        let mut stderr = io::stderr();
+        let progress: Option<&mut dyn Write> = match options.quiet {
+            true => None,
+            false => Some(&mut stderr),
+        };
+        let response = connection.fetch(request, progress)?;
+        store_fetched(response, promisor)?;
+    }
+
+    for r in followed {
+        if object_exists(&r.sha1)? {
+            updates.push(RefUpdate {
+                remote: r.name.clone(),
+                local: r.name.clone(),
+                sha1: r.sha1.clone(),
+                force: false,
+                for_merge: false,
+            });
+        }
+    }
+
+    let mut lines = Vec::new();
+    let mut rejected = false;
+    let width = updates
+        .iter()
+        .map(|u| shorten_ref(&u.remote).len())
+        .max()
+        .unwrap_or(0);
+    for update in updates.iter() {
+        let status = classify_update(update)?;
+        match status {
+            UpdateStatus::New | UpdateStatus::FastForward(_) | UpdateStatus::Forced(_) => {
+                write_ref(&update.local, &update.sha1)?;
+            }
+            UpdateStatus::Rejected(_) => rejected = true,
+            UpdateStatus::UpToDate | UpdateStatus::FetchHeadOnly => {}
+        }
+        lines.extend(format_update(update, &status, width));
+    }
+    write_fetch_head(&updates, &url)?;
+
+    if !options.quiet && !lines.is_empty() {
+        eprintln!("From {}", url);
+        for line in lines {
+            eprintln!("{}", line);
+        }
+    }
+    if rejected {
+        bail!("some local refs could not be updated");
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::current_dir;
+
+    use crate::clone::{clone, CloneOptions};
+    use crate::fetch::{fetch, match_refspecs, FetchOptions};
+    use crate::objects::read_object;
+    use crate::packs::{list_pack_indexes, PackIndex};
+    use crate::pktline::{write_flush, write_line};
+    use crate::protocol::RemoteRef;
+    use crate::refs::read_ref;
+    use crate::refspec::Refspec;
+    use crate::testutils::{self, git_in};
+    use sha1::Digest;
+
+    #[test]
+    fn test_promised_object_missing() {
+        testutils::in_tmp_git(|| {
+            // a promisor remote answering every fetch with an empty pack
+            let url = testutils::start_canned_http_server(|method, _| {
+                let mut body = Vec::new();
+                if method == "GET" {
+                    write_line(&mut body, "# service=git-upload-pack").unwrap();
+                    write_flush(&mut body).unwrap();
+                    let head = format!("{} HEAD\0agent=test", "1".repeat(40));
+                    write_line(&mut body, &head).unwrap();
+                    write_flush(&mut body).unwrap();
+                    return (
+                        "application/x-git-upload-pack-advertisement".to_string(),
+                        body,
+                    );
+                }
+                write_line(&mut body, "NAK").unwrap();
+                let header = [b"PACK".as_slice(), &[0, 0, 0, 2, 0, 0, 0, 0]].concat();
+                body.extend(&header);
+                body.extend(sha1::Sha1::digest(&header));
+                return ("application/x-git-upload-pack-result".to_string(), body);
+            });
+            let git = |args: &[&str]| testutils::get_git_output(args);
+            git(&["config", "extensions.partialclone", "origin"]);
+            git(&["config", "remote.origin.url", &format!("{}/remote", url)]);
+
+            let missing = "2".repeat(40);
+            let error = read_object(&missing).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("promised object {} missing from {}/remote", missing, url)
+            );
+        });
+    }
+
+    #[test]
+    fn test_match_refspecs() {
+        let sha1 = "1".repeat(40);
+        let advertised: Vec<RemoteRef> = [
+            "HEAD",
+            "refs/heads/master",
+            "refs/heads/../../hooks/post-checkout",
+            "refs/heads/a..b",
+            "refs/heads/x.lock",
+        ]
+        .iter()
+        .map(|name| RemoteRef {
+            name: name.to_string(),
+            sha1: sha1.clone(),
+            symref_target: None,
+            peeled: None,
+        })
+        .collect();
+        let refspecs = vec![
+            Refspec::parse("+refs/heads/*:refs/remotes/origin/*").unwrap(),
+            Refspec::parse("HEAD").unwrap(),
+        ];
+        let updates = match_refspecs(&advertised, &refspecs, None, false).unwrap();
+        let names: Vec<(&str, &str)> = updates
+            .iter()
+            .map(|u| (u.remote.as_str(), u.local.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("refs/heads/master", "refs/remotes/origin/master"),
+                ("HEAD", "")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fetch() {
+        testutils::in_tmp_dir(|| {
+            testutils::create_repo("remote.git", &[("a.txt", "a\n"), ("b.txt", "b\n")]);
+            let url = testutils::start_http_server(&current_dir().unwrap(), true);
+            let options = CloneOptions {
+                quiet: true,
+                ..Default::default()
+            };
+            clone(
+                &format!("{}/remote.git", url),
+                &"local".to_string(),
+                &options,
+            )
+            .unwrap();
+
+            let second = testutils::commit_files("remote.git", &[("c.txt", "c\n")], "second");
+            testutils::get_git_output_in("remote.git", &["branch", "feature"]);
+            testutils::tag_in("remote.git", "v2");
+
+            let quiet = FetchOptions {
+                quiet: true,
+                ..Default::default()
+            };
+            std::env::set_current_dir("local").unwrap();
+            let packs_before = list_pack_indexes().unwrap();
+            fetch("origin", &[], &quiet).unwrap();
+            assert_eq!(
+                read_ref("refs/remotes/origin/master").unwrap(),
+                Some(second.clone())
+            );
+            assert_eq!(
+                read_ref("refs/remotes/origin/feature").unwrap(),
+                Some(second.clone())
+            );
+            assert!(read_ref("refs/tags/v2").unwrap().is_some());
+
+            // only the new commit, its tree, the new blob and the tag are sent
+            let new_packs: Vec<_> = list_pack_indexes()
+                .unwrap()
+                .into_iter()
+                .filter(|p| !packs_before.contains(p))
+                .collect();
+            assert_eq!(new_packs.len(), 1);
+            assert_eq!(PackIndex::open(&new_packs[0]).unwrap().len(), 4);
+            assert_eq!(git_in(".", &["fsck", "--no-dangling"]), "");
+            assert!(std::fs::read_to_string(".git/FETCH_HEAD")
+                .unwrap()
+                .starts_with(&format!("{}\t\tbranch 'master' of", second)));
+
+            // rewritten history is only accepted by forced refspecs
+            std::env::set_current_dir("..").unwrap();
+            git_in("remote.git", &["reset", "-q", "--hard", "HEAD~1"]);
+            let rewritten = testutils::commit_files("remote.git", &[("d.txt", "d\n")], "rewritten");
+            std::env::set_current_dir("local").unwrap();
+            let spec = "refs/heads/master:refs/remotes/origin/master".to_string();
+            assert!(fetch("origin", &[spec], &quiet).is_err());
+            assert_eq!(
+                read_ref("refs/remotes/origin/master").unwrap(),
+                Some(second)
+            );
+            fetch("origin", &[], &quiet).unwrap();
+            assert_eq!(
+                read_ref("refs/remotes/origin/master").unwrap(),
+                Some(rewritten.clone())
+            );
+
+            let spec = "master:refs/heads/copy".to_string();
+            fetch("origin", &[spec], &quiet).unwrap();
+            assert_eq!(read_ref("refs/heads/copy").unwrap(), Some(rewritten));
+            assert!(fetch("origin", &["missing".to_string()], &quiet).is_err());
+            std::env::set_current_dir("..").unwrap();
+        });
+    }
+}
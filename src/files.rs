@@ -1,37 +1,244 @@
-use crate::objects;
+use crate::attributes::{convert_to_git, needs_conversion};
+use crate::objects::{self, GitObjectType, ObjectReader};
+use crate::packs;
+use crate::refs::resolve_object;
+use crate::tree::lstree;
 
 use anyhow::{bail, Result};
 use std::fs;
-use std::io::Read;
+use std::io::{self, BufRead, Read, Write};
 
-pub fn catfile(blobid: &String) -> Result<String> {
-    let obj = objects::load_object(blobid)?;
-    return match obj.type_ {
-        objects::GitObjectType::Blob => Ok(obj.data),
-        _ => bail!("object not a file"),
+/// Default output format of `cat-file --batch` and `--batch-check`
+pub const DEFAULT_BATCH_FORMAT: &str = "%(objectname) %(objecttype) %(objectsize)";
+
+/// What `cat-file` should print about an object
+pub enum CatFileMode {
+    Type,
+    Size,
+    Exists,
+    Pretty,
+}
+
+/// Writes the contents of an object in human readable form
+fn pretty_print<W: Write>(sha1: &String, out: &mut W) -> Result<()> {
+    let mut reader = ObjectReader::open(sha1)?;
+    match reader.type_ {
+        GitObjectType::Tree => {
+            for node in lstree(sha1)?.iter() {
+                writeln!(
+                    out,
+                    "{:0>6} {} {}\t{}",
+                    node.permissions,
+                    node.object_type(),
+                    node.hash,
+                    node.filename
+                )?;
+            }
+        }
+        GitObjectType::Blob | GitObjectType::Commit | GitObjectType::Tag => {
+            io::copy(&mut reader, out)?;
+        }
+    }
+    return Ok(());
+}
+
+/// Prints information about an object, returns false if it doesn't exist
+pub fn catfile<W: Write>(object: &String, mode: &CatFileMode, out: &mut W) -> Result<bool> {
+    let sha1 = match resolve_object(object) {
+        Ok(sha1) => sha1,
+        Err(_) if matches!(mode, CatFileMode::Exists) => return Ok(false),
+        Err(e) => bail!(e),
+    };
+    let reader = match ObjectReader::open(&sha1) {
+        Ok(reader) => reader,
+        Err(_) if matches!(mode, CatFileMode::Exists) => return Ok(false),
+        Err(_) => bail!("Not a valid object name {}", object),
     };
+    match mode {
+        CatFileMode::Type => writeln!(out, "{}", reader.type_)?,
+        CatFileMode::Size => writeln!(out, "{}", reader.size)?,
+        CatFileMode::Exists => {}
+        CatFileMode::Pretty => pretty_print(&sha1, out)?,
+    }
+    return Ok(true);
 }
 
-pub fn hashobject(path: &String, write: bool) -> Result<String> {
+/// Expands a `--batch` format string for the given object. The delta base
+/// is the null id unless the object is stored as a delta in a pack.
+fn format_batch_line(
+    format: &str,
+    sha1: &str,
+    reader: &ObjectReader,
+    rest: &str,
+) -> Result<String> {
+    let mut line = format
+        .replace("%(objectname)", sha1)
+        .replace("%(objecttype)", &reader.type_.to_string())
+        .replace("%(objectsize)", &reader.size.to_string());
+    if line.contains("%(deltabase)") {
+        let base = packs::packed_delta_base(sha1)?.unwrap_or_else(|| "0".repeat(40));
+        line = line.replace("%(deltabase)", &base);
+    }
+    return Ok(line.replace("%(rest)", rest));
+}
+
+/// Writes one `--batch` entry: the formatted line and optionally the contents
+fn batch_object<W: Write>(
+    object: &str,
+    rest: &str,
+    format: &str,
+    contents: bool,
+    out: &mut W,
+) -> Result<()> {
+    let resolved = resolve_object(object).and_then(|sha1| {
+        let reader = ObjectReader::open(&sha1)?;
+        return Ok((sha1, reader));
+    });
+    let (sha1, mut reader) = match resolved {
+        Ok(resolved) => resolved,
+        Err(_) => {
+            writeln!(out, "{} missing", object)?;
+            return Ok(());
+        }
+    };
+    writeln!(out, "{}", format_batch_line(format, &sha1, &reader, rest)?)?;
+    if contents {
+        let copied = io::copy(&mut reader, out)?;
+        if copied as usize != reader.size {
+            bail!("object {} is corrupt", sha1);
+        }
+        writeln!(out)?;
+    }
+    return Ok(());
+}
+
+/// Prints every object named in `input`, one per line, in `--batch` format
+pub fn catfile_batch<R: BufRead, W: Write>(
+    input: R,
+    format: &str,
+    contents: bool,
+    out: &mut W,
+) -> Result<()> {
+    let split_rest = format.contains("%(rest)");
+    for line in input.lines() {
+        let line = line?;
+        let (object, rest) = match line.split_once(char::is_whitespace) {
+            Some((object, rest)) if split_rest => (object, rest.trim_start()),
+            _ => (line.as_str(), ""),
+        };
+        batch_object(object, rest, format, contents, out)?;
+        out.flush()?;
+    }
+    return Ok(());
+}
+
+/// Prints every object in the local object store in `--batch` format
+pub fn catfile_batch_all<W: Write>(format: &str, contents: bool, out: &mut W) -> Result<()> {
+    for sha1 in objects::list_objects()? {
+        batch_object(&sha1, "", format, contents, out)?;
+    }
+    return Ok(());
+}
+
+/// Options of the `hash-object` command
+#[derive(Debug)]
+pub struct HashObjectOptions {
+    pub type_: String,
+    pub write: bool,
+    pub literally: bool,
+    pub no_filters: bool,
+    pub path: Option<String>,
+}
+
+impl Default for HashObjectOptions {
+    fn default() -> Self {
+        return HashObjectOptions {
+            type_: "blob".to_string(),
+            write: false,
+            literally: false,
+            no_filters: false,
+            path: None,
+        };
+    }
+}
+
+impl HashObjectOptions {
+    /// Returns the path used to look up attributes, if filters apply
+    fn filter_path<'a>(&'a self, path: Option<&'a str>) -> Option<&'a str> {
+        if self.no_filters || self.type_ != "blob" {
+            return None;
+        }
+        return self.path.as_deref().or(path);
+    }
+}
+
+/// Hashes (and optionally stores) in-memory content according to the options
+fn hash_content(
+    content: Vec<u8>,
+    path: Option<&str>,
+    options: &HashObjectOptions,
+) -> Result<String> {
+    let content = match options.filter_path(path) {
+        Some(path) => convert_to_git(path, content)?,
+        None => content,
+    };
+    if !options.literally {
+        objects::validate_object(GitObjectType::from_string(&options.type_)?, &content)?;
+    }
+    if options.write {
+        return objects::store_object(&options.type_, &content);
+    }
+    return Ok(objects::calculate_object_hash(&options.type_, &content));
+}
+
+/// Hashes (and optionally stores) the contents of a file
+pub fn hash_file(path: &String, options: &HashObjectOptions) -> Result<String> {
     let mut file = fs::File::open(path)?;
-    let mut content = Vec::new();
-    file.read_to_end(&mut content)?;
-    let type_ = &"blob".to_string();
-    if write {
-        return objects::store_object(type_, &content);
+    let needs_conversion = match options.filter_path(Some(path)) {
+        Some(path) => needs_conversion(path)?,
+        None => false,
+    };
+    if needs_conversion || options.type_ != "blob" {
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        return hash_content(content, Some(path), options);
     }
-    return Ok(objects::calculate_object_hash(type_, &content));
+
+    // plain blobs are streamed so files of any size use bounded memory
+    let size = file.metadata()?.len() as usize;
+    if options.write {
+        return objects::store_object_stream("blob", size, &mut file);
+    }
+    return objects::calculate_object_hash_stream("blob", size, &mut file);
+}
+
+/// Hashes (and optionally stores) the content read from a stream
+pub fn hash_reader<R: Read>(reader: &mut R, options: &HashObjectOptions) -> Result<String> {
+    let mut content = Vec::new();
+    reader.read_to_end(&mut content)?;
+    return hash_content(content, None, options);
+}
+
+pub fn hashobject(path: &String, write: bool) -> Result<String> {
+    let options = HashObjectOptions {
+        write,
+        ..Default::default()
+    };
+    return hash_file(path, &options);
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::files::catfile;
-    use crate::objects::objstore_path;
+    use crate::files::{
+        catfile, catfile_batch, catfile_batch_all, CatFileMode, DEFAULT_BATCH_FORMAT,
+    };
+    use crate::objects::{objstore_path, store_object};
     use crate::testutils;
+    use crate::tree::{committree, writetree};
     use std::fs;
     use std::path;
 
-    use super::hashobject;
+    use super::{hash_file, hash_reader, hashobject, HashObjectOptions};
 
     #[test]
     fn test_hashobject_and_catfile() {
@@ -50,13 +257,207 @@ mod tests {
                 assert_eq!(sha1, sha1_2);
                 assert!(path::Path::new(&objstore_path(&sha1)).exists());
 
-                let read_content = catfile(&sha1).unwrap();
+                let mut read_content = Vec::new();
+                catfile(&sha1, &CatFileMode::Pretty, &mut read_content).unwrap();
+                let read_content = String::from_utf8(read_content).unwrap();
                 assert!(read_content.eq(content));
 
                 let git_read_content =
-                    testutils::get_git_output(&["cat-file", "-p", &sha1.as_str()]);
+                    testutils::get_git_output(&["cat-file", "-p", sha1.as_str()]);
                 assert_eq!(git_read_content, read_content);
             }
         });
     }
+
+    fn catfile_output(object: &String, mode: CatFileMode) -> String {
+        let mut out = Vec::new();
+        catfile(object, &mode, &mut out).unwrap();
+        return String::from_utf8(out).unwrap();
+    }
+
+    #[test]
+    fn test_catfile_modes() {
+        testutils::in_tmp_git(|| {
+            fs::create_dir("dir").unwrap();
+            fs::write("dir/file.txt", "nested\n").unwrap();
+            fs::write("binary.bin", [0u8, 159, 146, 150, 255]).unwrap();
+            let tree = writetree().unwrap();
+            let commit = committree(
+                &"a <a@a.com>".to_string(),
+                &tree,
+                &String::new(),
+                &"message".to_string(),
+            )
+            .unwrap();
+            let tag = store_object(
+                &"tag".to_string(),
+                &format!(
+                    "object {}\ntype commit\ntag v1\ntagger a <a@a.com> 0 +0000\n\nv1\n",
+                    commit
+                )
+                .into_bytes(),
+            )
+            .unwrap();
+
+            for object in [&tree, &commit, &tag] {
+                for (mode, flag) in [
+                    (CatFileMode::Type, "-t"),
+                    (CatFileMode::Size, "-s"),
+                    (CatFileMode::Pretty, "-p"),
+                ] {
+                    assert_eq!(
+                        catfile_output(object, mode),
+                        testutils::get_git_output(&["cat-file", flag, object])
+                    );
+                }
+            }
+            assert_eq!(
+                catfile_output(&"HEAD".to_string(), CatFileMode::Type),
+                "commit\n"
+            );
+
+            let mut out = Vec::new();
+            assert!(catfile(&tree, &CatFileMode::Exists, &mut out).unwrap());
+            assert!(!catfile(&"0".repeat(40), &CatFileMode::Exists, &mut out).unwrap());
+            assert!(out.is_empty());
+            assert!(catfile(&"0".repeat(40), &CatFileMode::Type, &mut out).is_err());
+
+            let input = format!("{}\nmissing\n{} some rest\n", tree, commit);
+            let mut out = Vec::new();
+            catfile_batch(input.as_bytes(), DEFAULT_BATCH_FORMAT, true, &mut out).unwrap();
+            let git_out = testutils::get_git_output_with_stdin(&["cat-file", "--batch"], &input);
+            assert_eq!(out, git_out);
+
+            let mut out = Vec::new();
+            catfile_batch(input.as_bytes(), "%(objecttype) %(rest)", false, &mut out).unwrap();
+            assert_eq!(
+                String::from_utf8(out).unwrap(),
+                "tree \nmissing missing\ncommit some rest\n"
+            );
+
+            let mut out = Vec::new();
+            catfile_batch_all(DEFAULT_BATCH_FORMAT, false, &mut out).unwrap();
+            assert_eq!(
+                String::from_utf8(out).unwrap(),
+                testutils::get_git_output(&["cat-file", "--batch-check", "--batch-all-objects"])
+            );
+
+            // delta bases of packed objects, by offset then by hash
+            let content: String = (0..200).map(|i| format!("line {}\n", i)).collect();
+            fs::write("a.txt", &content).unwrap();
+            fs::write("b.txt", format!("{}end\n", content)).unwrap();
+            testutils::git_commit_all("similar files");
+            let format = "%(objectname) %(deltabase)";
+            let check = format!("--batch-check={}", format);
+            for ofs_delta in ["true", "false"] {
+                let option = format!("repack.useDeltaBaseOffset={}", ofs_delta);
+                testutils::get_git_output(&["-c", &option, "repack", "-a", "-d", "-q"]);
+                let mut out = Vec::new();
+                catfile_batch_all(format, false, &mut out).unwrap();
+                let out = String::from_utf8(out).unwrap();
+                assert!(out.lines().any(|line| !line.ends_with(&"0".repeat(40))));
+                assert_eq!(
+                    out,
+                    testutils::get_git_output(&["cat-file", &check, "--batch-all-objects"])
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_hashobject_options() {
+        testutils::in_tmp_git(|| {
+            fs::write(".gitattributes", "*.txt text\n*.bin -text\n").unwrap();
+            fs::write("crlf.txt", "one\r\ntwo\r\n").unwrap();
+            fs::write("crlf.bin", "one\r\ntwo\r\n").unwrap();
+
+            for (path, extra) in [
+                ("crlf.txt", vec![]),
+                ("crlf.bin", vec![]),
+                ("crlf.txt", vec!["--no-filters"]),
+                ("crlf.bin", vec!["--path=crlf.txt"]),
+            ] {
+                let options = HashObjectOptions {
+                    no_filters: extra.contains(&"--no-filters"),
+                    path: extra
+                        .iter()
+                        .find_map(|e| e.strip_prefix("--path="))
+                        .map(|p| p.to_string()),
+                    ..Default::default()
+                };
+                let mut args = vec!["hash-object"];
+                args.extend(extra.iter());
+                args.push(path);
+                assert_eq!(
+                    hash_file(&path.to_string(), &options).unwrap(),
+                    testutils::get_git_output(&args).trim(),
+                    "{} {:?}",
+                    path,
+                    extra
+                );
+            }
+
+            let options = HashObjectOptions::default();
+            let stdin_hash = hash_reader(&mut "stdin content\n".as_bytes(), &options).unwrap();
+            let git_hash = testutils::get_git_output_with_stdin(
+                &["hash-object", "--stdin"],
+                "stdin content\n",
+            );
+            assert_eq!(stdin_hash, String::from_utf8(git_hash).unwrap().trim());
+
+            let blob = hashobject(&"crlf.bin".to_string(), true).unwrap();
+            let mut tree = b"100644 crlf.bin\0".to_vec();
+            tree.extend(hex::decode(&blob).unwrap());
+            let options = HashObjectOptions {
+                type_: "tree".to_string(),
+                write: true,
+                ..Default::default()
+            };
+            let tree_hash = hash_reader(&mut tree.as_slice(), &options).unwrap();
+            assert_eq!(
+                testutils::get_git_output(&["cat-file", "-t", &tree_hash]),
+                "tree\n"
+            );
+
+            let commit = format!(
+                "tree {}\nauthor a <a@a.com> 1 +0000\ncommitter a <a@a.com> 1 +0000\n\nmsg\n",
+                tree_hash
+            );
+            let options = HashObjectOptions {
+                type_: "commit".to_string(),
+                ..Default::default()
+            };
+            assert!(hash_reader(&mut commit.as_bytes(), &options).is_ok());
+            let broken = commit.replace("committer", "commiter");
+            assert!(hash_reader(&mut broken.as_bytes(), &options).is_err());
+            assert!(hash_reader(&mut b"garbage".as_slice(), &options).is_err());
+
+            let options = HashObjectOptions {
+                type_: "commit".to_string(),
+                literally: true,
+                ..Default::default()
+            };
+            let literal = hash_reader(&mut b"garbage".as_slice(), &options).unwrap();
+            let git_literal = testutils::get_git_output_with_stdin(
+                &["hash-object", "-t", "commit", "--literally", "--stdin"],
+                "garbage",
+            );
+            assert_eq!(literal, String::from_utf8(git_literal).unwrap().trim());
+
+            let options = HashObjectOptions {
+                type_: "tag".to_string(),
+                ..Default::default()
+            };
+            let tag = format!(
+                "object {}\ntype tree\ntag v1\ntagger a <a@a.com> 1 +0000\n\nmsg\n",
+                tree_hash
+            );
+            assert!(hash_reader(&mut tag.as_bytes(), &options).is_ok());
+            let options = HashObjectOptions {
+                type_: "bogus".to_string(),
+                ..Default::default()
+            };
+            assert!(hash_reader(&mut b"x".as_slice(), &options).is_err());
+        });
+    }
 }
@@ -0,0 +1,649 @@
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::commitgraph::{write_commit_graph, CommitGraphOptions};
+use crate::config::Config;
+use crate::midx;
+use crate::objects::{object_exists, objstore_path, read_object, store_object_stream};
+use crate::packs::{self, PackIndex, PackOptions, PACK_DIR};
+use crate::protocol::ZERO_ID;
+use crate::refs::{is_sha1, list_refs, pack_refs, read_ref};
+use crate::repo_path;
+use crate::revwalk::list_objects_with_paths;
+use crate::shallow::parse_since;
+
+/// Unreachable objects younger than this are kept, `gc.pruneExpire`
+/// overrides it
+const DEFAULT_PRUNE_EXPIRE: &str = "2.weeks.ago";
+
+/// Options of `repack`
+#[derive(Debug, Clone, Default)]
+pub struct RepackOptions {
+    /// Pack every reachable object in a single pack, not only loose ones
+    pub all: bool,
+    /// With `all`, write the unreachable objects of the replaced packs as
+    /// loose objects instead of dropping them, so `prune` decides of them
+    pub keep_unreachable: bool,
+    /// Remove the packs and loose objects made redundant by the new pack
+    pub delete: bool,
+    /// Don't report what was packed on stderr
+    pub quiet: bool,
+}
+
+/// Options of `gc`
+#[derive(Debug, Clone, Default)]
+pub struct GcOptions {
+    /// Prune unreachable loose objects older than this date, `gc.pruneExpire`
+    /// (or two weeks ago) when not given
+    pub prune: Option<String>,
+    /// Don't prune any object
+    pub no_prune: bool,
+    pub quiet: bool,
+}
+
+/// Parses an expiry date like git: `now`, `never`, `<n>.<unit>.ago` (units
+/// from seconds to years, also with spaces) or an absolute date. Returns the
+/// unix time objects must be older than, `None` for `never`.
+pub fn parse_expire(date: &str) -> Result<Option<i64>> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    match date {
+        "now" => return Ok(Some(now)),
+        "never" | "false" => return Ok(None),
+        _ => {}
+    }
+    let words: Vec<&str> = date.split(['.', ' ']).collect();
+    if let [count, unit, "ago"] = words.as_slice() {
+        let count: i64 = match count.parse() {
+            Ok(count) => count,
+            Err(_) => bail!("invalid expiry date '{}'", date),
+        };
+        let seconds = match unit.trim_end_matches('s') {
+            "second" => 1,
+            "minute" => 60,
+            "hour" => 60 * 60,
+            "day" => 24 * 60 * 60,
+            "week" => 7 * 24 * 60 * 60,
+            "month" => 30 * 24 * 60 * 60,
+            "year" => 365 * 24 * 60 * 60,
+            _ => bail!("invalid expiry date '{}'", date),
+        };
+        return Ok(Some(now - count * seconds));
+    }
+    return Ok(Some(parse_since(date)?));
+}
+
+/// Pseudo refs of operations in progress or just done, which keep their
+/// objects like refs do
+const PSEUDO_REFS: [&str; 4] = ["ORIG_HEAD", "FETCH_HEAD", "MERGE_HEAD", "CHERRY_PICK_HEAD"];
+
+/// Returns the objects reachable from HEAD, every ref, the pseudo refs, the
+/// reflogs and the index, with their paths
+pub fn reachable_objects() -> Result<Vec<(String, String)>> {
+    let mut tips = Vec::new();
+    if let Some(head) = read_ref("HEAD")? {
+        tips.push(head);
+    }
+    tips.extend(list_refs("refs/")?.into_iter().map(|(_, sha1)| sha1));
+    let mut others = Vec::new();
+    for name in PSEUDO_REFS {
+        // FETCH_HEAD and MERGE_HEAD hold a hash at the start of each line
+        if let Ok(content) = fs::read_to_string(repo_path(".git").join(name)) {
+            others.extend(
+                content
+                    .lines()
+                    .filter_map(|l| l.get(..40))
+                    .map(String::from),
+            );
+        }
+    }
+    others.extend(reflog_objects(&repo_path(".git/logs"))?);
+    others.extend(index_objects()?);
+    // unlike refs these may name objects already gone, which are skipped
+    for sha1 in others {
+        if is_sha1(&sha1) && object_exists(&sha1)? {
+            tips.push(sha1);
+        }
+    }
+    tips.sort();
+    tips.dedup();
+    return list_objects_with_paths(&tips, &[]);
+}
+
+/// Returns the old and new values of the entries of the reflogs under `dir`
+fn reflog_objects(dir: &Path) -> Result<Vec<String>> {
+    let mut objects = Vec::new();
+    if !dir.is_dir() {
+        return Ok(objects);
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            objects.extend(reflog_objects(&path)?);
+            continue;
+        }
+        for line in fs::read_to_string(&path)?.lines() {
+            let mut fields = line.splitn(3, ' ');
+            for sha1 in [fields.next(), fields.next()].into_iter().flatten() {
+                if sha1 != ZERO_ID {
+                    objects.push(sha1.to_string());
+                }
+            }
+        }
+    }
+    return Ok(objects);
+}
+
+/// Returns the blobs staged in `.git/index`, and the trees of its cache-tree
+/// extension
+fn index_objects() -> Result<Vec<String>> {
+    let mut objects = Vec::new();
+    let data = match fs::read(repo_path(".git/index")) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(objects),
+        Err(e) => return Err(e.into()),
+    };
+    if data.len() < 32 || &data[..4] != b"DIRC" {
+        bail!("index file is corrupt");
+    }
+    let version = u32::from_be_bytes(data[4..8].try_into()?);
+    if !(2..=4).contains(&version) {
+        bail!("index version {} is not supported", version);
+    }
+    let count = u32::from_be_bytes(data[8..12].try_into()?);
+    // the index ends with its checksum
+    let end = data.len() - 20;
+    let mut position = 12;
+    for _ in 0..count {
+        let start = position;
+        let header = match data.get(position..position + 62) {
+            Some(header) if position + 62 <= end => header,
+            _ => bail!("index file is corrupt"),
+        };
+        let mode = u32::from_be_bytes(header[24..28].try_into()?);
+        let flags = u16::from_be_bytes(header[60..62].try_into()?);
+        // gitlinks are commits of submodules, not objects of this repository
+        if mode & 0o170000 != 0o160000 {
+            objects.push(hex::encode(&header[40..60]));
+        }
+        position += 62;
+        if version >= 3 && flags & 0x4000 != 0 {
+            position += 2;
+        }
+        if version == 4 {
+            // the path is prefix-compressed after a varint
+            while data.get(position).is_some_and(|b| b & 0x80 != 0) {
+                position += 1;
+            }
+            position += 1;
+        }
+        match data
+            .get(position..end)
+            .and_then(|d| d.iter().position(|&b| b == 0))
+        {
+            Some(len) => position += len + 1,
+            None => bail!("index file is corrupt"),
+        }
+        if version < 4 {
+            // entries are padded with NULs to a multiple of 8 bytes
+            position = start + (position - start).div_ceil(8) * 8;
+        }
+    }
+
+    while position + 8 <= end {
+        let size = u32::from_be_bytes(data[position + 4..position + 8].try_into()?) as usize;
+        let extension = match data.get(position + 8..position + 8 + size) {
+            Some(extension) if position + 8 + size <= end => extension,
+            _ => bail!("index file is corrupt"),
+        };
+        if &data[position..position + 4] == b"TREE" {
+            objects.extend(cache_tree_objects(extension)?);
+        }
+        position += 8 + size;
+    }
+    return Ok(objects);
+}
+
+/// Returns the trees of a cache-tree index extension: each entry is a path,
+/// its entry and subtree counts, then its hash unless it was invalidated
+fn cache_tree_objects(data: &[u8]) -> Result<Vec<String>> {
+    let mut trees = Vec::new();
+    let mut position = 0;
+    while position < data.len() {
+        let counts = match data[position..].iter().position(|&b| b == 0) {
+            Some(len) => position + len + 1,
+            None => bail!("index cache-tree is corrupt"),
+        };
+        let line_end = match data[counts..].iter().position(|&b| b == b'\n') {
+            Some(len) => counts + len,
+            None => bail!("index cache-tree is corrupt"),
+        };
+        let line = String::from_utf8_lossy(&data[counts..line_end]);
+        position = line_end + 1;
+        if line.starts_with('-') {
+            continue;
+        }
+        match data.get(position..position + 20) {
+            Some(hash) => trees.push(hex::encode(hash)),
+            None => bail!("index cache-tree is corrupt"),
+        }
+        position += 20;
+    }
+    return Ok(trees);
+}
+
+/// Returns the hashes and paths of the loose objects of the local store
+fn list_loose_objects() -> Result<Vec<(String, PathBuf)>> {
+    let mut loose = Vec::new();
+    let root = &repo_path(".git/objects");
+    if !root.is_dir() {
+        return Ok(loose);
+    }
+    for dir in fs::read_dir(root)? {
+        let dir = dir?;
+        let dirname = dir.file_name().to_string_lossy().to_string();
+        if dirname.len() != 2 || !dirname.chars().all(|c| c.is_ascii_hexdigit()) {
+            continue;
+        }
+        for file in fs::read_dir(dir.path())? {
+            let file = file?;
+            let sha1 = format!("{}{}", dirname, file.file_name().to_string_lossy());
+            if sha1.len() == 40 {
+                loose.push((sha1, file.path()));
+            }
+        }
+    }
+    loose.sort();
+    return Ok(loose);
+}
+
+/// Removes a loose object, and its fanout directory once empty
+fn remove_loose_object(path: &Path) -> Result<()> {
+    fs::remove_file(path)?;
+    if let Some(dir) = path.parent() {
+        // fails when other objects are left
+        let _ = fs::remove_dir(dir);
+    }
+    return Ok(());
+}
+
+/// Whether a pack must be left alone: kept with a `.keep` file, or holding
+/// objects of a promisor remote whose references may be missing
+fn is_kept_pack(index: &Path) -> bool {
+    return index.with_extension("keep").exists() || index.with_extension("promisor").exists();
+}
+
+/// Removes the loose objects that are also in a pack, returns their number
+pub fn prune_packed() -> Result<usize> {
+    let packed: HashSet<String> = packs::list_packed_objects()?.into_iter().collect();
+    let mut removed = 0;
+    for (sha1, path) in list_loose_objects()? {
+        if packed.contains(&sha1) {
+            remove_loose_object(&path)?;
+            removed += 1;
+        }
+    }
+    return Ok(removed);
+}
+
+/// Writes the objects of a pack that are not reachable as loose objects
+/// dated like the pack, unless they are stored elsewhere
+fn loosen_unreachable(
+    index_path: &Path,
+    reachable: &HashSet<String>,
+    packed: &HashSet<String>,
+) -> Result<()> {
+    let modified = fs::metadata(index_path.with_extension("pack"))?.modified()?;
+    for sha1 in PackIndex::open(index_path)?.hashes()? {
+        if reachable.contains(&sha1)
+            || packed.contains(&sha1)
+            || Path::new(&objstore_path(&sha1)).exists()
+        {
+            continue;
+        }
+        let (type_, data) = read_object(&sha1)?;
+        // written loose even though packed, the pack goes away
+        store_object_stream(&type_.to_string(), data.len(), &mut data.as_slice())?;
+        fs::File::open(objstore_path(&sha1))?.set_modified(modified)?;
+    }
+    return Ok(());
+}
+
+/// Packs the reachable loose objects, or with `options.all` every reachable
+/// object, in a new pack of the local object store. Packs with a `.keep` or
+/// `.promisor` file are left as they are. Returns the hash of the new pack,
+/// `None` when there was nothing to pack.
+pub fn repack(options: &RepackOptions) -> Result<Option<String>> {
+    let reachable = reachable_objects()?;
+    let mut kept = HashSet::new();
+    let mut packed = HashSet::new();
+    let indexes = packs::list_pack_indexes()?;
+    for path in indexes.iter() {
+        let hashes = PackIndex::open(path)?.hashes()?;
+        match is_kept_pack(path) {
+            true => kept.extend(hashes),
+            false => packed.extend(hashes),
+        }
+    }
+    let mut selected = Vec::new();
+    for (sha1, path) in reachable.iter() {
+        if kept.contains(sha1) {
+            continue;
+        }
+        // objects left out of a partial clone stay out
+        let wanted = match options.all {
+            true => packed.contains(sha1) || Path::new(&objstore_path(sha1)).exists(),
+            false => !packed.contains(sha1) && Path::new(&objstore_path(sha1)).exists(),
+        };
+        if wanted {
+            selected.push((sha1.clone(), path.clone()));
+        }
+    }
+
+    let mut new_pack = None;
+    if !selected.is_empty() {
+        let packfile =
+            packs::store_pack(packs::build_pack(&selected, &PackOptions::default())?.as_slice())?;
+        if !options.quiet {
+            eprintln!(
+                "Packed {} objects into pack-{}",
+                packfile.entries.len(),
+                packfile.sha1
+            );
+        }
+        new_pack = Some(packfile.sha1);
+    }
+    if !options.delete {
+        return Ok(new_pack);
+    }
+
+    if options.all {
+        let reachable: HashSet<String> = reachable.into_iter().map(|(sha1, _)| sha1).collect();
+        let new_name = new_pack.as_ref().map(|sha1| format!("pack-{}.idx", sha1));
+        for path in indexes.iter() {
+            let is_new = path.file_name().and_then(|n| n.to_str()) == new_name.as_deref();
+            if is_new || is_kept_pack(path) {
+                continue;
+            }
+            if options.keep_unreachable {
+                loosen_unreachable(path, &reachable, &kept)?;
+            }
+            // the index goes first, packs are only looked up through it
+            midx::forget_pack(path)?;
+            fs::remove_file(path)?;
+            for extension in ["pack", "rev", "bitmap"] {
+                let file = path.with_extension(extension);
+                if file.exists() {
+                    fs::remove_file(file)?;
+                }
+            }
+        }
+    }
+    prune_packed()?;
+    return Ok(new_pack);
+}
+
+/// Removes the unreachable loose objects, and the temporary files left in
+/// the object store, last modified before `expire`. Returns the hashes of
+/// the removed objects; with `dry_run` they are only listed.
+pub fn prune(expire: i64, dry_run: bool) -> Result<Vec<String>> {
+    // compared in whole seconds, `now` covers files written this second
+    let expire = UNIX_EPOCH + Duration::from_secs(expire.max(0) as u64 + 1);
+    let reachable: HashSet<String> = reachable_objects()?
+        .into_iter()
+        .map(|(sha1, _)| sha1)
+        .collect();
+    let mut pruned = Vec::new();
+    for (sha1, path) in list_loose_objects()? {
+        if reachable.contains(&sha1) || fs::metadata(&path)?.modified()? >= expire {
+            continue;
+        }
+        if !dry_run {
+            remove_loose_object(&path)?;
+        }
+        pruned.push(sha1);
+    }
+    if dry_run {
+        return Ok(pruned);
+    }
+    for dir in [repo_path(".git/objects"), repo_path(PACK_DIR)] {
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let temporary = entry.file_name().to_string_lossy().starts_with("tmp_");
+            if temporary && entry.file_type()?.is_file() && entry.metadata()?.modified()? < expire {
+                fs::remove_file(entry.path())?;
+            }
+        }
+    }
+    return Ok(pruned);
+}
+
+/// Cleans up the repository: packs the refs, repacks every reachable object
+/// in a single pack, keeping the unreachable ones loose, then prunes the
+/// unreachable objects older than the expiry date
+pub fn gc(options: &GcOptions) -> Result<()> {
+    let expire = match (&options.prune, options.no_prune) {
+        (_, true) => None,
+        (Some(date), false) => parse_expire(date)?,
+        (None, false) => {
+            let config = Config::load()?;
+            parse_expire(config.get("gc.pruneExpire").unwrap_or(DEFAULT_PRUNE_EXPIRE))?
+        }
+    };
+    pack_refs()?;
+    let repack_options = RepackOptions {
+        all: true,
+        keep_unreachable: true,
+        delete: true,
+        quiet: options.quiet,
+    };
+    repack(&repack_options)?;
+    if Config::load()?.get_bool("gc.writeCommitGraph")? != Some(false) {
+        let graph_options = CommitGraphOptions {
+            reachable: true,
+            ..Default::default()
+        };
+        write_commit_graph(&graph_options)?;
+    }
+    if let Some(expire) = expire {
+        let pruned = prune(expire, false)?;
+        if !options.quiet && !pruned.is_empty() {
+            eprintln!("Pruned {} unreachable objects", pruned.len());
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gc::{gc, parse_expire, prune, repack, GcOptions, RepackOptions};
+    use crate::objects::objstore_path;
+    use crate::testutils;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::time::{Duration, SystemTime};
+
+    fn count_objects() -> String {
+        return testutils::get_git_output(&["count-objects", "-v"]);
+    }
+
+    fn set_age(path: &Path, days: u64) {
+        let modified = SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60);
+        fs::File::open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    fn list_packs() -> Vec<PathBuf> {
+        let mut packs: Vec<PathBuf> = fs::read_dir(".git/objects/pack")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|e| e == "pack"))
+            .collect();
+        packs.sort();
+        return packs;
+    }
+
+    #[test]
+    fn test_gc() {
+        testutils::in_tmp_dir(|| {
+            testutils::create_repo(".", &[("a.txt", "a\n")]);
+            // reflogs would keep the dropped commit below
+            testutils::get_git_output(&["config", "core.logAllRefUpdates", "false"]);
+            fs::remove_dir_all(".git/logs").unwrap();
+            let head = testutils::commit_files(".", &[("b.txt", "b\n")], "second");
+            testutils::tag_in(".", "v1");
+            testutils::get_git_output(&["branch", "side"]);
+            let hash = |content: &str| {
+                testutils::get_git_output_with_stdin(&["hash-object", "-w", "--stdin"], content)
+            };
+            let old = String::from_utf8(hash("old\n")).unwrap().trim().to_string();
+            let recent = String::from_utf8(hash("recent\n"))
+                .unwrap()
+                .trim()
+                .to_string();
+            set_age(Path::new(&objstore_path(&old)), 30);
+
+            // incremental repack of the loose reachable objects
+            let options = RepackOptions {
+                delete: true,
+                quiet: true,
+                ..Default::default()
+            };
+            repack(&options).unwrap().unwrap();
+            assert!(Path::new(&objstore_path(&recent)).exists());
+            assert!(!Path::new(&objstore_path(&head)).exists());
+            assert!(repack(&options).unwrap().is_none());
+
+            // an unreachable commit in a pack becomes loose, then is pruned
+            testutils::commit_files(".", &[("c.txt", "c\n")], "dropped");
+            let before = list_packs();
+            testutils::get_git_output(&["repack", "-q", "-d"]);
+            testutils::get_git_output(&["reset", "-q", "--hard", "HEAD~1"]);
+            for pack in list_packs() {
+                if !before.contains(&pack) {
+                    set_age(&pack, 30);
+                }
+            }
+
+            let options = GcOptions {
+                quiet: true,
+                ..Default::default()
+            };
+            gc(&options).unwrap();
+            let counts = count_objects();
+            assert!(counts.contains("count: 1\n"), "{}", counts);
+            assert!(counts.contains("packs: 1\n"), "{}", counts);
+            assert!(Path::new(&objstore_path(&recent)).exists());
+            assert!(!Path::new(&objstore_path(&old)).exists());
+            assert!(testutils::get_git_output(&["fsck", "--no-dangling"]).is_empty());
+            assert!(Path::new(".git/objects/info/commit-graph").exists());
+            let verify = Command::new("git")
+                .args(["commit-graph", "verify"])
+                .status()
+                .unwrap();
+            assert!(verify.success());
+            assert_eq!(
+                testutils::get_git_output(&["rev-parse", "HEAD"]).trim(),
+                head
+            );
+
+            let packed_refs = fs::read_to_string(".git/packed-refs").unwrap();
+            let tag = testutils::get_git_output(&["rev-parse", "v1"]);
+            assert!(packed_refs.starts_with("# pack-refs with: peeled fully-peeled sorted \n"));
+            assert!(packed_refs.contains(&format!("{} refs/heads/side\n", head)));
+            assert!(
+                packed_refs.contains(&format!("{} refs/tags/v1\n^{}\n", tag.trim(), head)),
+                "{}",
+                packed_refs
+            );
+            assert!(!Path::new(".git/refs/heads/side").exists());
+            assert_eq!(
+                testutils::get_git_output(&["show-ref", "--heads"]),
+                format!("{} refs/heads/master\n{} refs/heads/side\n", head, head)
+            );
+
+            assert_eq!(
+                prune(parse_expire("now").unwrap().unwrap(), true).unwrap(),
+                [recent.as_str()]
+            );
+            assert!(Path::new(&objstore_path(&recent)).exists());
+            let options = GcOptions {
+                prune: Some("now".to_string()),
+                quiet: true,
+                ..Default::default()
+            };
+            gc(&options).unwrap();
+            assert!(!Path::new(&objstore_path(&recent)).exists());
+            assert!(count_objects().contains("count: 0\n"));
+        });
+    }
+
+    #[test]
+    fn test_gc_roots() {
+        testutils::in_tmp_dir(|| {
+            testutils::create_repo(".", &[("a.txt", "a\n")]);
+            let hash = |content: &str| {
+                let output = testutils::get_git_output_with_stdin(
+                    &["hash-object", "-w", "--stdin"],
+                    content,
+                );
+                return String::from_utf8(output).unwrap().trim().to_string();
+            };
+            let pruned = || prune(parse_expire("now").unwrap().unwrap(), true).unwrap();
+            let unreachable = hash("unreachable\n");
+
+            // a commit only left in the reflogs of HEAD and master
+            let dropped = testutils::commit_files(".", &[("b.txt", "b\n")], "dropped");
+            testutils::get_git_output(&["reset", "-q", "--hard", "HEAD~1"]);
+            fs::remove_file(".git/ORIG_HEAD").unwrap();
+            // a staged blob, in a tree only known to the cache-tree
+            fs::write("c.txt", "c\n").unwrap();
+            testutils::get_git_output(&["add", "c.txt"]);
+            let staged = hash("c\n");
+            let tree = testutils::get_git_output(&["write-tree"])
+                .trim()
+                .to_string();
+            // objects of pseudo refs
+            let orig = hash("orig\n");
+            let fetched = hash("fetched\n");
+            fs::write(".git/ORIG_HEAD", format!("{}\n", orig)).unwrap();
+            let fetch_head = format!("{}\t\tbranch 'master' of ../remote\n", fetched);
+            fs::write(".git/FETCH_HEAD", fetch_head).unwrap();
+
+            assert_eq!(pruned(), [unreachable.as_str()]);
+            testutils::get_git_output(&["update-index", "--index-version", "4"]);
+            assert_eq!(pruned(), [unreachable.as_str()]);
+
+            fs::remove_dir_all(".git/logs").unwrap();
+            fs::remove_file(".git/ORIG_HEAD").unwrap();
+            fs::remove_file(".git/FETCH_HEAD").unwrap();
+            fs::remove_file(".git/index").unwrap();
+            let pruned = pruned();
+            for sha1 in [unreachable, dropped, staged, tree, orig, fetched] {
+                assert!(pruned.contains(&sha1), "{} not in {:?}", sha1, pruned);
+            }
+        });
+    }
+
+    #[test]
+    fn test_parse_expire() {
+        let now = parse_expire("now").unwrap().unwrap();
+        assert_eq!(parse_expire("never").unwrap(), None);
+        assert_eq!(
+            parse_expire("2.weeks.ago").unwrap().unwrap(),
+            now - 14 * 24 * 3600
+        );
+        assert_eq!(parse_expire("1 hour ago").unwrap().unwrap(), now - 3600);
+        assert_eq!(parse_expire("2020-01-01").unwrap(), Some(1577836800));
+        assert!(parse_expire("2.fortnights.ago").is_err());
+    }
+}
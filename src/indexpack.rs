@@ -0,0 +1,1235 @@
+use anyhow::{anyhow, bail, Result};
+use flate2::bufread::ZlibDecoder;
+use sha1::Digest;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::objects::{self, calculate_object_hash, GitObjectType};
+use crate::packs::{self, Entry, ObjectType, Packfile, MAX_OBJECT_SIZE, PACK_DIR};
+use crate::repo_path;
+
+/// Options of `index-pack`
+#[derive(Debug, Clone)]
+pub struct IndexPackOptions {
+    /// Complete a thin pack read from stdin with the delta bases it lacks,
+    /// taken from the local object store
+    pub fix_thin: bool,
+    /// Threads resolving deltas, 0 for one per CPU
+    pub threads: usize,
+    /// Bytes of delta bases kept in memory, shared between the threads.
+    /// Evicted bases are rebuilt from the pack when needed again.
+    pub delta_base_cache_limit: usize,
+    /// Show progress on stderr
+    pub progress: bool,
+    /// Check that every object of the pack is well formed
+    pub strict: bool,
+    /// Bytes the entries of the pack may inflate to in total, deltas
+    /// resolved, None for no limit. Objects are limited one by one, this
+    /// keeps a small pack from declaring many of the largest ones.
+    pub max_inflated_size: Option<usize>,
+    /// Largest object, or delta, accepted: every resolver thread holds a
+    /// base and the object resolved from it
+    pub max_object_size: usize,
+}
+
+impl Default for IndexPackOptions {
+    fn default() -> Self {
+        return Self {
+            fix_thin: false,
+            threads: 0,
+            delta_base_cache_limit: 96 << 20,
+            progress: false,
+            strict: false,
+            max_inflated_size: None,
+            max_object_size: MAX_OBJECT_SIZE,
+        };
+    }
+}
+
+/// Called with the content of every object of a pack while it is indexed
+type ObjectVisitor<'a> = &'a (dyn Fn(ObjectType, &[u8]) -> Result<()> + Sync);
+
+/// Base of a delta entry: a hash for REF_DELTA, an offset for OFS_DELTA
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum DeltaBase {
+    Hash(String),
+    Offset(usize),
+}
+
+/// Where the entries of a pack are read again while resolving deltas
+#[derive(Clone, Copy)]
+pub enum PackSource<'a> {
+    Memory(&'a [u8]),
+    File(&'a File),
+}
+
+/// Reads a file from an offset without moving its cursor, so that threads
+/// can share it
+struct FileReader<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+impl Read for FileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read_at(buf, self.offset)?;
+        self.offset += read as u64;
+        return Ok(read);
+    }
+}
+
+/// Inflates the zlib stream at the start of `input` into `out`, checking
+/// that it holds `size` bytes. One byte past the declared size is enough to
+/// reject the stream, so nothing larger is ever inflated.
+fn inflate_entry<R: BufRead, W: Write>(input: R, size: usize, out: &mut W) -> Result<()> {
+    let mut decoder = ZlibDecoder::new(input);
+    let inflated = match io::copy(&mut (&mut decoder).take(size as u64 + 1), out) {
+        Ok(inflated) => inflated as usize,
+        Err(e) => bail!("corrupt zlib stream: {}", e),
+    };
+    if inflated > size {
+        bail!("inflates to more than {} bytes", size);
+    }
+    if inflated != size {
+        bail!("inflates to {} bytes, expected {}", inflated, size);
+    }
+    return Ok(());
+}
+
+impl PackSource<'_> {
+    /// Inflates the `size` bytes of the entry whose compressed data starts
+    /// at `offset`
+    fn read(&self, offset: usize, size: usize) -> Result<Vec<u8>> {
+        let mut content = Vec::with_capacity(size);
+        match self {
+            PackSource::Memory(data) => {
+                inflate_entry(data.get(offset..).unwrap_or_default(), size, &mut content)?
+            }
+            PackSource::File(file) => {
+                let reader = FileReader {
+                    file,
+                    offset: offset as u64,
+                };
+                inflate_entry(BufReader::new(reader), size, &mut content)?
+            }
+        }
+        return Ok(content);
+    }
+}
+
+/// Progress of a phase of indexing, shown on stderr as a percentage
+struct Progress {
+    title: &'static str,
+    total: usize,
+    done: AtomicUsize,
+    shown: AtomicUsize,
+    show: bool,
+}
+
+impl Progress {
+    fn new(title: &'static str, total: usize, show: bool) -> Self {
+        return Self {
+            title,
+            total,
+            done: AtomicUsize::new(0),
+            shown: AtomicUsize::new(usize::MAX),
+            show: show && total > 0,
+        };
+    }
+
+    fn tick(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        if !self.show {
+            return;
+        }
+        let percent = done * 100 / self.total;
+        if self.shown.swap(percent, Ordering::Relaxed) != percent {
+            eprint!("\r{}: {:3}% ({}/{})", self.title, percent, done, self.total);
+        }
+    }
+
+    fn finish(&self) {
+        if self.show {
+            let done = self.done.load(Ordering::Relaxed);
+            eprintln!("\r{}: 100% ({}/{}), done.", self.title, done, self.total);
+        }
+    }
+}
+
+/// Reader of a pack being indexed: hashes the bytes read, computes the CRC
+/// of the current entry and copies them to the output file
+struct PackStream<'a, R: BufRead> {
+    input: R,
+    /// End of the entries, when the length of the pack is known
+    limit: Option<usize>,
+    offset: usize,
+    hash: sha1::Sha1,
+    crc32: crc32fast::Hasher,
+    output: Option<BufWriter<&'a File>>,
+    /// First error writing the output, `consume` can't return it
+    write_error: Option<io::Error>,
+}
+
+impl<R: BufRead> Read for PackStream<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+        return Ok(read);
+    }
+}
+
+impl<R: BufRead> BufRead for PackStream<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let buf = self.input.fill_buf()?;
+        return Ok(match self.limit {
+            Some(limit) => &buf[..buf.len().min(limit - self.offset)],
+            None => buf,
+        });
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // the bytes are still buffered, this doesn't read
+        if let Ok(buf) = self.input.fill_buf() {
+            let bytes = &buf[..amt];
+            self.hash.update(bytes);
+            self.crc32.update(bytes);
+            if let (Some(output), None) = (&mut self.output, &self.write_error) {
+                self.write_error = output.write_all(bytes).err();
+            }
+        }
+        self.input.consume(amt);
+        self.offset += amt;
+    }
+}
+
+/// Entries of a pack read by the first pass, with the offset of their
+/// compressed data to read them again while resolving deltas. The hash,
+/// type and size of deltas are only known once resolved.
+struct Scan {
+    sha1: String,
+    entries: Vec<Entry>,
+    data_offsets: Vec<usize>,
+    bases: Vec<Option<DeltaBase>>,
+    /// Offset of the trailer
+    end: usize,
+    /// Bytes inflated so far, resolved deltas included, and the limit
+    inflated: AtomicUsize,
+    max_inflated: Option<usize>,
+    /// Largest size of an entry or of an object resolved from a delta
+    max_object: usize,
+}
+
+impl Scan {
+    /// Counts bytes about to be inflated, failing past the limit
+    fn count_inflated(&self, size: usize) -> Result<()> {
+        let total = self.inflated.fetch_add(size, Ordering::Relaxed) + size;
+        if let Some(max) = self.max_inflated.filter(|max| total > *max) {
+            bail!("pack inflates to more than {} bytes", max);
+        }
+        return Ok(());
+    }
+}
+
+/// Reads the entry at the current offset of `stream`. Whole objects are
+/// hashed while inflated, deltas only checked.
+fn read_entry<R: BufRead>(stream: &mut PackStream<R>, scan: &mut Scan) -> Result<()> {
+    let offset = stream.offset;
+    stream.crc32 = crc32fast::Hasher::new();
+    let header = match packs::read_varint(stream) {
+        Ok(header) => header,
+        Err(e) => bail!("entry at offset {}: {}", offset, e),
+    };
+    let type_ = match ObjectType::from_u8((header[0] & 0b01110000) >> 4) {
+        Ok(type_) => type_,
+        Err(e) => bail!("entry at offset {}: {}", offset, e),
+    };
+    let size = match packs::parse_size_encoding(&header, 0, 4) {
+        Ok((_, size)) => size,
+        Err(e) => bail!("entry at offset {}: {}", offset, e),
+    };
+    if size > scan.max_object {
+        bail!(
+            "entry at offset {} has {} bytes, more than the limit of {}",
+            offset,
+            size,
+            scan.max_object
+        );
+    }
+    scan.count_inflated(size)?;
+    let base = match type_ {
+        ObjectType::OfsDelta => {
+            let distance = match packs::read_varint(stream)
+                .and_then(|encoded| packs::parse_ofs_encoding(&encoded, 0))
+            {
+                Ok((_, distance)) => distance,
+                Err(e) => bail!("delta at offset {}: {}", offset, e),
+            };
+            match offset.checked_sub(distance).filter(|_| distance > 0) {
+                Some(base) => Some(DeltaBase::Offset(base)),
+                None => bail!("ofs-delta base at offset {} not found", offset),
+            }
+        }
+        ObjectType::RefDelta => {
+            let mut sha = [0u8; 20];
+            if let Err(e) = stream.read_exact(&mut sha) {
+                bail!("delta at offset {}: truncated hash: {}", offset, e);
+            }
+            Some(DeltaBase::Hash(hex::encode(sha)))
+        }
+        _ => None,
+    };
+
+    let data_offset = stream.offset;
+    let sha1 = match base {
+        Some(_) => match inflate_entry(&mut *stream, size, &mut io::sink()) {
+            Ok(()) => String::new(),
+            Err(e) => bail!("delta at offset {}: {}", offset, e),
+        },
+        None => {
+            let mut hash = sha1::Sha1::new();
+            hash.update(format!("{} {}\0", type_, size));
+            match inflate_entry(&mut *stream, size, &mut hash) {
+                Ok(()) => hex::encode(hash.finalize()),
+                Err(e) => bail!("object at offset {}: {}", offset, e),
+            }
+        }
+    };
+    scan.entries.push(Entry {
+        type_,
+        size,
+        sha1,
+        offset,
+        crc32: stream.crc32.clone().finalize(),
+        stored_size: size,
+        packed_size: stream.offset - offset,
+        base: None,
+        depth: 0,
+    });
+    scan.data_offsets.push(data_offset);
+    scan.bases.push(base);
+    return Ok(());
+}
+
+/// First pass over a pack: reads its entries, checking their size and the
+/// trailer checksum, and copies the pack to `output`. `len` is the length of
+/// the pack when known, all of it must then be entries.
+fn scan<R: BufRead>(
+    input: R,
+    len: Option<usize>,
+    output: Option<&File>,
+    show_progress: bool,
+    max_inflated: Option<usize>,
+    max_object: usize,
+) -> Result<Scan> {
+    let limit = match len {
+        Some(len) if len < 32 => bail!("pack of {} bytes is too short", len),
+        Some(len) => Some(len - 20),
+        None => None,
+    };
+    let mut stream = PackStream {
+        input,
+        limit,
+        offset: 0,
+        hash: sha1::Sha1::new(),
+        crc32: crc32fast::Hasher::new(),
+        output: output.map(BufWriter::new),
+        write_error: None,
+    };
+    let mut header = [0u8; 12];
+    if let Err(e) = stream.read_exact(&mut header) {
+        bail!("truncated pack header: {}", e);
+    }
+    let objects = packs::parse_header(&header)? as usize;
+    let title = match output {
+        Some(_) => "Receiving objects",
+        None => "Indexing objects",
+    };
+    let progress = Progress::new(title, objects, show_progress);
+    let mut scan = Scan {
+        sha1: String::new(),
+        entries: Vec::new(),
+        data_offsets: Vec::new(),
+        bases: Vec::new(),
+        end: 0,
+        inflated: AtomicUsize::new(0),
+        max_inflated,
+        max_object,
+    };
+    loop {
+        let more = match limit {
+            Some(limit) => stream.offset < limit,
+            None => scan.entries.len() < objects,
+        };
+        if !more {
+            break;
+        }
+        read_entry(&mut stream, &mut scan)?;
+        progress.tick();
+    }
+    if scan.entries.len() != objects {
+        bail!(
+            "pack declares {} objects but holds {}",
+            objects,
+            scan.entries.len()
+        );
+    }
+    scan.end = stream.offset;
+
+    let checksum = hex::encode(stream.hash.clone().finalize());
+    stream.limit = None;
+    let mut trailer = [0u8; 20];
+    if let Err(e) = stream.read_exact(&mut trailer) {
+        bail!("truncated pack trailer: {}", e);
+    }
+    scan.sha1 = hex::encode(trailer);
+    if scan.sha1 != checksum {
+        bail!(
+            "pack checksum mismatch: trailer is {}, content hashes to {}",
+            scan.sha1,
+            checksum
+        );
+    }
+    if len.is_none() && !stream.fill_buf()?.is_empty() {
+        bail!("pack has junk after its trailer");
+    }
+    if let Some(e) = stream.write_error.take() {
+        bail!("writing the pack: {}", e);
+    }
+    if let Some(output) = stream.output.as_mut() {
+        output.flush()?;
+    }
+    progress.finish();
+    return Ok(scan);
+}
+
+/// Most recently used delta bases of a thread, up to a total size
+struct DeltaBaseCache {
+    limit: usize,
+    used: usize,
+    tick: u64,
+    objects: HashMap<usize, (u64, Rc<Vec<u8>>)>,
+    /// Entries of the cached objects by last use
+    order: BTreeMap<u64, usize>,
+}
+
+impl DeltaBaseCache {
+    fn new(limit: usize) -> Self {
+        return Self {
+            limit,
+            used: 0,
+            tick: 0,
+            objects: HashMap::new(),
+            order: BTreeMap::new(),
+        };
+    }
+
+    fn get(&mut self, entry: usize) -> Option<Rc<Vec<u8>>> {
+        let (tick, content) = self.objects.get_mut(&entry)?;
+        self.order.remove(tick);
+        self.tick += 1;
+        *tick = self.tick;
+        self.order.insert(self.tick, entry);
+        return Some(content.clone());
+    }
+
+    /// Adds an object, evicting the least recently used ones past the
+    /// limit. The last object stays even when larger than the limit.
+    fn insert(&mut self, entry: usize, content: Rc<Vec<u8>>) {
+        if self.objects.contains_key(&entry) {
+            return;
+        }
+        self.tick += 1;
+        self.used += content.len();
+        self.objects.insert(entry, (self.tick, content));
+        self.order.insert(self.tick, entry);
+        while self.used > self.limit && self.order.len() > 1 {
+            if let Some((_, evicted)) = self.order.pop_first() {
+                if let Some((_, content)) = self.objects.remove(&evicted) {
+                    self.used -= content.len();
+                }
+            }
+        }
+    }
+}
+
+/// A delta of the pack once applied to its base
+struct Resolved {
+    type_: ObjectType,
+    size: usize,
+    sha1: String,
+    /// Entry of the base
+    base: usize,
+    base_sha1: String,
+    depth: usize,
+}
+
+/// Second pass over a pack: resolves the deltas, walking the trees of
+/// deltas rooted at each whole object. Trees are shared between threads,
+/// each keeping the bases it may need again in its own cache.
+struct Resolver<'a> {
+    scan: Scan,
+    source: PackSource<'a>,
+    /// Deltas by base
+    children: HashMap<DeltaBase, Vec<usize>>,
+    resolved: Vec<OnceLock<Resolved>>,
+    failed: AtomicBool,
+    progress: Progress,
+    visit: Option<ObjectVisitor<'a>>,
+}
+
+impl<'a> Resolver<'a> {
+    fn new(
+        scan: Scan,
+        source: PackSource<'a>,
+        show_progress: bool,
+        visit: Option<ObjectVisitor<'a>>,
+    ) -> Self {
+        let mut children: HashMap<DeltaBase, Vec<usize>> = HashMap::new();
+        for (i, base) in scan.bases.iter().enumerate() {
+            if let Some(base) = base {
+                children.entry(base.clone()).or_default().push(i);
+            }
+        }
+        let deltas = scan.bases.iter().filter(|base| base.is_some()).count();
+        return Self {
+            resolved: scan.entries.iter().map(|_| OnceLock::new()).collect(),
+            scan,
+            source,
+            children,
+            failed: AtomicBool::new(false),
+            progress: Progress::new("Resolving deltas", deltas, show_progress),
+            visit,
+        };
+    }
+
+    /// Entries of whole objects, the roots of the trees of deltas
+    fn roots(&self) -> Vec<usize> {
+        return (0..self.scan.entries.len())
+            .filter(|i| self.scan.bases[*i].is_none())
+            .collect();
+    }
+
+    fn sha1(&self, entry: usize) -> &str {
+        return match self.resolved[entry].get() {
+            Some(resolved) => &resolved.sha1,
+            None => &self.scan.entries[entry].sha1,
+        };
+    }
+
+    fn type_(&self, entry: usize) -> ObjectType {
+        return match self.resolved[entry].get() {
+            Some(resolved) => resolved.type_,
+            None => self.scan.entries[entry].type_,
+        };
+    }
+
+    /// Deltas based on an entry that are not resolved yet
+    fn unresolved_children(&self, entry: usize) -> Vec<usize> {
+        let keys = [
+            DeltaBase::Offset(self.scan.entries[entry].offset),
+            DeltaBase::Hash(self.sha1(entry).to_string()),
+        ];
+        return keys
+            .iter()
+            .filter_map(|key| self.children.get(key))
+            .flatten()
+            .filter(|child| self.resolved[**child].get().is_none())
+            .copied()
+            .collect();
+    }
+
+    /// Inflates the data of an entry: the whole object or the delta
+    fn read(&self, entry: usize) -> Result<Vec<u8>> {
+        let offset = self.scan.data_offsets[entry];
+        let size = self.scan.entries[entry].stored_size;
+        return match self.source.read(offset, size) {
+            Ok(data) => Ok(data),
+            Err(e) => bail!("entry at offset {}: {}", self.scan.entries[entry].offset, e),
+        };
+    }
+
+    /// Passes the content of an entry to the visitor
+    fn visit(&self, entry: usize, content: &[u8]) -> Result<()> {
+        let Some(visit) = self.visit else {
+            return Ok(());
+        };
+        let type_ = self.type_(entry);
+        if let Err(e) = visit(type_, content) {
+            bail!(
+                "{} {} at offset {}: {}",
+                type_,
+                self.sha1(entry),
+                self.scan.entries[entry].offset,
+                e
+            );
+        }
+        return Ok(());
+    }
+
+    /// Content of a whole object or resolved delta, from the cache or
+    /// rebuilt from the closest cached object of its chain of deltas
+    fn object(&self, entry: usize, cache: &mut DeltaBaseCache) -> Result<Rc<Vec<u8>>> {
+        let mut chain = Vec::new();
+        let mut current = entry;
+        let mut content = loop {
+            if let Some(content) = cache.get(current) {
+                break content;
+            }
+            match self.resolved[current].get() {
+                Some(resolved) => {
+                    chain.push(current);
+                    current = resolved.base;
+                }
+                None => break Rc::new(self.read(current)?),
+            }
+        };
+        while let Some(delta) = chain.pop() {
+            content = Rc::new(packs::resolve_delta(&self.read(delta)?, &content)?);
+        }
+        cache.insert(entry, content.clone());
+        return Ok(content);
+    }
+
+    /// Applies a delta to its base, returns the content of the object
+    fn apply(&self, delta: usize, base: usize, base_content: &[u8]) -> Result<Vec<u8>> {
+        let delta_data = self.read(delta)?;
+        let offset = self.scan.entries[delta].offset;
+        let size = packs::delta_target_size(&delta_data)?;
+        if size > self.scan.max_object {
+            bail!(
+                "delta at offset {} produces {} bytes, more than the limit of {}",
+                offset,
+                size,
+                self.scan.max_object
+            );
+        }
+        let content = match packs::resolve_delta(&delta_data, base_content) {
+            Ok(content) => content,
+            Err(e) => bail!("delta at offset {}: {}", self.scan.entries[delta].offset, e),
+        };
+        self.scan.count_inflated(content.len())?;
+        let type_ = self.type_(base);
+        let resolved = Resolved {
+            type_,
+            size: content.len(),
+            sha1: calculate_object_hash(&type_.to_string(), &content),
+            base,
+            base_sha1: self.sha1(base).to_string(),
+            depth: self.resolved[base].get().map_or(0, |base| base.depth) + 1,
+        };
+        // another thread may have resolved it from a duplicate base
+        if self.resolved[delta].set(resolved).is_ok() {
+            self.progress.tick();
+        }
+        self.visit(delta, &content)?;
+        return Ok(content);
+    }
+
+    /// Resolves the tree of deltas rooted at an entry, depth first
+    fn resolve_tree(&self, root: usize, cache: &mut DeltaBaseCache) -> Result<()> {
+        let children = self.unresolved_children(root);
+        if self.visit.is_some() {
+            let content = match children.is_empty() {
+                true => Rc::new(self.read(root)?),
+                false => self.object(root, cache)?,
+            };
+            self.visit(root, &content)?;
+        }
+        if children.is_empty() {
+            return Ok(());
+        }
+        let mut stack = vec![(root, children, 0)];
+        while let Some((base, children, next)) = stack.last_mut() {
+            if *next == children.len() || self.failed.load(Ordering::Relaxed) {
+                stack.pop();
+                continue;
+            }
+            let (base, delta) = (*base, children[*next]);
+            *next += 1;
+            if self.resolved[delta].get().is_some() {
+                continue;
+            }
+            let base_content = self.object(base, cache)?;
+            let content = self.apply(delta, base, &base_content)?;
+            let children = self.unresolved_children(delta);
+            if !children.is_empty() {
+                cache.insert(delta, Rc::new(content));
+                stack.push((delta, children, 0));
+            }
+        }
+        return Ok(());
+    }
+
+    /// Resolves the trees of deltas rooted at `roots` with up to `threads`
+    /// threads, which take the next tree once done with one
+    fn resolve(&self, roots: &[usize], threads: usize, cache_limit: usize) -> Result<()> {
+        let threads = threads.clamp(1, roots.len().max(1));
+        let next = AtomicUsize::new(0);
+        let work = || -> Result<()> {
+            let mut cache = DeltaBaseCache::new(cache_limit / threads);
+            while !self.failed.load(Ordering::Relaxed) {
+                let Some(root) = roots.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                    break;
+                };
+                if let Err(e) = self.resolve_tree(*root, &mut cache) {
+                    self.failed.store(true, Ordering::Relaxed);
+                    return Err(e);
+                }
+            }
+            return Ok(());
+        };
+        if threads == 1 {
+            return work();
+        }
+        return thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads).map(|_| scope.spawn(work)).collect();
+            let mut result = Ok(());
+            for worker in workers {
+                let done = worker
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow!("delta resolution thread panicked")));
+                if result.is_ok() {
+                    result = done;
+                }
+            }
+            return result;
+        });
+    }
+
+    /// Deltas left unresolved, in pack order
+    fn unresolved(&self) -> Vec<usize> {
+        return (0..self.scan.entries.len())
+            .filter(|i| self.scan.bases[*i].is_some() && self.resolved[*i].get().is_none())
+            .collect();
+    }
+
+    /// Completes a thin pack written to `output`: appends the objects of the
+    /// local object store that unresolved deltas are based on, then updates
+    /// the object count and the trailer. Returns the appended entries.
+    fn fix_thin(&mut self, output: &File) -> Result<Vec<usize>> {
+        let mut missing: Vec<String> = self
+            .unresolved()
+            .into_iter()
+            .filter_map(|i| match &self.scan.bases[i] {
+                Some(DeltaBase::Hash(sha1)) => Some(sha1.clone()),
+                _ => None,
+            })
+            .collect();
+        missing.sort();
+        missing.dedup();
+        let mut appended = Vec::new();
+        for sha1 in missing {
+            if !objects::object_exists(&sha1)? {
+                continue;
+            }
+            let (type_, content) = objects::read_object(&sha1)?;
+            let type_ = ObjectType::from_object_type(type_);
+            let encoded = packs::encode_entry(type_, &content)?;
+            let offset = self.scan.end;
+            output.write_all_at(&encoded, offset as u64)?;
+            appended.push(self.scan.entries.len());
+            self.scan.entries.push(Entry {
+                type_,
+                size: content.len(),
+                sha1,
+                offset,
+                crc32: crc32fast::hash(&encoded),
+                stored_size: content.len(),
+                packed_size: encoded.len(),
+                base: None,
+                depth: 0,
+            });
+            let header = packs::encode_entry_header(type_, content.len());
+            self.scan.data_offsets.push(offset + header.len());
+            self.scan.bases.push(None);
+            self.resolved.push(OnceLock::new());
+            self.scan.end += encoded.len();
+        }
+        if appended.is_empty() {
+            return Ok(appended);
+        }
+
+        let objects = u32::try_from(self.scan.entries.len())?;
+        output.write_all_at(&objects.to_be_bytes(), 8)?;
+        let mut hash = sha1::Sha1::new();
+        let mut reader = FileReader {
+            file: output,
+            offset: 0,
+        };
+        io::copy(&mut (&mut reader).take(self.scan.end as u64), &mut hash)?;
+        let checksum = hash.finalize();
+        output.write_all_at(&checksum, self.scan.end as u64)?;
+        output.set_len(self.scan.end as u64 + 20)?;
+        self.scan.sha1 = hex::encode(checksum);
+        return Ok(appended);
+    }
+
+    /// Fails on the first delta whose base is still missing
+    fn check_resolved(&self) -> Result<()> {
+        let unresolved = self.unresolved();
+        let Some(first) = unresolved.first() else {
+            return Ok(());
+        };
+        let offset = self.scan.entries[*first].offset;
+        return match &self.scan.bases[*first] {
+            Some(DeltaBase::Offset(base)) => bail!(
+                "pack has {} unresolved deltas, the first at offset {} with base at offset {}",
+                unresolved.len(),
+                offset,
+                base
+            ),
+            Some(DeltaBase::Hash(sha1)) => bail!(
+                "pack has {} unresolved deltas, the first at offset {} with base {}",
+                unresolved.len(),
+                offset,
+                sha1
+            ),
+            None => Ok(()),
+        };
+    }
+
+    fn into_packfile(self) -> Packfile {
+        let mut entries = self.scan.entries;
+        for (entry, resolved) in entries.iter_mut().zip(self.resolved) {
+            if let Some(resolved) = resolved.into_inner() {
+                entry.type_ = resolved.type_;
+                entry.size = resolved.size;
+                entry.sha1 = resolved.sha1;
+                entry.base = Some(resolved.base_sha1);
+                entry.depth = resolved.depth;
+            }
+        }
+        return Packfile {
+            sha1: self.scan.sha1,
+            entries,
+        };
+    }
+}
+
+/// Indexes a pack read from `input`, of `len` bytes when known: a first
+/// pass reads its entries, copying them to `output`, then the deltas are
+/// resolved in parallel, reading their data again from `source`. Only the
+/// bases of deltas being resolved are held in memory. With
+/// `options.fix_thin`, delta bases missing from the pack are appended to
+/// `output` from the local object store. With `options.strict`, every
+/// object must be well formed.
+pub fn index<R: BufRead>(
+    input: R,
+    len: Option<usize>,
+    source: PackSource,
+    output: Option<&File>,
+    options: &IndexPackOptions,
+) -> Result<Packfile> {
+    return index_objects(input, len, source, output, options, None);
+}
+
+/// Checks that an object read from a pack is well formed
+fn validate_object(type_: ObjectType, data: &[u8]) -> Result<()> {
+    return objects::validate_object(GitObjectType::from_string(&type_.to_string())?, data);
+}
+
+/// Indexes a pack like `index`, passing every object to `visit`
+fn index_objects<R: BufRead>(
+    input: R,
+    len: Option<usize>,
+    source: PackSource,
+    output: Option<&File>,
+    options: &IndexPackOptions,
+    visit: Option<ObjectVisitor>,
+) -> Result<Packfile> {
+    let visit = match (visit, options.strict) {
+        (None, true) => Some(&validate_object as ObjectVisitor),
+        (visit, _) => visit,
+    };
+    let scan = scan(
+        input,
+        len,
+        output,
+        options.progress,
+        options.max_inflated_size,
+        options.max_object_size,
+    )?;
+    let threads = match options.threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        threads => threads,
+    };
+    let mut resolver = Resolver::new(scan, source, options.progress, visit);
+    let cache_limit = options.delta_base_cache_limit;
+    resolver.resolve(&resolver.roots(), threads, cache_limit)?;
+    if let (true, Some(output)) = (options.fix_thin, output) {
+        let appended = resolver.fix_thin(output)?;
+        resolver.resolve(&appended, threads, cache_limit)?;
+    }
+    resolver.check_resolved()?;
+    resolver.progress.finish();
+    return Ok(resolver.into_packfile());
+}
+
+fn check_pack_name(path: &Path) -> Result<()> {
+    if path.extension().is_none_or(|e| e != "pack") {
+        bail!(
+            "packfile name '{}' does not end with '.pack'",
+            path.display()
+        );
+    }
+    return Ok(());
+}
+
+/// Creates a new temporary pack file in `dir`
+fn create_temp_pack(dir: &Path) -> Result<(PathBuf, File)> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    fs::create_dir_all(dir)?;
+    loop {
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let path = dir.join(format!("tmp_pack_{}_{}_{:08x}", process::id(), n, nanos));
+        match fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => bail!(e),
+        }
+    }
+}
+
+/// Stores a pack read from `input`, of `len` bytes when known, at `path`
+/// or in the local object store without `path`, then writes its index next
+/// to it. The pack is written to a temporary file while read and renamed
+/// once indexed. Returns its parsed contents.
+pub fn store_pack<R: BufRead>(
+    input: R,
+    len: Option<usize>,
+    path: Option<&Path>,
+    options: &IndexPackOptions,
+) -> Result<Packfile> {
+    let dir = match path.and_then(|path| path.parent()) {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        Some(_) => PathBuf::from("."),
+        None => repo_path(PACK_DIR),
+    };
+    let (temp, file) = create_temp_pack(&dir)?;
+    let packfile = match index(input, len, PackSource::File(&file), Some(&file), options) {
+        Ok(packfile) => packfile,
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+    };
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => repo_path(PACK_DIR).join(format!("pack-{}.pack", packfile.sha1)),
+    };
+    // the object store keeps the pack it already has
+    if path.starts_with(repo_path(PACK_DIR))
+        && (packfile.entries.is_empty() || path.with_extension("idx").exists())
+    {
+        fs::remove_file(&temp)?;
+        return Ok(packfile);
+    }
+    file.sync_all()?;
+    let mut permissions = file.metadata()?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(&temp, permissions)?;
+    fs::rename(&temp, &path)?;
+    // the index goes last, packs are only looked up through their index
+    packs::write_pack_file(&path.with_extension("idx"), &packs::build_index(&packfile)?)?;
+    return Ok(packfile);
+}
+
+/// Writes the index of a pack file next to it, returns the hash of the pack.
+/// The pack must hold the bases of all its deltas.
+pub fn index_pack(path: &Path, options: &IndexPackOptions) -> Result<String> {
+    check_pack_name(path)?;
+    let file = File::open(path)?;
+    let len = usize::try_from(file.metadata()?.len())?;
+    let packfile = index(
+        BufReader::new(&file),
+        Some(len),
+        PackSource::File(&file),
+        None,
+        options,
+    )?;
+    fs::write(path.with_extension("idx"), packs::build_index(&packfile)?)?;
+    return Ok(packfile.sha1);
+}
+
+/// Stores a pack read from `input` at `path` with its index next to it, or
+/// in the local object store without `path`. Returns the hash of the pack,
+/// which changes when a thin pack is completed.
+pub fn index_pack_stdin<R: BufRead>(
+    input: R,
+    path: Option<&Path>,
+    options: &IndexPackOptions,
+) -> Result<String> {
+    if let Some(path) = path {
+        check_pack_name(path)?;
+    }
+    return Ok(store_pack(input, None, path, options)?.sha1);
+}
+
+/// Options of `unpack-objects`
+#[derive(Debug, Clone, Default)]
+pub struct UnpackObjectsOptions {
+    /// Check the objects without writing them
+    pub dry_run: bool,
+    /// Check that every object of the pack is well formed
+    pub strict: bool,
+    /// Show progress on stderr
+    pub progress: bool,
+}
+
+/// Writes the objects of a pack read from `input` as loose objects of the
+/// local object store, returns the parsed pack. The pack is kept in a
+/// temporary file while its deltas are resolved, their bases may be objects
+/// of the object store.
+pub fn unpack_objects<R: BufRead>(input: R, options: &UnpackObjectsOptions) -> Result<Packfile> {
+    let index_options = IndexPackOptions {
+        fix_thin: true,
+        progress: options.progress,
+        ..Default::default()
+    };
+    let store = |type_: ObjectType, data: &[u8]| -> Result<()> {
+        if options.strict {
+            validate_object(type_, data)?;
+        }
+        if !options.dry_run {
+            objects::store_object(&type_.to_string(), &data.to_vec())?;
+        }
+        return Ok(());
+    };
+    let (temp, file) = create_temp_pack(&repo_path(".git/objects"))?;
+    let source = PackSource::File(&file);
+    let unpacked = index_objects(
+        input,
+        None,
+        source,
+        Some(&file),
+        &index_options,
+        Some(&store),
+    );
+    fs::remove_file(&temp)?;
+    return unpacked;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::indexpack::{
+        index_pack, index_pack_stdin, unpack_objects, IndexPackOptions, UnpackObjectsOptions,
+    };
+    use crate::packs::{encode_entry, ObjectType};
+    use crate::testutils;
+    use sha1::Digest;
+    use std::fs;
+    use std::path::Path;
+
+    /// Commits versions of a file that git stores as deltas of each other
+    fn commit_versions(count: usize) {
+        for i in 1..=count {
+            let lines: Vec<String> = (0..i * 200).map(|n| n.to_string()).collect();
+            fs::write("file.txt", lines.join("\n")).unwrap();
+            testutils::git_commit_all(&format!("commit {}", i));
+        }
+    }
+
+    #[test]
+    fn test_index_pack() {
+        testutils::in_tmp_git(|| {
+            commit_versions(3);
+            let pack = testutils::get_git_output_with_stdin(
+                &["pack-objects", "--stdout", "--revs", "-q"],
+                "HEAD\n",
+            );
+            fs::write("test.pack", &pack).unwrap();
+            let options = IndexPackOptions::default();
+            let sha1 = index_pack(Path::new("test.pack"), &options).unwrap();
+            testutils::get_git_output(&["index-pack", "-o", "expected.idx", "test.pack"]);
+            assert_eq!(
+                fs::read("test.idx").unwrap(),
+                fs::read("expected.idx").unwrap()
+            );
+            assert!(testutils::get_git_output(&["verify-pack", "test.idx"]).is_empty());
+
+            let error = index_pack(Path::new("test.idx"), &options).unwrap_err();
+            assert!(error.to_string().contains("does not end with '.pack'"));
+
+            let stored = index_pack_stdin(&mut pack.as_slice(), None, &options).unwrap();
+            assert_eq!(stored, sha1);
+            let idx = format!(".git/objects/pack/pack-{}.idx", sha1);
+            assert_eq!(fs::read(idx).unwrap(), fs::read("expected.idx").unwrap());
+        });
+    }
+
+    #[test]
+    fn test_index_pack_fix_thin() {
+        testutils::in_tmp_git(|| {
+            commit_versions(3);
+            // deltas against the objects of the first commit, left out
+            let thin = testutils::get_git_output_with_stdin(
+                &["pack-objects", "--stdout", "--revs", "--thin", "-q"],
+                "HEAD\n^HEAD~1\n",
+            );
+
+            let options = IndexPackOptions::default();
+            let error =
+                index_pack_stdin(&mut thin.as_slice(), Some(Path::new("thin.pack")), &options)
+                    .unwrap_err();
+            assert!(error.to_string().contains("unresolved deltas"), "{}", error);
+
+            let options = IndexPackOptions {
+                fix_thin: true,
+                ..Default::default()
+            };
+            let sha1 = index_pack_stdin(
+                &mut thin.as_slice(),
+                Some(Path::new("fixed.pack")),
+                &options,
+            )
+            .unwrap();
+            let verified = testutils::get_git_output(&["verify-pack", "-v", "fixed.idx"]);
+            assert!(verified.contains("fixed.pack: ok"), "{}", verified);
+            let fixed = fs::read("fixed.pack").unwrap();
+            assert!(fixed.len() > thin.len());
+            assert_eq!(hex::encode(&fixed[fixed.len() - 20..]), sha1);
+
+            // the base of the file is in the completed pack
+            let base = testutils::get_git_output(&["rev-parse", "HEAD~1:file.txt"]);
+            assert!(verified.contains(base.trim()));
+        });
+    }
+
+    #[test]
+    fn test_index_pack_threads() {
+        testutils::in_tmp_git(|| {
+            commit_versions(12);
+            let pack = testutils::get_git_output_with_stdin(
+                &["pack-objects", "--stdout", "--revs", "-q"],
+                "HEAD\n",
+            );
+            fs::write("test.pack", &pack).unwrap();
+            testutils::get_git_output(&["index-pack", "-o", "expected.idx", "test.pack"]);
+            let expected = fs::read("expected.idx").unwrap();
+
+            // one thread, or several sharing a cache too small to hold more
+            // than one base, so that evicted bases are rebuilt
+            for (threads, delta_base_cache_limit) in [(1, 96 << 20), (4, 1), (0, 4096)] {
+                let options = IndexPackOptions {
+                    threads,
+                    delta_base_cache_limit,
+                    ..Default::default()
+                };
+                fs::remove_file("test.idx").unwrap_or_default();
+                index_pack(Path::new("test.pack"), &options).unwrap();
+                assert_eq!(fs::read("test.idx").unwrap(), expected);
+
+                let path = Path::new("stored.pack");
+                if path.exists() {
+                    fs::remove_file(path).unwrap();
+                }
+                index_pack_stdin(pack.as_slice(), Some(path), &options).unwrap();
+                assert_eq!(fs::read("stored.pack").unwrap(), pack);
+                assert_eq!(fs::read("stored.idx").unwrap(), expected);
+            }
+
+            // trailing data after the pack read from stdin
+            let mut junk = pack.clone();
+            junk.push(0);
+            let options = IndexPackOptions::default();
+            let error = index_pack_stdin(junk.as_slice(), None, &options).unwrap_err();
+            assert!(error.to_string().contains("junk"), "{}", error);
+        });
+    }
+
+    #[test]
+    fn test_unpack_objects() {
+        testutils::in_tmp_git(|| {
+            commit_versions(3);
+            let pack = testutils::get_git_output_with_stdin(
+                &["pack-objects", "--stdout", "--revs", "-q"],
+                "HEAD\n",
+            );
+            let head = testutils::get_git_output(&["rev-parse", "HEAD"]);
+            let content = fs::read_to_string("file.txt").unwrap();
+            fs::rename(".git", "source.git").unwrap();
+            testutils::get_git_output(&["init", "-q"]);
+
+            let dry_run = UnpackObjectsOptions {
+                dry_run: true,
+                strict: true,
+                ..Default::default()
+            };
+            let packfile = unpack_objects(pack.as_slice(), &dry_run).unwrap();
+            let loose = |sha1: &str| format!(".git/objects/{}/{}", &sha1[..2], &sha1[2..]);
+            assert!(packfile.entries.iter().any(|entry| entry.depth > 0));
+            assert!(packfile
+                .entries
+                .iter()
+                .all(|entry| !Path::new(&loose(&entry.sha1)).exists()));
+
+            let options = UnpackObjectsOptions::default();
+            let packfile = unpack_objects(pack.as_slice(), &options).unwrap();
+            assert!(packfile
+                .entries
+                .iter()
+                .all(|entry| Path::new(&loose(&entry.sha1)).exists()));
+            let file = testutils::get_git_output(&[
+                "cat-file",
+                "-p",
+                &format!("{}:file.txt", head.trim()),
+            ]);
+            assert_eq!(file, content);
+            // the temporary pack is gone
+            assert!(!fs::read_dir(".git/objects").unwrap().any(|entry| entry
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with("tmp_pack")));
+        });
+    }
+
+    #[test]
+    fn test_strict() {
+        testutils::in_tmp_git(|| {
+            // a tree entry without its hash
+            let mut pack = b"PACK\0\0\0\x02\0\0\0\x01".to_vec();
+            pack.extend(encode_entry(ObjectType::Tree, b"100644 file.txt\0").unwrap());
+            let checksum = sha1::Sha1::digest(&pack);
+            pack.extend_from_slice(&checksum);
+            fs::write("bad.pack", &pack).unwrap();
+
+            index_pack(Path::new("bad.pack"), &IndexPackOptions::default()).unwrap();
+            let strict = IndexPackOptions {
+                strict: true,
+                ..Default::default()
+            };
+            let error = index_pack(Path::new("bad.pack"), &strict).unwrap_err();
+            assert!(error.to_string().contains("tree"), "{}", error);
+
+            let strict = UnpackObjectsOptions {
+                strict: true,
+                ..Default::default()
+            };
+            assert!(unpack_objects(pack.as_slice(), &strict).is_err());
+            let packfile =
+                unpack_objects(pack.as_slice(), &UnpackObjectsOptions::default()).unwrap();
+            assert_eq!(packfile.entries.len(), 1);
+        });
+    }
+}
@@ -0,0 +1,103 @@
+use anyhow::Result;
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub mod attributes;
+pub mod clone;
+pub mod commitgraph;
+pub mod config;
+pub mod credential;
+pub mod delta;
+pub mod dumb;
+pub mod fetch;
+pub mod files;
+pub mod gc;
+pub mod indexpack;
+pub mod midx;
+pub mod objects;
+pub mod packs;
+pub mod pktline;
+pub mod protocol;
+pub mod push;
+pub mod refs;
+pub mod refspec;
+pub mod revwalk;
+pub mod serve;
+pub mod shallow;
+#[cfg(test)]
+mod testutils;
+pub mod transport;
+pub mod tree;
+
+thread_local! {
+    /// Git directory of the repository the thread works on, when it isn't
+    /// the `.git` directory in the cwd
+    static GIT_DIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` on the repository whose git directory is `git_dir` rather than
+/// the one in the cwd, bare repositories included. Only the thread running
+/// `f` is affected, unlike changing the cwd.
+pub fn with_repository<T>(git_dir: &Path, f: impl FnOnce() -> T) -> T {
+    let previous = GIT_DIR.with(|d| d.replace(Some(git_dir.to_path_buf())));
+    // restored even if `f` panics
+    struct Restore(Option<PathBuf>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            GIT_DIR.with(|d| d.replace(self.0.take()));
+        }
+    }
+    let _restore = Restore(previous);
+    return f();
+}
+
+/// Path of a file of the repository the thread works on, given as in a
+/// repository in the cwd such as `.git/HEAD`
+pub fn repo_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    return GIT_DIR.with(|d| match (d.borrow().as_ref(), path.strip_prefix(".git")) {
+        (Some(git_dir), Ok(relative)) => git_dir.join(relative),
+        _ => path.to_path_buf(),
+    });
+}
+
+/// Absolute path of the git directory the thread works on, which identifies
+/// the repository in the caches of settings and pack indexes
+pub fn repository_dir() -> Result<PathBuf> {
+    let dir = repo_path(".git");
+    return match dir.is_absolute() {
+        true => Ok(dir),
+        false => Ok(env::current_dir()?.join(dir)),
+    };
+}
+
+/// Creates the `.git` directory of an empty repository in the cwd, or the
+/// git directory the thread works on
+pub fn init() -> Result<()> {
+    fs::create_dir(repo_path(".git"))?;
+    fs::create_dir(repo_path(".git/objects"))?;
+    fs::create_dir(repo_path(".git/refs"))?;
+    fs::write(repo_path(".git/HEAD"), "ref: refs/heads/master\n")?;
+    println!("Initialized git directory");
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testutils;
+    use std::path;
+
+    #[test]
+    fn test_init() {
+        testutils::in_tmp_dir(|| {
+            crate::init().unwrap();
+            assert!(path::Path::new(".git").exists());
+            let data = testutils::get_git_output(&["status"]);
+            assert!(data.contains("On branch master"));
+            assert!(data.contains("No commits yet"));
+            assert!(data.contains("nothing to commit"));
+        });
+    }
+}
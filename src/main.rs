@@ -1,15 +1,15 @@
-use anyhow::Result;
+use clap::ArgGroup;
 use clap::Parser;
 use clap::Subcommand;
 
-use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+use std::process;
 use std::str;
 
-mod clone;
-mod files;
-mod objects;
-mod packs;
-mod tree;
+use git_rust::{
+    clone, commitgraph, fetch, files, gc, indexpack, midx, packs, push, refs, revwalk, serve, tree,
+};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -24,28 +24,120 @@ enum Commands {
     /// initialize git repository
     Init,
 
-    /// print contents of blob objects
+    /// print type, size or contents of repository objects
+    #[command(group(
+        ArgGroup::new("mode")
+            .required(true)
+            .args(["pretty", "show_type", "size", "exists", "batch", "batch_check"]),
+    ))]
+    #[command(group(ArgGroup::new("batch_mode").args(["batch", "batch_check"])))]
     CatFile {
-        object: String,
+        #[clap(required_unless_present_any = ["batch", "batch_check"])]
+        object: Option<String>,
 
         #[clap(short = 'p', help = "pretty print object")]
         pretty: bool,
+
+        #[clap(short = 't', help = "show object type")]
+        show_type: bool,
+
+        #[clap(short = 's', help = "show object size")]
+        size: bool,
+
+        #[clap(short = 'e', help = "exit with zero status if object exists")]
+        exists: bool,
+
+        #[clap(
+            long,
+            num_args = 0..=1,
+            default_missing_value = files::DEFAULT_BATCH_FORMAT,
+            help = "print info and contents of objects named in stdin"
+        )]
+        batch: Option<String>,
+
+        #[clap(
+            long,
+            num_args = 0..=1,
+            default_missing_value = files::DEFAULT_BATCH_FORMAT,
+            help = "print info of objects named in stdin"
+        )]
+        batch_check: Option<String>,
+
+        #[clap(
+            long,
+            requires = "batch_mode",
+            help = "show all objects instead of reading stdin"
+        )]
+        batch_all_objects: bool,
     },
 
     /// calculate sha1 for file and optionally store the object
     HashObject {
-        path: String,
+        #[clap(required_unless_present_any = ["stdin", "stdin_paths"])]
+        paths: Vec<String>,
 
         #[clap(short = 'w', help = "write object to object store")]
         write: bool,
+
+        #[clap(short = 't', default_value = "blob", help = "type of the object")]
+        type_: String,
+
+        #[clap(long, help = "read the object from stdin")]
+        stdin: bool,
+
+        #[clap(long, conflicts_with = "stdin", help = "read file paths from stdin")]
+        stdin_paths: bool,
+
+        #[clap(long, help = "skip validation of the object format")]
+        literally: bool,
+
+        #[clap(long, help = "hash the contents as is, without conversions")]
+        no_filters: bool,
+
+        #[clap(
+            long,
+            conflicts_with = "no_filters",
+            help = "path used to choose the conversions applied"
+        )]
+        path: Option<String>,
     },
 
     /// print contents of tree objects
     LsTree {
-        treeid: String,
+        #[clap(help = "tree, commit, tag or ref whose tree is listed")]
+        treeish: String,
+
+        #[clap(help = "only show entries matching these paths")]
+        paths: Vec<String>,
+
+        #[clap(short = 'r', help = "recurse into subtrees")]
+        recursive: bool,
 
-        #[clap(long, help = "print only object names")]
+        #[clap(short = 't', help = "show tree entries even when recursing into them")]
+        show_trees: bool,
+
+        #[clap(short = 'd', help = "show only tree entries")]
+        only_trees: bool,
+
+        #[clap(short = 'l', long = "long", help = "show object size of blobs")]
+        long: bool,
+
+        #[clap(short = 'z', help = "terminate entries with NUL instead of LF")]
+        null_terminated: bool,
+
+        #[clap(long, visible_alias = "name-status", help = "print only object names")]
         name_only: bool,
+
+        #[clap(
+            long,
+            num_args = 0..=1,
+            default_missing_value = "7",
+            help = "abbreviate object names to the given length"
+        )]
+        abbrev: Option<usize>,
+
+        #[clap(long, help = "custom format using %(objectmode), %(objecttype), etc.")]
+        format: Option<String>,
     },
 
     /// recursively store current working directory as repository objects
@@ -63,7 +155,295 @@ enum Commands {
     },
 
     /// Clone remote repository
-    Clone { url: String, path: String },
+    Clone {
+        url: String,
+        path: String,
+        #[clap(
+            short = 'b',
+            long,
+            help = "check out this branch (or tag) instead of the remote HEAD"
+        )]
+        branch: Option<String>,
+        #[clap(long, help = "only fetch the history of the checked out branch")]
+        single_branch: bool,
+        #[clap(short = 'n', long, help = "do not check out the working tree")]
+        no_checkout: bool,
+        #[clap(
+            short = 'q',
+            long,
+            help = "do not show the progress sent by the server"
+        )]
+        quiet: bool,
+        #[clap(long, help = "only fetch this number of commits of history")]
+        depth: Option<u32>,
+        #[clap(
+            long,
+            value_name = "DATE",
+            help = "only fetch the history after a date"
+        )]
+        shallow_since: Option<String>,
+        #[clap(
+            long,
+            value_name = "REF",
+            help = "do not fetch the history reachable from a remote ref"
+        )]
+        shallow_exclude: Vec<String>,
+        #[clap(
+            long,
+            value_name = "FILTER-SPEC",
+            help = "leave out objects (blob:none, blob:limit=<n>, tree:0), fetching them on demand"
+        )]
+        filter: Option<String>,
+        #[clap(long, help = "fetch from local paths instead of copying their objects")]
+        no_local: bool,
+        #[clap(
+            long,
+            help = "copy the objects of local paths instead of hardlinking them"
+        )]
+        no_hardlinks: bool,
+    },
+
+    /// Download objects and refs from another repository
+    Fetch {
+        #[clap(default_value = "origin", help = "remote name or URL")]
+        remote: String,
+        #[clap(help = "refspecs, the remote's configured ones by default")]
+        refspecs: Vec<String>,
+        #[clap(
+            short = 'q',
+            long,
+            help = "do not show the progress nor the updated refs"
+        )]
+        quiet: bool,
+        #[clap(
+            long,
+            help = "limit the history to this number of commits from the tips"
+        )]
+        depth: Option<u32>,
+        #[clap(
+            long,
+            value_name = "DATE",
+            help = "limit the history to the commits after a date"
+        )]
+        shallow_since: Option<String>,
+        #[clap(
+            long,
+            value_name = "REF",
+            help = "do not fetch the history reachable from a remote ref"
+        )]
+        shallow_exclude: Vec<String>,
+        #[clap(long, help = "fetch the whole history of a shallow repository")]
+        unshallow: bool,
+    },
+    /// Update remote refs along with the objects they need
+    Push {
+        #[clap(default_value = "origin", help = "remote name or URL")]
+        remote: String,
+        #[clap(help = "refspecs, the current branch by default")]
+        refspecs: Vec<String>,
+        #[clap(short = 'f', long, help = "allow updates that are not fast-forwards")]
+        force: bool,
+        #[clap(
+            long,
+            value_name = "REFNAME[:EXPECT]",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "",
+            help = "force updates only if the remote refs have the expected values"
+        )]
+        force_with_lease: Option<String>,
+        #[clap(short = 'd', long, help = "delete the given remote refs")]
+        delete: bool,
+        #[clap(long, help = "push all the tags")]
+        tags: bool,
+        #[clap(
+            short = 'q',
+            long,
+            help = "do not show the progress nor the updated refs"
+        )]
+        quiet: bool,
+    },
+
+    /// build the index of a pack, or store a pack read from stdin
+    IndexPack {
+        #[clap(
+            required_unless_present = "stdin",
+            help = "pack to index, or where to write the pack read with --stdin"
+        )]
+        pack: Option<String>,
+        #[clap(long, help = "read the pack from stdin and store it")]
+        stdin: bool,
+        #[clap(
+            long,
+            requires = "stdin",
+            help = "add the delta bases a thin pack lacks"
+        )]
+        fix_thin: bool,
+        #[clap(short = 'v', long, help = "show progress on stderr")]
+        verbose: bool,
+        #[clap(
+            long,
+            default_value_t = 0,
+            help = "threads resolving deltas, 0 for one per CPU"
+        )]
+        threads: usize,
+        #[clap(long, help = "check that every object is well formed")]
+        strict: bool,
+    },
+
+    /// write the objects of a pack read from stdin as loose objects
+    UnpackObjects {
+        #[clap(short = 'n', long, help = "check the objects without writing them")]
+        dry_run: bool,
+        #[clap(short = 'q', long, help = "don't show progress")]
+        quiet: bool,
+        #[clap(long, help = "check that every object is well formed")]
+        strict: bool,
+    },
+
+    /// check packs against their index
+    VerifyPack {
+        #[clap(required = true, help = "packs or their .idx files")]
+        paths: Vec<String>,
+        #[clap(short = 'v', long, help = "list the objects and delta chains")]
+        verbose: bool,
+        #[clap(short = 's', long, help = "only show the delta chain histogram")]
+        stat_only: bool,
+    },
+
+    /// pack refs and objects, and prune unreachable objects
+    Gc {
+        #[clap(
+            long,
+            value_name = "date",
+            help = "prune unreachable objects older than date (default: gc.pruneExpire or 2.weeks.ago)"
+        )]
+        prune: Option<String>,
+        #[clap(
+            long,
+            conflicts_with = "prune",
+            help = "don't prune unreachable objects"
+        )]
+        no_prune: bool,
+        #[clap(short = 'q', long, help = "don't report what was packed")]
+        quiet: bool,
+    },
+
+    /// pack the reachable loose objects, or all of them
+    Repack {
+        #[clap(short = 'a', help = "pack all reachable objects in a single pack")]
+        all: bool,
+        #[clap(
+            short = 'A',
+            help = "like -a, keeping the unreachable objects of the old packs loose"
+        )]
+        keep_unreachable: bool,
+        #[clap(short = 'd', help = "remove the redundant packs and loose objects")]
+        delete: bool,
+        #[clap(short = 'q', long, help = "don't report what was packed")]
+        quiet: bool,
+    },
+
+    /// remove unreachable loose objects
+    Prune {
+        #[clap(short = 'n', long, help = "only list the objects to remove")]
+        dry_run: bool,
+        #[clap(
+            long,
+            value_name = "date",
+            default_value = "now",
+            help = "only remove objects older than date"
+        )]
+        expire: String,
+    },
+
+    /// move loose refs to packed-refs
+    PackRefs {
+        #[clap(long, help = "pack every ref (the only behavior supported)")]
+        all: bool,
+    },
+
+    /// write or verify the multi-pack-index
+    MultiPackIndex {
+        #[command(subcommand)]
+        action: MultiPackIndexAction,
+    },
+
+    /// write or verify the commit-graph
+    CommitGraph {
+        #[command(subcommand)]
+        action: CommitGraphAction,
+    },
+
+    /// list commits in reverse chronological order
+    RevList {
+        #[clap(required = true, help = "commits to start from")]
+        commits: Vec<String>,
+        #[clap(short = 'n', long, value_name = "n", help = "list at most n commits")]
+        max_count: Option<usize>,
+        #[clap(long, help = "print the number of commits")]
+        count: bool,
+        #[clap(last = true, help = "only list commits changing these paths")]
+        paths: Vec<String>,
+    },
+
+    /// find the best common ancestors of two commits
+    MergeBase {
+        #[clap(num_args = 2, required = true)]
+        commits: Vec<String>,
+        #[clap(short = 'a', long, help = "print all the best common ancestors")]
+        all: bool,
+        #[clap(
+            long,
+            conflicts_with = "all",
+            help = "exit with 0 if the first commit is an ancestor of the second, 1 otherwise"
+        )]
+        is_ancestor: bool,
+    },
+
+    /// serve the repositories of a directory over smart HTTP
+    #[command(alias = "http-backend")]
+    Serve {
+        #[clap(default_value = ".", help = "directory holding the repositories")]
+        dir: String,
+        #[clap(long, default_value = "127.0.0.1", help = "address to listen on")]
+        listen: String,
+        #[clap(long, default_value_t = 8080, help = "port to listen on")]
+        port: u16,
+    },
+}
+
+#[derive(Subcommand)]
+enum MultiPackIndexAction {
+    /// index the objects of every pack
+    Write,
+    /// check the multi-pack-index against the pack indexes
+    Verify,
+}
+
+#[derive(Subcommand)]
+enum CommitGraphAction {
+    /// write the commits of the packs, or of the refs, and their ancestors
+    Write {
+        #[clap(long, help = "start from the refs rather than the packs")]
+        reachable: bool,
+        #[clap(long, help = "compute the Bloom filters of the changed paths")]
+        changed_paths: bool,
+    },
+    /// check the commit-graph against the commits
+    Verify,
+}
+
+/// Resolves a command line argument to a commit
+fn resolve_commit(name: &str) -> String {
+    let sha1 = refs::resolve_object(name).unwrap();
+    return match revwalk::peel_to_commit(&sha1).unwrap() {
+        Some(commit) => commit,
+        None => {
+            eprintln!("fatal: {} is not a commit", name);
+            process::exit(128);
+        }
+    };
 }
 
 fn main() {
@@ -71,24 +451,102 @@ fn main() {
 
     match &cli.command {
         Commands::Init => {
-            init().unwrap();
-        }
-        Commands::CatFile { object, pretty: _ } => {
-            print!("{}", files::catfile(object).unwrap());
-        }
-        Commands::HashObject { write, path } => {
-            println!("{}", files::hashobject(path, *write).unwrap())
+            git_rust::init().unwrap();
         }
-        Commands::LsTree { treeid, name_only } => {
-            let tree = tree::lstree(&treeid).unwrap();
-            for node in tree.iter() {
-                if *name_only {
-                    println!("{}", node.filename);
+        Commands::CatFile {
+            object,
+            pretty,
+            show_type,
+            size,
+            exists,
+            batch,
+            batch_check,
+            batch_all_objects,
+        } => {
+            let mut stdout = io::stdout().lock();
+            if let Some(format) = batch.as_ref().or(batch_check.as_ref()) {
+                let contents = batch.is_some();
+                if *batch_all_objects {
+                    files::catfile_batch_all(format, contents, &mut stdout).unwrap();
                 } else {
-                    println!("{}\t{}\t{}", node.permissions, node.filename, node.hash);
+                    files::catfile_batch(io::stdin().lock(), format, contents, &mut stdout)
+                        .unwrap();
+                }
+                return;
+            }
+            let mode = match (pretty, show_type, size, exists) {
+                (true, _, _, _) => files::CatFileMode::Pretty,
+                (_, true, _, _) => files::CatFileMode::Type,
+                (_, _, true, _) => files::CatFileMode::Size,
+                _ => files::CatFileMode::Exists,
+            };
+            let object = object.as_ref().unwrap();
+            if !files::catfile(object, &mode, &mut stdout).unwrap() {
+                process::exit(1);
+            }
+        }
+        Commands::HashObject {
+            paths,
+            write,
+            type_,
+            stdin,
+            stdin_paths,
+            literally,
+            no_filters,
+            path,
+        } => {
+            let options = files::HashObjectOptions {
+                type_: type_.clone(),
+                write: *write,
+                literally: *literally,
+                no_filters: *no_filters,
+                path: path.clone(),
+            };
+            if *stdin {
+                println!(
+                    "{}",
+                    files::hash_reader(&mut io::stdin().lock(), &options).unwrap()
+                );
+            }
+            for path in paths {
+                println!("{}", files::hash_file(path, &options).unwrap());
+            }
+            if *stdin_paths {
+                for path in io::stdin().lock().lines() {
+                    println!("{}", files::hash_file(&path.unwrap(), &options).unwrap());
                 }
             }
         }
+        Commands::LsTree {
+            treeish,
+            paths,
+            recursive,
+            show_trees,
+            only_trees,
+            long,
+            null_terminated,
+            name_only,
+            abbrev,
+            format,
+        } => {
+            let options = tree::LsTreeOptions {
+                recursive: *recursive,
+                show_trees: *show_trees,
+                only_trees: *only_trees,
+                long: *long,
+                name_only: *name_only,
+                abbrev: *abbrev,
+                null_terminated: *null_terminated,
+                format: format.clone(),
+                paths: paths.clone(),
+            };
+            let terminator = if *null_terminated { '\0' } else { '\n' };
+            let mut stdout = io::stdout().lock();
+            for entry in tree::ls_tree(treeish, &options).unwrap() {
+                let line = tree::format_ls_tree_entry(&entry, &options).unwrap();
+                write!(stdout, "{}{}", line, terminator).unwrap();
+            }
+        }
         Commands::WriteTree => {
             let digest = tree::writetree().unwrap();
             println!("{}", digest);
@@ -98,93 +556,254 @@ fn main() {
             parent,
             message,
         } => {
-            let newcommitid =
-                tree::committree(&"manuel@manuel.com".to_string(), treeid, parent, message)
-                    .unwrap();
+            let identity = tree::commit_identity().unwrap();
+            let newcommitid = tree::committree(&identity, treeid, parent, message).unwrap();
             println!("{}", newcommitid);
         }
-        Commands::Clone { url, path } => {
-            clone::clone(url, path).unwrap();
+        Commands::Clone {
+            url,
+            path,
+            branch,
+            single_branch,
+            no_checkout,
+            quiet,
+            depth,
+            shallow_since,
+            shallow_exclude,
+            filter,
+            no_local,
+            no_hardlinks,
+        } => {
+            let options = clone::CloneOptions {
+                branch: branch.clone(),
+                single_branch: *single_branch,
+                no_checkout: *no_checkout,
+                quiet: *quiet,
+                depth: *depth,
+                shallow_since: shallow_since.clone(),
+                shallow_exclude: shallow_exclude.clone(),
+                filter: filter.clone(),
+                no_local: *no_local,
+                no_hardlinks: *no_hardlinks,
+            };
+            clone::clone(url, path, &options).unwrap();
+        }
+        Commands::Fetch {
+            remote,
+            refspecs,
+            quiet,
+            depth,
+            shallow_since,
+            shallow_exclude,
+            unshallow,
+        } => {
+            let options = fetch::FetchOptions {
+                quiet: *quiet,
+                depth: *depth,
+                shallow_since: shallow_since.clone(),
+                shallow_exclude: shallow_exclude.clone(),
+                unshallow: *unshallow,
+            };
+            fetch::fetch(remote, refspecs, &options).unwrap();
+        }
+        Commands::Push {
+            remote,
+            refspecs,
+            force,
+            force_with_lease,
+            delete,
+            tags,
+            quiet,
+        } => {
+            let options = push::PushOptions {
+                force: *force,
+                force_with_lease: force_with_lease.clone(),
+                delete: *delete,
+                tags: *tags,
+                quiet: *quiet,
+            };
+            push::push(remote, refspecs, &options).unwrap();
+        }
+        Commands::IndexPack {
+            pack,
+            stdin,
+            fix_thin,
+            verbose,
+            threads,
+            strict,
+        } => {
+            let options = indexpack::IndexPackOptions {
+                fix_thin: *fix_thin,
+                threads: *threads,
+                progress: *verbose,
+                strict: *strict,
+                ..Default::default()
+            };
+            let pack = pack.as_ref().map(Path::new);
+            let sha1 = match stdin {
+                true => indexpack::index_pack_stdin(io::stdin().lock(), pack, &options),
+                false => indexpack::index_pack(pack.unwrap(), &options),
+            };
+            println!("{}", sha1.unwrap());
+        }
+        Commands::UnpackObjects {
+            dry_run,
+            quiet,
+            strict,
+        } => {
+            let options = indexpack::UnpackObjectsOptions {
+                dry_run: *dry_run,
+                strict: *strict,
+                progress: !*quiet && io::stderr().is_terminal(),
+            };
+            indexpack::unpack_objects(io::stdin().lock(), &options).unwrap();
+        }
+        Commands::VerifyPack {
+            paths,
+            verbose,
+            stat_only,
+        } => {
+            let mut stdout = io::stdout().lock();
+            let mut failed = false;
+            for path in paths {
+                let pack_path = Path::new(path).with_extension("pack");
+                match packs::verify_pack(Path::new(path)) {
+                    Ok(packfile) if *verbose || *stat_only => {
+                        packs::write_pack_stats(&packfile, *stat_only, &mut stdout).unwrap();
+                        if !*stat_only {
+                            writeln!(stdout, "{}: ok", pack_path.display()).unwrap();
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!("error: {}", e);
+                        if *verbose {
+                            writeln!(stdout, "{}: bad", pack_path.display()).unwrap();
+                        }
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                process::exit(1);
+            }
+        }
+        Commands::Gc {
+            prune,
+            no_prune,
+            quiet,
+        } => {
+            let options = gc::GcOptions {
+                prune: prune.clone(),
+                no_prune: *no_prune,
+                quiet: *quiet,
+            };
+            gc::gc(&options).unwrap();
+        }
+        Commands::Repack {
+            all,
+            keep_unreachable,
+            delete,
+            quiet,
+        } => {
+            let options = gc::RepackOptions {
+                all: *all || *keep_unreachable,
+                keep_unreachable: *keep_unreachable,
+                delete: *delete,
+                quiet: *quiet,
+            };
+            gc::repack(&options).unwrap();
+        }
+        Commands::Prune { dry_run, expire } => {
+            let expire = match gc::parse_expire(expire).unwrap() {
+                Some(expire) => expire,
+                None => return,
+            };
+            for sha1 in gc::prune(expire, *dry_run).unwrap() {
+                if *dry_run {
+                    println!("{}", sha1);
+                }
+            }
+        }
+        Commands::PackRefs { all: _ } => {
+            refs::pack_refs().unwrap();
+        }
+        Commands::MultiPackIndex { action } => match action {
+            MultiPackIndexAction::Write => {
+                midx::write_multi_pack_index().unwrap();
+            }
+            MultiPackIndexAction::Verify => {
+                midx::verify_multi_pack_index().unwrap();
+            }
+        },
+        Commands::CommitGraph { action } => match action {
+            CommitGraphAction::Write {
+                reachable,
+                changed_paths,
+            } => {
+                let options = commitgraph::CommitGraphOptions {
+                    reachable: *reachable,
+                    changed_paths: *changed_paths,
+                };
+                commitgraph::write_commit_graph(&options).unwrap();
+            }
+            CommitGraphAction::Verify => {
+                commitgraph::verify_commit_graph().unwrap();
+            }
+        },
+        Commands::RevList {
+            commits,
+            max_count,
+            count,
+            paths,
+        } => {
+            let tips: Vec<String> = commits.iter().map(|c| resolve_commit(c)).collect();
+            let mut walk = revwalk::RevWalk::new(&tips).unwrap();
+            walk.limit_to_paths(paths);
+            let mut stdout = io::stdout().lock();
+            let mut listed = 0;
+            while max_count.is_none_or(|max| listed < max) {
+                let commit = match walk.next_commit().unwrap() {
+                    Some(commit) => commit,
+                    None => break,
+                };
+                listed += 1;
+                if !*count {
+                    writeln!(stdout, "{}", commit.sha1).unwrap();
+                }
+            }
+            if *count {
+                writeln!(stdout, "{}", listed).unwrap();
+            }
+        }
+        Commands::MergeBase {
+            commits,
+            all,
+            is_ancestor,
+        } => {
+            let one = resolve_commit(&commits[0]);
+            let two = resolve_commit(&commits[1]);
+            if *is_ancestor {
+                let code = match revwalk::is_ancestor(&one, &two).unwrap() {
+                    true => 0,
+                    false => 1,
+                };
+                process::exit(code);
+            }
+            let bases = revwalk::merge_bases(&one, &two).unwrap();
+            if bases.is_empty() {
+                process::exit(1);
+            }
+            for base in bases.iter().take(if *all { bases.len() } else { 1 }) {
+                println!("{}", base);
+            }
+        }
+        Commands::Serve { dir, listen, port } => {
+            let options = serve::ServeOptions {
+                listen: listen.clone(),
+                port: *port,
+            };
+            serve::serve(dir, &options).unwrap();
         }
-    }
-}
-
-fn init() -> Result<()> {
-    fs::create_dir(".git")?;
-    fs::create_dir(".git/objects")?;
-    fs::create_dir(".git/refs")?;
-    fs::write(".git/HEAD", "ref: refs/heads/master\n")?;
-    println!("Initialized git directory");
-    return Ok(());
-}
-
-#[cfg(test)]
-mod testutils {
-    use std::env::{current_dir, set_current_dir};
-    use std::path::PathBuf;
-    use std::process::Command;
-    use std::sync::Mutex;
-    use tempdir::TempDir;
-
-    use crate::init;
-
-    static MUTEX: Mutex<u8> = Mutex::new(0u8);
-
-    pub fn get_git_output(args: &[&str]) -> String {
-        let stdout = Command::new("git")
-            .args(args)
-            .output()
-            .expect("error running git")
-            .stdout;
-        return String::from_utf8(stdout).unwrap();
-    }
-
-    fn tempdir() -> (PathBuf, TempDir) {
-        let cwd = current_dir().unwrap();
-        let tempdir = TempDir::new("gittest").unwrap();
-        assert!(tempdir.path().is_dir());
-        return (cwd, tempdir);
-    }
-
-    pub fn in_tmp_dir<F>(func: F)
-    where
-        F: FnOnce() -> (),
-    {
-        let _lock = match MUTEX.lock() {
-            Ok(guard) => guard,
-            Err(poison) => poison.into_inner(),
-        };
-        let (cwd, dir) = tempdir();
-        set_current_dir(&dir).unwrap();
-        func();
-        set_current_dir(cwd).unwrap();
-    }
-
-    pub fn in_tmp_git<F>(func: F)
-    where
-        F: FnOnce() -> (),
-    {
-        in_tmp_dir(|| {
-            init().unwrap();
-            func();
-        });
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::testutils;
-    use std::path;
-
-    #[test]
-    fn test_init() {
-        testutils::in_tmp_dir(|| {
-            crate::init().unwrap();
-            assert!(path::Path::new(".git").exists());
-            let data = testutils::get_git_output(&["status"]);
-            assert!(data.contains("On branch master"));
-            assert!(data.contains("No commits yet"));
-            assert!(data.contains("nothing to commit"));
-        });
     }
 }
@@ -0,0 +1,493 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{bail, Result};
+use sha1::Digest;
+
+use crate::packs::{self, PackIndex, PACK_DIR};
+use crate::repo_path;
+
+/// Path of the multi-pack-index of the local object store
+pub const MIDX_PATH: &str = ".git/objects/pack/multi-pack-index";
+
+const MIDX_MAGIC: &[u8] = b"MIDX";
+const MIDX_VERSION: u8 = 1;
+/// Hash version of SHA-1 in multi-pack-index and commit-graph files
+pub const SHA1_VERSION: u8 = 1;
+
+const CHUNK_PACK_NAMES: &[u8; 4] = b"PNAM";
+const CHUNK_FANOUT: &[u8; 4] = b"OIDF";
+const CHUNK_OIDS: &[u8; 4] = b"OIDL";
+const CHUNK_OFFSETS: &[u8; 4] = b"OOFF";
+const CHUNK_LARGE_OFFSETS: &[u8; 4] = b"LOFF";
+
+/// Offsets of the OOFF chunk with this bit set index the LOFF chunk
+const LARGE_OFFSET: u32 = 0x80000000;
+
+/// Builds a file made of chunks, the format of multi-pack-index and
+/// commit-graph files: `header`, the table of contents of the chunks, the
+/// chunks then the checksum trailer
+pub fn write_chunk_file(header: &[u8], chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+    let mut data = header.to_vec();
+    let mut offset = (header.len() + (chunks.len() + 1) * 12) as u64;
+    for (id, chunk) in chunks {
+        data.extend_from_slice(*id);
+        data.extend_from_slice(&offset.to_be_bytes());
+        offset += chunk.len() as u64;
+    }
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(&offset.to_be_bytes());
+    for (_, chunk) in chunks {
+        data.extend_from_slice(chunk);
+    }
+    let checksum = sha1::Sha1::digest(&data);
+    data.extend_from_slice(&checksum);
+    return data;
+}
+
+/// Parses the table of contents of a chunk file of `len` bytes, held in
+/// `data` from `start`, into the position and size of each chunk. The
+/// chunks must be in order and end before the trailer.
+pub fn parse_chunk_table(
+    data: &[u8],
+    start: usize,
+    chunks: usize,
+    len: usize,
+) -> Result<HashMap<[u8; 4], (usize, usize)>> {
+    let end = start + (chunks + 1) * 12;
+    if data.len() < end || len < end + 20 {
+        bail!("chunk table of {} chunks is truncated", chunks);
+    }
+    let mut table = HashMap::new();
+    for i in 0..chunks {
+        let entry = &data[start + i * 12..start + i * 12 + 24];
+        let id: [u8; 4] = entry[..4].try_into()?;
+        let offset = u64::from_be_bytes(entry[4..12].try_into()?) as usize;
+        let next = u64::from_be_bytes(entry[16..24].try_into()?) as usize;
+        if offset < end || next < offset || next > len - 20 {
+            bail!(
+                "chunk {} has invalid bounds {}..{}",
+                String::from_utf8_lossy(&id),
+                offset,
+                next
+            );
+        }
+        table.insert(id, (offset, next - offset));
+    }
+    return Ok(table);
+}
+
+/// Hashes and fan-out table of the objects of a multi-pack-index or
+/// commit-graph: the fan-out gives, for each first byte, the number of
+/// hashes up to it
+pub fn write_fanout(hashes: &[[u8; 20]]) -> Vec<u8> {
+    let mut counts = [0u32; 256];
+    for hash in hashes {
+        counts[hash[0] as usize] += 1;
+    }
+    let mut fanout = Vec::with_capacity(256 * 4);
+    let mut total = 0;
+    for count in counts {
+        total += count;
+        fanout.extend_from_slice(&total.to_be_bytes());
+    }
+    return fanout;
+}
+
+/// Parses a fan-out table, checking it never decreases and ends at `count`
+pub fn parse_fanout(data: &[u8], count: usize) -> Result<[u32; 256]> {
+    if data.len() != 256 * 4 {
+        bail!("fan-out table has {} bytes", data.len());
+    }
+    let mut fanout = [0u32; 256];
+    let mut previous = 0;
+    for (i, value) in fanout.iter_mut().enumerate() {
+        *value = u32::from_be_bytes(data[i * 4..i * 4 + 4].try_into()?);
+        if *value < previous {
+            bail!("fan-out table decreases at {:02x}", i);
+        }
+        previous = *value;
+    }
+    if fanout[255] as usize != count {
+        bail!("fan-out table ends at {}, expected {}", fanout[255], count);
+    }
+    return Ok(fanout);
+}
+
+/// Finds `hash` in a sorted table of hashes narrowed by its fan-out, with
+/// `read_hash` reading the hash at a position of the table
+pub fn search_fanout<F>(fanout: &[u32; 256], hash: &[u8], mut read_hash: F) -> Result<Option<usize>>
+where
+    F: FnMut(usize) -> Result<[u8; 20]>,
+{
+    let first = hash[0] as usize;
+    let mut low = match first {
+        0 => 0,
+        _ => fanout[first - 1] as usize,
+    };
+    let mut high = fanout[first] as usize;
+    while low < high {
+        let mid = (low + high) / 2;
+        match read_hash(mid)?.as_slice().cmp(hash) {
+            std::cmp::Ordering::Less => low = mid + 1,
+            std::cmp::Ordering::Greater => high = mid,
+            std::cmp::Ordering::Equal => return Ok(Some(mid)),
+        }
+    }
+    return Ok(None);
+}
+
+/// Reader for the multi-pack-index, an index of the objects of several
+/// packs: lookups are binary searches in the file, which is not loaded
+/// in memory
+pub struct MultiPackIndex {
+    file: fs::File,
+    /// Names of the pack indexes covered, sorted
+    pub pack_names: Vec<String>,
+    fanout: [u32; 256],
+    oids: u64,
+    offsets: u64,
+    large_offsets: Option<(u64, usize)>,
+}
+
+impl MultiPackIndex {
+    pub fn open(path: &Path) -> Result<MultiPackIndex> {
+        let mut file = fs::File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        let mut header = [0u8; 12];
+        if file.read_exact(&mut header).is_err() || &header[..4] != MIDX_MAGIC {
+            bail!("'{}' is not a multi-pack-index", path.display());
+        }
+        if header[4] != MIDX_VERSION || header[5] != SHA1_VERSION {
+            bail!(
+                "multi-pack-index version {} with hash version {} is not supported",
+                header[4],
+                header[5]
+            );
+        }
+        if header[7] != 0 {
+            bail!("incremental multi-pack-index chains are not supported");
+        }
+        let chunks = header[6] as usize;
+        let packs = u32::from_be_bytes(header[8..12].try_into()?) as usize;
+        let mut table = vec![0u8; 12 + (chunks + 1) * 12];
+        file.seek(SeekFrom::Start(0))?;
+        if file.read_exact(&mut table).is_err() {
+            bail!("multi-pack-index is truncated");
+        }
+        let chunks = parse_chunk_table(&table, 12, chunks, len)?;
+        let chunk = |id: &[u8; 4]| match chunks.get(id) {
+            Some(chunk) => Ok(*chunk),
+            None => bail!(
+                "multi-pack-index has no {} chunk",
+                String::from_utf8_lossy(id)
+            ),
+        };
+
+        let (names_start, names_len) = chunk(CHUNK_PACK_NAMES)?;
+        let mut names = vec![0u8; names_len];
+        file.seek(SeekFrom::Start(names_start as u64))?;
+        file.read_exact(&mut names)?;
+        let pack_names: Vec<String> = names
+            .split(|b| *b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8_lossy(name).to_string())
+            .collect();
+        if pack_names.len() != packs {
+            bail!(
+                "multi-pack-index names {} packs, expected {}",
+                pack_names.len(),
+                packs
+            );
+        }
+
+        let (fanout_start, fanout_len) = chunk(CHUNK_FANOUT)?;
+        let mut fanout_data = vec![0u8; fanout_len];
+        file.seek(SeekFrom::Start(fanout_start as u64))?;
+        file.read_exact(&mut fanout_data)?;
+        let (oids, oids_len) = chunk(CHUNK_OIDS)?;
+        let count = oids_len / 20;
+        let fanout = parse_fanout(&fanout_data, count)?;
+        let (offsets, offsets_len) = chunk(CHUNK_OFFSETS)?;
+        if oids_len != count * 20 || offsets_len != count * 8 {
+            bail!("multi-pack-index chunks don't match its {} objects", count);
+        }
+        let large_offsets = chunks
+            .get(CHUNK_LARGE_OFFSETS)
+            .map(|(start, len)| (*start as u64, len / 8));
+        return Ok(MultiPackIndex {
+            file,
+            pack_names,
+            fanout,
+            oids: oids as u64,
+            offsets: offsets as u64,
+            large_offsets,
+        });
+    }
+
+    /// Opens the multi-pack-index of the local object store, if any
+    pub fn open_local() -> Result<Option<MultiPackIndex>> {
+        return match repo_path(MIDX_PATH).exists() {
+            true => Ok(Some(MultiPackIndex::open(&repo_path(MIDX_PATH))?)),
+            false => Ok(None),
+        };
+    }
+
+    /// Number of objects indexed
+    pub fn len(&self) -> usize {
+        return self.fanout[255] as usize;
+    }
+
+    /// Whether no object is indexed
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    fn read_at(&mut self, position: u64, buf: &mut [u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(position))?;
+        self.file.read_exact(buf)?;
+        return Ok(());
+    }
+
+    /// Returns the hash of the object at position `i`
+    fn hash_at(&mut self, i: usize) -> Result<[u8; 20]> {
+        let mut hash = [0u8; 20];
+        self.read_at(self.oids + i as u64 * 20, &mut hash)?;
+        return Ok(hash);
+    }
+
+    /// Returns the hashes of all the indexed objects, sorted
+    pub fn hashes(&mut self) -> Result<Vec<String>> {
+        let mut table = vec![0u8; self.len() * 20];
+        self.read_at(self.oids, &mut table)?;
+        return Ok(table.chunks(20).map(hex::encode).collect());
+    }
+
+    /// Returns the pack (its position in `pack_names`) and offset of the
+    /// object at position `i`
+    fn location_at(&mut self, i: usize) -> Result<(usize, u64)> {
+        let mut entry = [0u8; 8];
+        self.read_at(self.offsets + i as u64 * 8, &mut entry)?;
+        let pack = u32::from_be_bytes(entry[..4].try_into()?) as usize;
+        let offset = u32::from_be_bytes(entry[4..].try_into()?);
+        if pack >= self.pack_names.len() {
+            bail!(
+                "multi-pack-index refers to pack {} of {}",
+                pack,
+                self.pack_names.len()
+            );
+        }
+        if offset & LARGE_OFFSET == 0 {
+            return Ok((pack, offset as u64));
+        }
+        let large = (offset & !LARGE_OFFSET) as usize;
+        let start = match self.large_offsets {
+            Some((start, count)) if large < count => start,
+            _ => bail!("multi-pack-index has no large offset {}", large),
+        };
+        let mut buf = [0u8; 8];
+        self.read_at(start + large as u64 * 8, &mut buf)?;
+        return Ok((pack, u64::from_be_bytes(buf)));
+    }
+
+    /// Looks up the name of the pack index holding an object and the offset
+    /// of the object in the pack
+    pub fn find(&mut self, sha1: &str) -> Result<Option<(String, u64)>> {
+        let target = hex::decode(sha1)?;
+        let fanout = self.fanout;
+        let position = search_fanout(&fanout, &target, |i| self.hash_at(i))?;
+        return match position {
+            Some(i) => {
+                let (pack, offset) = self.location_at(i)?;
+                Ok(Some((self.pack_names[pack].clone(), offset)))
+            }
+            None => Ok(None),
+        };
+    }
+}
+
+/// Writes the multi-pack-index of every pack of the local object store.
+/// Objects found in several packs are taken from the most recently modified
+/// one. Returns the number of indexed objects.
+pub fn write_multi_pack_index() -> Result<usize> {
+    let mut pack_names = Vec::new();
+    let mut entries: Vec<([u8; 20], u32, u64, u64)> = Vec::new();
+    for (pack, path) in packs::list_pack_indexes()?.iter().enumerate() {
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let mtime = match fs::metadata(path.with_extension("pack")) {
+            Ok(metadata) => metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs(),
+            Err(_) => bail!("pack of '{}' is missing", name),
+        };
+        let (indexed, _) = packs::parse_index(&fs::read(path)?)?;
+        for entry in indexed {
+            let hash: [u8; 20] = hex::decode(&entry.sha1)?.try_into().unwrap();
+            entries.push((hash, pack as u32, entry.offset, mtime));
+        }
+        pack_names.push(name);
+    }
+    // list_pack_indexes sorts the paths, so the names are sorted too
+    entries.sort_by(|a, b| a.0.cmp(&b.0).then(b.3.cmp(&a.3)).then(a.1.cmp(&b.1)));
+    entries.dedup_by(|next, kept| next.0 == kept.0);
+
+    let mut names = Vec::new();
+    for name in pack_names.iter() {
+        names.extend_from_slice(name.as_bytes());
+        names.push(0);
+    }
+    names.resize(names.len().div_ceil(4) * 4, 0);
+    let hashes: Vec<[u8; 20]> = entries.iter().map(|entry| entry.0).collect();
+    let need_large = entries.iter().any(|entry| entry.2 > u32::MAX as u64);
+    let mut offsets = Vec::with_capacity(entries.len() * 8);
+    let mut large_offsets = Vec::new();
+    for (_, pack, offset, _) in entries.iter() {
+        offsets.extend_from_slice(&pack.to_be_bytes());
+        // offsets up to 4 GiB fit unless some offset needs the large table
+        if need_large && *offset > 0x7fffffff {
+            let large = LARGE_OFFSET | (large_offsets.len() / 8) as u32;
+            offsets.extend_from_slice(&large.to_be_bytes());
+            large_offsets.extend_from_slice(&offset.to_be_bytes());
+        } else {
+            offsets.extend_from_slice(&(*offset as u32).to_be_bytes());
+        }
+    }
+
+    let mut chunks = vec![
+        (CHUNK_PACK_NAMES, names),
+        (CHUNK_FANOUT, write_fanout(&hashes)),
+        (CHUNK_OIDS, hashes.concat()),
+        (CHUNK_OFFSETS, offsets),
+    ];
+    if need_large {
+        chunks.push((CHUNK_LARGE_OFFSETS, large_offsets));
+    }
+    let mut header = MIDX_MAGIC.to_vec();
+    header.extend_from_slice(&[MIDX_VERSION, SHA1_VERSION, chunks.len() as u8, 0]);
+    header.extend_from_slice(&(pack_names.len() as u32).to_be_bytes());
+    let path = &repo_path(MIDX_PATH);
+    if path.exists() {
+        // written read-only
+        fs::remove_file(path)?;
+    }
+    packs::write_pack_file(path, &write_chunk_file(&header, &chunks))?;
+    return Ok(hashes.len());
+}
+
+/// Checks the multi-pack-index of the local object store: its checksum,
+/// and that each object is where the index of its pack says
+pub fn verify_multi_pack_index() -> Result<usize> {
+    let data = fs::read(repo_path(MIDX_PATH))?;
+    if data.len() < 20
+        || sha1::Sha1::digest(&data[..data.len() - 20]).as_slice() != &data[data.len() - 20..]
+    {
+        bail!("multi-pack-index checksum mismatch");
+    }
+    let mut midx = MultiPackIndex::open(&repo_path(MIDX_PATH))?;
+    let hashes = midx.hashes()?;
+    for (i, pair) in hashes.windows(2).enumerate() {
+        if pair[0] >= pair[1] {
+            bail!("multi-pack-index is not sorted at object {}", i + 1);
+        }
+    }
+    let mut indexes: HashMap<String, PackIndex> = HashMap::new();
+    for name in midx.pack_names.iter() {
+        let path = repo_path(PACK_DIR).join(name);
+        match PackIndex::open(&path) {
+            Ok(index) => indexes.insert(name.clone(), index),
+            Err(e) => bail!("multi-pack-index covers '{}': {}", name, e),
+        };
+    }
+    for (i, sha1) in hashes.iter().enumerate() {
+        let (pack, offset) = midx.location_at(i)?;
+        let name = &midx.pack_names[pack];
+        let index = indexes.get_mut(name).unwrap();
+        if index.find(sha1)? != Some(offset) {
+            bail!(
+                "multi-pack-index puts {} at offset {} of '{}', its index disagrees",
+                sha1,
+                offset,
+                name
+            );
+        }
+    }
+    return Ok(hashes.len());
+}
+
+/// Removes the multi-pack-index when it covers the pack of the index at
+/// `path`, about to be deleted
+pub fn forget_pack(path: &Path) -> Result<()> {
+    let name = path.file_name().unwrap().to_string_lossy().to_string();
+    if let Some(midx) = MultiPackIndex::open_local()? {
+        if midx.pack_names.contains(&name) {
+            fs::remove_file(repo_path(MIDX_PATH))?;
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::midx::{
+        forget_pack, verify_multi_pack_index, write_multi_pack_index, MultiPackIndex, MIDX_PATH,
+    };
+    use crate::objects;
+    use crate::testutils;
+    use std::fs;
+    use std::path::Path;
+    use std::process::Command;
+
+    #[test]
+    fn test_multi_pack_index() {
+        testutils::in_tmp_dir(|| {
+            testutils::create_repo(".", &[("a.txt", "a\n"), ("dir/b.txt", "b\n")]);
+            testutils::get_git_output(&["repack", "-q", "-d"]);
+            testutils::commit_files(".", &[("a.txt", "a\na\n")], "second");
+            testutils::get_git_output(&["repack", "-q", "-d"]);
+            testutils::commit_files(".", &[("dir/c.txt", "c\n")], "third");
+            testutils::get_git_output(&["repack", "-q", "-d"]);
+
+            let count = write_multi_pack_index().unwrap();
+            let ours = fs::read(MIDX_PATH).unwrap();
+            testutils::get_git_output(&["multi-pack-index", "write"]);
+            assert_eq!(ours, fs::read(MIDX_PATH).unwrap());
+            assert_eq!(verify_multi_pack_index().unwrap(), count);
+
+            let mut midx = MultiPackIndex::open_local().unwrap().unwrap();
+            assert_eq!(midx.pack_names.len(), 3);
+            assert_eq!(midx.len(), count);
+            let expected =
+                testutils::get_git_output(&["rev-list", "--objects", "--all", "--no-object-names"]);
+            let mut hashes = midx.hashes().unwrap();
+            let mut expected: Vec<&str> = expected.lines().collect();
+            hashes.sort();
+            expected.sort();
+            assert_eq!(hashes, expected);
+
+            for sha1 in expected {
+                assert!(midx.find(sha1).unwrap().is_some());
+                let (type_, data) = objects::read_object(&sha1.to_string()).unwrap();
+                let content = Command::new("git")
+                    .args(["cat-file", &type_.to_string(), sha1])
+                    .output()
+                    .unwrap()
+                    .stdout;
+                assert_eq!(data, content);
+            }
+            let missing = "0000000000000000000000000000000000000000";
+            assert!(midx.find(missing).unwrap().is_none());
+
+            // the index goes away with one of its packs
+            let idx = fs::read_dir(".git/objects/pack")
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .find(|path| path.extension().is_some_and(|e| e == "idx"))
+                .unwrap();
+            forget_pack(&idx).unwrap();
+            assert!(!Path::new(MIDX_PATH).exists());
+        });
+    }
+}
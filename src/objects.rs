@@ -1,12 +1,24 @@
 use anyhow::{bail, Result};
 use core::slice::Iter;
-use flate2::read::{ZlibDecoder, ZlibEncoder};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
 use flate2::Compression;
 use sha1::Digest;
 use std::fmt::Display;
 use std::fs;
-use std::io::{Cursor, Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::fetch;
+use crate::packs;
+use crate::refs::is_sha1;
+use crate::tree::Tree;
+use crate::{repo_path, repository_dir};
 
 /// GitObject is a public facing struct representing a `loaded` git object.
 #[derive(Debug)]
@@ -15,11 +27,12 @@ pub struct GitObject {
     pub data: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GitObjectType {
     Blob,
     Tree,
     Commit,
+    Tag,
 }
 
 impl Display for GitObjectType {
@@ -28,25 +41,27 @@ impl Display for GitObjectType {
             GitObjectType::Commit => "commit",
             GitObjectType::Tree => "tree",
             GitObjectType::Blob => "blob",
+            GitObjectType::Tag => "tag",
         };
         return write!(f, "{}", type_);
     }
 }
 
 impl GitObjectType {
-    fn from_string(string: &str) -> GitObjectType {
-        match string {
-            "commit" => GitObjectType::Commit,
-            "tree" => GitObjectType::Tree,
-            "blob" => GitObjectType::Blob,
-            _ => panic!("git object type not known"),
-        }
+    pub fn from_string(string: &str) -> Result<GitObjectType> {
+        return match string {
+            "commit" => Ok(GitObjectType::Commit),
+            "tree" => Ok(GitObjectType::Tree),
+            "blob" => Ok(GitObjectType::Blob),
+            "tag" => Ok(GitObjectType::Tag),
+            _ => bail!("git object type '{}' not known", string),
+        };
     }
 }
 
 /// Returns the relative path to a git object given its hash, in the cwd
 pub fn objstore_path(sha1digest: &String) -> String {
-    return Path::new(".git/objects")
+    return repo_path(".git/objects")
         .join(&sha1digest[..2])
         .join(&sha1digest[2..])
         .to_str()
@@ -54,26 +69,138 @@ pub fn objstore_path(sha1digest: &String) -> String {
         .to_string();
 }
 
-/// Returns size and type of git object from iterator of binary data
-fn parse_header(iter: &mut Iter<u8>) -> (usize, GitObjectType) {
-    let mut buf = String::new();
-    for &i in iter {
-        if i == 0 {
+/// Returns whether an object is in the local git object store, either
+/// loose or packed
+pub fn object_exists(sha1digest: &String) -> Result<bool> {
+    if Path::new(&objstore_path(sha1digest)).exists() {
+        return Ok(true);
+    }
+    return packs::has_packed_object(sha1digest);
+}
+
+/// Returns the hashes of every object in the local git object store
+pub fn list_objects() -> Result<Vec<String>> {
+    return find_objects_by_prefix("");
+}
+
+/// Returns the hashes of the loose and packed objects starting with the
+/// given hex prefix
+pub fn find_objects_by_prefix(prefix: &str) -> Result<Vec<String>> {
+    let root = &repo_path(".git/objects");
+    let mut hashes = Vec::new();
+    if !root.is_dir() {
+        return Ok(hashes);
+    }
+    for sha1 in packs::list_packed_objects()? {
+        if sha1.starts_with(prefix) {
+            hashes.push(sha1);
+        }
+    }
+    for dir in fs::read_dir(root)? {
+        let dir = dir?;
+        let dirname = dir.file_name().to_string_lossy().to_string();
+        let is_fanout = dirname.len() == 2 && dirname.chars().all(|c| c.is_ascii_hexdigit());
+        if !is_fanout || !(dirname.starts_with(prefix) || prefix.starts_with(&dirname)) {
+            continue;
+        }
+        for file in fs::read_dir(dir.path())? {
+            let sha1 = format!("{}{}", dirname, file?.file_name().to_string_lossy());
+            if sha1.len() == 40 && sha1.starts_with(prefix) {
+                hashes.push(sha1);
+            }
+        }
+    }
+    hashes.sort();
+    hashes.dedup();
+    return Ok(hashes);
+}
+
+/// Streaming reader over the contents of an object.
+///
+/// The header is parsed when the object is opened, reads of loose objects
+/// return the decompressed object data without ever holding all of it in
+/// memory. Packed objects are resolved in memory when opened.
+pub struct ObjectReader {
+    pub type_: GitObjectType,
+    pub size: usize,
+    decoder: Box<dyn Read>,
+}
+
+impl ObjectReader {
+    /// Opens the object with the given hash from the local git object store
+    pub fn open(sha1digest: &String) -> Result<ObjectReader> {
+        if let Some(reader) = ObjectReader::open_local(sha1digest)? {
+            return Ok(reader);
+        }
+        // partial clones fetch the objects they left out on demand, once
+        if is_sha1(sha1digest) && fetch::fetch_promised_objects(std::slice::from_ref(sha1digest))? {
+            if let Some(reader) = ObjectReader::open_local(sha1digest)? {
+                return Ok(reader);
+            }
+        }
+        bail!("object {} not found", sha1digest);
+    }
+
+    /// Opens an object of the local store, None when it isn't there
+    fn open_local(sha1digest: &String) -> Result<Option<ObjectReader>> {
+        let fpath = objstore_path(sha1digest);
+        let file = match fs::File::open(&fpath) {
+            Ok(f) => f,
+            Err(_) => {
+                if is_sha1(sha1digest) {
+                    if let Some((type_, data)) = packs::read_packed_object(sha1digest)? {
+                        return Ok(Some(ObjectReader {
+                            type_,
+                            size: data.len(),
+                            decoder: Box::new(io::Cursor::new(data)),
+                        }));
+                    }
+                }
+                return Ok(None);
+            }
+        };
+        let mut decoder = ZlibDecoder::new(file);
+        let (size, type_) = parse_header(&mut decoder)?;
+        return Ok(Some(ObjectReader {
+            type_,
+            size,
+            decoder: Box::new(decoder),
+        }));
+    }
+}
+
+impl Read for ObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        return self.decoder.read(buf);
+    }
+}
+
+/// Returns size and type of git object reading its header from a stream
+fn parse_header<R: Read>(reader: &mut R) -> Result<(usize, GitObjectType)> {
+    let mut buf = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        if reader.read(&mut byte)? == 0 {
+            bail!("unexpected end of object header");
+        }
+        if byte[0] == 0 {
             break;
         }
-        buf.push(i as char)
+        buf.push(byte[0]);
     }
-    let mut header_parts = buf.split(" ");
-    let type_ = header_parts.next().unwrap();
-    let size: usize = header_parts.next().unwrap().parse().unwrap();
-    return (size, GitObjectType::from_string(type_));
+    let header = String::from_utf8(buf)?;
+    let mut header_parts = header.split(' ');
+    let type_ = GitObjectType::from_string(header_parts.next().unwrap_or(""))?;
+    let size: usize = match header_parts.next().map(|s| s.parse()) {
+        Some(Ok(size)) => size,
+        _ => bail!("malformed object header '{}'", header),
+    };
+    return Ok((size, type_));
 }
 
 /// Parses tree data as String from iterator of binary data
 fn parse_tree_data(iter: &mut Iter<u8>) -> String {
     let mut s = String::new();
-    // TODO: Add file type to output (calling a func similar to get header, but without reading the whole file)
-    //      https://stackoverflow.com/questions/30412521/how-to-read-a-specific-number-of-bytes-from-a-stream
     while iter.len() > 0 {
         // permissions
         for &i in iter.by_ref() {
@@ -108,91 +235,467 @@ fn parse_tree_data(iter: &mut Iter<u8>) -> String {
 
 /// Parses blob data as String from iterator of binary data
 fn parse_blob_data(iter: &mut Iter<u8>) -> String {
-    let d: Vec<u8> = iter.map(|x| *x).collect();
+    let d: Vec<u8> = iter.copied().collect();
     let s = String::from_utf8(d);
     return s.unwrap();
 }
 
 /// Loads object from local git object store and returns a GitObject
 pub fn load_object(sha1digest: &String) -> Result<GitObject> {
-    // Decode file
-    let fpath = objstore_path(&sha1digest);
-    let file = match fs::File::open(&fpath) {
-        Ok(f) => f,
-        Err(_) => bail!("file '{}' does not exists", &fpath),
-    };
+    let (type_, buf) = read_object(sha1digest)?;
 
-    let mut buf = Vec::new();
-    if let Err(e) = ZlibDecoder::new(file).read_to_end(&mut buf) {
-        bail!(e)
-    };
-
-    // Parse file data
     let mut iter = buf.iter();
-    let (_, type_) = parse_header(&mut iter);
     let data = match type_ {
-        GitObjectType::Blob | GitObjectType::Commit => parse_blob_data(&mut iter),
+        GitObjectType::Blob | GitObjectType::Commit | GitObjectType::Tag => {
+            parse_blob_data(&mut iter)
+        }
         GitObjectType::Tree => parse_tree_data(&mut iter),
     };
-    return Ok(GitObject {
-        type_: type_,
-        data: data,
-    });
-}
-
-/// Prepares object data for hashing and writting
-fn prepare_data(type_: &String, data: &Vec<u8>) -> Cursor<Vec<u8>> {
-    let mut content = Cursor::new(Vec::new());
-    content
-        .write(
-            format!("{} {}{}", type_, data.len(), '\0')
-                .to_string()
-                .as_bytes(),
-        )
-        .unwrap();
-    content.write(data).unwrap();
-    content.seek(SeekFrom::Start(0)).unwrap();
-    return content;
-}
-
-/// Writes ZlibEncoder contents into a file
-fn write_encoder<R: Read>(encoder: &mut ZlibEncoder<R>, file: &mut fs::File) -> Result<()> {
-    let mut buffer = [0; 1024];
+    return Ok(GitObject { type_, data });
+}
+
+/// Reads the whole contents of an object from the local git object store
+pub fn read_object(sha1digest: &String) -> Result<(GitObjectType, Vec<u8>)> {
+    let mut reader = ObjectReader::open(sha1digest)?;
+    let mut buf = Vec::with_capacity(reader.size);
+    reader.read_to_end(&mut buf)?;
+    return Ok((reader.type_, buf));
+}
+
+/// Checks that a commit or tag header line is a valid `<name> <email> <time> <tz>`
+fn validate_signature(line: &str) -> Result<()> {
+    let (_, rest) = match line.split_once(" <") {
+        Some(parts) => parts,
+        None => bail!("missing email in '{}'", line),
+    };
+    let (_, date) = match rest.split_once("> ") {
+        Some(parts) => parts,
+        None => bail!("bad email in '{}'", line),
+    };
+    let mut date = date.split(' ');
+    let timestamp_ok = date.next().is_some_and(|t| t.parse::<i64>().is_ok());
+    let tz_ok = date
+        .next()
+        .is_some_and(|tz| tz.len() == 5 && (tz.starts_with('+') || tz.starts_with('-')));
+    if !timestamp_ok || !tz_ok {
+        bail!("bad date in '{}'", line);
+    }
+    return Ok(());
+}
+
+/// Checks that an object header field is present and holds a valid hash
+fn validate_hash_field<'a>(
+    lines: &mut std::iter::Peekable<std::str::Lines<'a>>,
+    field: &str,
+) -> Result<()> {
+    let line = lines.next().unwrap_or("");
+    match line.strip_prefix(field).and_then(|l| l.strip_prefix(' ')) {
+        Some(hash) if is_sha1(hash) => return Ok(()),
+        _ => bail!("invalid '{}' line: '{}'", field, line),
+    }
+}
+
+/// Validates that raw data is a well formed object of the given type
+pub fn validate_object(type_: GitObjectType, data: &[u8]) -> Result<()> {
+    match type_ {
+        GitObjectType::Blob => {}
+        GitObjectType::Tree => {
+            let tree = Tree::parse(data)?;
+            let mut previous: Option<String> = None;
+            for node in tree.iter() {
+                if !matches!(
+                    node.permissions.as_str(),
+                    "100644" | "100755" | "120000" | "40000" | "160000"
+                ) {
+                    bail!("invalid mode {} for '{}'", node.permissions, node.filename);
+                }
+                if node.filename.is_empty() || node.filename.contains('/') {
+                    bail!("invalid filename '{}' in tree", node.filename);
+                }
+                // trees sort as if their names ended with a slash
+                let key = match node.object_type() {
+                    GitObjectType::Tree => format!("{}/", node.filename),
+                    _ => node.filename.clone(),
+                };
+                if previous.is_some_and(|previous| previous >= key) {
+                    bail!("tree entries not sorted at '{}'", node.filename);
+                }
+                previous = Some(key);
+            }
+        }
+        GitObjectType::Commit => {
+            let content = String::from_utf8_lossy(data);
+            let mut lines = content.lines().peekable();
+            validate_hash_field(&mut lines, "tree")?;
+            while lines.peek().is_some_and(|l| l.starts_with("parent ")) {
+                validate_hash_field(&mut lines, "parent")?;
+            }
+            for field in ["author ", "committer "] {
+                match lines.next().and_then(|l| l.strip_prefix(field)) {
+                    Some(signature) => validate_signature(signature)?,
+                    None => bail!("missing '{}' line in commit", field.trim()),
+                }
+            }
+        }
+        GitObjectType::Tag => {
+            let content = String::from_utf8_lossy(data);
+            let mut lines = content.lines().peekable();
+            validate_hash_field(&mut lines, "object")?;
+            match lines.next().and_then(|l| l.strip_prefix("type ")) {
+                Some(type_) => {
+                    GitObjectType::from_string(type_)?;
+                }
+                None => bail!("missing 'type' line in tag"),
+            }
+            match lines.next().and_then(|l| l.strip_prefix("tag ")) {
+                Some(name) if !name.is_empty() => {}
+                _ => bail!("missing 'tag' line in tag"),
+            }
+            if let Some(tagger) = lines.next().and_then(|l| l.strip_prefix("tagger ")) {
+                validate_signature(tagger)?;
+            }
+        }
+    }
+    return Ok(());
+}
+
+/// Returns the object header that precedes the data when hashing and storing
+fn object_header(type_: &str, size: usize) -> String {
+    return format!("{} {}\0", type_, size);
+}
+
+/// Creates a new temporary object file inside the object store.
+///
+/// Names are unique per process and thread, `create_new` guarantees that
+/// concurrent writers never share a temporary file.
+fn create_temp_object() -> Result<(PathBuf, fs::File)> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    fs::create_dir_all(repo_path(".git/objects"))?;
     loop {
-        let bytes = encoder.read(&mut buffer)?;
-        if bytes == 0 {
-            break;
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let path = repo_path(".git/objects").join(format!(
+            "tmp_obj_{}_{}_{:08x}",
+            process::id(),
+            n,
+            nanos
+        ));
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => bail!(e),
         }
-        file.write(&buffer[..bytes])?;
     }
-    Ok(())
+}
+
+/// Returns whether object files should be flushed to disk (`core.fsync`).
+///
+/// The setting is loaded once per repository rather than for each object,
+/// keyed by the cwd since clones switch to the new repository.
+fn fsync_objects() -> Result<bool> {
+    static FSYNC: Mutex<Option<(PathBuf, bool)>> = Mutex::new(None);
+    let repository = repository_dir()?;
+    let mut cached = match FSYNC.lock() {
+        Ok(guard) => guard,
+        Err(poison) => poison.into_inner(),
+    };
+    if let Some((path, fsync)) = cached.as_ref() {
+        if *path == repository {
+            return Ok(*fsync);
+        }
+    }
+    let fsync = load_fsync_setting()?;
+    *cached = Some((repository, fsync));
+    return Ok(fsync);
+}
+
+/// Reads `core.fsync` (or the older `core.fsyncObjectFiles`) from the config
+fn load_fsync_setting() -> Result<bool> {
+    let config = Config::load()?;
+    let mut fsync = config.get_bool("core.fsyncObjectFiles")?.unwrap_or(false);
+    if let Some(components) = config.get("core.fsync") {
+        for component in components.split(',').map(|c| c.trim()) {
+            match component {
+                "loose-object" | "objects" | "added" | "committed" | "all" => fsync = true,
+                "-loose-object" | "-objects" | "-added" | "-committed" | "none" => fsync = false,
+                _ => {}
+            }
+        }
+    }
+    return Ok(fsync);
+}
+
+/// Moves a finished temporary object into its final path.
+///
+/// A hard link is used so an object written concurrently by another process
+/// is never replaced, falling back to a rename where links aren't supported.
+fn finalize_object(temp_path: &Path, outpath: &Path) -> Result<()> {
+    match fs::hard_link(temp_path, outpath) {
+        Ok(()) => {
+            fs::remove_file(temp_path)?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            fs::remove_file(temp_path)?;
+        }
+        Err(_) => {
+            fs::rename(temp_path, outpath)?;
+        }
+    }
+    return Ok(());
+}
+
+/// Streaming writer that hashes and compresses an object on the fly.
+///
+/// Data is compressed into a temporary file inside the object store which is
+/// only renamed into its final location by `finish`, once the hash is known.
+pub struct ObjectWriter {
+    hasher: sha1::Sha1,
+    encoder: ZlibEncoder<fs::File>,
+    temp_path: PathBuf,
+    size: usize,
+    written: usize,
+}
+
+impl ObjectWriter {
+    /// Starts writing an object of the given type and size
+    pub fn new(type_: &str, size: usize) -> Result<ObjectWriter> {
+        let (temp_path, file) = create_temp_object()?;
+        let header = object_header(type_, size);
+        let mut hasher = sha1::Sha1::new();
+        hasher.update(header.as_bytes());
+        let mut encoder = ZlibEncoder::new(file, Compression::fast());
+        encoder.write_all(header.as_bytes())?;
+        return Ok(ObjectWriter {
+            hasher,
+            encoder,
+            temp_path,
+            size,
+            written: 0,
+        });
+    }
+
+    /// Completes the object, moving it to its final path, and returns its hash
+    pub fn finish(self) -> Result<String> {
+        let temp_path = self.temp_path.clone();
+        let result = self.inner_finish();
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        return result;
+    }
+
+    fn inner_finish(self) -> Result<String> {
+        if self.written != self.size {
+            bail!(
+                "object size mismatch: expected {} bytes, got {}",
+                self.size,
+                self.written
+            );
+        }
+        let file = self.encoder.finish()?;
+        let sha1 = format!("{:x}", self.hasher.finalize());
+
+        let pathstr = objstore_path(&sha1);
+        let outpath = Path::new(&pathstr);
+        if outpath.exists() {
+            fs::remove_file(&self.temp_path)?;
+            return Ok(sha1);
+        }
+
+        let fsync = fsync_objects()?;
+        if fsync {
+            file.sync_all()?;
+        }
+        drop(file);
+        let mut permissions = fs::metadata(&self.temp_path)?.permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&self.temp_path, permissions)?;
+
+        let parent = outpath.parent().unwrap();
+        fs::create_dir_all(parent)?;
+        finalize_object(&self.temp_path, outpath)?;
+        #[cfg(unix)]
+        if fsync {
+            fs::File::open(parent)?.sync_all()?;
+        }
+        return Ok(sha1);
+    }
+}
+
+impl Write for ObjectWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.encoder.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.written += n;
+        return Ok(n);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.encoder.flush();
+    }
+}
+
+/// Stores object read from a stream in local git object database (in cwd)
+pub fn store_object_stream<R: Read>(type_: &str, size: usize, reader: &mut R) -> Result<String> {
+    let mut writer = ObjectWriter::new(type_, size)?;
+    if let Err(e) = io::copy(reader, &mut writer) {
+        let _ = fs::remove_file(&writer.temp_path);
+        bail!(e);
+    }
+    return writer.finish();
 }
 
 /// Stores object in local git object database (in cwd)
 pub fn store_object(type_: &String, data: &Vec<u8>) -> Result<String> {
-    let mut data_to_write = prepare_data(type_, data);
-    let sha1 = inner_calculate_object_hash(&mut data_to_write);
-    data_to_write.seek(SeekFrom::Start(0))?;
-
-    let pathstr = objstore_path(&sha1);
-    let outpath = Path::new(&pathstr);
-    fs::create_dir_all(outpath.parent().unwrap())?;
+    let sha1 = calculate_object_hash(type_, data);
+    if object_exists(&sha1)? {
+        return Ok(sha1);
+    }
+    return store_object_stream(type_, data.len(), &mut data.as_slice());
+}
 
-    let mut file = fs::File::create(outpath)?;
-    let mut encoder = ZlibEncoder::new(data_to_write, Compression::fast());
-    write_encoder(&mut encoder, &mut file)?;
-    return Ok(sha1);
+/// Calculates object sha1 hash of an object read from a stream
+pub fn calculate_object_hash_stream<R: Read>(
+    type_: &str,
+    size: usize,
+    reader: &mut R,
+) -> Result<String> {
+    let mut hash = sha1::Sha1::new();
+    hash.update(object_header(type_, size).as_bytes());
+    let mut buffer = [0; 8192];
+    let mut total = 0;
+    loop {
+        let bytes = reader.read(&mut buffer)?;
+        if bytes == 0 {
+            break;
+        }
+        hash.update(&buffer[..bytes]);
+        total += bytes;
+    }
+    if total != size {
+        bail!(
+            "object size mismatch: expected {} bytes, got {}",
+            size,
+            total
+        );
+    }
+    return Ok(format!("{:x}", hash.finalize()));
 }
 
 /// Calculates object sha1 hash
-fn inner_calculate_object_hash(data: &mut Cursor<Vec<u8>>) -> String {
+pub fn calculate_object_hash(type_: &String, data: &Vec<u8>) -> String {
     let mut hash = sha1::Sha1::new();
-    hash.update(data.get_mut());
+    hash.update(object_header(type_, data.len()).as_bytes());
+    hash.update(data);
     return format!("{:x}", hash.finalize());
 }
 
-/// Calculates object sha1 hash
-pub fn calculate_object_hash(type_: &String, data: &Vec<u8>) -> String {
-    let mut cursor = prepare_data(type_, data);
-    return inner_calculate_object_hash(&mut cursor);
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::path;
+    use std::thread;
+
+    use crate::objects::{objstore_path, store_object, ObjectReader, ObjectWriter};
+    use crate::testutils;
+
+    #[test]
+    fn test_streaming_write_and_read() {
+        testutils::in_tmp_git(|| {
+            let chunk = vec![b'x'; 64 * 1024];
+            let chunks = 32;
+
+            let mut writer = ObjectWriter::new("blob", chunk.len() * chunks).unwrap();
+            for _ in 0..chunks {
+                writer.write_all(&chunk).unwrap();
+            }
+            let sha1 = writer.finish().unwrap();
+            assert!(path::Path::new(&objstore_path(&sha1)).exists());
+
+            let git_size = testutils::get_git_output(&["cat-file", "-s", &sha1]);
+            assert_eq!(git_size.trim(), (chunk.len() * chunks).to_string());
+
+            let mut reader = ObjectReader::open(&sha1).unwrap();
+            assert_eq!(reader.size, chunk.len() * chunks);
+            let mut buf = vec![0u8; chunk.len()];
+            let mut total = 0;
+            loop {
+                let n = reader.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                assert!(buf[..n].iter().all(|b| *b == b'x'));
+                total += n;
+            }
+            assert_eq!(total, chunk.len() * chunks);
+
+            let mut short = ObjectWriter::new("blob", 10).unwrap();
+            short.write_all(b"abc").unwrap();
+            assert!(short.finish().is_err());
+            let leftovers = fs::read_dir(".git/objects")
+                .unwrap()
+                .filter(|e| {
+                    e.as_ref()
+                        .unwrap()
+                        .file_name()
+                        .to_string_lossy()
+                        .starts_with("tmp_obj_")
+                })
+                .count();
+            assert_eq!(leftovers, 0);
+        });
+    }
+
+    #[test]
+    fn test_atomic_object_writes() {
+        testutils::in_tmp_git(|| {
+            fs::write(".git/config", "[core]\n\tfsync = loose-object\n").unwrap();
+            let content = b"concurrent content\n".to_vec();
+
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    let content = content.clone();
+                    thread::spawn(move || store_object(&"blob".to_string(), &content).unwrap())
+                })
+                .collect();
+            let hashes: Vec<String> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+            assert!(hashes.iter().all(|h| *h == hashes[0]));
+
+            let path = objstore_path(&hashes[0]);
+            let metadata = fs::metadata(&path).unwrap();
+            assert!(metadata.permissions().readonly());
+            let modified = metadata.modified().unwrap();
+
+            let sha1 = store_object(&"blob".to_string(), &content).unwrap();
+            assert_eq!(sha1, hashes[0]);
+            assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), modified);
+
+            let mut writer = ObjectWriter::new("blob", content.len()).unwrap();
+            writer.write_all(&content).unwrap();
+            assert_eq!(writer.finish().unwrap(), sha1);
+
+            let leftovers = fs::read_dir(".git/objects")
+                .unwrap()
+                .filter(|e| {
+                    e.as_ref()
+                        .unwrap()
+                        .file_name()
+                        .to_string_lossy()
+                        .starts_with("tmp_obj_")
+                })
+                .count();
+            assert_eq!(leftovers, 0);
+
+            let git_content = testutils::get_git_output(&["cat-file", "-p", &sha1]);
+            assert_eq!(git_content.as_bytes(), content.as_slice());
+        });
+    }
 }
@@ -1,14 +1,47 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::Display,
-    io::{Cursor, Read},
+    fs,
+    io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
 };
 
 use anyhow::{bail, Result};
-use bytes::{Buf, Bytes};
+use bytes::Buf;
 use flate2::bufread::ZlibDecoder;
+use sha1::Digest;
 
-use crate::objects::calculate_object_hash;
+use crate::config::Config;
+use crate::delta;
+use crate::indexpack::{self, IndexPackOptions, PackSource};
+use crate::midx::{MultiPackIndex, MIDX_PATH};
+use crate::objects::{self, GitObjectType};
+use crate::{repo_path, repository_dir};
+
+/// Directory holding the packfiles of the local object store
+pub const PACK_DIR: &str = ".git/objects/pack";
+
+/// Magic number and version of pack index files (version 2)
+const IDX_MAGIC: &[u8] = b"\xfftOc";
+const IDX_VERSION: u32 = 2;
+
+/// Largest object, or delta, accepted from a pack. Entries are never
+/// inflated past the size they declare, so a hostile pack can't make us
+/// allocate more than this for one object.
+pub const MAX_OBJECT_SIZE: usize = 1 << 32;
+
+/// Most bytes the objects of a pack parsed in memory may inflate to in
+/// total, deltas resolved
+pub const MAX_INFLATED_PACK_SIZE: usize = 1 << 32;
+
+/// Most bytes the objects of a pack received from a remote may inflate to in
+/// total, deltas resolved, unless `transfer.maxInflatedSize` says otherwise
+const MAX_RECEIVED_INFLATED_SIZE: usize = 1 << 34;
+
+/// Largest object of a pack received from a remote, unless
+/// `transfer.maxObjectSize` says otherwise
+const MAX_RECEIVED_OBJECT_SIZE: usize = 512 << 20;
 
 #[derive(Debug, Clone, Copy)]
 pub enum ObjectType {
@@ -21,16 +54,27 @@ pub enum ObjectType {
 }
 
 impl ObjectType {
-    fn from_u8(b: u8) -> Self {
-        match b {
-            1 => ObjectType::Commit,
-            2 => ObjectType::Tree,
-            3 => ObjectType::Blob,
-            4 => ObjectType::Tag,
-            6 => ObjectType::OfsDelta,
-            7 => ObjectType::RefDelta,
-            _ => panic!("unexpected object type {b:08b}"),
-        }
+    pub fn from_u8(b: u8) -> Result<Self> {
+        return match b {
+            1 => Ok(ObjectType::Commit),
+            2 => Ok(ObjectType::Tree),
+            3 => Ok(ObjectType::Blob),
+            4 => Ok(ObjectType::Tag),
+            6 => Ok(ObjectType::OfsDelta),
+            7 => Ok(ObjectType::RefDelta),
+            _ => bail!("unexpected object type {}", b),
+        };
+    }
+}
+
+impl ObjectType {
+    pub fn from_object_type(type_: GitObjectType) -> Self {
+        return match type_ {
+            GitObjectType::Commit => ObjectType::Commit,
+            GitObjectType::Tree => ObjectType::Tree,
+            GitObjectType::Blob => ObjectType::Blob,
+            GitObjectType::Tag => ObjectType::Tag,
+        };
     }
 }
 
@@ -59,26 +103,37 @@ pub struct Entry {
     pub type_: ObjectType,
     pub size: usize,
     pub sha1: String,
-    pub data: Bytes,
+    /// Offset of the entry from the start of the pack
+    pub offset: usize,
+    /// Checksum of the raw (compressed) entry, as stored in the index
+    pub crc32: u32,
+    /// Size given in the entry header, the size of the delta for deltas
+    pub stored_size: usize,
+    /// Number of bytes of the entry in the pack, header included
+    pub packed_size: usize,
+    /// Hash of the delta base of deltas
+    pub base: Option<String>,
+    /// Length of the chain of deltas leading to a whole object
+    pub depth: usize,
 }
 
-fn unpack_compressed_data(data: &[u8]) -> Result<(usize, Bytes)> {
-    let bytes = Bytes::from(data.to_vec());
-    let mut decoder = ZlibDecoder::new(bytes.as_ref());
-    let mut content = Vec::new();
-    let r = decoder.read_to_end(&mut content);
-    match r {
-        Ok(_size) => return Ok((decoder.total_in() as usize, Bytes::from(content))),
-        Err(_err) => return Ok((0, Bytes::new())),
-    };
+/// An object listed in a pack index
+#[derive(Debug, Clone)]
+pub struct IndexEntry {
+    pub sha1: String,
+    pub crc32: u32,
+    pub offset: u64,
 }
 
-fn parse_header(data: &[u8]) -> Result<u32> {
-    let pack = String::from_utf8(data[..4].try_into()?)?;
+pub fn parse_header(data: &[u8]) -> Result<u32> {
+    let pack = String::from_utf8(data[..4].into())?;
     if !pack.eq("PACK") {
         bail!("packfile data missing PACK header");
     }
-    let _version = u32::from_be_bytes(data[4..8].try_into()?);
+    let version = u32::from_be_bytes(data[4..8].try_into()?);
+    if version != 2 && version != 3 {
+        bail!("unsupported pack version {}", version);
+    }
     let objects = u32::from_be_bytes(data[8..].try_into()?);
     return Ok(objects);
 }
@@ -87,142 +142,1676 @@ fn parse_header(data: &[u8]) -> Result<u32> {
 /// returns a tuple of the (bytes_read, encoded_size)
 ///
 /// (*1) https://github.com/git/git/blob/795ea8776befc95ea2becd8020c7a284677b4161/Documentation/gitformat-pack.txt#L83
-fn parse_size_encoding(data: &[u8], ix: usize, starting_shift: u8) -> (usize, usize) {
-    let mut ix_ = ix.clone();
-    let mut byte = data[ix_];
-    let mut size: usize = usize::from(byte & (u8::from(2).pow(starting_shift.into()) - 1));
+pub fn parse_size_encoding(data: &[u8], ix: usize, starting_shift: u8) -> Result<(usize, usize)> {
+    let mut ix_ = ix;
+    let mut byte = match data.get(ix_) {
+        Some(byte) => *byte,
+        None => bail!("truncated size encoding"),
+    };
+    let mut size: usize = usize::from(byte & (2u8.pow(starting_shift.into()) - 1));
     let mut shift = starting_shift;
     while byte > 127 {
         ix_ += 1;
-        byte = data[ix_];
+        byte = match data.get(ix_) {
+            Some(byte) => *byte,
+            None => bail!("truncated size encoding"),
+        };
+        if u32::from(shift) + 7 > usize::BITS {
+            bail!("size encoding overflows");
+        }
         size |= usize::from(byte & 0b01111111) << shift;
         shift += 7
     }
     ix_ += 1;
-    return (ix_ - ix, size);
+    return Ok((ix_ - ix, size));
 }
 
-fn apply_delta(data: &[u8], source_buf: &Bytes, target_size: usize) -> Result<Vec<u8>> {
+/// Reads the little endian value of a copy instruction, made of the bytes
+/// whose bit is set in `mask`
+fn read_delta_value(buf: &mut Cursor<&[u8]>, mask: u8, bytes: u8) -> Result<usize> {
+    let mut value: usize = 0;
+    for i in 0..bytes {
+        if mask & (1 << i) != 0 {
+            if !buf.has_remaining() {
+                bail!("delta ends in a copy instruction");
+            }
+            value |= (buf.get_u8() as usize) << (8 * i);
+        }
+    }
+    return Ok(value);
+}
+
+fn apply_delta(data: &[u8], source_buf: &[u8], target_size: usize) -> Result<Vec<u8>> {
     let mut buf = Cursor::new(data);
     let mut target_buf = Vec::new();
     while buf.remaining() > 0 {
         let b = buf.get_u8();
-        let mut offset: usize = 0;
-        let mut size: usize = 0;
         if (b >> 7) == 1 {
             // Copy mode
-            if (b & 0b1) != 0 {
-                offset |= buf.get_u8() as usize;
-            }
-            if (b & 0b10) != 0 {
-                offset |= (buf.get_u8() as usize) << 8;
-            }
-            if (b & 0b100) != 0 {
-                offset |= (buf.get_u8() as usize) << 16;
-            }
-            if (b & 0b1000) != 0 {
-                offset |= (buf.get_u8() as usize) << 24;
-            }
-            if (b & 0b1_0000) != 0 {
-                size |= buf.get_u8() as usize;
-            }
-            if (b & 0b10_0000) != 0 {
-                size |= (buf.get_u8() as usize) << 8;
-            }
-            if (b & 0b100_0000) != 0 {
-                size |= (buf.get_u8() as usize) << 16;
+            let offset = read_delta_value(&mut buf, b, 4)?;
+            let size = match read_delta_value(&mut buf, b >> 4, 3)? {
+                0 => 0x10000,
+                size => size,
+            };
+            match source_buf.get(offset..offset + size) {
+                Some(chunk) => target_buf.extend_from_slice(chunk),
+                None => bail!(
+                    "delta copies {} bytes at offset {} of a base of {} bytes",
+                    size,
+                    offset,
+                    source_buf.len()
+                ),
             }
-            target_buf.append(&mut source_buf[offset..offset + size].to_vec());
+        } else if b == 0 {
+            bail!("delta has the reserved instruction 0");
         } else {
             // Add mode
+            if buf.remaining() < b as usize {
+                bail!("delta adds {} bytes past its end", b);
+            }
             let mut data = vec![0u8; b as usize];
             buf.copy_to_slice(&mut data);
             target_buf.append(&mut data);
         }
+        if target_buf.len() > target_size {
+            bail!("delta produces more than {} bytes", target_size);
+        }
     }
     if target_buf.len() != target_size {
-        bail!("buffer size differrs from target size");
+        bail!(
+            "delta produces {} bytes, expected {}",
+            target_buf.len(),
+            target_size
+        );
     }
     return Ok(target_buf);
 }
 
-fn read_hash(data: &[u8], ix: usize) -> String {
-    return hex::encode(
-        &data
-            .get(ix..ix + 20)
-            .expect("should have 20 bytes for sha1"),
+/// Returns the size of the object a delta (with its size header) produces
+pub fn delta_target_size(delta: &[u8]) -> Result<usize> {
+    let (source_len_bytes, _) = parse_size_encoding(delta, 0, 7)?;
+    let (_, target_len) = parse_size_encoding(delta, source_len_bytes, 7)?;
+    return Ok(target_len);
+}
+
+/// Rebuilds an object from a delta (with its size header) and its base
+pub fn resolve_delta(delta: &[u8], base: &[u8]) -> Result<Vec<u8>> {
+    let (source_len_bytes, source_len) = parse_size_encoding(delta, 0, 7)?;
+    let (target_len_bytes, target_len) = parse_size_encoding(delta, source_len_bytes, 7)?;
+    if target_len > MAX_OBJECT_SIZE {
+        bail!(
+            "delta produces {} bytes, more than the limit of {}",
+            target_len,
+            MAX_OBJECT_SIZE
+        );
+    }
+    if base.len() != source_len {
+        bail!(
+            "delta base has {} bytes, expected {}",
+            base.len(),
+            source_len
+        );
+    }
+    return apply_delta(
+        &delta[source_len_bytes + target_len_bytes..],
+        base,
+        target_len,
+    );
+}
+
+/// Parses the negative offset to the base of an OFS_DELTA entry, returns
+/// the bytes read and the offset
+pub fn parse_ofs_encoding(data: &[u8], ix: usize) -> Result<(usize, usize)> {
+    let mut ix_ = ix;
+    let mut byte = match data.get(ix_) {
+        Some(byte) => *byte,
+        None => bail!("truncated ofs-delta offset"),
+    };
+    let mut offset = usize::from(byte & 0b01111111);
+    while byte > 127 {
+        ix_ += 1;
+        byte = match data.get(ix_) {
+            Some(byte) => *byte,
+            None => bail!("truncated ofs-delta offset"),
+        };
+        offset = match offset.checked_add(1).and_then(|o| o.checked_mul(128)) {
+            Some(shifted) => shifted | usize::from(byte & 0b01111111),
+            None => bail!("ofs-delta offset overflows"),
+        };
+    }
+    ix_ += 1;
+    return Ok((ix_ - ix, offset));
+}
+
+/// Encodes the negative offset to the base of an OFS_DELTA entry, the
+/// inverse of `parse_ofs_encoding`
+pub fn encode_ofs_encoding(offset: usize) -> Vec<u8> {
+    let mut bytes = vec![(offset & 0x7f) as u8];
+    let mut offset = offset >> 7;
+    while offset > 0 {
+        offset -= 1;
+        bytes.push(0x80 | (offset & 0x7f) as u8);
+        offset >>= 7;
+    }
+    bytes.reverse();
+    return bytes;
+}
+
+/// Reads a variable length number at the start of a pack entry: the bytes
+/// up to the first one without its high bit
+pub fn read_varint<R: Read>(input: &mut R) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        input.read_exact(&mut byte)?;
+        bytes.push(byte[0]);
+        if byte[0] & 0x80 == 0 {
+            return Ok(bytes);
+        }
+        if bytes.len() > 10 {
+            bail!("size encoding overflows");
+        }
+    }
+}
+
+/// Parses a pack held in memory, checking its trailer checksum, the size of
+/// each entry, their total size once inflated and the number of entries.
+/// The bases of all deltas must be in the pack.
+pub fn parse_packfile(data: &[u8]) -> Result<Packfile> {
+    if data.len() < 32 {
+        bail!("pack of {} bytes is too short", data.len());
+    }
+    let trailer = hex::encode(&data[data.len() - 20..]);
+    let checksum = hex::encode(sha1::Sha1::digest(&data[..data.len() - 20]));
+    if trailer != checksum {
+        bail!(
+            "pack checksum mismatch: trailer is {}, content hashes to {}",
+            trailer,
+            checksum
+        );
+    }
+    return indexpack::index(
+        data,
+        Some(data.len()),
+        PackSource::Memory(data),
+        None,
+        &IndexPackOptions {
+            max_inflated_size: Some(MAX_INFLATED_PACK_SIZE),
+            ..Default::default()
+        },
     );
 }
 
-fn parse_entries(data: &[u8]) -> Result<Vec<Entry>> {
-    let mut byhash: HashMap<String, Entry> = HashMap::new();
-    let mut ix = 0;
-    while ix < data.len() - 20 {
-        let type_bytes = data[ix] & 0b01110000;
-        let object_type = ObjectType::from_u8(type_bytes >> 4);
-        let (bytes_read, size) = parse_size_encoding(&data, ix, 4);
-        ix += bytes_read;
-        match object_type {
-            ObjectType::OfsDelta => {
-                panic!("unsupported object type ofs-delta")
-            }
-            ObjectType::RefDelta => {
-                let parent_sha = read_hash(&data, ix);
-                ix += 20;
-
-                let (bytes_read, content) = unpack_compressed_data(&data[ix..])?;
-                let (source_len_bytes, source_len) = parse_size_encoding(&content, 0, 7);
-                let (target_len_bytes, target_len) =
-                    parse_size_encoding(&content, source_len_bytes, 7);
-                ix += bytes_read;
-
-                let header_bytes_read = target_len_bytes + source_len_bytes;
-                let intructions = &content[header_bytes_read..];
-                let prev_data = byhash.get(&parent_sha).unwrap();
-                assert_eq!(prev_data.data.len(), source_len);
-
-                let deltified = Bytes::from(apply_delta(intructions, &prev_data.data, target_len)?);
-                let sha1 = calculate_object_hash(&prev_data.type_.to_string(), &deltified.to_vec());
-                let entry = Entry {
-                    type_: prev_data.type_,
-                    size: target_len,
-                    sha1: sha1.clone(),
-                    data: deltified,
-                };
-                byhash.insert(sha1, entry);
+/// Encodes the type and size header of a pack entry
+pub fn encode_entry_header(type_: ObjectType, size: usize) -> Vec<u8> {
+    let type_bits = match type_ {
+        ObjectType::Commit => 1,
+        ObjectType::Tree => 2,
+        ObjectType::Blob => 3,
+        ObjectType::Tag => 4,
+        ObjectType::OfsDelta => 6,
+        ObjectType::RefDelta => 7,
+    };
+    let mut header = Vec::new();
+    let mut byte = (type_bits << 4) | (size & 0b1111) as u8;
+    let mut size = size >> 4;
+    while size > 0 {
+        header.push(byte | 0x80);
+        byte = (size & 0x7f) as u8;
+        size >>= 7;
+    }
+    header.push(byte);
+    return header;
+}
+
+/// Encodes a whole object as a pack entry: its header then its compressed
+/// content
+pub fn encode_entry(type_: ObjectType, data: &[u8]) -> Result<Vec<u8>> {
+    return encode_delta_entry(type_, &[], data);
+}
+
+/// Encodes a pack entry whose header is followed by `base`, the reference to
+/// the base of deltas, before the compressed content
+fn encode_delta_entry(type_: ObjectType, base: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let mut header = encode_entry_header(type_, data.len());
+    header.extend_from_slice(base);
+    let mut encoder = flate2::write::ZlibEncoder::new(header, flate2::Compression::default());
+    encoder.write_all(data)?;
+    return Ok(encoder.finish()?);
+}
+
+/// Streaming writer of version 2 packs: the header is written when it's
+/// created, then each object, and the checksum trailer by `finish`
+pub struct PackWriter<W: Write> {
+    out: W,
+    hasher: sha1::Sha1,
+    expected: u32,
+    written: u32,
+    /// Bytes written so far, the offset of the next entry
+    offset: usize,
+}
+
+impl<W: Write> PackWriter<W> {
+    pub fn new(out: W, objects: u32) -> Result<PackWriter<W>> {
+        let mut writer = PackWriter {
+            out,
+            hasher: sha1::Sha1::new(),
+            expected: objects,
+            written: 0,
+            offset: 0,
+        };
+        let mut header = b"PACK".to_vec();
+        header.extend_from_slice(&2u32.to_be_bytes());
+        header.extend_from_slice(&objects.to_be_bytes());
+        writer.write_raw(&header)?;
+        return Ok(writer);
+    }
+
+    fn write_raw(&mut self, data: &[u8]) -> Result<()> {
+        self.hasher.update(data);
+        self.out.write_all(data)?;
+        self.offset += data.len();
+        return Ok(());
+    }
+
+    /// Appends an entry, returns its offset
+    fn write_entry(&mut self, entry: &[u8]) -> Result<usize> {
+        if self.written == self.expected {
+            bail!("pack already has its {} objects", self.expected);
+        }
+        let offset = self.offset;
+        self.write_raw(entry)?;
+        self.written += 1;
+        return Ok(offset);
+    }
+
+    /// Appends a whole (non delta) object, returns the offset of its entry
+    pub fn write_object(&mut self, type_: GitObjectType, data: &[u8]) -> Result<usize> {
+        return self.write_entry(&encode_entry(ObjectType::from_object_type(type_), data)?);
+    }
+
+    /// Appends a delta against the entry written at `base_offset`
+    pub fn write_ofs_delta(&mut self, base_offset: usize, delta: &[u8]) -> Result<usize> {
+        if base_offset >= self.offset {
+            bail!("delta base at offset {} isn't written yet", base_offset);
+        }
+        let base = encode_ofs_encoding(self.offset - base_offset);
+        return self.write_entry(&encode_delta_entry(ObjectType::OfsDelta, &base, delta)?);
+    }
+
+    /// Appends a delta against the object with the hash `base`
+    pub fn write_ref_delta(&mut self, base: &str, delta: &[u8]) -> Result<usize> {
+        let base = hex::decode(base)?;
+        return self.write_entry(&encode_delta_entry(ObjectType::RefDelta, &base, delta)?);
+    }
+
+    /// Writes the trailer, returns the output and the hash of the pack
+    pub fn finish(mut self) -> Result<(W, String)> {
+        if self.written != self.expected {
+            bail!(
+                "pack declares {} objects but {} were written",
+                self.expected,
+                self.written
+            );
+        }
+        let checksum = self.hasher.finalize();
+        self.out.write_all(&checksum)?;
+        return Ok((self.out, hex::encode(checksum)));
+    }
+}
+
+/// Options of the packs built from the local object store
+#[derive(Debug, Clone)]
+pub struct PackOptions {
+    /// Number of objects each one is compared to when looking for a delta
+    /// base, 0 to store every object whole
+    pub window: usize,
+    /// Longest chain of deltas leading to a whole object
+    pub depth: usize,
+    /// Refer to delta bases by their offset in the pack (OFS_DELTA) rather
+    /// than by their hash (REF_DELTA), which the other side must support
+    pub ofs_delta: bool,
+}
+
+impl Default for PackOptions {
+    fn default() -> Self {
+        return PackOptions {
+            window: 10,
+            depth: 50,
+            ofs_delta: true,
+        };
+    }
+}
+
+/// Builds a pack holding the given objects of the local object store, each
+/// given with the path it was found at (see `revwalk::list_objects_with_paths`).
+///
+/// Objects are delta compressed against each other as chosen by
+/// `delta::search_deltas`. They are written in the given order, except
+/// that delta bases are written before their deltas.
+pub fn build_pack(objects: &[(String, String)], options: &PackOptions) -> Result<Vec<u8>> {
+    let deltas = delta::search_deltas(objects, options.window, options.depth)?;
+    return write_pack(objects, &deltas, options);
+}
+
+/// Builds a pack holding the given objects, stored as the deltas found for
+/// them by `delta::search_deltas` (see `build_pack`)
+pub fn write_pack(
+    objects: &[(String, String)],
+    deltas: &[Option<delta::Delta>],
+    options: &PackOptions,
+) -> Result<Vec<u8>> {
+    let mut writer = PackWriter::new(Vec::new(), objects.len() as u32)?;
+    let mut offsets: Vec<Option<usize>> = vec![None; objects.len()];
+    for index in 0..objects.len() {
+        // the chain of deltas whose bases aren't written yet
+        let mut chain = vec![index];
+        while let Some(Some(delta)) = chain.last().map(|&last| &deltas[last]) {
+            if offsets[delta.base].is_some() {
+                break;
             }
+            chain.push(delta.base);
+        }
+        for &next in chain.iter().rev() {
+            if offsets[next].is_some() {
+                continue;
+            }
+            let offset = match &deltas[next] {
+                Some(delta) if options.ofs_delta => {
+                    writer.write_ofs_delta(offsets[delta.base].unwrap(), &delta.data)?
+                }
+                Some(delta) => writer.write_ref_delta(&objects[delta.base].0, &delta.data)?,
+                None => {
+                    let (type_, data) = objects::read_object(&objects[next].0)?;
+                    writer.write_object(type_, &data)?
+                }
+            };
+            offsets[next] = Some(offset);
+        }
+    }
+    return Ok(writer.finish()?.0);
+}
+
+/// Builds a version 2 pack index for the entries of a pack
+pub fn build_index(packfile: &Packfile) -> Result<Vec<u8>> {
+    let mut entries: Vec<&Entry> = packfile.entries.iter().collect();
+    entries.sort_by(|a, b| a.sha1.cmp(&b.sha1));
+
+    let mut buf = Vec::new();
+    buf.extend_from_slice(IDX_MAGIC);
+    buf.extend_from_slice(&IDX_VERSION.to_be_bytes());
+    let mut fanout = [0u32; 256];
+    for entry in entries.iter() {
+        let first = u8::from_str_radix(&entry.sha1[..2], 16)?;
+        for count in fanout[first as usize..].iter_mut() {
+            *count += 1;
+        }
+    }
+    for count in fanout {
+        buf.extend_from_slice(&count.to_be_bytes());
+    }
+    for entry in entries.iter() {
+        buf.extend_from_slice(&hex::decode(&entry.sha1)?);
+    }
+    for entry in entries.iter() {
+        buf.extend_from_slice(&entry.crc32.to_be_bytes());
+    }
+    // offsets that don't fit in 31 bits point into a table of 64 bit offsets
+    let mut large_offsets = Vec::new();
+    for entry in entries.iter() {
+        let offset = match u32::try_from(entry.offset) {
+            Ok(offset) if offset < 0x80000000 => offset,
             _ => {
-                let (bytes_read, content) = unpack_compressed_data(&data[ix..])?;
-                ix += bytes_read;
-                let sha1 = calculate_object_hash(&object_type.to_string(), &content.to_vec());
-                let entry = Entry {
-                    type_: object_type,
-                    size: size,
-                    sha1: sha1.clone(),
-                    data: content,
-                };
-                byhash.insert(sha1, entry);
+                large_offsets.push(entry.offset as u64);
+                0x80000000 | (large_offsets.len() as u32 - 1)
             }
         };
+        buf.extend_from_slice(&offset.to_be_bytes());
+    }
+    for offset in large_offsets {
+        buf.extend_from_slice(&offset.to_be_bytes());
     }
+    buf.extend_from_slice(&hex::decode(&packfile.sha1)?);
+    let checksum = sha1::Sha1::digest(&buf);
+    buf.extend_from_slice(&checksum);
+    return Ok(buf);
+}
 
-    // It would be better to store the entries directly in a Vec<Entry> and only
-    // store the (sha => index) of each entry in the HashMap. This would avoid the
-    // need to .clone() every entry in the HashMap to get a Vec<Entry>, while still allowing
-    // fast lookup for delta objects.
-    return Ok(byhash.values().cloned().collect());
+/// Writes a file of the pack directory through a temporary file, so readers
+/// never see it partially written
+pub fn write_pack_file(path: &Path, data: &[u8]) -> Result<()> {
+    let temp = path.with_extension("tmp");
+    let mut file = fs::File::create(&temp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    let mut permissions = file.metadata()?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(&temp, permissions)?;
+    fs::rename(&temp, path)?;
+    return Ok(());
 }
 
-pub fn parse_packfile(data: &[u8]) -> Result<Packfile> {
-    let data = Bytes::from(data.to_vec());
-    let expected_objects = parse_header(&data[..12])?;
-    let entries = parse_entries(&data[12..])?;
-    let packhash = read_hash(&data, data.len() - 20);
-    if entries.len() != expected_objects as usize {
-        bail!("parsed entries differ from expected entries");
-    }
-    return Ok(Packfile {
-        sha1: packhash,
-        entries: entries,
+/// Stores a pack received from a remote in `.git/objects/pack` along with
+/// its index, as it is read from `input`, returns its parsed contents. Thin
+/// packs are stored completed with their delta bases. The size of its
+/// objects is limited by `transfer.maxObjectSize` and their total size by
+/// `transfer.maxInflatedSize`, 0 lifting the limit.
+pub fn store_pack<R: BufRead>(input: R) -> Result<Packfile> {
+    let config = Config::load()?;
+    let max_inflated_size = match config.get_size("transfer.maxInflatedSize")? {
+        Some(0) => None,
+        Some(size) => Some(size),
+        None => Some(MAX_RECEIVED_INFLATED_SIZE),
+    };
+    let max_object_size = match config.get_size("transfer.maxObjectSize")? {
+        Some(0) => MAX_OBJECT_SIZE,
+        Some(size) => size.min(MAX_OBJECT_SIZE),
+        None => MAX_RECEIVED_OBJECT_SIZE,
+    };
+    let options = IndexPackOptions {
+        fix_thin: true,
+        max_inflated_size,
+        max_object_size,
+        ..Default::default()
+    };
+    return indexpack::store_pack(input, None, None, &options);
+}
+
+/// Marks a stored pack as coming from a promisor remote: the objects its
+/// objects refer to may be missing locally and fetched on demand
+pub fn mark_promisor_pack(packfile: &Packfile) -> Result<()> {
+    if packfile.entries.is_empty() {
+        return Ok(());
+    }
+    let path = repo_path(PACK_DIR).join(format!("pack-{}.promisor", packfile.sha1));
+    fs::write(path, "")?;
+    return Ok(());
+}
+
+/// Returns the paths of the indexes of all packs in the local object store
+pub fn list_pack_indexes() -> Result<Vec<PathBuf>> {
+    let dir = &repo_path(PACK_DIR);
+    let mut indexes = Vec::new();
+    if !dir.is_dir() {
+        return Ok(indexes);
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "idx") {
+            indexes.push(path);
+        }
+    }
+    indexes.sort();
+    return Ok(indexes);
+}
+
+/// Returns the hashes listed in a version 2 pack index held in memory
+pub fn parse_index_hashes(data: &[u8]) -> Result<Vec<String>> {
+    let table_start = 8 + 256 * 4;
+    if data.len() < table_start
+        || &data[..4] != IDX_MAGIC
+        || data[4..8] != IDX_VERSION.to_be_bytes()
+    {
+        bail!("not a version 2 pack index");
+    }
+    let count = u32::from_be_bytes(data[table_start - 4..table_start].try_into()?) as usize;
+    let table = match data.get(table_start..table_start + count * 20) {
+        Some(table) => table,
+        None => bail!("pack index of {} objects is truncated", count),
+    };
+    return Ok(table.chunks(20).map(hex::encode).collect());
+}
+
+/// Parses a version 2 pack index held in memory, checking its checksum and
+/// its tables, returns its entries sorted by hash and the hash of the pack
+pub fn parse_index(data: &[u8]) -> Result<(Vec<IndexEntry>, String)> {
+    let hashes = parse_index_hashes(data)?;
+    let n = hashes.len();
+    let crcs_start = 8 + 256 * 4 + n * 20;
+    let offsets_start = crcs_start + n * 4;
+    let large_start = offsets_start + n * 4;
+    if data.len() < large_start + 40 {
+        bail!("pack index of {} objects is truncated", n);
+    }
+    let checksum = sha1::Sha1::digest(&data[..data.len() - 20]);
+    if checksum.as_slice() != &data[data.len() - 20..] {
+        bail!("pack index checksum mismatch");
+    }
+    for (i, pair) in hashes.windows(2).enumerate() {
+        if pair[0] >= pair[1] {
+            bail!("pack index is not sorted at entry {}", i + 1);
+        }
+    }
+    let mut counts = [0u32; 256];
+    for sha1 in hashes.iter() {
+        counts[u8::from_str_radix(&sha1[..2], 16)? as usize] += 1;
+    }
+    let mut total = 0;
+    for (first, count) in counts.iter().enumerate() {
+        total += count;
+        let start = 8 + first * 4;
+        if u32::from_be_bytes(data[start..start + 4].try_into()?) != total {
+            bail!("pack index fan-out is wrong at {:02x}", first);
+        }
+    }
+
+    let large_offsets = (data.len() - 40 - large_start) / 8;
+    if large_start + large_offsets * 8 + 40 != data.len() {
+        bail!(
+            "pack index has {} unexpected bytes",
+            data.len() - 40 - large_start
+        );
+    }
+    let mut entries = Vec::with_capacity(n);
+    for (i, sha1) in hashes.into_iter().enumerate() {
+        let crc32 =
+            u32::from_be_bytes(data[crcs_start + i * 4..crcs_start + i * 4 + 4].try_into()?);
+        let offset =
+            u32::from_be_bytes(data[offsets_start + i * 4..offsets_start + i * 4 + 4].try_into()?);
+        let offset = match offset & 0x80000000 {
+            0 => offset as u64,
+            _ => {
+                let large = (offset & 0x7fffffff) as usize;
+                if large >= large_offsets {
+                    bail!("pack index entry {} has no large offset {}", sha1, large);
+                }
+                let start = large_start + large * 8;
+                u64::from_be_bytes(data[start..start + 8].try_into()?)
+            }
+        };
+        entries.push(IndexEntry {
+            sha1,
+            crc32,
+            offset,
+        });
+    }
+    let pack_sha1 = hex::encode(&data[data.len() - 40..data.len() - 20]);
+    return Ok((entries, pack_sha1));
+}
+
+/// Reader for a version 2 pack index, looking up hashes without loading
+/// the whole index in memory
+pub struct PackIndex {
+    path: PathBuf,
+    file: fs::File,
+    fanout: [u32; 256],
+}
+
+impl PackIndex {
+    pub fn open(path: &Path) -> Result<PackIndex> {
+        let mut file = fs::File::open(path)?;
+        let mut header = [0u8; 8 + 256 * 4];
+        if file.read_exact(&mut header).is_err()
+            || &header[..4] != IDX_MAGIC
+            || header[4..8] != IDX_VERSION.to_be_bytes()
+        {
+            bail!("'{}' is not a version 2 pack index", path.display());
+        }
+        let mut fanout = [0u32; 256];
+        for (i, count) in fanout.iter_mut().enumerate() {
+            let start = 8 + i * 4;
+            *count = u32::from_be_bytes(header[start..start + 4].try_into()?);
+        }
+        return Ok(PackIndex {
+            path: path.to_path_buf(),
+            file,
+            fanout,
+        });
+    }
+
+    /// Number of objects in the pack
+    pub fn len(&self) -> usize {
+        return self.fanout[255] as usize;
+    }
+
+    /// Whether the pack has no objects
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    /// Path of the pack this index describes
+    pub fn pack_path(&self) -> PathBuf {
+        return self.path.with_extension("pack");
+    }
+
+    fn read_at(&mut self, position: u64, buf: &mut [u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(position))?;
+        self.file.read_exact(buf)?;
+        return Ok(());
+    }
+
+    /// Returns the hashes of all the objects in the pack, sorted
+    pub fn hashes(&mut self) -> Result<Vec<String>> {
+        let mut table = vec![0u8; self.len() * 20];
+        self.read_at(8 + 256 * 4, &mut table)?;
+        return Ok(table.chunks(20).map(hex::encode).collect());
+    }
+
+    /// Returns the offset of an object in the pack
+    fn offset_at(&mut self, i: usize) -> Result<u64> {
+        let n = self.len() as u64;
+        let offsets_start = 8 + 256 * 4 + n * 24;
+        let mut buf = [0u8; 4];
+        self.read_at(offsets_start + i as u64 * 4, &mut buf)?;
+        let offset = u32::from_be_bytes(buf);
+        if offset & 0x80000000 == 0 {
+            return Ok(offset as u64);
+        }
+        let mut buf = [0u8; 8];
+        let large = (offset & 0x7fffffff) as u64;
+        self.read_at(offsets_start + n * 4 + large * 8, &mut buf)?;
+        return Ok(u64::from_be_bytes(buf));
+    }
+
+    /// Returns the hash of the object at `offset` of the pack, if an entry
+    /// starts there
+    pub fn hash_at_offset(&mut self, offset: u64) -> Result<Option<String>> {
+        let mut table = vec![0u8; self.len() * 4];
+        self.read_at(8 + 256 * 4 + self.len() as u64 * 24, &mut table)?;
+        for (i, entry) in table.chunks(4).enumerate() {
+            let small = u32::from_be_bytes(entry.try_into()?);
+            let matches = match small & 0x80000000 {
+                0 => small as u64 == offset,
+                _ => self.offset_at(i)? == offset,
+            };
+            if matches {
+                let mut hash = [0u8; 20];
+                self.read_at(8 + 256 * 4 + i as u64 * 20, &mut hash)?;
+                return Ok(Some(hex::encode(hash)));
+            }
+        }
+        return Ok(None);
+    }
+
+    /// Returns the hashes of the commits in the pack, typed from the headers
+    /// of their entries and of their delta bases, nothing being inflated
+    pub fn commits(&mut self) -> Result<Vec<String>> {
+        let mut pack = BufReader::new(fs::File::open(self.pack_path())?);
+        let mut types = HashMap::new();
+        let mut commits = Vec::new();
+        for (i, sha1) in self.hashes()?.into_iter().enumerate() {
+            let offset = self.offset_at(i)?;
+            if let ObjectType::Commit = self.entry_type(&mut pack, offset, &mut types)? {
+                commits.push(sha1);
+            }
+        }
+        return Ok(commits);
+    }
+
+    /// Returns the type of the object at `offset` of the pack, following
+    /// its chain of deltas. `types` keeps the types of the entries already
+    /// seen.
+    fn entry_type(
+        &mut self,
+        pack: &mut BufReader<fs::File>,
+        offset: u64,
+        types: &mut HashMap<u64, ObjectType>,
+    ) -> Result<ObjectType> {
+        let mut chain = Vec::new();
+        let mut visited = HashSet::new();
+        let mut offset = offset;
+        let type_ = loop {
+            if let Some(type_) = types.get(&offset) {
+                break *type_;
+            }
+            if !visited.insert(offset) {
+                bail!(
+                    "delta cycle at offset {} of {}",
+                    offset,
+                    self.pack_path().display()
+                );
+            }
+            if chain.len() > MAX_DELTA_DEPTH {
+                bail!(
+                    "chain of more than {} deltas in {}",
+                    MAX_DELTA_DEPTH,
+                    self.pack_path().display()
+                );
+            }
+            chain.push(offset);
+            pack.seek(SeekFrom::Start(offset))?;
+            let header = read_entry_header(pack, self, offset)?;
+            match header.base {
+                None => break header.type_,
+                Some((None, base_offset)) => offset = base_offset,
+                Some((Some(base_sha), _)) => match self.find(&base_sha)? {
+                    Some(base_offset) => offset = base_offset,
+                    None => {
+                        let (type_, _) = objects::read_object(&base_sha)?;
+                        break ObjectType::from_object_type(type_);
+                    }
+                },
+            }
+        };
+        for offset in chain {
+            types.insert(offset, type_);
+        }
+        return Ok(type_);
+    }
+
+    /// Looks up the offset of an object in the pack
+    pub fn find(&mut self, sha1: &str) -> Result<Option<u64>> {
+        let target = hex::decode(sha1)?;
+        let first = target[0] as usize;
+        let mut low = if first == 0 {
+            0
+        } else {
+            self.fanout[first - 1] as usize
+        };
+        let mut high = self.fanout[first] as usize;
+        let mut hash = [0u8; 20];
+        while low < high {
+            let mid = (low + high) / 2;
+            self.read_at(8 + 256 * 4 + mid as u64 * 20, &mut hash)?;
+            match hash.as_slice().cmp(target.as_slice()) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(Some(self.offset_at(mid)?)),
+            }
+        }
+        return Ok(None);
+    }
+}
+
+/// Longest chain of deltas followed to read an object, well above the
+/// chains git and we write
+const MAX_DELTA_DEPTH: usize = 10000;
+
+/// An entry of a pack as stored, deltas not applied
+struct RawEntry {
+    type_: ObjectType,
+    /// Base of deltas: the hash of REF_DELTAs, the offset of OFS_DELTAs
+    base: Option<(Option<String>, u64)>,
+    content: Vec<u8>,
+}
+
+/// Header of an entry of a pack: its type, size and delta base
+struct EntryHeader {
+    type_: ObjectType,
+    size: usize,
+    base: Option<(Option<String>, u64)>,
+}
+
+/// Reads the header of the entry at `offset` of a pack, up to its content
+fn read_entry_header<R: BufRead>(
+    reader: &mut R,
+    index: &PackIndex,
+    offset: u64,
+) -> Result<EntryHeader> {
+    let header = read_varint(reader)?;
+    let object_type = ObjectType::from_u8((header[0] & 0b01110000) >> 4)?;
+    let (_, size) = parse_size_encoding(&header, 0, 4)?;
+    if size > MAX_OBJECT_SIZE {
+        bail!(
+            "object at offset {} of {} has {} bytes, more than the limit of {}",
+            offset,
+            index.pack_path().display(),
+            size,
+            MAX_OBJECT_SIZE
+        );
+    }
+    let base = match object_type {
+        ObjectType::OfsDelta => {
+            let encoded = read_varint(reader)?;
+            let (_, distance) = parse_ofs_encoding(&encoded, 0)?;
+            match offset.checked_sub(distance as u64).filter(|_| distance > 0) {
+                Some(base_offset) => Some((None, base_offset)),
+                None => bail!("invalid ofs-delta base in {}", index.pack_path().display()),
+            }
+        }
+        ObjectType::RefDelta => {
+            let mut sha = [0u8; 20];
+            reader.read_exact(&mut sha)?;
+            Some((Some(hex::encode(sha)), 0))
+        }
+        _ => None,
+    };
+    return Ok(EntryHeader {
+        type_: object_type,
+        size,
+        base,
     });
 }
+
+/// Reads and inflates the entry at `offset` of an open pack
+fn read_raw_entry(pack: &mut fs::File, index: &PackIndex, offset: u64) -> Result<RawEntry> {
+    pack.seek(SeekFrom::Start(offset))?;
+    let mut reader = BufReader::new(&mut *pack);
+    let EntryHeader { type_, size, base } = read_entry_header(&mut reader, index, offset)?;
+    let mut content = Vec::new();
+    ZlibDecoder::new(reader)
+        .take(size as u64 + 1)
+        .read_to_end(&mut content)?;
+    if content.len() != size {
+        bail!(
+            "object at offset {} of {} has {} bytes, expected {}",
+            offset,
+            index.pack_path().display(),
+            content.len(),
+            size
+        );
+    }
+    return Ok(RawEntry {
+        type_,
+        base,
+        content,
+    });
+}
+
+/// Reads and resolves the entry at `offset` of an open pack. The chain of
+/// deltas is followed down to its base, then applied back up.
+fn read_pack_entry(
+    pack: &mut fs::File,
+    index: &mut PackIndex,
+    offset: u64,
+) -> Result<(ObjectType, Vec<u8>)> {
+    let mut deltas = Vec::new();
+    let mut visited = HashSet::new();
+    let mut offset = offset;
+    let (object_type, mut content) = loop {
+        if !visited.insert(offset) {
+            bail!(
+                "delta cycle at offset {} of {}",
+                offset,
+                index.pack_path().display()
+            );
+        }
+        if deltas.len() > MAX_DELTA_DEPTH {
+            bail!(
+                "chain of more than {} deltas in {}",
+                MAX_DELTA_DEPTH,
+                index.pack_path().display()
+            );
+        }
+        let RawEntry {
+            type_,
+            base,
+            content,
+        } = read_raw_entry(pack, index, offset)?;
+        match base {
+            None => break (type_, content),
+            Some((None, base_offset)) => offset = base_offset,
+            Some((Some(base_sha), _)) => match index.find(&base_sha)? {
+                Some(base_offset) => offset = base_offset,
+                None => {
+                    deltas.push(content);
+                    let (type_, data) = objects::read_object(&base_sha)?;
+                    break (ObjectType::from_object_type(type_), data);
+                }
+            },
+        }
+        deltas.push(content);
+    };
+    while let Some(delta) = deltas.pop() {
+        content = resolve_delta(&delta, &content)?;
+    }
+    return Ok((object_type, content));
+}
+
+/// Indexes of the packs of a repository, kept open between lookups
+struct OpenPacks {
+    repository: PathBuf,
+    midx: Option<(MultiPackIndex, fs::Metadata)>,
+    /// Indexes of the packs the multi-pack-index doesn't cover
+    indexes: Vec<PackIndex>,
+}
+
+impl OpenPacks {
+    /// Opens the indexes of the packs in the pack directory, reusing those
+    /// of `previous` that are still there
+    fn scan(repository: PathBuf, previous: Option<OpenPacks>) -> Result<OpenPacks> {
+        let (previous_midx, mut previous_indexes) = match previous {
+            Some(previous) => (previous.midx, previous.indexes),
+            None => (None, Vec::new()),
+        };
+        let midx = match fs::metadata(repo_path(MIDX_PATH)) {
+            Ok(metadata) => match previous_midx {
+                Some((midx, opened))
+                    if opened.len() == metadata.len()
+                        && opened.modified().ok() == metadata.modified().ok() =>
+                {
+                    Some((midx, opened))
+                }
+                _ => Some((MultiPackIndex::open(&repo_path(MIDX_PATH))?, metadata)),
+            },
+            Err(_) => None,
+        };
+        // a stale multi-pack-index is ignored, all packs are searched
+        let midx = midx.filter(|(midx, _)| {
+            midx.pack_names.iter().all(|name| {
+                let path = repo_path(PACK_DIR).join(name);
+                return path.exists() && path.with_extension("pack").exists();
+            })
+        });
+        let covered: HashSet<&String> = match &midx {
+            Some((midx, _)) => midx.pack_names.iter().collect(),
+            None => HashSet::new(),
+        };
+        let mut indexes = Vec::new();
+        for path in list_pack_indexes()? {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            if covered.contains(&name) {
+                continue;
+            }
+            match previous_indexes.iter().position(|index| index.path == path) {
+                Some(i) => indexes.push(previous_indexes.swap_remove(i)),
+                None => indexes.push(PackIndex::open(&path)?),
+            }
+        }
+        return Ok(OpenPacks {
+            repository,
+            midx,
+            indexes,
+        });
+    }
+
+    /// Returns the path of the index of the pack holding an object and the
+    /// offset of the object in the pack
+    fn find(&mut self, sha1: &str) -> Result<Option<(PathBuf, u64)>> {
+        if let Some((midx, _)) = self.midx.as_mut() {
+            if let Some((name, offset)) = midx.find(sha1)? {
+                return Ok(Some((repo_path(PACK_DIR).join(name), offset)));
+            }
+        }
+        for index in self.indexes.iter_mut() {
+            if let Some(offset) = index.find(sha1)? {
+                return Ok(Some((index.path.clone(), offset)));
+            }
+        }
+        return Ok(None);
+    }
+}
+
+/// Packs of the last repository objects were looked up in. Like git, the
+/// pack directory is scanned once, and again only when an object isn't
+/// found or its pack is gone, so lookups don't reopen every index.
+static OPEN_PACKS: Mutex<Option<OpenPacks>> = Mutex::new(None);
+
+/// Looks an object up in the open packs of the repository in the cwd,
+/// scanning the pack directory again when `rescan` is set or the object
+/// isn't found
+fn locate_packed_object(sha1: &str, rescan: bool) -> Result<Option<(PathBuf, u64)>> {
+    let repository = repository_dir()?;
+    let mut open_packs = match OPEN_PACKS.lock() {
+        Ok(guard) => guard,
+        Err(poison) => poison.into_inner(),
+    };
+    let cached = open_packs.take().filter(|p| p.repository == repository);
+    let (mut packs, scanned) = match cached {
+        Some(packs) if !rescan => (packs, false),
+        cached => (OpenPacks::scan(repository.clone(), cached)?, true),
+    };
+    let mut found = packs.find(sha1)?;
+    if found.is_none() && !scanned {
+        packs = OpenPacks::scan(repository, Some(packs))?;
+        found = packs.find(sha1)?;
+    }
+    *open_packs = Some(packs);
+    return Ok(found);
+}
+
+/// Finds the local pack holding an object, returns its open pack and index
+/// and the offset of the object. The multi-pack-index is searched first,
+/// then the indexes of the packs it doesn't cover.
+fn find_packed_object(sha1: &str) -> Result<Option<(fs::File, PackIndex, u64)>> {
+    let mut rescan = false;
+    loop {
+        let (path, offset) = match locate_packed_object(sha1, rescan)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let opened = PackIndex::open(&path)
+            .and_then(|index| Ok((fs::File::open(index.pack_path())?, index)));
+        let removed = || !path.exists() || !path.with_extension("pack").exists();
+        match opened {
+            Ok((pack, index)) => return Ok(Some((pack, index, offset))),
+            // the pack was removed since the packs were scanned
+            Err(_) if !rescan && removed() => rescan = true,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Returns whether an object is stored in one of the local packs
+pub fn has_packed_object(sha1: &str) -> Result<bool> {
+    return Ok(locate_packed_object(sha1, false)?.is_some());
+}
+
+/// Reads an object stored in one of the local packs
+pub fn read_packed_object(sha1: &str) -> Result<Option<(GitObjectType, Vec<u8>)>> {
+    let (mut pack, mut index, offset) = match find_packed_object(sha1)? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let (type_, data) = read_pack_entry(&mut pack, &mut index, offset)?;
+    return Ok(Some((
+        GitObjectType::from_string(&type_.to_string())?,
+        data,
+    )));
+}
+
+/// Returns the hash of the delta base of an object stored as a delta in one
+/// of the local packs, None when it isn't a delta or isn't packed
+pub fn packed_delta_base(sha1: &str) -> Result<Option<String>> {
+    let (mut pack, mut index, offset) = match find_packed_object(sha1)? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    pack.seek(SeekFrom::Start(offset))?;
+    let header = read_entry_header(&mut BufReader::new(&mut pack), &index, offset)?;
+    return match header.base {
+        None => Ok(None),
+        Some((Some(base_sha), _)) => Ok(Some(base_sha)),
+        Some((None, base_offset)) => match index.hash_at_offset(base_offset)? {
+            Some(base_sha) => Ok(Some(base_sha)),
+            None => bail!(
+                "no object at offset {} of {}, the base of {}",
+                base_offset,
+                index.pack_path().display(),
+                sha1
+            ),
+        },
+    };
+}
+
+/// Returns the hashes of all the objects in local packs
+pub fn list_packed_objects() -> Result<Vec<String>> {
+    let mut hashes = Vec::new();
+    for path in list_pack_indexes()? {
+        hashes.extend(PackIndex::open(&path)?.hashes()?);
+    }
+    return Ok(hashes);
+}
+
+/// Checks a pack and its index, given the path of either: the checksums of
+/// both, each entry of the pack and that the index lists all of them with
+/// their offset and CRC
+pub fn verify_pack(path: &Path) -> Result<Packfile> {
+    let index_path = path.with_extension("idx");
+    let index = match fs::read(&index_path) {
+        Ok(index) => index,
+        Err(e) => bail!("unable to read '{}': {}", index_path.display(), e),
+    };
+    let (indexed, pack_sha1) = match parse_index(&index) {
+        Ok(parsed) => parsed,
+        Err(e) => bail!("'{}': {}", index_path.display(), e),
+    };
+    let pack_path = path.with_extension("pack");
+    let data = match fs::read(&pack_path) {
+        Ok(data) => data,
+        Err(e) => bail!("unable to read '{}': {}", pack_path.display(), e),
+    };
+    let packfile = match parse_packfile(&data) {
+        Ok(packfile) => packfile,
+        Err(e) => bail!("'{}': {}", pack_path.display(), e),
+    };
+    if packfile.sha1 != pack_sha1 {
+        bail!(
+            "'{}' is the index of pack {}, not {}",
+            index_path.display(),
+            pack_sha1,
+            packfile.sha1
+        );
+    }
+    if indexed.len() != packfile.entries.len() {
+        bail!(
+            "'{}' lists {} objects but the pack holds {}",
+            index_path.display(),
+            indexed.len(),
+            packfile.entries.len()
+        );
+    }
+    let byhash: HashMap<&str, &Entry> = packfile
+        .entries
+        .iter()
+        .map(|entry| (entry.sha1.as_str(), entry))
+        .collect();
+    for index_entry in indexed.iter() {
+        let entry = match byhash.get(index_entry.sha1.as_str()) {
+            Some(entry) => entry,
+            None => bail!(
+                "object {} of the index is not in the pack",
+                index_entry.sha1
+            ),
+        };
+        if entry.offset as u64 != index_entry.offset {
+            bail!(
+                "object {} is at offset {} of the pack, the index says {}",
+                entry.sha1,
+                entry.offset,
+                index_entry.offset
+            );
+        }
+        if entry.crc32 != index_entry.crc32 {
+            bail!(
+                "CRC mismatch for object {} at offset {}",
+                entry.sha1,
+                entry.offset
+            );
+        }
+    }
+    return Ok(packfile);
+}
+
+/// Writes what `verify-pack -v` shows of a pack: each entry with its type,
+/// size, size in the pack, offset and for deltas their depth and base, then
+/// the number of objects by delta chain length (only that with `stat_only`)
+pub fn write_pack_stats<W: Write>(packfile: &Packfile, stat_only: bool, out: &mut W) -> Result<()> {
+    let mut chains: Vec<usize> = Vec::new();
+    for entry in packfile.entries.iter() {
+        if chains.len() <= entry.depth {
+            chains.resize(entry.depth + 1, 0);
+        }
+        chains[entry.depth] += 1;
+        if stat_only {
+            continue;
+        }
+        write!(
+            out,
+            "{} {:<6} {} {} {}",
+            entry.sha1,
+            entry.type_.to_string(),
+            entry.stored_size,
+            entry.packed_size,
+            entry.offset
+        )?;
+        if let Some(base) = &entry.base {
+            write!(out, " {} {}", entry.depth, base)?;
+        }
+        writeln!(out)?;
+    }
+    let objects = |count: usize| if count == 1 { "object" } else { "objects" };
+    for (depth, count) in chains.iter().enumerate() {
+        match (depth, *count) {
+            (_, 0) => {}
+            (0, count) => writeln!(out, "non delta: {} {}", count, objects(count))?,
+            (depth, count) => writeln!(
+                out,
+                "chain length = {}: {} {}",
+                depth,
+                count,
+                objects(count)
+            )?,
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::indexpack::{self, IndexPackOptions, PackSource};
+    use crate::objects::calculate_object_hash;
+    use crate::packs::{
+        apply_delta, build_index, build_pack, encode_entry, encode_entry_header,
+        encode_ofs_encoding, has_packed_object, list_pack_indexes, parse_ofs_encoding,
+        parse_packfile, read_packed_object, resolve_delta, store_pack, verify_pack,
+        write_pack_stats, Entry, ObjectType, PackIndex, PackOptions, Packfile, MAX_OBJECT_SIZE,
+    };
+    use crate::revwalk::list_objects_with_paths;
+    use crate::testutils;
+    use sha1::Digest;
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    /// Builds a pack of raw entries with a valid header and trailer
+    fn raw_pack(objects: u32, entries: &[u8]) -> Vec<u8> {
+        let mut data = b"PACK\0\0\0\x02".to_vec();
+        data.extend_from_slice(&objects.to_be_bytes());
+        data.extend_from_slice(entries);
+        let checksum = sha1::Sha1::digest(&data);
+        data.extend_from_slice(&checksum);
+        return data;
+    }
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        return encoder.finish().unwrap();
+    }
+
+    /// Rewrites a pack after `corrupt` with a valid trailer
+    fn rewrite_pack(path: &PathBuf, corrupt: impl Fn(&mut Vec<u8>)) {
+        let mut data = fs::read(path).unwrap();
+        corrupt(&mut data);
+        let end = data.len() - 20;
+        let checksum = sha1::Sha1::digest(&data[..end]);
+        data[end..].copy_from_slice(&checksum);
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn test_open_packs() {
+        testutils::in_tmp_dir(|| {
+            let first = testutils::create_repo(".", &[("a.txt", "a\n")]);
+            testutils::get_git_output(&["repack", "-q", "-d"]);
+            assert!(has_packed_object(&first).unwrap());
+
+            // packs added after the first lookup are found
+            let second = testutils::commit_files(".", &[("b.txt", "b\n")], "second");
+            assert!(!has_packed_object(&second).unwrap());
+            testutils::get_git_output(&["repack", "-q", "-d"]);
+            assert!(has_packed_object(&second).unwrap());
+
+            // and objects of removed packs are found in their new pack
+            testutils::get_git_output(&["repack", "-q", "-a", "-d"]);
+            for sha1 in [&first, &second] {
+                let (type_, _) = read_packed_object(sha1).unwrap().unwrap();
+                assert_eq!(type_.to_string(), "commit");
+            }
+            assert!(!has_packed_object(&"0".repeat(40)).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_verify_pack() {
+        testutils::in_tmp_git(|| {
+            for i in 1..=4 {
+                let lines: Vec<String> = (0..i * 300).map(|n| n.to_string()).collect();
+                fs::write("file.txt", lines.join("\n")).unwrap();
+                testutils::git_commit_all(&format!("commit {}", i));
+            }
+            testutils::get_git_output(&["repack", "-q", "-a", "-d"]);
+            let index = fs::read_dir(".git/objects/pack")
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .find(|path| path.extension().is_some_and(|e| e == "idx"))
+                .unwrap();
+
+            let packfile = verify_pack(&index).unwrap();
+            assert!(packfile.entries.iter().any(|entry| entry.depth > 0));
+            let mut stats = Vec::new();
+            write_pack_stats(&packfile, false, &mut stats).unwrap();
+            let expected =
+                testutils::get_git_output(&["verify-pack", "-v", index.to_str().unwrap()]);
+            let ok_line = format!("{}: ok\n", index.with_extension("pack").display());
+            assert_eq!(
+                String::from_utf8(stats).unwrap(),
+                expected.strip_suffix(&ok_line).unwrap()
+            );
+
+            // a wrong CRC in the index
+            let pack = index.with_extension("pack");
+            let writable = fs::Permissions::from_mode(0o644);
+            fs::set_permissions(&pack, writable.clone()).unwrap();
+            fs::set_permissions(&index, writable).unwrap();
+            let original = fs::read(&pack).unwrap();
+            let original_index = fs::read(&index).unwrap();
+            let n = packfile.entries.len();
+            rewrite_pack(&index, |data| data[8 + 256 * 4 + n * 20] ^= 1);
+            let error = verify_pack(&index).unwrap_err().to_string();
+            assert!(error.contains("CRC mismatch"), "{}", error);
+            fs::write(&index, &original_index).unwrap();
+
+            // a flipped bit without fixing the trailer
+            let mut data = original.clone();
+            data[100] ^= 1;
+            fs::write(&pack, &data).unwrap();
+            let error = verify_pack(&index).unwrap_err().to_string();
+            assert!(error.contains("checksum mismatch"), "{}", error);
+
+            // garbage in the compressed data of the first entry
+            let first = packfile.entries.iter().find(|e| e.offset == 12).unwrap();
+            let end = 12 + first.packed_size;
+            rewrite_pack(&pack, |data| data[end - 6..end].fill(0xff));
+            let error = verify_pack(&index).unwrap_err().to_string();
+            assert!(error.contains("object at offset 12"), "{}", error);
+
+            // a wrong object count
+            fs::write(&pack, &original).unwrap();
+            rewrite_pack(&pack, |data| data[11] += 1);
+            let error = verify_pack(&index).unwrap_err().to_string();
+            assert!(
+                error.contains(&format!("declares {} objects", n + 1)),
+                "{}",
+                error
+            );
+        });
+    }
+
+    #[test]
+    fn test_build_pack() {
+        testutils::in_tmp_git(|| {
+            let mut lines: Vec<String> = (0..2000).map(|n| format!("line {}", n)).collect();
+            for i in 0..8 {
+                lines[i * 200] = format!("changed in commit {}", i);
+                lines.insert(i * 150, format!("inserted in commit {}", i));
+                fs::write("a.txt", lines.join("\n")).unwrap();
+                fs::write("b.txt", lines[..1000 - i * 100].join("\n")).unwrap();
+                testutils::git_commit_all(&format!("commit {}", i));
+            }
+            let head = testutils::get_git_output(&["rev-parse", "HEAD"]);
+            let objects = list_objects_with_paths(&[head.trim().to_string()], &[]).unwrap();
+
+            let pack = build_pack(&objects, &PackOptions::default()).unwrap();
+            let packfile = parse_packfile(&pack).unwrap();
+            assert_eq!(packfile.entries.len(), objects.len());
+            let deltas = packfile.entries.iter().filter(|e| e.base.is_some());
+            assert!(deltas.clone().count() >= 14);
+            // the type bits of the entry headers
+            assert!(deltas.clone().all(|e| (pack[e.offset] >> 4) & 7 == 6));
+            fs::write("ours.pack", &pack).unwrap();
+            testutils::get_git_output(&["index-pack", "ours.pack"]);
+            let stats = testutils::get_git_output(&["verify-pack", "-v", "ours.idx"]);
+            assert!(stats.contains("chain length = 2:"), "{}", stats);
+
+            // about as small as what git makes
+            let expected = testutils::get_git_output_with_stdin(
+                &["pack-objects", "--stdout", "--revs", "-q"],
+                "HEAD\n",
+            );
+            assert!(
+                pack.len() < expected.len() * 3 / 2,
+                "{} bytes, git makes {}",
+                pack.len(),
+                expected.len()
+            );
+
+            // bases referred to by hash, and no deltas at all
+            let options = PackOptions {
+                ofs_delta: false,
+                ..Default::default()
+            };
+            let pack = build_pack(&objects, &options).unwrap();
+            let packfile = parse_packfile(&pack).unwrap();
+            let deltas = packfile.entries.iter().filter(|e| e.base.is_some());
+            assert!(deltas.clone().count() >= 14);
+            assert!(deltas.clone().all(|e| (pack[e.offset] >> 4) & 7 == 7));
+            fs::write("refs.pack", &pack).unwrap();
+            testutils::get_git_output(&["index-pack", "refs.pack"]);
+            let options = PackOptions {
+                window: 0,
+                ..Default::default()
+            };
+            let pack = build_pack(&objects, &options).unwrap();
+            let packfile = parse_packfile(&pack).unwrap();
+            assert!(packfile.entries.iter().all(|e| e.base.is_none()));
+
+            // chains no longer than the depth
+            let options = PackOptions {
+                depth: 2,
+                ..Default::default()
+            };
+            let pack = build_pack(&objects, &options).unwrap();
+            let packfile = parse_packfile(&pack).unwrap();
+            assert!(packfile.entries.iter().all(|e| e.depth <= 2));
+            assert!(packfile.entries.iter().any(|e| e.depth == 2));
+        });
+    }
+
+    #[test]
+    fn test_ofs_encoding() {
+        for offset in [0, 1, 127, 128, 16511, 16512, 1 << 20, usize::MAX >> 8] {
+            let bytes = encode_ofs_encoding(offset);
+            assert_eq!(
+                parse_ofs_encoding(&bytes, 0).unwrap(),
+                (bytes.len(), offset)
+            );
+        }
+        assert_eq!(encode_ofs_encoding(128), [0x80, 0x00]);
+    }
+
+    #[test]
+    fn test_apply_delta_bounds() {
+        let base = b"0123456789";
+        // copy 4 bytes at offset 2, then add "ab"
+        assert_eq!(
+            apply_delta(&[0x91, 2, 4, 2, b'a', b'b'], base, 6).unwrap(),
+            b"2345ab"
+        );
+        let error = apply_delta(&[0x91, 8, 4], base, 4).unwrap_err();
+        assert!(error.to_string().contains("copies 4 bytes at offset 8"));
+        let error = apply_delta(&[0x91, 8], base, 4).unwrap_err();
+        assert!(error.to_string().contains("ends in a copy instruction"));
+        let error = apply_delta(&[5, b'a'], base, 5).unwrap_err();
+        assert!(error.to_string().contains("past its end"));
+        let error = apply_delta(&[0], base, 0).unwrap_err();
+        assert!(error.to_string().contains("reserved"));
+        let error = apply_delta(&[2, b'a', b'b'], base, 1).unwrap_err();
+        assert!(error.to_string().contains("more than 1 bytes"));
+    }
+
+    #[test]
+    fn test_hostile_packs() {
+        testutils::in_tmp_git(|| {
+            for i in 1..=3 {
+                let lines: Vec<String> = (0..i * 100).map(|n| n.to_string()).collect();
+                fs::write("file.txt", lines.join("\n")).unwrap();
+                testutils::git_commit_all(&format!("commit {}", i));
+            }
+            let pack = testutils::get_git_output_with_stdin(
+                &["pack-objects", "--stdout", "--revs", "-q"],
+                "HEAD\n",
+            );
+            let packfile = parse_packfile(&pack).unwrap();
+            assert!(packfile.entries.iter().any(|entry| entry.base.is_some()));
+
+            // every truncation and every flipped byte, with a trailer
+            // matching the corrupted content, is an error and not a panic
+            for len in 0..pack.len() {
+                assert!(parse_packfile(&pack[..len]).is_err());
+            }
+            for i in 12..pack.len() - 20 {
+                let mut data = pack[..pack.len() - 20].to_vec();
+                data[i] ^= 0xff;
+                let checksum = sha1::Sha1::digest(&data);
+                data.extend_from_slice(&checksum);
+                let _ = parse_packfile(&data);
+            }
+        });
+
+        // a tiny entry inflating to a megabyte
+        let mut entry = encode_entry_header(ObjectType::Blob, 10);
+        entry.extend(deflate(&vec![0u8; 1 << 20]));
+        let error = parse_packfile(&raw_pack(1, &entry)).unwrap_err();
+        assert!(error.to_string().contains("inflates to more than 10 bytes"));
+
+        let mut entry = encode_entry_header(ObjectType::Blob, MAX_OBJECT_SIZE + 1);
+        entry.extend(deflate(b""));
+        let error = parse_packfile(&raw_pack(1, &entry)).unwrap_err();
+        assert!(error.to_string().contains("more than the limit"));
+
+        // type 5 is reserved
+        let error = parse_packfile(&raw_pack(1, &[0x50])).unwrap_err();
+        assert!(error.to_string().contains("unexpected object type 5"));
+
+        // a size encoding running past 64 bits
+        let mut entry = vec![0xb0];
+        entry.extend([0xff; 10]);
+        let error = parse_packfile(&raw_pack(1, &entry)).unwrap_err();
+        assert!(error.to_string().contains("overflows"));
+
+        let error = resolve_delta(&[0x80], b"").unwrap_err();
+        assert!(error.to_string().contains("truncated size encoding"));
+        let error = resolve_delta(&[0, 0xff, 0xff, 0xff, 0xff, 0x7f], b"").unwrap_err();
+        assert!(error.to_string().contains("more than the limit"));
+    }
+
+    #[test]
+    fn test_deltas_before_their_base() {
+        let blob = |data: &[u8]| calculate_object_hash(&"blob".to_string(), &data.to_vec());
+        // a REF_DELTA, an OFS_DELTA based on it, then the base of both
+        let delta = [12, 13, 0x90, 11, 2, b'!', b'\n'];
+        let mut first = encode_entry_header(ObjectType::RefDelta, delta.len());
+        first.extend(hex::decode(blob(b"hello world\n")).unwrap());
+        first.extend(deflate(&delta));
+        let delta = [13, 14, 0x90, 12, 2, b'!', b'\n'];
+        let mut second = encode_entry_header(ObjectType::OfsDelta, delta.len());
+        second.push(first.len() as u8);
+        second.extend(deflate(&delta));
+        let third = encode_entry(ObjectType::Blob, b"hello world\n").unwrap();
+        let entries = [first.clone(), second.clone(), third].concat();
+
+        let packfile = parse_packfile(&raw_pack(3, &entries)).unwrap();
+        let found: Vec<(usize, String, usize)> = packfile
+            .entries
+            .iter()
+            .map(|entry| (entry.offset, entry.sha1.clone(), entry.depth))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (12, blob(b"hello world!\n"), 1),
+                (12 + first.len(), blob(b"hello world!!\n"), 2),
+                (12 + first.len() + second.len(), blob(b"hello world\n"), 0),
+            ]
+        );
+
+        // without the base, the deltas can't be resolved
+        let error = parse_packfile(&raw_pack(2, &[first, second].concat())).unwrap_err();
+        assert!(
+            error.to_string().contains("2 unresolved deltas"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_pack_index_commits() {
+        testutils::in_tmp_dir(|| {
+            testutils::create_repo(".", &[("a.txt", "a\n")]);
+            // long and close messages get the commits deltified
+            let message = "a commit message long enough to be worth a delta\n".repeat(20);
+            for i in 0..5 {
+                let content = format!("{}\n", i);
+                testutils::commit_files(".", &[("a.txt", &content)], &format!("{}{}", message, i));
+            }
+            testutils::get_git_output(&["repack", "-q", "-a", "-d", "-f", "--window=50"]);
+            let index = list_pack_indexes().unwrap().pop().unwrap();
+            let verify = testutils::get_git_output(&["verify-pack", "-v", index.to_str().unwrap()]);
+            // deltas have their depth and base after the sizes and offset
+            assert!(verify
+                .lines()
+                .any(|line| line.contains(" commit ") && line.split_whitespace().count() == 7));
+
+            let mut commits = PackIndex::open(&index).unwrap().commits().unwrap();
+            commits.sort();
+            let mut expected: Vec<String> = testutils::get_git_output(&["rev-list", "--all"])
+                .lines()
+                .map(String::from)
+                .collect();
+            expected.sort();
+            assert_eq!(commits, expected);
+        });
+    }
+
+    #[test]
+    fn test_inflated_pack_limit() {
+        let entries = [b"first\n".as_slice(), b"second\n"]
+            .map(|data| encode_entry(ObjectType::Blob, data).unwrap())
+            .concat();
+        let pack = raw_pack(2, &entries);
+        let index = |limit: usize| {
+            let options = IndexPackOptions {
+                max_inflated_size: Some(limit),
+                ..Default::default()
+            };
+            return indexpack::index(
+                &pack[..],
+                Some(pack.len()),
+                PackSource::Memory(&pack),
+                None,
+                &options,
+            );
+        };
+        assert_eq!(index(13).unwrap().entries.len(), 2);
+        let error = index(12).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("pack inflates to more than 12 bytes"));
+    }
+
+    #[test]
+    fn test_object_size_limit() {
+        // a blob of 12 bytes and a delta of 7 bytes making one of 13 from it
+        let base = encode_entry(ObjectType::Blob, b"hello world\n").unwrap();
+        let delta = [12, 13, 0x90, 11, 2, b'!', b'\n'];
+        let mut entry = encode_entry_header(ObjectType::OfsDelta, delta.len());
+        entry.push(base.len() as u8);
+        entry.extend(deflate(&delta));
+        let pack = raw_pack(2, &[base, entry].concat());
+        let index = |limit: usize| {
+            let options = IndexPackOptions {
+                max_object_size: limit,
+                ..Default::default()
+            };
+            return indexpack::index(
+                &pack[..],
+                Some(pack.len()),
+                PackSource::Memory(&pack),
+                None,
+                &options,
+            );
+        };
+        assert_eq!(index(13).unwrap().entries.len(), 2);
+        let error = index(12).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("produces 13 bytes, more than the limit of 12"),
+            "{}",
+            error
+        );
+        let error = index(11).unwrap_err();
+        assert!(error.to_string().contains("has 12 bytes"), "{}", error);
+
+        // received packs are limited by the config, with a default
+        testutils::in_tmp_git(|| {
+            let git = |args: &[&str]| testutils::get_git_output(args);
+            assert_eq!(store_pack(&pack[..]).unwrap().entries.len(), 2);
+            git(&["config", "transfer.maxObjectSize", "12"]);
+            let error = store_pack(&pack[..]).unwrap_err();
+            assert!(error.to_string().contains("limit of 12"), "{}", error);
+            git(&["config", "transfer.maxObjectSize", "1k"]);
+            git(&["config", "transfer.maxInflatedSize", "24"]);
+            let error = store_pack(&pack[..]).unwrap_err();
+            assert!(
+                error.to_string().contains("pack inflates to more than 24"),
+                "{}",
+                error
+            );
+            git(&["config", "transfer.maxInflatedSize", "0"]);
+            assert_eq!(store_pack(&pack[..]).unwrap().entries.len(), 2);
+        });
+    }
+
+    #[test]
+    fn test_delta_cycle() {
+        testutils::in_tmp_git(|| {
+            // two REF_DELTAs based on each other, with an index saying so
+            let (first, second) = ("1".repeat(40), "2".repeat(40));
+            let delta = |base: &str| {
+                let mut entry = encode_entry_header(ObjectType::RefDelta, 2);
+                entry.extend(hex::decode(base).unwrap());
+                entry.extend(deflate(&[0, 0]));
+                return entry;
+            };
+            let entries = [delta(&second), delta(&first)];
+            let pack = raw_pack(2, &entries.concat());
+            let entry = |sha1: &String, offset: usize| Entry {
+                type_: ObjectType::RefDelta,
+                size: 0,
+                sha1: sha1.clone(),
+                offset,
+                crc32: 0,
+                stored_size: 2,
+                packed_size: 0,
+                base: None,
+                depth: 0,
+            };
+            let packfile = Packfile {
+                sha1: hex::encode(&pack[pack.len() - 20..]),
+                entries: vec![entry(&first, 12), entry(&second, 12 + entries[0].len())],
+            };
+            let path = format!(".git/objects/pack/pack-{}", packfile.sha1);
+            fs::create_dir_all(".git/objects/pack").unwrap();
+            fs::write(format!("{}.pack", path), &pack).unwrap();
+            fs::write(format!("{}.idx", path), build_index(&packfile).unwrap()).unwrap();
+
+            let error = read_packed_object(&first).unwrap_err();
+            assert!(error.to_string().contains("delta cycle"), "{}", error);
+        });
+    }
+}
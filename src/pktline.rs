@@ -0,0 +1,311 @@
+use anyhow::{bail, Result};
+use std::io::{self, Read, Write};
+
+/// Largest payload a single pkt-line can carry
+pub const MAX_PKT_DATA: usize = 65516;
+
+/// A pkt-line as described in gitprotocol-common(5)
+#[derive(Debug, PartialEq, Eq)]
+pub enum Packet {
+    Data(Vec<u8>),
+    /// `0000`: end of a message
+    Flush,
+    /// `0001`: separates sections of a message (protocol v2)
+    Delim,
+    /// `0002`: end of a response for stateless connections (protocol v2)
+    ResponseEnd,
+}
+
+impl Packet {
+    /// Returns the payload as a string without the trailing newline
+    pub fn as_text(&self) -> Option<String> {
+        return match self {
+            Packet::Data(data) => {
+                let data = data.strip_suffix(b"\n").unwrap_or(data);
+                Some(String::from_utf8_lossy(data).to_string())
+            }
+            _ => None,
+        };
+    }
+
+    /// Writes the packet in its wire encoding
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        return match self {
+            Packet::Data(data) => write_packet(out, data),
+            Packet::Flush => out.write_all(b"0000"),
+            Packet::Delim => out.write_all(b"0001"),
+            Packet::ResponseEnd => out.write_all(b"0002"),
+        };
+    }
+}
+
+/// Reads pkt-lines from an underlying stream
+pub struct PktReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> PktReader<R> {
+    pub fn new(inner: R) -> PktReader<R> {
+        return PktReader { inner };
+    }
+
+    /// Reads the next packet, returns None when the stream ends cleanly
+    pub fn read_packet(&mut self) -> Result<Option<Packet>> {
+        let mut len_buf = [0u8; 4];
+        let mut read = 0;
+        while read < 4 {
+            let n = self.inner.read(&mut len_buf[read..])?;
+            if n == 0 {
+                if read == 0 {
+                    return Ok(None);
+                }
+                bail!("unexpected end of stream reading pkt-line length");
+            }
+            read += n;
+        }
+        let len = match std::str::from_utf8(&len_buf)
+            .ok()
+            .and_then(|s| usize::from_str_radix(s, 16).ok())
+        {
+            Some(len) => len,
+            None => bail!(
+                "invalid pkt-line length {:?}",
+                String::from_utf8_lossy(&len_buf)
+            ),
+        };
+        return match len {
+            0 => Ok(Some(Packet::Flush)),
+            1 => Ok(Some(Packet::Delim)),
+            2 => Ok(Some(Packet::ResponseEnd)),
+            3 => bail!("invalid pkt-line length 3"),
+            _ if len - 4 > MAX_PKT_DATA => bail!("pkt-line of {} bytes is too long", len),
+            _ => {
+                let mut data = vec![0u8; len - 4];
+                self.inner.read_exact(&mut data)?;
+                Ok(Some(Packet::Data(data)))
+            }
+        };
+    }
+
+    /// Reads the next packet failing if the stream ended
+    pub fn read_expected(&mut self) -> Result<Packet> {
+        return match self.read_packet()? {
+            Some(packet) => Ok(packet),
+            None => bail!("unexpected end of stream"),
+        };
+    }
+
+    /// Reads data lines as text until a flush, delim or end of response packet
+    pub fn read_lines_until_flush(&mut self) -> Result<Vec<String>> {
+        let mut lines = Vec::new();
+        loop {
+            match self.read_expected()? {
+                packet @ Packet::Data(_) => lines.push(packet.as_text().unwrap()),
+                _ => return Ok(lines),
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        return self.inner;
+    }
+}
+
+/// Demultiplexes a side-band stream: channel 1 carries data and is returned
+/// by `read`, channel 2 carries progress forwarded to `progress`, prefixed
+/// with `remote: `, and channel 3 carries a fatal error. The data ends at the
+/// first flush packet.
+pub struct SidebandReader<R: Read, P: Write> {
+    reader: PktReader<R>,
+    progress: Option<P>,
+    /// Data of the current channel 1 packet not returned yet
+    buf: Vec<u8>,
+    pos: usize,
+    /// Progress text received after the last line terminator
+    partial_progress: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read, P: Write> SidebandReader<R, P> {
+    pub fn new(reader: PktReader<R>, progress: Option<P>) -> SidebandReader<R, P> {
+        return SidebandReader {
+            reader,
+            progress,
+            buf: Vec::new(),
+            pos: 0,
+            partial_progress: Vec::new(),
+            done: false,
+        };
+    }
+
+    /// Writes every complete progress line, keeping `\r` so that progress
+    /// meters are redrawn in place like git does
+    fn write_progress(&mut self, data: &[u8]) -> io::Result<()> {
+        let progress = match self.progress.as_mut() {
+            Some(progress) => progress,
+            None => return Ok(()),
+        };
+        self.partial_progress.extend_from_slice(data);
+        while let Some(end) = self
+            .partial_progress
+            .iter()
+            .position(|b| *b == b'\n' || *b == b'\r')
+        {
+            let line: Vec<u8> = self.partial_progress.drain(..=end).collect();
+            progress.write_all(b"remote: ")?;
+            progress.write_all(&line)?;
+        }
+        return progress.flush();
+    }
+
+    /// Reads packets until one with data on channel 1 is found, returns false
+    /// once the data ended
+    fn fill_buf(&mut self) -> io::Result<bool> {
+        while !self.done && self.pos == self.buf.len() {
+            let packet = self
+                .reader
+                .read_packet()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let mut data = match packet {
+                Some(Packet::Data(data)) => data,
+                _ => {
+                    self.done = true;
+                    break;
+                }
+            };
+            match data.first() {
+                Some(1) => {
+                    data.remove(0);
+                    self.buf = data;
+                    self.pos = 0;
+                }
+                Some(2) => self.write_progress(&data[1..])?,
+                Some(3) => {
+                    let message = String::from_utf8_lossy(&data[1..]);
+                    return Err(io::Error::other(format!(
+                        "remote error: {}",
+                        message.trim_end()
+                    )));
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "invalid side-band channel",
+                    ))
+                }
+            }
+        }
+        if self.done && !self.partial_progress.is_empty() {
+            let rest = std::mem::take(&mut self.partial_progress);
+            self.write_progress(&rest)?;
+            self.write_progress(b"\n")?;
+        }
+        return Ok(self.pos < self.buf.len());
+    }
+}
+
+impl<R: Read, P: Write> Read for SidebandReader<R, P> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.fill_buf()? {
+            return Ok(0);
+        }
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        return Ok(n);
+    }
+}
+
+/// Writes a data packet
+pub fn write_packet<W: Write>(out: &mut W, data: &[u8]) -> io::Result<()> {
+    if data.len() > MAX_PKT_DATA {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "pkt-line payload too long",
+        ));
+    }
+    write!(out, "{:04x}", data.len() + 4)?;
+    return out.write_all(data);
+}
+
+/// Writes a text packet terminated by a newline
+pub fn write_line<W: Write>(out: &mut W, line: &str) -> io::Result<()> {
+    return write_packet(out, format!("{}\n", line).as_bytes());
+}
+
+/// Writes a flush packet
+pub fn write_flush<W: Write>(out: &mut W) -> io::Result<()> {
+    return out.write_all(b"0000");
+}
+
+/// Writes a delimiter packet
+pub fn write_delim<W: Write>(out: &mut W) -> io::Result<()> {
+    return out.write_all(b"0001");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::{
+        write_delim, write_flush, write_line, write_packet, Packet, PktReader, SidebandReader,
+    };
+
+    #[test]
+    fn test_pktline_roundtrip() {
+        let mut buf = Vec::new();
+        write_line(&mut buf, "want 1234").unwrap();
+        write_delim(&mut buf).unwrap();
+        write_packet(&mut buf, b"\x01binary").unwrap();
+        write_flush(&mut buf).unwrap();
+        assert_eq!(buf, b"000ewant 1234\n0001000b\x01binary0000");
+
+        let mut reader = PktReader::new(buf.as_slice());
+        assert_eq!(
+            reader.read_packet().unwrap().unwrap().as_text().unwrap(),
+            "want 1234"
+        );
+        assert_eq!(reader.read_packet().unwrap(), Some(Packet::Delim));
+        assert_eq!(
+            reader.read_packet().unwrap(),
+            Some(Packet::Data(b"\x01binary".to_vec()))
+        );
+        assert_eq!(reader.read_packet().unwrap(), Some(Packet::Flush));
+        assert_eq!(reader.read_packet().unwrap(), None);
+
+        assert!(PktReader::new(&b"zzzz"[..]).read_packet().is_err());
+        assert!(PktReader::new(&b"0003"[..]).read_packet().is_err());
+        assert!(PktReader::new(&b"0010short"[..]).read_packet().is_err());
+        assert!(write_packet(&mut Vec::new(), &vec![0u8; 70000]).is_err());
+    }
+
+    #[test]
+    fn test_sideband_demux() {
+        let mut buf = Vec::new();
+        write_packet(&mut buf, b"\x02Counting: 50%\r").unwrap();
+        write_packet(&mut buf, b"\x01PACK").unwrap();
+        write_packet(&mut buf, b"\x02Counting: 100%\rdone.").unwrap();
+        write_packet(&mut buf, b"\x02\n").unwrap();
+        write_packet(&mut buf, b"\x01data").unwrap();
+        write_flush(&mut buf).unwrap();
+        write_packet(&mut buf, b"\x01after flush").unwrap();
+
+        let mut progress = Vec::new();
+        let mut data = Vec::new();
+        SidebandReader::new(PktReader::new(buf.as_slice()), Some(&mut progress))
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, b"PACKdata");
+        assert_eq!(
+            String::from_utf8(progress).unwrap(),
+            "remote: Counting: 50%\rremote: Counting: 100%\rremote: done.\n"
+        );
+
+        let mut buf = Vec::new();
+        write_packet(&mut buf, b"\x01PA").unwrap();
+        write_packet(&mut buf, b"\x03upload-pack: not our ref\n").unwrap();
+        let mut reader = SidebandReader::new(PktReader::new(buf.as_slice()), None::<Vec<u8>>);
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.to_string(), "remote error: upload-pack: not our ref");
+    }
+}
@@ -0,0 +1,903 @@
+use anyhow::{bail, Result};
+use std::io::{Read, Write};
+
+use crate::pktline::{write_delim, write_flush, write_line, Packet, PktReader, SidebandReader};
+use crate::refs::is_sha1;
+use crate::transport::{Service, Transport};
+
+/// Agent sent to servers to identify this client
+pub const AGENT: &str = concat!("git/git-rust-", env!("CARGO_PKG_VERSION"));
+
+/// A ref advertised by a remote repository
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteRef {
+    pub name: String,
+    pub sha1: String,
+    pub symref_target: Option<String>,
+    pub peeled: Option<String>,
+}
+
+/// Object id standing for a missing ref in ref update commands
+pub const ZERO_ID: &str = "0000000000000000000000000000000000000000";
+
+/// Number of `have` lines sent in the first negotiation round, it doubles
+/// in every following round up to `MAX_HAVES_PER_ROUND`
+const INITIAL_HAVES: usize = 16;
+const MAX_HAVES_PER_ROUND: usize = 1024;
+
+/// Number of `have` lines sent without finding new common commits after
+/// which negotiation gives up, as git does
+const MAX_IN_VAIN: usize = 256;
+
+/// Local commits offered to the server during negotiation, newest first.
+/// They are only produced as negotiation asks for more, so it stops
+/// walking the local history once it is done.
+pub struct Haves(Box<dyn Iterator<Item = Result<String>>>);
+
+impl Haves {
+    pub fn new(haves: impl Iterator<Item = Result<String>> + 'static) -> Haves {
+        return Haves(Box::new(haves));
+    }
+}
+
+impl Default for Haves {
+    fn default() -> Haves {
+        return Haves::new(std::iter::empty());
+    }
+}
+
+impl Iterator for Haves {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Result<String>> {
+        return self.0.next();
+    }
+}
+
+/// Objects requested from the remote in a fetch
+#[derive(Default)]
+pub struct FetchRequest {
+    pub wants: Vec<String>,
+    pub haves: Haves,
+    /// Commits the local history is cut at (`.git/shallow`)
+    pub shallows: Vec<String>,
+    /// Number of commits of history to fetch from each want
+    pub depth: Option<u32>,
+    /// Only fetch the history after this timestamp
+    pub deepen_since: Option<i64>,
+    /// Don't fetch the history reachable from these refs
+    pub deepen_not: Vec<String>,
+    /// Objects the server may leave out, as a filter-spec like `blob:none`
+    pub filter: Option<String>,
+}
+
+impl FetchRequest {
+    /// Whether the history sent by the server is cut
+    fn deepens(&self) -> bool {
+        return self.depth.is_some() || self.deepen_since.is_some() || !self.deepen_not.is_empty();
+    }
+}
+
+/// What the server sends back for a fetch
+pub struct FetchResponse<'a> {
+    /// The packfile, read from the connection as it is consumed
+    pub pack: Box<dyn Read + 'a>,
+    /// Commits whose parents were left out of the pack
+    pub shallow: Vec<String>,
+    /// Formerly shallow commits whose parents are now in the pack
+    pub unshallow: Vec<String>,
+}
+
+/// Checks that a filter-spec is one we know of: `blob:none`,
+/// `blob:limit=<n>[kmg]` or `tree:<depth>`
+pub fn check_filter_spec(filter: &str) -> Result<()> {
+    let valid = match filter.split_once(':') {
+        Some(("blob", "none")) => true,
+        Some(("blob", limit)) => limit
+            .strip_prefix("limit=")
+            .map(|l| l.trim_end_matches(['k', 'm', 'g']))
+            .is_some_and(|l| !l.is_empty() && l.chars().all(|c| c.is_ascii_digit())),
+        Some(("tree", depth)) => !depth.is_empty() && depth.chars().all(|c| c.is_ascii_digit()),
+        _ => false,
+    };
+    if !valid {
+        bail!("invalid filter-spec '{}'", filter);
+    }
+    return Ok(());
+}
+
+/// Parses the `shallow`/`unshallow` lines the server sends when the client
+/// is or becomes shallow, returns the shallow and the unshallow commits
+fn parse_shallow_info(lines: &[String]) -> Result<(Vec<String>, Vec<String>)> {
+    let (mut shallow, mut unshallow) = (Vec::new(), Vec::new());
+    for line in lines {
+        check_error_line(line)?;
+        match line.split_once(' ') {
+            Some(("shallow", sha1)) if is_sha1(sha1) => shallow.push(sha1.to_string()),
+            Some(("unshallow", sha1)) if is_sha1(sha1) => unshallow.push(sha1.to_string()),
+            _ => bail!("unexpected shallow info '{}'", line),
+        }
+    }
+    return Ok((shallow, unshallow));
+}
+
+/// A ref update asked to receive-pack, `ZERO_ID` as the old value creates
+/// the ref and as the new value deletes it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefCommand {
+    pub name: String,
+    pub old: String,
+    pub new: String,
+}
+
+/// Result of a push as reported by the server
+#[derive(Debug, Default)]
+pub struct PushReport {
+    /// Error the server hit unpacking the pack, if any
+    pub unpack_error: Option<String>,
+    /// Reason the server refused to update a ref, for every ref it refused
+    pub rejected: Vec<(String, String)>,
+}
+
+/// State of a stateless negotiation: every round resends the commits known
+/// to be common, followed by a growing batch of new `have`s
+struct Negotiation {
+    candidates: Haves,
+    common: Vec<String>,
+    round_size: usize,
+    sent_in_vain: usize,
+    found_common: bool,
+    ready: bool,
+}
+
+impl Negotiation {
+    fn new(haves: Haves) -> Negotiation {
+        return Negotiation {
+            candidates: haves,
+            common: Vec::new(),
+            round_size: INITIAL_HAVES,
+            sent_in_vain: 0,
+            found_common: false,
+            ready: false,
+        };
+    }
+
+    /// Returns the haves of the next round, None once negotiation is over
+    fn next_round(&mut self) -> Result<Option<Vec<String>>> {
+        if self.ready || self.sent_in_vain >= MAX_IN_VAIN {
+            return Ok(None);
+        }
+        let batch = self
+            .candidates
+            .by_ref()
+            .take(self.round_size)
+            .collect::<Result<Vec<String>>>()?;
+        if batch.is_empty() {
+            return Ok(None);
+        }
+        self.sent_in_vain += batch.len();
+        self.round_size = (self.round_size * 2).min(MAX_HAVES_PER_ROUND);
+        let mut haves = self.common.clone();
+        haves.extend(batch);
+        return Ok(Some(haves));
+    }
+
+    fn ack(&mut self, sha1: &str, ready: bool) {
+        if !self.common.iter().any(|c| c == sha1) {
+            self.common.push(sha1.to_string());
+            self.found_common = true;
+        }
+        self.ready |= ready;
+    }
+
+    fn end_round(&mut self) {
+        if self.found_common {
+            self.sent_in_vain = 0;
+        }
+        self.found_common = false;
+    }
+}
+
+/// An open conversation with a service of a remote repository, speaking
+/// protocol v2 when the server supports it and v0/v1 otherwise
+pub struct Connection {
+    transport: Box<dyn Transport>,
+    service: Service,
+    version: u8,
+    capabilities: Vec<String>,
+    advertised: Vec<RemoteRef>,
+}
+
+/// Parses a v0/v1 ref advertisement, returning the refs and capabilities
+fn parse_v0_advertisement(lines: &[String]) -> Result<(Vec<RemoteRef>, Vec<String>)> {
+    let mut refs: Vec<RemoteRef> = Vec::new();
+    let mut capabilities = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        let line = match line.split_once('\0') {
+            Some((line, caps)) if i == 0 => {
+                capabilities = caps.split(' ').map(|c| c.to_string()).collect();
+                line
+            }
+            _ => line.as_str(),
+        };
+        let (sha1, name) = match line.split_once(' ') {
+            Some(parts) => parts,
+            None => bail!("malformed ref advertisement line '{}'", line),
+        };
+        if name == "capabilities^{}" {
+            continue;
+        }
+        if let Some(peeled_name) = name.strip_suffix("^{}") {
+            if let Some(last) = refs.last_mut().filter(|r| r.name == peeled_name) {
+                last.peeled = Some(sha1.to_string());
+            }
+            continue;
+        }
+        refs.push(RemoteRef {
+            name: name.to_string(),
+            sha1: sha1.to_string(),
+            symref_target: None,
+            peeled: None,
+        });
+    }
+    for capability in capabilities.iter() {
+        if let Some((source, target)) = capability
+            .strip_prefix("symref=")
+            .and_then(|c| c.split_once(':'))
+        {
+            for r in refs.iter_mut().filter(|r| r.name == source) {
+                r.symref_target = Some(target.to_string());
+            }
+        }
+    }
+    return Ok((refs, capabilities));
+}
+
+/// Parses a ref line of a v2 `ls-refs` response
+fn parse_v2_ref(line: &str) -> Result<RemoteRef> {
+    let mut parts = line.split(' ');
+    let (sha1, name) = match (parts.next(), parts.next()) {
+        (Some(sha1), Some(name)) => (sha1, name),
+        _ => bail!("malformed ls-refs line '{}'", line),
+    };
+    let mut remote_ref = RemoteRef {
+        name: name.to_string(),
+        sha1: sha1.to_string(),
+        symref_target: None,
+        peeled: None,
+    };
+    for attribute in parts {
+        if let Some(target) = attribute.strip_prefix("symref-target:") {
+            remote_ref.symref_target = Some(target.to_string());
+        } else if let Some(peeled) = attribute.strip_prefix("peeled:") {
+            remote_ref.peeled = Some(peeled.to_string());
+        }
+    }
+    return Ok(remote_ref);
+}
+
+/// Fails if a packet is an `ERR` message sent by the server
+fn check_error_line(line: &str) -> Result<()> {
+    if let Some(message) = line.strip_prefix("ERR ") {
+        bail!("remote error: {}", message);
+    }
+    return Ok(());
+}
+
+impl Connection {
+    /// Starts a service on the remote and reads its advertisement
+    pub fn open(mut transport: Box<dyn Transport>, service: Service) -> Result<Connection> {
+        // there is no protocol v2 for pushes
+        let requested = match service {
+            Service::UploadPack => 2,
+            Service::ReceivePack => 0,
+        };
+        let mut lines = Vec::new();
+        {
+            // servers refusing the request send an `ERR` line and hang up
+            // without a flush
+            let mut reader = PktReader::new(transport.advertise(service, requested)?);
+            while let packet @ Packet::Data(_) = reader.read_expected()? {
+                let line = packet.as_text().unwrap();
+                check_error_line(&line)?;
+                lines.push(line);
+            }
+        }
+
+        let (version, capabilities, advertised) = match lines.first().map(|l| l.as_str()) {
+            Some("version 2") => (2, lines[1..].to_vec(), Vec::new()),
+            Some("version 1") => {
+                let (refs, caps) = parse_v0_advertisement(&lines[1..])?;
+                (1, caps, refs)
+            }
+            _ => {
+                let (refs, caps) = parse_v0_advertisement(&lines)?;
+                (0, caps, refs)
+            }
+        };
+        return Ok(Connection {
+            transport,
+            service,
+            version,
+            capabilities,
+            advertised,
+        });
+    }
+
+    /// Returns the value of a capability, or an empty string for flags
+    pub fn capability(&self, name: &str) -> Option<&str> {
+        return self
+            .capabilities
+            .iter()
+            .find_map(|c| match c.split_once('=') {
+                Some((key, value)) if key == name => Some(value),
+                None if c == name => Some(""),
+                _ => None,
+            });
+    }
+
+    /// Writes the v2 command header: command, capabilities and delimiter
+    fn write_v2_command(&self, body: &mut Vec<u8>, command: &str) -> Result<()> {
+        write_line(body, &format!("command={}", command))?;
+        write_line(body, &format!("agent={}", AGENT))?;
+        if self.capability("object-format").is_some() {
+            write_line(body, "object-format=sha1")?;
+        }
+        write_delim(body)?;
+        return Ok(());
+    }
+
+    /// Lists the remote refs starting with any of the given prefixes
+    pub fn ls_refs(&mut self, prefixes: &[&str]) -> Result<Vec<RemoteRef>> {
+        if self.version < 2 {
+            return Ok(self
+                .advertised
+                .iter()
+                .filter(|r| prefixes.is_empty() || prefixes.iter().any(|p| r.name.starts_with(p)))
+                .cloned()
+                .collect());
+        }
+
+        let mut body = Vec::new();
+        self.write_v2_command(&mut body, "ls-refs")?;
+        write_line(&mut body, "symrefs")?;
+        write_line(&mut body, "peel")?;
+        for prefix in prefixes {
+            write_line(&mut body, &format!("ref-prefix {}", prefix))?;
+        }
+        write_flush(&mut body)?;
+
+        let mut reader = PktReader::new(self.transport.request(self.service, body)?);
+        let mut refs = Vec::new();
+        for line in reader.read_lines_until_flush()? {
+            check_error_line(&line)?;
+            refs.push(parse_v2_ref(&line)?);
+        }
+        return Ok(refs);
+    }
+
+    /// Returns whether the server supports a feature of fetches, which
+    /// protocol v2 lists in the value of its `fetch` capability
+    fn supports(&self, feature: &str) -> bool {
+        if self.version < 2 {
+            return self.capability(feature).is_some();
+        }
+        let feature = match feature {
+            "deepen-since" | "deepen-not" => "shallow",
+            feature => feature,
+        };
+        return self
+            .capability("fetch")
+            .is_some_and(|f| f.split(' ').any(|f| f == feature));
+    }
+
+    /// Fetches the requested objects and returns the packfile sent by the
+    /// server, to be read to its end before the connection is used again.
+    /// Progress messages of the server are written to `progress`, the
+    /// server is asked not to send them when it is None.
+    pub fn fetch<'a>(
+        &mut self,
+        mut request: FetchRequest,
+        progress: Option<&'a mut dyn Write>,
+    ) -> Result<FetchResponse<'a>> {
+        if request.wants.is_empty() {
+            bail!("nothing to fetch");
+        }
+        if (request.deepens() || !request.shallows.is_empty()) && !self.supports("shallow") {
+            bail!("the server does not support shallow clients");
+        }
+        if request.deepen_since.is_some() && !self.supports("deepen-since") {
+            bail!("the server does not support --shallow-since");
+        }
+        if !request.deepen_not.is_empty() && !self.supports("deepen-not") {
+            bail!("the server does not support --shallow-exclude");
+        }
+        if let Some(filter) = request.filter.as_ref() {
+            check_filter_spec(filter)?;
+            if !self.supports("filter") {
+                bail!("the server does not support filters");
+            }
+        }
+        let haves = std::mem::take(&mut request.haves);
+        return match self.version {
+            2 => self.fetch_v2(&request, haves, progress),
+            _ => self.fetch_v0(&request, haves, progress),
+        };
+    }
+
+    /// Reader of a pack sent over side-band, turning remote errors into
+    /// read errors
+    fn sideband_pack<'a, R: Read + 'a>(
+        reader: PktReader<R>,
+        progress: Option<&'a mut dyn Write>,
+    ) -> Box<dyn Read + 'a> {
+        return Box::new(SidebandReader::new(reader, progress));
+    }
+
+    /// Writes the lines describing the history and objects wanted besides
+    /// the wants: shallow commits, deepening and filter, the same in v0 and v2
+    fn write_fetch_args(&self, body: &mut Vec<u8>, request: &FetchRequest) -> Result<()> {
+        for shallow in request.shallows.iter() {
+            write_line(body, &format!("shallow {}", shallow))?;
+        }
+        if let Some(depth) = request.depth {
+            write_line(body, &format!("deepen {}", depth))?;
+        }
+        if let Some(since) = request.deepen_since {
+            write_line(body, &format!("deepen-since {}", since))?;
+        }
+        for rev in request.deepen_not.iter() {
+            write_line(body, &format!("deepen-not {}", rev))?;
+        }
+        if let Some(filter) = request.filter.as_ref() {
+            write_line(body, &format!("filter {}", filter))?;
+        }
+        return Ok(());
+    }
+
+    /// Writes the `want` lines of a v0 request, with the capabilities we use
+    fn write_v0_wants(
+        &self,
+        body: &mut Vec<u8>,
+        request: &FetchRequest,
+        progress: bool,
+    ) -> Result<()> {
+        let mut capabilities = vec![format!("agent={}", AGENT)];
+        for capability in [
+            "multi_ack_detailed",
+            "thin-pack",
+            "ofs-delta",
+            "include-tag",
+        ] {
+            if self.capability(capability).is_some() {
+                capabilities.push(capability.to_string());
+            }
+        }
+        let used = [
+            ("shallow", request.deepens() || !request.shallows.is_empty()),
+            ("deepen-since", request.deepen_since.is_some()),
+            ("deepen-not", !request.deepen_not.is_empty()),
+            ("filter", request.filter.is_some()),
+        ];
+        for (capability, _) in used.iter().filter(|(_, used)| *used) {
+            capabilities.push(capability.to_string());
+        }
+        if let Some(sideband) = self.sideband() {
+            capabilities.push(sideband.to_string());
+            if !progress {
+                capabilities.push("no-progress".to_string());
+            }
+        }
+        for (i, want) in request.wants.iter().enumerate() {
+            match i {
+                0 => write_line(body, &format!("want {} {}", want, capabilities.join(" ")))?,
+                _ => write_line(body, &format!("want {}", want))?,
+            }
+        }
+        self.write_fetch_args(body, request)?;
+        write_flush(body)?;
+        return Ok(());
+    }
+
+    /// Returns the side-band capability to ask for in v0, if any
+    fn sideband(&self) -> Option<&'static str> {
+        return ["side-band-64k", "side-band"]
+            .into_iter()
+            .find(|c| self.capability(c).is_some());
+    }
+
+    fn fetch_v0<'a>(
+        &mut self,
+        request: &FetchRequest,
+        haves: Haves,
+        progress: Option<&'a mut dyn Write>,
+    ) -> Result<FetchResponse<'a>> {
+        // stateless servers forget everything between requests, so the wants
+        // are sent with every request and answered by the shallow commits of
+        // the client each time. Stateful ones only get them once.
+        let stateless = self.transport.stateless();
+        let shallow_info = request.deepens() || !request.shallows.is_empty();
+        let mut sent_wants = false;
+        let (mut shallow, mut unshallow) = (Vec::new(), Vec::new());
+        let mut negotiation = Negotiation::new(haves);
+        let final_haves = if self.capability("multi_ack_detailed").is_some() {
+            while let Some(haves) = negotiation.next_round()? {
+                let mut body = Vec::new();
+                let haves = match (stateless, sent_wants) {
+                    (true, _) | (false, false) => {
+                        self.write_v0_wants(&mut body, request, progress.is_some())?;
+                        haves.as_slice()
+                    }
+                    // stateful servers already know the common commits
+                    (false, true) => &haves[negotiation.common.len()..],
+                };
+                for have in haves.iter() {
+                    write_line(&mut body, &format!("have {}", have))?;
+                }
+                write_flush(&mut body)?;
+
+                let mut reader = PktReader::new(self.transport.request(self.service, body)?);
+                if shallow_info && (stateless || !sent_wants) {
+                    (shallow, unshallow) = parse_shallow_info(&reader.read_lines_until_flush()?)?;
+                }
+                sent_wants = true;
+                loop {
+                    let line = reader.read_expected()?.as_text().unwrap_or_default();
+                    check_error_line(&line)?;
+                    if line == "NAK" {
+                        break;
+                    }
+                    match line.split(' ').collect::<Vec<&str>>()[..] {
+                        ["ACK", sha1, "common"] => negotiation.ack(sha1, false),
+                        ["ACK", sha1, "ready"] => negotiation.ack(sha1, true),
+                        _ => bail!("unexpected negotiation response '{}'", line),
+                    }
+                }
+                negotiation.end_round();
+            }
+            match stateless {
+                true => negotiation.common.clone(),
+                false => Vec::new(),
+            }
+        } else {
+            // without multi_ack the server only tells about the first common
+            // commit, so the haves are sent at once
+            negotiation
+                .candidates
+                .by_ref()
+                .take(MAX_IN_VAIN)
+                .collect::<Result<Vec<String>>>()?
+        };
+
+        let mut body = Vec::new();
+        let read_shallow_info = shallow_info && (stateless || !sent_wants);
+        if stateless || !sent_wants {
+            self.write_v0_wants(&mut body, request, progress.is_some())?;
+        }
+        for have in final_haves.iter() {
+            write_line(&mut body, &format!("have {}", have))?;
+        }
+        write_line(&mut body, "done")?;
+
+        let sideband = self.sideband().is_some();
+        let mut reader = PktReader::new(self.transport.request(self.service, body)?);
+        if read_shallow_info {
+            (shallow, unshallow) = parse_shallow_info(&reader.read_lines_until_flush()?)?;
+        }
+        loop {
+            let line = reader.read_expected()?.as_text().unwrap_or_default();
+            check_error_line(&line)?;
+            match line.split(' ').collect::<Vec<&str>>()[..] {
+                ["NAK"] | ["ACK", _] => break,
+                ["ACK", _, _] => continue,
+                _ => bail!("unexpected response to fetch: '{}'", line),
+            }
+        }
+        let pack = match sideband {
+            true => Connection::sideband_pack(reader, progress),
+            false => reader.into_inner(),
+        };
+        return Ok(FetchResponse {
+            pack,
+            shallow,
+            unshallow,
+        });
+    }
+
+    fn fetch_v2<'a>(
+        &mut self,
+        request: &FetchRequest,
+        haves: Haves,
+        progress: Option<&'a mut dyn Write>,
+    ) -> Result<FetchResponse<'a>> {
+        let mut negotiation = Negotiation::new(haves);
+        let mut reader = loop {
+            let round = negotiation.next_round()?;
+            let done = round.is_none();
+            let haves = round.unwrap_or_else(|| negotiation.common.clone());
+
+            let mut body = Vec::new();
+            self.write_v2_command(&mut body, "fetch")?;
+            if progress.is_none() {
+                write_line(&mut body, "no-progress")?;
+            }
+            write_line(&mut body, "thin-pack")?;
+            write_line(&mut body, "ofs-delta")?;
+            write_line(&mut body, "include-tag")?;
+            for want in request.wants.iter() {
+                write_line(&mut body, &format!("want {}", want))?;
+            }
+            self.write_fetch_args(&mut body, request)?;
+            for have in haves.iter() {
+                write_line(&mut body, &format!("have {}", have))?;
+            }
+            if done {
+                write_line(&mut body, "done")?;
+            }
+            write_flush(&mut body)?;
+
+            let mut reader = PktReader::new(self.transport.request(self.service, body)?);
+            if done {
+                break reader;
+            }
+            let section = reader.read_expected()?.as_text().unwrap_or_default();
+            check_error_line(&section)?;
+            if section != "acknowledgments" {
+                bail!("unexpected section '{}' in fetch response", section);
+            }
+            for line in reader.read_lines_until_flush()? {
+                match line.split(' ').collect::<Vec<&str>>()[..] {
+                    ["NAK"] => {}
+                    ["ACK", sha1] => negotiation.ack(sha1, false),
+                    ["ready"] => negotiation.ready = true,
+                    _ => bail!("unexpected acknowledgment '{}'", line),
+                }
+            }
+            negotiation.end_round();
+            // once ready the server follows with the packfile right away
+            if negotiation.ready {
+                break reader;
+            }
+        };
+
+        let (mut shallow, mut unshallow) = (Vec::new(), Vec::new());
+        loop {
+            let section = match reader.read_expected()? {
+                packet @ Packet::Data(_) => packet.as_text().unwrap(),
+                packet => bail!("unexpected {:?} in fetch response", packet),
+            };
+            check_error_line(&section)?;
+            match section.as_str() {
+                "packfile" => break,
+                "shallow-info" => {
+                    (shallow, unshallow) = parse_shallow_info(&reader.read_lines_until_flush()?)?
+                }
+                // sections we don't use yet are skipped up to their delimiter
+                _ => {
+                    reader.read_lines_until_flush()?;
+                }
+            }
+        }
+        // the packfile section is always multiplexed in protocol v2
+        return Ok(FetchResponse {
+            pack: Connection::sideband_pack(reader, progress),
+            shallow,
+            unshallow,
+        });
+    }
+
+    /// Sends ref update commands to receive-pack, followed by the pack with
+    /// the objects they need (ignored when every command is a delete), and
+    /// returns what the server reports
+    pub fn push(
+        &mut self,
+        commands: &[RefCommand],
+        pack: &[u8],
+        progress: Option<&mut dyn Write>,
+    ) -> Result<PushReport> {
+        if commands.is_empty() {
+            bail!("nothing to push");
+        }
+        let deletes_only = commands.iter().all(|c| c.new == ZERO_ID);
+        if commands.iter().any(|c| c.new == ZERO_ID) && self.capability("delete-refs").is_none() {
+            bail!("the remote does not support deleting refs");
+        }
+
+        let mut capabilities = vec![format!("agent={}", AGENT)];
+        let report_status = self.capability("report-status").is_some();
+        if report_status {
+            capabilities.push("report-status".to_string());
+        }
+        if self.capability("delete-refs").is_some() {
+            capabilities.push("delete-refs".to_string());
+        }
+        if progress.is_none() && self.capability("quiet").is_some() {
+            capabilities.push("quiet".to_string());
+        }
+        // the pack uses OFS_DELTA entries only when the remote reads them
+        if self.capability("ofs-delta").is_some() {
+            capabilities.push("ofs-delta".to_string());
+        }
+        let sideband = self.capability("side-band-64k").is_some();
+        if sideband {
+            capabilities.push("side-band-64k".to_string());
+        }
+
+        let mut body = Vec::new();
+        for (i, command) in commands.iter().enumerate() {
+            let line = format!("{} {} {}", command.old, command.new, command.name);
+            match i {
+                0 => write_line(&mut body, &format!("{}\0{}", line, capabilities.join(" ")))?,
+                _ => write_line(&mut body, &line)?,
+            }
+        }
+        write_flush(&mut body)?;
+        if !deletes_only {
+            body.extend_from_slice(pack);
+        }
+
+        let response = self.transport.request(self.service, body)?;
+        if !report_status {
+            return Ok(PushReport::default());
+        }
+        let lines = if sideband {
+            let mut status = Vec::new();
+            SidebandReader::new(PktReader::new(response), progress).read_to_end(&mut status)?;
+            PktReader::new(status.as_slice()).read_lines_until_flush()?
+        } else {
+            PktReader::new(response).read_lines_until_flush()?
+        };
+        return parse_report_status(&lines);
+    }
+}
+
+/// Parses the `report-status` sent by receive-pack after a push
+fn parse_report_status(lines: &[String]) -> Result<PushReport> {
+    let mut report = PushReport::default();
+    let unpack = match lines.first().and_then(|l| l.strip_prefix("unpack ")) {
+        Some(unpack) => unpack,
+        None => bail!("malformed push status, expected the unpack result"),
+    };
+    if unpack != "ok" {
+        report.unpack_error = Some(unpack.to_string());
+    }
+    for line in lines[1..].iter() {
+        if line.starts_with("ok ") {
+            continue;
+        }
+        match line.strip_prefix("ng ").and_then(|l| l.split_once(' ')) {
+            Some((name, reason)) => report.rejected.push((name.to_string(), reason.to_string())),
+            None => bail!("malformed push status line '{}'", line),
+        }
+    }
+    return Ok(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::env::current_dir;
+    use std::io::Read;
+    use std::rc::Rc;
+
+    use crate::protocol::{
+        check_filter_spec, Connection, FetchRequest, Haves, MAX_HAVES_PER_ROUND, MAX_IN_VAIN,
+    };
+    use crate::testutils;
+    use crate::transport::{HttpTransport, Service};
+
+    #[test]
+    fn test_protocol_versions() {
+        testutils::in_tmp_dir(|| {
+            let head = testutils::create_repo("remote.git", &[("file.txt", "content\n")]);
+            testutils::tag_in("remote.git", "v1");
+            testutils::get_git_output_in("remote.git", &["checkout", "-q", "-b", "next"]);
+            let next = testutils::commit_files("remote.git", &[("next.txt", "next\n")], "next");
+            testutils::get_git_output_in("remote.git", &["checkout", "-q", "master"]);
+            let root = current_dir().unwrap();
+
+            let mut all_refs = Vec::new();
+            for allow_v2 in [true, false] {
+                let url = testutils::start_http_server(&root, allow_v2);
+                let transport = HttpTransport::new(&format!("{}/remote.git", url)).unwrap();
+                let mut connection =
+                    Connection::open(Box::new(transport), Service::UploadPack).unwrap();
+                assert_eq!(connection.version, if allow_v2 { 2 } else { 0 });
+
+                let refs = connection.ls_refs(&["HEAD", "refs/tags/"]).unwrap();
+                assert_eq!(refs.len(), 2);
+                assert_eq!(refs[0].name, "HEAD");
+                assert_eq!(refs[0].sha1, head);
+                assert_eq!(refs[0].symref_target.as_deref(), Some("refs/heads/master"));
+                assert_eq!(refs[1].name, "refs/tags/v1");
+                assert_eq!(refs[1].peeled.as_deref(), Some(head.as_str()));
+                all_refs.push(refs);
+
+                let request = FetchRequest {
+                    wants: vec![head.clone()],
+                    ..Default::default()
+                };
+                let mut progress = Vec::new();
+                let mut pack = Vec::new();
+                connection
+                    .fetch(request, Some(&mut progress))
+                    .unwrap()
+                    .pack
+                    .read_to_end(&mut pack)
+                    .unwrap();
+                assert!(pack.starts_with(b"PACK"));
+                assert!(String::from_utf8(progress)
+                    .unwrap()
+                    .starts_with("remote: Enumerating objects"));
+                // commit, tree, blob and the annotated tag (include-tag)
+                assert_eq!(
+                    crate::packs::parse_packfile(&pack).unwrap().entries.len(),
+                    4
+                );
+
+                // unknown haves take a few rounds before the common one, the
+                // endless ones after it are only pulled until negotiation ends
+                let mut haves: Vec<String> = (0..40).map(|i| format!("{:040x}", i + 1)).collect();
+                haves.push(head.clone());
+                let unknown = (41..).map(|i| format!("{:040x}", i));
+                let pulled = Rc::new(Cell::new(0));
+                let counter = pulled.clone();
+                let haves = haves.into_iter().chain(unknown).map(move |have| {
+                    counter.set(counter.get() + 1);
+                    return Ok(have);
+                });
+                let request = FetchRequest {
+                    wants: vec![next.clone()],
+                    haves: Haves::new(haves),
+                    ..Default::default()
+                };
+                let mut pack = Vec::new();
+                let mut response = connection.fetch(request, None).unwrap();
+                response.pack.read_to_end(&mut pack).unwrap();
+                assert_eq!(
+                    crate::packs::parse_packfile(&pack).unwrap().entries.len(),
+                    3
+                );
+                assert!(pulled.get() <= 41 + MAX_IN_VAIN + MAX_HAVES_PER_ROUND);
+
+                // the server tells where the history it sent is cut
+                let request = FetchRequest {
+                    wants: vec![next.clone()],
+                    depth: Some(1),
+                    ..Default::default()
+                };
+                let mut pack = Vec::new();
+                let mut response = connection.fetch(request, None).unwrap();
+                response.pack.read_to_end(&mut pack).unwrap();
+                assert_eq!(response.shallow, vec![next.clone()]);
+                assert_eq!(
+                    crate::packs::parse_packfile(&pack).unwrap().entries.len(),
+                    4
+                );
+                let request = FetchRequest {
+                    wants: vec![next.clone()],
+                    filter: Some("blob:none".to_string()),
+                    ..Default::default()
+                };
+                assert!(connection.fetch(request, None).is_err());
+            }
+            assert_eq!(all_refs[0], all_refs[1]);
+        });
+    }
+
+    #[test]
+    fn test_filter_spec() {
+        for filter in ["blob:none", "blob:limit=10", "blob:limit=1k", "tree:0"] {
+            assert!(check_filter_spec(filter).is_ok());
+        }
+        for filter in [
+            "blob:limit=",
+            "blob:limit=k",
+            "tree:",
+            "sparse:oid=x",
+            "none",
+        ] {
+            assert!(check_filter_spec(filter).is_err());
+        }
+    }
+}
@@ -0,0 +1,515 @@
+use anyhow::{bail, Result};
+use std::fs;
+use std::io::{self, Write};
+
+use crate::config::Config;
+use crate::fetch::{ref_kind, SUMMARY_WIDTH};
+use crate::objects::object_exists;
+use crate::packs::{build_pack, PackOptions};
+use crate::protocol::{Connection, RefCommand, RemoteRef, ZERO_ID};
+use crate::refs::{delete_ref, list_refs, read_ref, resolve_object, shorten_ref, write_ref};
+use crate::refspec::{dwim_names, expand_dst, Refspec};
+use crate::repo_path;
+use crate::revwalk::{is_ancestor, list_objects_with_paths, peel_to_commit};
+use crate::transport::{transport_for_url, Service};
+
+/// Options of `push`
+#[derive(Debug, Default)]
+pub struct PushOptions {
+    /// Update remote refs even when it's not a fast-forward
+    pub force: bool,
+    /// Value of `--force-with-lease`: empty to protect every pushed ref with
+    /// its remote-tracking ref, `<ref>` to protect only that ref and
+    /// `<ref>:<expect>` to expect an explicit value
+    pub force_with_lease: Option<String>,
+    /// Delete the given remote refs
+    pub delete: bool,
+    /// Push all the tags
+    pub tags: bool,
+    /// Don't show the progress sent by the server nor the updated refs
+    pub quiet: bool,
+}
+
+/// A remote ref to point at a local object
+#[derive(Debug)]
+struct PushUpdate {
+    /// Local ref (or object name) the new value comes from, empty for deletes
+    src: String,
+    dst: String,
+    /// `ZERO_ID` to delete the remote ref
+    new: String,
+    /// Value of the ref on the remote, `ZERO_ID` when it doesn't exist
+    old: String,
+    force: bool,
+}
+
+/// What happened to a remote ref, as printed in the push summary
+#[derive(Debug, PartialEq, Eq)]
+enum PushStatus {
+    UpToDate,
+    New,
+    Deleted,
+    FastForward,
+    Forced,
+    Rejected(&'static str),
+    RemoteRejected(String),
+}
+
+impl PushStatus {
+    fn is_rejected(&self) -> bool {
+        return matches!(
+            self,
+            PushStatus::Rejected(_) | PushStatus::RemoteRejected(_)
+        );
+    }
+}
+
+/// Qualifies a short remote ref name, preferring the refs the remote has
+fn remote_ref_name(name: &str, src: &str, advertised: &[RemoteRef]) -> String {
+    for candidate in dwim_names(name) {
+        if advertised.iter().any(|r| r.name == candidate) {
+            return candidate;
+        }
+    }
+    return expand_dst(name, src);
+}
+
+/// Resolves the refspecs of a push into the remote refs to update
+fn match_push_refspecs(
+    refspecs: &[Refspec],
+    advertised: &[RemoteRef],
+    force: bool,
+) -> Result<Vec<PushUpdate>> {
+    let remote_value = |name: &str| {
+        advertised
+            .iter()
+            .find(|r| r.name == name)
+            .map(|r| r.sha1.clone())
+            .unwrap_or_else(|| ZERO_ID.to_string())
+    };
+    let mut updates: Vec<PushUpdate> = Vec::new();
+    let mut add = |update: PushUpdate| {
+        if !updates.iter().any(|u| u.dst == update.dst) {
+            updates.push(update);
+        }
+    };
+
+    for refspec in refspecs {
+        if refspec.src.is_empty() {
+            let dst = remote_ref_name(&refspec.dst, "", advertised);
+            let old = remote_value(&dst);
+            if old == ZERO_ID {
+                bail!(
+                    "unable to delete '{}': remote ref does not exist",
+                    refspec.dst
+                );
+            }
+            add(PushUpdate {
+                src: String::new(),
+                dst,
+                new: ZERO_ID.to_string(),
+                old,
+                force: true,
+            });
+            continue;
+        }
+
+        if refspec.is_glob() {
+            for prefix in refspec.prefixes() {
+                for (name, sha1) in list_refs(&prefix)? {
+                    if let Some(dst) = refspec.map(&name) {
+                        add(PushUpdate {
+                            old: remote_value(&dst),
+                            src: name,
+                            dst,
+                            new: sha1,
+                            force: force || refspec.force,
+                        });
+                    }
+                }
+            }
+            continue;
+        }
+
+        let mut local = None;
+        for candidate in dwim_names(&refspec.src) {
+            if let Some(sha1) = read_ref(&candidate)? {
+                local = Some((candidate, sha1));
+                break;
+            }
+        }
+        let (src, sha1) = match local {
+            Some(local) => local,
+            None => match resolve_object(&refspec.src) {
+                Ok(sha1) => (String::new(), sha1),
+                Err(_) => bail!("src refspec {} does not match any", refspec.src),
+            },
+        };
+        let dst = match (refspec.dst.as_str(), src.as_str()) {
+            ("", "") => bail!(
+                "the destination of '{}' must be a full ref name",
+                refspec.src
+            ),
+            ("", src) => src.to_string(),
+            (dst, src) => remote_ref_name(dst, src, advertised),
+        };
+        add(PushUpdate {
+            src: match src.is_empty() {
+                true => refspec.src.clone(),
+                false => src,
+            },
+            old: remote_value(&dst),
+            dst,
+            new: sha1,
+            force: force || refspec.force,
+        });
+    }
+    return Ok(updates);
+}
+
+/// Returns the value `--force-with-lease` expects a remote ref to have, or
+/// None when the ref isn't protected
+fn lease_expectation(
+    lease: &Option<String>,
+    dst: &str,
+    tracking: &[Refspec],
+) -> Result<Option<String>> {
+    let lease = match lease {
+        Some(lease) => lease,
+        None => return Ok(None),
+    };
+    if !lease.is_empty() {
+        let (name, expect) = match lease.split_once(':') {
+            Some((name, expect)) => (name, Some(expect)),
+            None => (lease.as_str(), None),
+        };
+        if !dwim_names(name).iter().any(|n| n == dst) {
+            return Ok(None);
+        }
+        match expect {
+            Some("") => return Ok(Some(ZERO_ID.to_string())),
+            Some(expect) => return Ok(Some(resolve_object(expect)?)),
+            None => {}
+        }
+    }
+    // without a remote-tracking ref the remote ref must not exist
+    for refspec in tracking {
+        if let Some(local) = refspec.map(dst).filter(|l| !l.is_empty()) {
+            if let Some(sha1) = read_ref(&local)? {
+                return Ok(Some(sha1));
+            }
+        }
+    }
+    return Ok(Some(ZERO_ID.to_string()));
+}
+
+/// Decides whether a remote ref can be updated, before sending anything
+fn classify_push(update: &PushUpdate, lease: Option<String>) -> Result<PushStatus> {
+    if update.old == update.new {
+        return Ok(PushStatus::UpToDate);
+    }
+    if let Some(expected) = lease.as_ref() {
+        if *expected != update.old {
+            return Ok(PushStatus::Rejected("stale info"));
+        }
+    }
+    if update.new == ZERO_ID {
+        return Ok(PushStatus::Deleted);
+    }
+    if update.old == ZERO_ID {
+        return Ok(PushStatus::New);
+    }
+    let old_known = object_exists(&update.old)?;
+    let fast_forward = old_known
+        && match (peel_to_commit(&update.old)?, peel_to_commit(&update.new)?) {
+            (Some(old), Some(new)) => is_ancestor(&old, &new)?,
+            _ => false,
+        };
+    let tag = update.dst.starts_with("refs/tags/");
+    if fast_forward && !tag {
+        return Ok(PushStatus::FastForward);
+    }
+    if update.force || lease.is_some() {
+        return Ok(PushStatus::Forced);
+    }
+    if tag {
+        return Ok(PushStatus::Rejected("already exists"));
+    }
+    if !old_known {
+        return Ok(PushStatus::Rejected("fetch first"));
+    }
+    return Ok(PushStatus::Rejected("non-fast-forward"));
+}
+
+/// Formats a line of the summary git prints for every pushed ref
+fn format_push(update: &PushUpdate, status: &PushStatus) -> Option<String> {
+    let (flag, summary, message) = match status {
+        PushStatus::UpToDate => return None,
+        PushStatus::New => {
+            let kind = match ref_kind(&update.dst) {
+                "ref" => "reference",
+                kind => kind,
+            };
+            ('*', format!("[new {}]", kind), None)
+        }
+        PushStatus::Deleted => ('-', "[deleted]".to_string(), None),
+        PushStatus::FastForward => (
+            ' ',
+            format!("{}..{}", &update.old[..7], &update.new[..7]),
+            None,
+        ),
+        PushStatus::Forced => (
+            '+',
+            format!("{}...{}", &update.old[..7], &update.new[..7]),
+            Some("forced update"),
+        ),
+        PushStatus::Rejected(reason) => ('!', "[rejected]".to_string(), Some(*reason)),
+        PushStatus::RemoteRejected(reason) => {
+            ('!', "[remote rejected]".to_string(), Some(reason.as_str()))
+        }
+    };
+    let refs = match update.src.as_str() {
+        "" => shorten_ref(&update.dst).to_string(),
+        src => format!("{} -> {}", shorten_ref(src), shorten_ref(&update.dst)),
+    };
+    let message = message.map(|m| format!(" ({})", m)).unwrap_or_default();
+    return Some(format!(
+        " {} {:<sw$} {}{}",
+        flag,
+        summary,
+        refs,
+        message,
+        sw = SUMMARY_WIDTH
+    ));
+}
+
+/// Returns the refspec pushing the current branch to the same name
+fn current_branch_refspec() -> Result<Refspec> {
+    let head = fs::read_to_string(repo_path(".git/HEAD"))?;
+    return match head.trim().strip_prefix("ref: refs/heads/") {
+        Some(branch) => Refspec::parse(&format!("refs/heads/{0}:refs/heads/{0}", branch)),
+        None => bail!("you are not currently on a branch"),
+    };
+}
+
+/// Pushes local refs to a remote (a configured remote name or a URL) along
+/// with the objects it's missing, then updates the remote-tracking refs
+pub fn push(remote: &str, refspecs: &[String], options: &PushOptions) -> Result<()> {
+    let config = Config::load()?;
+    let configured_url = config.get(&format!("remote.{}.url", remote));
+    let url = configured_url.unwrap_or(remote).to_string();
+    let tracking: Vec<Refspec> = config
+        .get_all(&format!("remote.{}.fetch", remote))
+        .into_iter()
+        .map(Refspec::parse)
+        .collect::<Result<_>>()?;
+
+    let mut specs = Vec::new();
+    if options.delete {
+        if refspecs.is_empty() {
+            bail!("--delete doesn't make sense without any refs");
+        }
+        for name in refspecs {
+            specs.push(Refspec::parse(&format!(":{}", name))?);
+        }
+    } else {
+        for refspec in refspecs {
+            specs.push(Refspec::parse(refspec)?);
+        }
+    }
+    if options.tags {
+        specs.push(Refspec::parse("refs/tags/*:refs/tags/*")?);
+    } else if specs.is_empty() {
+        specs.push(current_branch_refspec()?);
+    }
+
+    let mut connection = Connection::open(transport_for_url(&url)?, Service::ReceivePack)?;
+    let advertised = connection.ls_refs(&[])?;
+    let updates = match_push_refspecs(&specs, &advertised, options.force)?;
+
+    let mut statuses = Vec::new();
+    let mut commands = Vec::new();
+    for update in updates.iter() {
+        let lease = lease_expectation(&options.force_with_lease, &update.dst, &tracking)?;
+        let status = classify_push(update, lease)?;
+        if !status.is_rejected() && status != PushStatus::UpToDate {
+            commands.push(RefCommand {
+                name: update.dst.clone(),
+                old: update.old.clone(),
+                new: update.new.clone(),
+            });
+        }
+        statuses.push(status);
+    }
+
+    if !commands.is_empty() {
+        let include: Vec<String> = commands
+            .iter()
+            .filter(|c| c.new != ZERO_ID)
+            .map(|c| c.new.clone())
+            .collect();
+        let mut exclude = Vec::new();
+        for r in advertised.iter() {
+            if object_exists(&r.sha1)? {
+                exclude.push(r.sha1.clone());
+            }
+        }
+        let pack_options = PackOptions {
+            ofs_delta: connection.capability("ofs-delta").is_some(),
+            ..Default::default()
+        };
+        let objects = list_objects_with_paths(&include, &exclude)?;
+        let pack = build_pack(&objects, &pack_options)?;
+
+        let mut stderr = io::stderr();
+        let progress: Option<&mut dyn Write> = match options.quiet {
+            true => None,
+            false => Some(&mut stderr),
+        };
+        let report = connection.push(&commands, &pack, progress)?;
+        if let Some(error) = report.unpack_error.as_ref() {
+            eprintln!("error: remote unpack failed: {}", error);
+        }
+        for (name, reason) in report.rejected {
+            if let Some(i) = updates.iter().position(|u| u.dst == name) {
+                statuses[i] = PushStatus::RemoteRejected(reason);
+            }
+        }
+    }
+
+    for (update, status) in updates.iter().zip(statuses.iter()) {
+        if status.is_rejected() || *status == PushStatus::UpToDate {
+            continue;
+        }
+        for refspec in tracking.iter() {
+            if let Some(local) = refspec.map(&update.dst).filter(|l| !l.is_empty()) {
+                match update.new.as_str() {
+                    ZERO_ID => delete_ref(&local)?,
+                    new => write_ref(&local, new)?,
+                }
+            }
+        }
+    }
+
+    let rejected = statuses.iter().any(|s| s.is_rejected());
+    let lines: Vec<String> = updates
+        .iter()
+        .zip(statuses.iter())
+        .filter_map(|(update, status)| format_push(update, status))
+        .collect();
+    if lines.is_empty() {
+        if !options.quiet {
+            eprintln!("Everything up-to-date");
+        }
+    } else if !options.quiet || rejected {
+        eprintln!("To {}", url);
+        for line in lines {
+            eprintln!("{}", line);
+        }
+    }
+    if rejected {
+        bail!("failed to push some refs to '{}'", url);
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::{current_dir, set_current_dir};
+
+    use crate::clone::{clone, CloneOptions};
+    use crate::push::{push, PushOptions};
+    use crate::refs::{read_ref, write_ref};
+    use crate::testutils;
+
+    #[test]
+    fn test_push() {
+        testutils::in_tmp_dir(|| {
+            testutils::create_repo("source", &[("a.txt", "a\n")]);
+            testutils::get_git_output(&["clone", "-q", "--bare", "source", "remote.git"]);
+            let url = testutils::start_http_server(&current_dir().unwrap(), false);
+            let options = CloneOptions {
+                quiet: true,
+                ..Default::default()
+            };
+            clone(
+                &format!("{}/remote.git", url),
+                &"local".to_string(),
+                &options,
+            )
+            .unwrap();
+            let remote = |args: &[&str]| testutils::git_in("../remote.git", args);
+            let quiet = || PushOptions {
+                quiet: true,
+                ..Default::default()
+            };
+
+            set_current_dir("local").unwrap();
+            let second = testutils::commit_files(".", &[("b.txt", "b\n")], "second");
+            push("origin", &[], &quiet()).unwrap();
+            assert_eq!(remote(&["rev-parse", "master"]), second);
+            assert_eq!(remote(&["fsck", "--no-dangling"]), "");
+            assert_eq!(
+                read_ref("refs/remotes/origin/master").unwrap(),
+                Some(second.clone())
+            );
+            // pushing again has nothing to send
+            push("origin", &[], &quiet()).unwrap();
+
+            // a new branch, a tag and a push to another name
+            testutils::get_git_output(&["branch", "feature"]);
+            testutils::get_git_output(&["tag", "v1"]);
+            let specs = ["feature".to_string(), "master:copy".to_string()];
+            push("origin", &specs, &quiet()).unwrap();
+            let options = PushOptions {
+                tags: true,
+                ..quiet()
+            };
+            push("origin", &[], &options).unwrap();
+            assert_eq!(
+                remote(&["rev-parse", "feature", "copy", "v1"])
+                    .lines()
+                    .count(),
+                3
+            );
+            assert_eq!(remote(&["rev-parse", "v1"]), second);
+
+            // rewritten history needs force, unless the lease is stale
+            testutils::get_git_output(&["reset", "-q", "--hard", "HEAD~1"]);
+            let rewritten = testutils::commit_files(".", &[("c.txt", "c\n")], "rewritten");
+            assert!(push("origin", &[], &quiet()).is_err());
+            assert_eq!(remote(&["rev-parse", "master"]), second);
+            let options = PushOptions {
+                force_with_lease: Some(String::new()),
+                ..quiet()
+            };
+            write_ref("refs/remotes/origin/master", &rewritten).unwrap();
+            assert!(push("origin", &[], &options).is_err());
+            write_ref("refs/remotes/origin/master", &second).unwrap();
+            push("origin", &[], &options).unwrap();
+            assert_eq!(remote(&["rev-parse", "master"]), rewritten);
+            let options = PushOptions {
+                force: true,
+                ..quiet()
+            };
+            push("origin", &["+feature:master".to_string()], &quiet()).unwrap();
+            push("origin", &["master".to_string()], &options).unwrap();
+            assert_eq!(remote(&["rev-parse", "master"]), rewritten);
+
+            // tags aren't moved without force
+            testutils::get_git_output(&["tag", "-f", "v1", &rewritten]);
+            assert!(push("origin", &["v1".to_string()], &quiet()).is_err());
+
+            let options = PushOptions {
+                delete: true,
+                ..quiet()
+            };
+            push("origin", &["feature".to_string()], &options).unwrap();
+            push("origin", &[":copy".to_string()], &quiet()).unwrap();
+            assert_eq!(remote(&["branch", "--list", "feature", "copy"]), "");
+            assert_eq!(read_ref("refs/remotes/origin/feature").unwrap(), None);
+            assert!(push("origin", &[":missing".to_string()], &quiet()).is_err());
+            set_current_dir("..").unwrap();
+        });
+    }
+}
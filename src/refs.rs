@@ -0,0 +1,460 @@
+use anyhow::{bail, Result};
+use std::fs;
+use std::io::{self, Write};
+
+use crate::objects::{find_objects_by_prefix, read_object, GitObjectType};
+use crate::repo_path;
+
+/// Maximum number of symbolic refs followed before giving up
+const MAX_SYMREF_DEPTH: usize = 5;
+
+/// Returns whether the string is a full hex encoded sha1
+pub fn is_sha1(name: &str) -> bool {
+    return name.len() == 40 && name.chars().all(|c| c.is_ascii_hexdigit());
+}
+
+/// Checks a ref name against the rules of `git check-ref-format`, which
+/// also keep the names given by remotes inside `.git/refs`
+pub fn check_ref_format(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name != "@"
+        && !name.starts_with('/')
+        && !name.ends_with('/')
+        && !name.ends_with('.')
+        && !name.contains("//")
+        && !name.contains("..")
+        && !name.contains("@{")
+        && !name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+        && name
+            .split('/')
+            .all(|component| !component.starts_with('.') && !component.ends_with(".lock"));
+    if !valid {
+        bail!("'{}' is not a valid ref name", name);
+    }
+    return Ok(());
+}
+
+/// Checks that a ref about to be written or deleted is either a valid name
+/// under `refs/` or a pseudo-ref like `HEAD`
+fn check_ref_path(name: &str) -> Result<()> {
+    if name.starts_with("refs/") {
+        return check_ref_format(name);
+    }
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_uppercase() || c == '_') {
+        bail!("refusing to write ref '{}' outside of refs/", name);
+    }
+    return Ok(());
+}
+
+/// Looks up a fully qualified ref in `.git/packed-refs`
+fn read_packed_ref(name: &str) -> Result<Option<String>> {
+    let path = &repo_path(".git/packed-refs");
+    if !path.exists() {
+        return Ok(None);
+    }
+    for line in fs::read_to_string(path)?.lines() {
+        if line.starts_with('#') || line.starts_with('^') {
+            continue;
+        }
+        if let Some((sha1, refname)) = line.split_once(' ') {
+            if refname == name {
+                return Ok(Some(sha1.to_string()));
+            }
+        }
+    }
+    return Ok(None);
+}
+
+/// Collects the loose refs under `dir` (relative to `.git`) into `refs`
+fn collect_loose_refs(dir: &str, refs: &mut Vec<String>) -> Result<()> {
+    let path = repo_path(".git").join(dir);
+    if !path.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let name = format!("{}/{}", dir, entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            collect_loose_refs(&name, refs)?;
+        } else if !name.ends_with(".lock") {
+            refs.push(name);
+        }
+    }
+    return Ok(());
+}
+
+/// Lists the refs (loose and packed) starting with `prefix` along with the
+/// hash they resolve to, sorted by name
+pub fn list_refs(prefix: &str) -> Result<Vec<(String, String)>> {
+    let mut names = Vec::new();
+    collect_loose_refs("refs", &mut names)?;
+    let packed = &repo_path(".git/packed-refs");
+    if packed.exists() {
+        for line in fs::read_to_string(packed)?.lines() {
+            if line.starts_with('#') || line.starts_with('^') {
+                continue;
+            }
+            if let Some((_, name)) = line.split_once(' ') {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names.retain(|name| name.starts_with(prefix));
+    names.sort();
+    names.dedup();
+
+    let mut refs = Vec::new();
+    for name in names {
+        if let Some(sha1) = read_ref(&name)? {
+            refs.push((name, sha1));
+        }
+    }
+    return Ok(refs);
+}
+
+/// Reads a fully qualified ref (like `HEAD` or `refs/heads/master`),
+/// following symbolic refs, and returns the hash it points to
+pub fn read_ref(name: &str) -> Result<Option<String>> {
+    let mut name = name.to_string();
+    for _ in 0..MAX_SYMREF_DEPTH {
+        let path = repo_path(".git").join(&name);
+        if !path.is_file() {
+            return read_packed_ref(&name);
+        }
+        let content = fs::read_to_string(path)?;
+        let content = content.trim();
+        match content.strip_prefix("ref: ") {
+            Some(target) => name = target.to_string(),
+            None if is_sha1(content) => return Ok(Some(content.to_string())),
+            None => bail!("ref '{}' is malformed", name),
+        }
+    }
+    bail!("too many levels of symbolic refs resolving '{}'", name);
+}
+
+/// Atomically replaces the content of a ref file through a `.lock` file
+fn write_ref_file(name: &str, content: &str) -> Result<()> {
+    let path = repo_path(".git").join(name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let lock = repo_path(".git").join(format!("{}.lock", name));
+    let mut file = match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            bail!("unable to lock ref '{}', '{}' exists", name, lock.display())
+        }
+        Err(e) => bail!(e),
+    };
+    if let Err(e) = file.write_all(content.as_bytes()) {
+        let _ = fs::remove_file(&lock);
+        bail!(e);
+    }
+    fs::rename(&lock, &path)?;
+    return Ok(());
+}
+
+/// Points a ref (like `refs/heads/master`) at the given hash
+pub fn write_ref(name: &str, sha1: &str) -> Result<()> {
+    check_ref_path(name)?;
+    if !is_sha1(sha1) {
+        bail!("'{}' is not a valid hash for ref '{}'", sha1, name);
+    }
+    return write_ref_file(name, &format!("{}\n", sha1));
+}
+
+/// Makes `name` a symbolic ref pointing at the ref `target`
+pub fn write_symref(name: &str, target: &str) -> Result<()> {
+    check_ref_path(name)?;
+    if !target.starts_with("refs/") {
+        bail!("refusing to point '{}' outside of refs/", name);
+    }
+    check_ref_format(target)?;
+    return write_ref_file(name, &format!("ref: {}\n", target));
+}
+
+/// Removes a ref, both its loose file and its line in `.git/packed-refs`
+pub fn delete_ref(name: &str) -> Result<()> {
+    check_ref_path(name)?;
+    let path = repo_path(".git").join(name);
+    if path.is_file() {
+        fs::remove_file(&path)?;
+    }
+    let packed = &repo_path(".git/packed-refs");
+    if !packed.exists() {
+        return Ok(());
+    }
+    let content = fs::read_to_string(packed)?;
+    let mut kept = String::new();
+    let mut removed = false;
+    for line in content.lines() {
+        // peeled lines belong to the ref right before them
+        let deleted = match line.strip_prefix('^') {
+            Some(_) => removed,
+            None => line.split_once(' ').map(|(_, n)| n) == Some(name),
+        };
+        removed = deleted;
+        if !deleted {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+    if kept != content {
+        write_ref_file("packed-refs", &kept)?;
+    }
+    return Ok(());
+}
+
+/// Returns the object an annotated tag points to once every tag in between
+/// is followed, `None` for other objects
+fn peel_tag(sha1: &str) -> Result<Option<String>> {
+    let mut peeled = None;
+    let mut sha1 = sha1.to_string();
+    loop {
+        let (type_, data) = read_object(&sha1)?;
+        if type_ != GitObjectType::Tag {
+            return Ok(peeled);
+        }
+        let content = String::from_utf8_lossy(&data);
+        match content
+            .lines()
+            .next()
+            .and_then(|l| l.strip_prefix("object "))
+        {
+            Some(target) => sha1 = target.to_string(),
+            None => bail!("tag {} has no object line", sha1),
+        }
+        peeled = Some(sha1.clone());
+    }
+}
+
+/// Removes the empty directories under `dir` (relative to `.git`), keeping
+/// `dir` itself
+fn remove_empty_dirs(dir: &str) -> Result<()> {
+    let path = repo_path(".git").join(dir);
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            let name = format!("{}/{}", dir, entry.file_name().to_string_lossy());
+            remove_empty_dirs(&name)?;
+            // fails when not empty
+            let _ = fs::remove_dir(entry.path());
+        }
+    }
+    return Ok(());
+}
+
+/// Moves every loose ref under `refs/` to `.git/packed-refs`, along with the
+/// object annotated tags peel to. Symbolic refs stay loose. Returns the
+/// number of refs packed.
+pub fn pack_refs() -> Result<usize> {
+    let mut loose = Vec::new();
+    collect_loose_refs("refs", &mut loose)?;
+    let mut packed = Vec::new();
+    for name in loose {
+        let content = fs::read_to_string(repo_path(".git").join(&name))?;
+        if !content.starts_with("ref: ") {
+            packed.push(name);
+        }
+    }
+    if packed.is_empty() {
+        return Ok(0);
+    }
+
+    let mut content = String::from("# pack-refs with: peeled fully-peeled sorted \n");
+    for (name, sha1) in list_refs("refs/")? {
+        let symbolic = repo_path(".git").join(&name).is_file() && !packed.contains(&name);
+        if symbolic {
+            continue;
+        }
+        content.push_str(&format!("{} {}\n", sha1, name));
+        if let Some(peeled) = peel_tag(&sha1)? {
+            content.push_str(&format!("^{}\n", peeled));
+        }
+    }
+    write_ref_file("packed-refs", &content)?;
+    for name in packed.iter() {
+        fs::remove_file(repo_path(".git").join(name))?;
+    }
+    for dir in ["refs/heads", "refs/tags", "refs/remotes"] {
+        if repo_path(".git").join(dir).is_dir() {
+            remove_empty_dirs(dir)?;
+        }
+    }
+    return Ok(packed.len());
+}
+
+/// Returns the short name of a ref as displayed by git
+pub fn shorten_ref(name: &str) -> &str {
+    for prefix in ["refs/heads/", "refs/tags/", "refs/remotes/"] {
+        if let Some(short) = name.strip_prefix(prefix) {
+            return short;
+        }
+    }
+    return name;
+}
+
+/// Resolves a possibly abbreviated ref name following git's lookup rules
+pub fn resolve_ref(name: &str) -> Result<Option<String>> {
+    let candidates = [
+        name.to_string(),
+        format!("refs/{}", name),
+        format!("refs/tags/{}", name),
+        format!("refs/heads/{}", name),
+        format!("refs/remotes/{}", name),
+        format!("refs/remotes/{}/HEAD", name),
+    ];
+    for candidate in candidates.iter() {
+        if let Some(sha1) = read_ref(candidate)? {
+            return Ok(Some(sha1));
+        }
+    }
+    return Ok(None);
+}
+
+/// Resolves an object name (full or abbreviated hash, or ref) into a hash
+pub fn resolve_object(name: &str) -> Result<String> {
+    if is_sha1(name) {
+        return Ok(name.to_lowercase());
+    }
+    if let Some(sha1) = resolve_ref(name)? {
+        return Ok(sha1);
+    }
+    if name.len() >= 4 && name.chars().all(|c| c.is_ascii_hexdigit()) {
+        let mut matches = find_objects_by_prefix(&name.to_lowercase())?;
+        if matches.len() > 1 {
+            bail!("short object ID {} is ambiguous", name);
+        }
+        if let Some(sha1) = matches.pop() {
+            return Ok(sha1);
+        }
+    }
+    bail!("Not a valid object name {}", name);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::refs::{
+        check_ref_format, delete_ref, list_refs, read_ref, resolve_object, write_ref, write_symref,
+    };
+    use crate::testutils;
+    use crate::tree::{committree, writetree};
+
+    #[test]
+    fn test_resolve_object() {
+        testutils::in_tmp_git(|| {
+            fs::write("file.txt", "content\n").unwrap();
+            let tree = writetree().unwrap();
+            let commit = committree(
+                &"a <a@a.com>".to_string(),
+                &tree,
+                &String::new(),
+                &"message".to_string(),
+            )
+            .unwrap();
+
+            assert_eq!(resolve_object("HEAD").unwrap(), commit);
+            assert_eq!(resolve_object("master").unwrap(), commit);
+            assert_eq!(resolve_object("refs/heads/master").unwrap(), commit);
+            assert_eq!(resolve_object(&commit[..7]).unwrap(), commit);
+            assert_eq!(resolve_object(&tree).unwrap(), tree);
+            assert!(resolve_object("missing").is_err());
+
+            fs::remove_file(".git/refs/heads/master").unwrap();
+            fs::write(
+                ".git/packed-refs",
+                format!("# pack-refs with: peeled\n{} refs/tags/v1\n", commit),
+            )
+            .unwrap();
+            assert_eq!(resolve_object("v1").unwrap(), commit);
+
+            write_ref("refs/remotes/origin/main", &commit).unwrap();
+            write_symref("refs/remotes/origin/HEAD", "refs/remotes/origin/main").unwrap();
+            assert_eq!(resolve_object("origin").unwrap(), commit);
+            assert_eq!(
+                testutils::get_git_output(&["symbolic-ref", "refs/remotes/origin/HEAD"]),
+                "refs/remotes/origin/main\n"
+            );
+            assert_eq!(
+                list_refs("refs/").unwrap(),
+                vec![
+                    ("refs/remotes/origin/HEAD".to_string(), commit.clone()),
+                    ("refs/remotes/origin/main".to_string(), commit.clone()),
+                    ("refs/tags/v1".to_string(), commit.clone()),
+                ]
+            );
+            delete_ref("refs/tags/v1").unwrap();
+            assert_eq!(read_ref("refs/tags/v1").unwrap(), None);
+            assert_eq!(
+                fs::read_to_string(".git/packed-refs").unwrap(),
+                "# pack-refs with: peeled\n"
+            );
+            delete_ref("refs/remotes/origin/main").unwrap();
+            assert_eq!(read_ref("refs/remotes/origin/main").unwrap(), None);
+
+            write_symref("HEAD", "refs/heads/main").unwrap();
+            assert_eq!(read_ref("HEAD").unwrap(), None);
+            assert!(write_ref("refs/heads/main", "1234").is_err());
+
+            fs::write(".git/refs/heads/main.lock", "").unwrap();
+            assert!(write_ref("refs/heads/main", &commit).is_err());
+        });
+    }
+
+    #[test]
+    fn test_check_ref_format() {
+        for name in [
+            "refs/heads/master",
+            "refs/tags/v1.0",
+            "refs/heads/a/b-c_d",
+            "HEAD",
+        ] {
+            assert!(check_ref_format(name).is_ok(), "{}", name);
+        }
+        let invalid = [
+            "",
+            "@",
+            "refs/heads/../../hooks/post-checkout",
+            "refs/heads/a..b",
+            "/refs/heads/a",
+            "refs/heads/a/",
+            "refs/heads/a.",
+            "refs/heads/.hidden",
+            "refs//heads",
+            "refs/heads/a@{1}",
+            "refs/heads/a b",
+            "refs/heads/a\tb",
+            "refs/heads/a\nb",
+            "refs/heads/a~1",
+            "refs/heads/a^",
+            "refs/heads/a:b",
+            "refs/heads/a?",
+            "refs/heads/a*",
+            "refs/heads/a[b",
+            "refs/heads/a\\b",
+            "refs/heads/a.lock",
+            "refs/heads/a.lock/b",
+        ];
+        for name in invalid {
+            assert!(check_ref_format(name).is_err(), "{:?}", name);
+        }
+
+        testutils::in_tmp_git(|| {
+            fs::write(".git/config", "").unwrap();
+            let sha1 = "1".repeat(40);
+            assert!(write_ref("refs/heads/../../config", &sha1).is_err());
+            assert!(write_ref("../config", &sha1).is_err());
+            assert!(write_symref("refs/heads/a", "refs/heads/../../x").is_err());
+            assert!(delete_ref("refs/../config").is_err());
+            assert_eq!(fs::read_to_string(".git/config").unwrap(), "");
+        });
+    }
+}
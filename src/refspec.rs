@@ -0,0 +1,123 @@
+use anyhow::{bail, Result};
+
+/// A refspec such as `+refs/heads/*:refs/remotes/origin/*`, mapping refs
+/// of a source repository to refs of a destination one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refspec {
+    /// Whether non fast-forward updates are allowed (leading `+`)
+    pub force: bool,
+    pub src: String,
+    /// Empty when the refspec has no destination
+    pub dst: String,
+}
+
+impl Refspec {
+    pub fn parse(spec: &str) -> Result<Refspec> {
+        let (force, spec) = match spec.strip_prefix('+') {
+            Some(spec) => (true, spec),
+            None => (false, spec),
+        };
+        let (src, dst) = spec.split_once(':').unwrap_or((spec, ""));
+        let globs = (src.matches('*').count(), dst.matches('*').count());
+        match globs {
+            (0, 0) | (1, 1) => {}
+            (1, 0) if dst.is_empty() => {}
+            _ => bail!("invalid refspec '{}'", spec),
+        }
+        return Ok(Refspec {
+            force,
+            src: src.to_string(),
+            dst: dst.to_string(),
+        });
+    }
+
+    pub fn is_glob(&self) -> bool {
+        return self.src.contains('*');
+    }
+
+    /// Returns the destination a source ref maps to, None if the refspec
+    /// doesn't match it. Exact refspecs also match their short name, as in
+    /// `master` for `refs/heads/master`.
+    pub fn map(&self, name: &str) -> Option<String> {
+        if self.is_glob() {
+            let (prefix, suffix) = self.src.split_once('*').unwrap();
+            let matched = name
+                .strip_prefix(prefix)
+                .and_then(|n| n.strip_suffix(suffix))?;
+            return Some(self.dst.replacen('*', matched, 1));
+        }
+        if !dwim_names(&self.src).iter().any(|n| n == name) {
+            return None;
+        }
+        return Some(expand_dst(&self.dst, name));
+    }
+
+    /// Prefixes of the refs this refspec can match, for `ls-refs`
+    pub fn prefixes(&self) -> Vec<String> {
+        if self.is_glob() {
+            return vec![self.src.split_once('*').unwrap().0.to_string()];
+        }
+        return dwim_names(&self.src);
+    }
+}
+
+/// Returns the full ref names a possibly abbreviated name can refer to
+pub fn dwim_names(name: &str) -> Vec<String> {
+    if name.starts_with("refs/") || name == "HEAD" {
+        return vec![name.to_string()];
+    }
+    return vec![
+        format!("refs/{}", name),
+        format!("refs/tags/{}", name),
+        format!("refs/heads/{}", name),
+        format!("refs/remotes/{}", name),
+    ];
+}
+
+/// Qualifies a short destination like the source it receives: a branch
+/// `main` becomes `refs/heads/main`
+pub fn expand_dst(dst: &str, src: &str) -> String {
+    if dst.is_empty() || dst.starts_with("refs/") || dst == "HEAD" {
+        return dst.to_string();
+    }
+    if src.starts_with("refs/tags/") {
+        return format!("refs/tags/{}", dst);
+    }
+    return format!("refs/heads/{}", dst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Refspec;
+
+    #[test]
+    fn test_refspec() {
+        let spec = Refspec::parse("+refs/heads/*:refs/remotes/origin/*").unwrap();
+        assert!(spec.force);
+        assert_eq!(
+            spec.map("refs/heads/feature/x").as_deref(),
+            Some("refs/remotes/origin/feature/x")
+        );
+        assert_eq!(spec.map("refs/tags/v1"), None);
+        assert_eq!(spec.prefixes(), vec!["refs/heads/"]);
+
+        let spec = Refspec::parse("refs/heads/*-wip:refs/wip/*").unwrap();
+        assert!(!spec.force);
+        assert_eq!(spec.map("refs/heads/a-wip").as_deref(), Some("refs/wip/a"));
+        assert_eq!(spec.map("refs/heads/a"), None);
+
+        let spec = Refspec::parse("master:tmp").unwrap();
+        assert_eq!(
+            spec.map("refs/heads/master").as_deref(),
+            Some("refs/heads/tmp")
+        );
+        assert_eq!(spec.map("refs/heads/main"), None);
+        let spec = Refspec::parse("v1:v1").unwrap();
+        assert_eq!(spec.map("refs/tags/v1").as_deref(), Some("refs/tags/v1"));
+        let spec = Refspec::parse("refs/heads/master").unwrap();
+        assert_eq!(spec.map("refs/heads/master").as_deref(), Some(""));
+
+        assert!(Refspec::parse("refs/heads/*:refs/remotes/origin/main").is_err());
+        assert!(Refspec::parse("refs/*/*:refs/*").is_err());
+    }
+}
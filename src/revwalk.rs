@@ -0,0 +1,581 @@
+use anyhow::{bail, Result};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::commitgraph::{CommitGraph, GENERATION_INFINITY};
+use crate::objects::{read_object, GitObjectType};
+use crate::shallow::read_shallow;
+use crate::tree::{find_path, lstree};
+
+/// The parts of a commit object needed to walk history
+#[derive(Debug, Clone)]
+pub struct Commit {
+    pub sha1: String,
+    pub tree: String,
+    pub parents: Vec<String>,
+    /// Committer timestamp, in seconds since the epoch
+    pub time: i64,
+    /// Generation number from the commit-graph, `GENERATION_INFINITY` for
+    /// commits it doesn't have: a commit can't be an ancestor of commits
+    /// of a lower generation
+    pub generation: u64,
+}
+
+/// Parses the timestamp of an `author`/`committer` signature line
+fn parse_signature_time(signature: &str) -> Option<i64> {
+    let (_, date) = signature.rsplit_once("> ")?;
+    return date.split(' ').next()?.parse().ok();
+}
+
+/// Reads a commit from the local object store
+pub fn read_commit(sha1: &String) -> Result<Commit> {
+    let (type_, data) = read_object(sha1)?;
+    if type_ != GitObjectType::Commit {
+        bail!("object {} is a {}, not a commit", sha1, type_);
+    }
+    let content = String::from_utf8_lossy(&data);
+    let mut commit = Commit {
+        sha1: sha1.clone(),
+        tree: String::new(),
+        parents: Vec::new(),
+        time: 0,
+        generation: GENERATION_INFINITY,
+    };
+    let mut author_time = None;
+    for line in content.lines() {
+        if line.is_empty() {
+            break;
+        }
+        if let Some(tree) = line.strip_prefix("tree ") {
+            commit.tree = tree.to_string();
+        } else if let Some(parent) = line.strip_prefix("parent ") {
+            commit.parents.push(parent.to_string());
+        } else if let Some(signature) = line.strip_prefix("committer ") {
+            commit.time = parse_signature_time(signature).unwrap_or(0);
+        } else if let Some(signature) = line.strip_prefix("author ") {
+            author_time = parse_signature_time(signature);
+        }
+    }
+    if commit.time == 0 {
+        commit.time = author_time.unwrap_or(0);
+    }
+    return Ok(commit);
+}
+
+/// Peels tags down to the commit they point to, None for other objects
+pub fn peel_to_commit(sha1: &String) -> Result<Option<String>> {
+    let mut sha1 = sha1.clone();
+    loop {
+        let (type_, data) = read_object(&sha1)?;
+        match type_ {
+            GitObjectType::Commit => return Ok(Some(sha1)),
+            GitObjectType::Tag => {
+                let content = String::from_utf8_lossy(&data);
+                match content
+                    .lines()
+                    .next()
+                    .and_then(|l| l.strip_prefix("object "))
+                {
+                    Some(target) => sha1 = target.to_string(),
+                    None => bail!("tag {} has no object line", sha1),
+                }
+            }
+            _ => return Ok(None),
+        }
+    }
+}
+
+/// Reads the commits of history walks, from the commit-graph when it has
+/// them. In shallow repositories the commit-graph isn't used and the
+/// shallow commits are read without their parents.
+pub struct CommitReader {
+    graph: Option<CommitGraph>,
+    shallow: HashSet<String>,
+}
+
+impl CommitReader {
+    pub fn open() -> Result<CommitReader> {
+        return Ok(CommitReader {
+            graph: CommitGraph::open_local()?,
+            shallow: read_shallow()?,
+        });
+    }
+
+    pub fn read(&self, sha1: &String) -> Result<Commit> {
+        if let Some(graph) = self.graph.as_ref() {
+            if let Some(commit) = graph.read_commit(sha1)? {
+                return Ok(commit);
+            }
+        }
+        let mut commit = read_commit(sha1)?;
+        if self.shallow.contains(sha1) {
+            commit.parents.clear();
+        }
+        return Ok(commit);
+    }
+
+    /// Returns the generation of a commit without reading commits missing
+    /// from the commit-graph
+    pub fn generation(&self, sha1: &String) -> Result<u64> {
+        return match self.graph.as_ref() {
+            Some(graph) => graph.generation(sha1),
+            None => Ok(GENERATION_INFINITY),
+        };
+    }
+
+    /// Returns whether `ancestor` is reachable from `descendant`, see
+    /// `is_ancestor`
+    pub fn is_ancestor(&self, ancestor: &String, descendant: &String) -> Result<bool> {
+        let min_generation = self.generation(ancestor)?;
+        let mut stack = vec![descendant.clone()];
+        let mut seen = HashSet::new();
+        while let Some(sha1) = stack.pop() {
+            if sha1 == *ancestor {
+                return Ok(true);
+            }
+            if !seen.insert(sha1.clone()) {
+                continue;
+            }
+            let commit = self.read(&sha1)?;
+            if commit.generation >= min_generation {
+                stack.extend(commit.parents);
+            }
+        }
+        return Ok(false);
+    }
+
+    /// Tells from the commit-graph whether a commit may change `paths`
+    /// compared to its first parent, None when it can't tell
+    fn maybe_changes(&self, sha1: &String, paths: &[String]) -> Result<Option<bool>> {
+        return match self.graph.as_ref() {
+            Some(graph) => graph.maybe_changes(sha1, paths),
+            None => Ok(None),
+        };
+    }
+}
+
+/// Walks the history of some commits, newest first by committer date, like
+/// `git rev-list` does. In shallow repositories the history stops at the
+/// shallow commits, which are walked as if they had no parents.
+pub struct RevWalk {
+    /// Commits by date, then in the order they were queued
+    queue: BinaryHeap<(i64, Reverse<usize>, String)>,
+    /// Commits in the queue, read when they were first seen
+    queued: HashMap<String, Commit>,
+    seen: HashSet<String>,
+    commits: CommitReader,
+    /// Paths limiting the walk to the commits changing them
+    paths: Vec<String>,
+}
+
+impl RevWalk {
+    pub fn new(tips: &[String]) -> Result<RevWalk> {
+        let mut walk = RevWalk {
+            queue: BinaryHeap::new(),
+            queued: HashMap::new(),
+            seen: HashSet::new(),
+            commits: CommitReader::open()?,
+            paths: Vec::new(),
+        };
+        for tip in tips {
+            walk.push(tip)?;
+        }
+        return Ok(walk);
+    }
+
+    /// Limits the walk to the commits changing one of `paths` (files or
+    /// directories), simplifying history like git does by default: a commit
+    /// with the same content at the paths as one of its parents is skipped
+    /// and only that parent is followed
+    pub fn limit_to_paths(&mut self, paths: &[String]) {
+        self.paths = paths
+            .iter()
+            .map(|path| path.trim_matches('/').to_string())
+            .collect();
+    }
+
+    fn push(&mut self, sha1: &String) -> Result<()> {
+        if self.seen.insert(sha1.clone()) {
+            let commit = self.commits.read(sha1)?;
+            let order = Reverse(self.seen.len());
+            self.queue.push((commit.time, order, commit.sha1.clone()));
+            self.queued.insert(commit.sha1.clone(), commit);
+        }
+        return Ok(());
+    }
+
+    /// Returns the entries at the paths of the walk in a tree
+    fn path_entries(&self, tree: &String) -> Result<Vec<Option<(String, String)>>> {
+        return self
+            .paths
+            .iter()
+            .map(|path| find_path(tree, path))
+            .collect();
+    }
+
+    /// Compares a commit to its parents at the paths of the walk, keeping
+    /// only the first parent with the same entries. Returns whether the
+    /// commit changes the paths. The Bloom filters of the commit-graph
+    /// tell without reading trees that most commits don't change them.
+    fn simplify(&self, commit: &mut Commit) -> Result<bool> {
+        let mut entries = None;
+        for (i, parent) in commit.parents.iter().enumerate() {
+            let same = match i {
+                0 if self.commits.maybe_changes(&commit.sha1, &self.paths)? == Some(false) => true,
+                _ => {
+                    if entries.is_none() {
+                        entries = Some(self.path_entries(&commit.tree)?);
+                    }
+                    let parent_tree = self.commits.read(parent)?.tree;
+                    entries.as_ref() == Some(&self.path_entries(&parent_tree)?)
+                }
+            };
+            if same {
+                commit.parents = vec![parent.clone()];
+                return Ok(false);
+            }
+        }
+        if commit.parents.is_empty() {
+            let entries = self.path_entries(&commit.tree)?;
+            return Ok(entries.iter().any(|entry| entry.is_some()));
+        }
+        return Ok(true);
+    }
+
+    /// Returns the next commit of the walk
+    pub fn next_commit(&mut self) -> Result<Option<Commit>> {
+        loop {
+            let sha1 = match self.queue.pop() {
+                Some((_, _, sha1)) => sha1,
+                None => return Ok(None),
+            };
+            let mut commit = self.queued.remove(&sha1).unwrap();
+            let shown = match self.paths.is_empty() {
+                true => true,
+                false => self.simplify(&mut commit)?,
+            };
+            for parent in commit.parents.iter() {
+                self.push(parent)?;
+            }
+            if shown {
+                return Ok(Some(commit));
+            }
+        }
+    }
+}
+
+/// Returns whether `ancestor` is reachable from `descendant`. With a
+/// commit-graph, commits of a lower generation than `ancestor` aren't
+/// explored.
+pub fn is_ancestor(ancestor: &String, descendant: &String) -> Result<bool> {
+    return CommitReader::open()?.is_ancestor(ancestor, descendant);
+}
+
+/// Returns the best common ancestors of two commits, newest first: the
+/// common ancestors that are not ancestors of other common ancestors.
+///
+/// Both histories are walked at once, highest generation first, marking
+/// which side reaches each commit, until only commits reached from both
+/// sides through a common ancestor are left.
+pub fn merge_bases(one: &String, two: &String) -> Result<Vec<String>> {
+    if one == two {
+        return Ok(vec![one.clone()]);
+    }
+    const ONE: u8 = 1;
+    const TWO: u8 = 2;
+    const STALE: u8 = 4;
+    let commits = CommitReader::open()?;
+    let mut flags: HashMap<String, u8> = HashMap::new();
+    let mut loaded: HashMap<String, Commit> = HashMap::new();
+    let mut queue = BinaryHeap::new();
+    for (sha1, flag) in [(one, ONE), (two, TWO)] {
+        let commit = commits.read(sha1)?;
+        queue.push((commit.generation, commit.time, sha1.clone()));
+        loaded.insert(sha1.clone(), commit);
+        flags.insert(sha1.clone(), flag);
+    }
+    let mut bases = Vec::new();
+    while queue.iter().any(|(_, _, sha1)| flags[sha1] & STALE == 0) {
+        let (_, _, sha1) = queue.pop().unwrap();
+        let mut flag = flags[&sha1];
+        if flag & (ONE | TWO | STALE) == ONE | TWO {
+            if !bases.contains(&sha1) {
+                bases.push(sha1.clone());
+            }
+            flag |= STALE;
+        }
+        for parent in loaded[&sha1].parents.clone() {
+            let parent_flags = flags.entry(parent.clone()).or_insert(0);
+            if *parent_flags & flag == flag {
+                continue;
+            }
+            *parent_flags |= flag;
+            if !loaded.contains_key(&parent) {
+                loaded.insert(parent.clone(), commits.read(&parent)?);
+            }
+            let commit = &loaded[&parent];
+            queue.push((commit.generation, commit.time, parent));
+        }
+    }
+    bases.retain(|sha1| flags[sha1] & STALE == 0);
+    bases.sort_by_key(|sha1| Reverse(loaded[sha1].time));
+    // a base can still be an ancestor of another one reached through a
+    // longer path
+    let mut best = Vec::new();
+    for base in bases.iter() {
+        let mut redundant = false;
+        for other in bases.iter().filter(|other| *other != base) {
+            if commits.is_ancestor(base, other)? {
+                redundant = true;
+                break;
+            }
+        }
+        if !redundant {
+            best.push(base.clone());
+        }
+    }
+    return Ok(best);
+}
+
+/// Adds a tree found at `path` and everything it contains to `objects`
+/// along with their paths, skipping what is already in `seen`
+fn collect_tree_objects(
+    tree: &String,
+    path: &str,
+    seen: &mut HashSet<String>,
+    objects: &mut Vec<(String, String)>,
+) -> Result<()> {
+    if !seen.insert(tree.clone()) {
+        return Ok(());
+    }
+    objects.push((tree.clone(), path.to_string()));
+    for node in lstree(tree)?.iter() {
+        let node_path = match path {
+            "" => node.filename.clone(),
+            _ => format!("{}/{}", path, node.filename),
+        };
+        match node.object_type() {
+            GitObjectType::Tree => collect_tree_objects(&node.hash, &node_path, seen, objects)?,
+            // submodule commits live in another repository
+            GitObjectType::Commit => {}
+            _ => {
+                if seen.insert(node.hash.clone()) {
+                    objects.push((node.hash.clone(), node_path));
+                }
+            }
+        }
+    }
+    return Ok(());
+}
+
+/// Lists the objects reachable from `include` but not from `exclude`, like
+/// `git rev-list --objects include --not exclude`.
+///
+/// Only the trees of the excluded commits bordering the included history are
+/// excluded, so a few objects the other side has may still be listed.
+pub fn list_objects(include: &[String], exclude: &[String]) -> Result<Vec<String>> {
+    let objects = list_objects_with_paths(include, exclude)?;
+    return Ok(objects.into_iter().map(|(sha1, _)| sha1).collect());
+}
+
+/// Lists the objects like `list_objects`, each with the path it was first
+/// found at in a tree (empty for commits, tags and root trees), which tells
+/// which objects are versions of the same file when packing them
+pub fn list_objects_with_paths(
+    include: &[String],
+    exclude: &[String],
+) -> Result<Vec<(String, String)>> {
+    let mut uninteresting = HashSet::new();
+    let mut exclude_commits = Vec::new();
+    for sha1 in exclude {
+        uninteresting.insert(sha1.clone());
+        if let Some(commit) = peel_to_commit(sha1)? {
+            exclude_commits.push(commit);
+        }
+    }
+    let mut walk = RevWalk::new(&exclude_commits)?;
+    while let Some(commit) = walk.next_commit()? {
+        uninteresting.insert(commit.sha1);
+    }
+
+    let mut objects = Vec::new();
+    let mut commits = Vec::new();
+    let mut tips = Vec::new();
+    let mut other_tips = Vec::new();
+    let mut seen = HashSet::new();
+    for sha1 in include {
+        let mut sha1 = sha1.clone();
+        loop {
+            if uninteresting.contains(&sha1) || !seen.insert(sha1.clone()) {
+                break;
+            }
+            let (type_, data) = read_object(&sha1)?;
+            match type_ {
+                GitObjectType::Commit => {
+                    seen.remove(&sha1);
+                    tips.push(sha1);
+                    break;
+                }
+                GitObjectType::Tag => {
+                    objects.push((sha1.clone(), String::new()));
+                    let content = String::from_utf8_lossy(&data);
+                    match content
+                        .lines()
+                        .next()
+                        .and_then(|l| l.strip_prefix("object "))
+                    {
+                        Some(target) => sha1 = target.to_string(),
+                        None => bail!("tag {} has no object line", sha1),
+                    }
+                }
+                GitObjectType::Tree | GitObjectType::Blob => {
+                    seen.remove(&sha1);
+                    other_tips.push((type_, sha1));
+                    break;
+                }
+            }
+        }
+    }
+
+    let shallow = read_shallow()?;
+    let mut boundary = HashSet::new();
+    let mut queue = tips;
+    while let Some(sha1) = queue.pop() {
+        if uninteresting.contains(&sha1) || !seen.insert(sha1.clone()) {
+            continue;
+        }
+        let mut commit = read_commit(&sha1)?;
+        if shallow.contains(&sha1) {
+            commit.parents.clear();
+        }
+        for parent in commit.parents.iter() {
+            if uninteresting.contains(parent) {
+                boundary.insert(parent.clone());
+            } else {
+                queue.push(parent.clone());
+            }
+        }
+        commits.push(commit);
+    }
+
+    let mut excluded = HashSet::new();
+    for sha1 in boundary {
+        let tree = read_commit(&sha1)?.tree;
+        collect_tree_objects(&tree, "", &mut excluded, &mut Vec::new())?;
+    }
+    for commit in commits {
+        objects.push((commit.sha1, String::new()));
+        let mut trees = Vec::new();
+        collect_tree_objects(&commit.tree, "", &mut excluded, &mut trees)?;
+        objects.extend(trees);
+    }
+    for (type_, sha1) in other_tips {
+        match type_ {
+            GitObjectType::Tree => collect_tree_objects(&sha1, "", &mut excluded, &mut objects)?,
+            _ if excluded.insert(sha1.clone()) => objects.push((sha1, String::new())),
+            _ => {}
+        }
+    }
+    return Ok(objects);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::revwalk::{is_ancestor, list_objects, merge_bases, RevWalk};
+    use crate::testutils;
+
+    #[test]
+    fn test_revwalk() {
+        testutils::in_tmp_dir(|| {
+            let first = testutils::create_repo(".", &[("a.txt", "a\n")]);
+            testutils::get_git_output(&["checkout", "-q", "-b", "side"]);
+            let side = testutils::commit_files(".", &[("b.txt", "b\n")], "side");
+            testutils::get_git_output(&["checkout", "-q", "master"]);
+            let second = testutils::commit_files(".", &[("c.txt", "c\n")], "second");
+
+            let mut walk = RevWalk::new(&[second.clone(), side.clone()]).unwrap();
+            let mut walked = Vec::new();
+            while let Some(commit) = walk.next_commit().unwrap() {
+                walked.push(commit.sha1);
+            }
+            let expected = testutils::get_git_output(&["rev-list", "master", "side"]);
+            let mut expected: Vec<&str> = expected.lines().collect();
+            let mut sorted = walked.clone();
+            expected.sort();
+            sorted.sort();
+            assert_eq!(sorted, expected);
+
+            assert!(is_ancestor(&first, &second).unwrap());
+            assert!(is_ancestor(&second, &second).unwrap());
+            assert!(!is_ancestor(&side, &second).unwrap());
+
+            let mut objects =
+                list_objects(std::slice::from_ref(&second), std::slice::from_ref(&side)).unwrap();
+            let expected = testutils::get_git_output(&[
+                "rev-list",
+                "--objects",
+                "--no-object-names",
+                &second,
+                "--not",
+                &side,
+            ]);
+            let mut expected: Vec<&str> = expected.lines().collect();
+            objects.sort();
+            expected.sort();
+            assert_eq!(objects, expected);
+            assert_eq!(
+                list_objects(std::slice::from_ref(&first), &[second])
+                    .unwrap()
+                    .len(),
+                0
+            );
+            assert_eq!(list_objects(&[first], &[]).unwrap().len(), 3);
+        });
+    }
+
+    #[test]
+    fn test_history_with_commit_graph() {
+        testutils::in_tmp_dir(|| {
+            let first = testutils::create_repo(".", &[("a.txt", "a\n"), ("dir/b.txt", "b\n")]);
+            testutils::get_git_output(&["checkout", "-q", "-b", "side"]);
+            let side = testutils::commit_files(".", &[("dir/b.txt", "b\nb\n")], "side");
+            testutils::commit_files(".", &[("c.txt", "c\n")], "side 2");
+            testutils::get_git_output(&["checkout", "-q", "master"]);
+            let second = testutils::commit_files(".", &[("a.txt", "a\na\n")], "second");
+            testutils::commit_files(".", &[("dir/d.txt", "d\n")], "third");
+
+            let tips: Vec<String> = ["master", "side"]
+                .iter()
+                .map(|name| {
+                    let sha1 = testutils::get_git_output(&["rev-parse", name]);
+                    return sha1.trim().to_string();
+                })
+                .collect();
+            let check = || {
+                for paths in [vec!["dir"], vec!["a.txt", "c.txt"], vec!["dir/b.txt"]] {
+                    let mut walk = RevWalk::new(&tips).unwrap();
+                    walk.limit_to_paths(&paths.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+                    let mut walked = Vec::new();
+                    while let Some(commit) = walk.next_commit().unwrap() {
+                        walked.push(commit.sha1);
+                    }
+                    let mut args = vec!["rev-list", "master", "side", "--"];
+                    args.extend(&paths);
+                    let expected = testutils::get_git_output(&args);
+                    assert_eq!(walked, expected.lines().collect::<Vec<_>>());
+                }
+                assert_eq!(
+                    merge_bases(&tips[0], &tips[1]).unwrap(),
+                    vec![first.clone()]
+                );
+                assert_eq!(merge_bases(&second, &side).unwrap(), vec![first.clone()]);
+                assert_eq!(merge_bases(&first, &tips[0]).unwrap(), vec![first.clone()]);
+                assert!(is_ancestor(&first, &tips[1]).unwrap());
+                assert!(!is_ancestor(&side, &tips[0]).unwrap());
+            };
+            check();
+            testutils::get_git_output(&["commit-graph", "write", "--reachable", "--changed-paths"]);
+            check();
+        });
+    }
+}
@@ -0,0 +1,1071 @@
+use anyhow::{bail, Result};
+use flate2::read::GzDecoder;
+use std::collections::HashSet;
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::Config;
+use crate::delta::search_deltas;
+use crate::objects::{object_exists, read_object, GitObjectType};
+use crate::packs::{store_pack, write_pack, PackOptions};
+use crate::pktline::{
+    write_delim, write_flush, write_line, write_packet, Packet, PktReader, MAX_PKT_DATA,
+};
+use crate::protocol::{AGENT, ZERO_ID};
+use crate::refs::{check_ref_format, delete_ref, is_sha1, list_refs, read_ref, write_ref};
+use crate::revwalk::{is_ancestor, list_objects, list_objects_with_paths};
+use crate::{repo_path, with_repository};
+
+/// Options of the `serve` command
+#[derive(Debug)]
+pub struct ServeOptions {
+    /// Address to listen on
+    pub listen: String,
+    pub port: u16,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        return ServeOptions {
+            listen: "127.0.0.1".to_string(),
+            port: 8080,
+        };
+    }
+}
+
+/// Largest request body accepted, pushes included
+const MAX_BODY_SIZE: usize = 1 << 30;
+
+/// Largest body of an upload-pack request, which only holds wants and haves
+const MAX_UPLOAD_BODY_SIZE: usize = 1 << 24;
+
+/// A request as read from a client
+#[derive(Debug, Default)]
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: String,
+    /// Headers with their names in lowercase
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        return self
+            .headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str());
+    }
+}
+
+/// A response to send back to a client
+#[derive(Debug)]
+struct HttpResponse {
+    status: &'static str,
+    content_type: String,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn ok(content_type: String, body: Vec<u8>) -> HttpResponse {
+        return HttpResponse {
+            status: "200 OK",
+            content_type,
+            body,
+        };
+    }
+
+    fn text(status: &'static str, message: &str) -> HttpResponse {
+        return HttpResponse {
+            status,
+            content_type: "text/plain".to_string(),
+            body: format!("{}\n", message).into_bytes(),
+        };
+    }
+}
+
+/// Reads a request from a connection, None if the client closed it first
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Result<Option<HttpRequest>> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => bail!("malformed request line '{}'", line.trim_end()),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = HttpRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        ..Default::default()
+    };
+    loop {
+        line.clear();
+        stream.read_line(&mut line).await?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            request
+                .headers
+                .push((name.trim().to_lowercase(), value.trim().to_string()));
+        }
+    }
+
+    if request.header("expect") == Some("100-continue") {
+        stream
+            .get_mut()
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .await?;
+    }
+    // the body grows with the data received, whatever length is announced
+    let max_size = match request.path.ends_with("/git-upload-pack") {
+        true => MAX_UPLOAD_BODY_SIZE,
+        false => MAX_BODY_SIZE,
+    };
+    if let Some(length) = request.header("content-length") {
+        let length: usize = match length.parse() {
+            Ok(length) if length <= max_size => length,
+            _ => bail!("invalid Content-Length '{}'", length),
+        };
+        let mut body = Vec::new();
+        (&mut *stream)
+            .take(length as u64)
+            .read_to_end(&mut body)
+            .await?;
+        if body.len() != length {
+            bail!("request body shorter than its Content-Length");
+        }
+        request.body = body;
+    } else if request.header("transfer-encoding") == Some("chunked") {
+        loop {
+            line.clear();
+            stream.read_line(&mut line).await?;
+            let size = line.trim_end().split(';').next().unwrap_or("");
+            let size = match usize::from_str_radix(size, 16) {
+                Ok(size) if request.body.len() + size <= max_size => size,
+                _ => bail!("invalid chunk size '{}'", line.trim_end()),
+            };
+            let read = (&mut *stream)
+                .take(size as u64)
+                .read_to_end(&mut request.body)
+                .await?;
+            let mut end = [0u8; 2];
+            stream.read_exact(&mut end).await?;
+            if read != size || &end != b"\r\n" {
+                bail!("malformed chunk");
+            }
+            if size == 0 {
+                break;
+            }
+        }
+    }
+    if request.header("content-encoding") == Some("gzip") {
+        let mut body = Vec::new();
+        GzDecoder::new(request.body.as_slice())
+            .take(max_size as u64 + 1)
+            .read_to_end(&mut body)?;
+        if body.len() > max_size {
+            bail!("decompressed body larger than {} bytes", max_size);
+        }
+        request.body = body;
+    }
+    return Ok(Some(request));
+}
+
+/// Answers the request of a connection, which is then closed
+async fn handle_connection(stream: TcpStream, root: PathBuf) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request = match read_request(&mut stream).await {
+        Ok(Some(request)) => request,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            let response = HttpResponse::text("400 Bad Request", &e.to_string());
+            return write_response(stream.get_mut(), &response).await;
+        }
+    };
+    let response =
+        tokio::task::spawn_blocking(move || return handle_request(&root, &request)).await?;
+    return write_response(stream.get_mut(), &response).await;
+}
+
+async fn write_response(stream: &mut TcpStream, response: &HttpResponse) -> Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await?;
+    return Ok(());
+}
+
+/// Finds the git directory of the repository a request path refers to under
+/// `root`: the `.git` directory of a directory, or a bare repository. Their
+/// names may be given with a `.git` suffix.
+fn find_repository(root: &Path, path: &str) -> Option<PathBuf> {
+    let relative = Path::new(path.trim_start_matches('/'));
+    // the path must stay under the root
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+    let mut candidates = vec![root.join(relative)];
+    if let Some(stripped) = path.strip_suffix(".git") {
+        candidates.push(root.join(stripped.trim_start_matches('/')));
+    }
+    let is_git_dir = |dir: &Path| dir.join("HEAD").is_file() && dir.join("objects").is_dir();
+    return candidates.into_iter().find_map(|dir| {
+        return match dir.join(".git") {
+            git_dir if is_git_dir(&git_dir) => Some(git_dir),
+            _ if is_git_dir(&dir) => Some(dir),
+            _ => None,
+        };
+    });
+}
+
+/// Routes a request to the service it asks for, running it on the
+/// repository
+fn handle_request(root: &Path, request: &HttpRequest) -> HttpResponse {
+    let service = request.query.strip_prefix("service=");
+    let (repo, route) = match (request.method.as_str(), service) {
+        ("GET", Some(service)) => match request.path.strip_suffix("/info/refs") {
+            Some(repo) => (repo, service),
+            None => return HttpResponse::text("404 Not Found", "not found"),
+        },
+        ("POST", None) => match request.path.rsplit_once('/') {
+            Some((repo, service)) => (repo, service),
+            None => return HttpResponse::text("404 Not Found", "not found"),
+        },
+        ("GET", None) if request.path.ends_with("/info/refs") => {
+            return HttpResponse::text("403 Forbidden", "only the smart protocol is served")
+        }
+        _ => return HttpResponse::text("404 Not Found", "not found"),
+    };
+    if route != "git-upload-pack" && route != "git-receive-pack" {
+        return HttpResponse::text("404 Not Found", "not found");
+    }
+    let repo = match find_repository(root, repo) {
+        Some(repo) => repo,
+        None => return HttpResponse::text("404 Not Found", "repository not found"),
+    };
+    let version = match request.header("git-protocol") {
+        Some(protocol) if protocol.split(':').any(|p| p == "version=2") => 2,
+        _ => 0,
+    };
+
+    // the repository is given to this thread only, concurrent requests
+    // don't share a cwd
+    let result = with_repository(&repo, || run_service(route, request, version));
+    return match result {
+        Ok(response) => response,
+        Err(e) => HttpResponse::text("500 Internal Server Error", &e.to_string()),
+    };
+}
+
+/// Answers a request for a service of the repository the thread works on
+fn run_service(service: &str, request: &HttpRequest, version: u8) -> Result<HttpResponse> {
+    let receive = service == "git-receive-pack";
+    // pushes are refused unless enabled, as no one is authenticated
+    if receive
+        && !Config::load()?
+            .get_bool("http.receivepack")?
+            .unwrap_or(false)
+    {
+        let message = format!("Service not enabled: '{}'", service);
+        return Ok(HttpResponse::text("403 Forbidden", &message));
+    }
+    if request.method == "GET" {
+        let mut body = Vec::new();
+        match (receive, version) {
+            (false, 2) => advertise_v2(&mut body)?,
+            _ => {
+                write_line(&mut body, &format!("# service={}", service))?;
+                write_flush(&mut body)?;
+                advertise_v0(&mut body, receive)?;
+            }
+        }
+        let content_type = format!("application/x-{}-advertisement", service);
+        return Ok(HttpResponse::ok(content_type, body));
+    }
+
+    let content_type = format!("application/x-{}-request", service);
+    if request.header("content-type") != Some(content_type.as_str()) {
+        return Ok(HttpResponse::text(
+            "415 Unsupported Media Type",
+            "bad content type",
+        ));
+    }
+    let mut body = Vec::new();
+    let result = match (receive, version) {
+        (true, _) => receive_pack(&request.body, &mut body),
+        (false, 2) => upload_pack_v2(&request.body, &mut body),
+        (false, _) => upload_pack_v0(&request.body, &mut body),
+    };
+    // errors reach the client in its own protocol
+    if let Err(e) = result {
+        body.clear();
+        write_line(&mut body, &format!("ERR {}", e))?;
+    }
+    let content_type = format!("application/x-{}-result", service);
+    return Ok(HttpResponse::ok(content_type, body));
+}
+
+/// Returns what a tag points to once fully peeled, None for other objects
+fn peel_tag(sha1: &String) -> Result<Option<String>> {
+    let mut peeled = None;
+    let mut current = sha1.clone();
+    loop {
+        let (type_, data) = read_object(&current)?;
+        if type_ != GitObjectType::Tag {
+            return Ok(peeled);
+        }
+        let content = String::from_utf8_lossy(&data);
+        match content
+            .lines()
+            .next()
+            .and_then(|l| l.strip_prefix("object "))
+        {
+            Some(target) => current = target.to_string(),
+            None => bail!("tag {} has no object line", current),
+        }
+        peeled = Some(current.clone());
+    }
+}
+
+/// Returns the branch HEAD points to, None when it's detached
+fn head_target() -> Result<Option<String>> {
+    let head = fs::read_to_string(repo_path(".git/HEAD"))?;
+    return Ok(head
+        .trim()
+        .strip_prefix("ref: ")
+        .map(|target| target.to_string()));
+}
+
+/// Lists the refs served, HEAD first for upload-pack
+fn served_refs(with_head: bool) -> Result<Vec<(String, String)>> {
+    let mut refs = Vec::new();
+    if with_head {
+        if let Some(sha1) = read_ref("HEAD")? {
+            refs.push(("HEAD".to_string(), sha1));
+        }
+    }
+    refs.extend(list_refs("refs/")?);
+    return Ok(refs);
+}
+
+/// Writes the v0 ref advertisement, capabilities after the first ref
+fn advertise_v0(out: &mut Vec<u8>, receive: bool) -> Result<()> {
+    let mut capabilities = match receive {
+        true => vec![
+            "report-status",
+            "delete-refs",
+            "side-band-64k",
+            "quiet",
+            "ofs-delta",
+        ],
+        false => vec![
+            "multi_ack_detailed",
+            "side-band-64k",
+            "ofs-delta",
+            "no-progress",
+            "include-tag",
+        ],
+    }
+    .into_iter()
+    .map(|c| c.to_string())
+    .collect::<Vec<String>>();
+    if !receive {
+        if let Some(target) = head_target()?.filter(|t| read_ref(t).ok().flatten().is_some()) {
+            capabilities.push(format!("symref=HEAD:{}", target));
+        }
+    }
+    capabilities.push(format!("agent={}", AGENT));
+
+    let refs = served_refs(!receive)?;
+    if refs.is_empty() {
+        let line = format!("{} capabilities^{{}}\0{}", ZERO_ID, capabilities.join(" "));
+        write_line(out, &line)?;
+    }
+    for (i, (name, sha1)) in refs.iter().enumerate() {
+        match i {
+            0 => write_line(
+                out,
+                &format!("{} {}\0{}", sha1, name, capabilities.join(" ")),
+            )?,
+            _ => write_line(out, &format!("{} {}", sha1, name))?,
+        }
+        if let Some(peeled) = peel_tag(sha1)? {
+            write_line(out, &format!("{} {}^{{}}", peeled, name))?;
+        }
+    }
+    write_flush(out)?;
+    return Ok(());
+}
+
+/// Writes the v2 capability advertisement of upload-pack
+fn advertise_v2(out: &mut Vec<u8>) -> Result<()> {
+    write_line(out, "version 2")?;
+    write_line(out, &format!("agent={}", AGENT))?;
+    write_line(out, "ls-refs")?;
+    write_line(out, "fetch")?;
+    write_line(out, "object-format=sha1")?;
+    write_flush(out)?;
+    return Ok(());
+}
+
+/// Writes data on a side-band channel, split into packets
+fn write_sideband(out: &mut Vec<u8>, band: u8, data: &[u8]) -> Result<()> {
+    for chunk in data.chunks(MAX_PKT_DATA - 1) {
+        let mut packet = Vec::with_capacity(chunk.len() + 1);
+        packet.push(band);
+        packet.extend_from_slice(chunk);
+        write_packet(out, &packet)?;
+    }
+    return Ok(());
+}
+
+/// Objects asked for in a fetch, along with what the client already has
+#[derive(Debug, Default)]
+struct UploadRequest {
+    wants: Vec<String>,
+    haves: Vec<String>,
+    done: bool,
+    progress: bool,
+    include_tag: bool,
+    ofs_delta: bool,
+    /// Objects of the advertised refs, peeled tags included, which are the
+    /// only ones wanted unless the config allows more
+    advertised: HashSet<String>,
+    /// `uploadpack.allowAnySHA1InWant`
+    allow_any: bool,
+    /// `uploadpack.allowReachableSHA1InWant`, the objects reachable from
+    /// the refs being listed the first time they are needed
+    allow_reachable: bool,
+    reachable: Option<HashSet<String>>,
+}
+
+impl UploadRequest {
+    fn new() -> Result<UploadRequest> {
+        let config = Config::load()?;
+        let mut advertised = HashSet::new();
+        for (_, sha1) in served_refs(true)? {
+            if let Some(peeled) = peel_tag(&sha1)? {
+                advertised.insert(peeled);
+            }
+            advertised.insert(sha1);
+        }
+        return Ok(UploadRequest {
+            advertised,
+            allow_any: config
+                .get_bool("uploadpack.allowAnySHA1InWant")?
+                .unwrap_or(false),
+            allow_reachable: config
+                .get_bool("uploadpack.allowReachableSHA1InWant")?
+                .unwrap_or(false),
+            ..Default::default()
+        });
+    }
+
+    /// Whether a client may ask for an object: one of the advertised refs,
+    /// or any object the config allows
+    fn is_allowed(&mut self, sha1: &String) -> Result<bool> {
+        if !object_exists(sha1)? {
+            return Ok(false);
+        }
+        if self.allow_any || self.advertised.contains(sha1) {
+            return Ok(true);
+        }
+        if !self.allow_reachable {
+            return Ok(false);
+        }
+        if self.reachable.is_none() {
+            let tips: Vec<String> = self.advertised.iter().cloned().collect();
+            self.reachable = Some(list_objects(&tips, &[])?.into_iter().collect());
+        }
+        return Ok(self.reachable.as_ref().unwrap().contains(sha1));
+    }
+
+    /// Parses a `want`/`have` line or one of the fetch options, arguments
+    /// of fetches we can't serve are refused
+    fn parse_line(&mut self, line: &str) -> Result<()> {
+        match line.split_once(' ') {
+            Some(("want", sha1)) => {
+                if !is_sha1(sha1) || !self.is_allowed(&sha1.to_string())? {
+                    bail!("upload-pack: not our ref {}", sha1);
+                }
+                self.wants.push(sha1.to_string());
+            }
+            Some(("have", sha1)) if is_sha1(sha1) => self.haves.push(sha1.to_string()),
+            None if line == "done" => self.done = true,
+            None if line == "no-progress" => self.progress = false,
+            None if line == "include-tag" => self.include_tag = true,
+            None if line == "ofs-delta" => self.ofs_delta = true,
+            None if line == "thin-pack" => {}
+            _ => bail!("upload-pack: unexpected line '{}'", line),
+        }
+        return Ok(());
+    }
+
+    /// Returns the haves found in the local object store
+    fn common(&self) -> Result<Vec<String>> {
+        let mut common = Vec::new();
+        for have in self.haves.iter() {
+            if object_exists(have)? {
+                common.push(have.clone());
+            }
+        }
+        return Ok(common);
+    }
+}
+
+/// Builds the pack answering a fetch, with the annotated tags pointing into
+/// it when the client asked for them. Returns it with the number of objects
+/// stored as deltas.
+fn build_upload_pack(request: &UploadRequest, common: &[String]) -> Result<(Vec<u8>, usize)> {
+    let mut objects = list_objects_with_paths(&request.wants, common)?;
+    if request.include_tag {
+        let included: HashSet<String> = objects.iter().map(|(sha1, _)| sha1.clone()).collect();
+        for (_, sha1) in list_refs("refs/tags/")? {
+            if included.contains(&sha1) {
+                continue;
+            }
+            if let Some(peeled) = peel_tag(&sha1)? {
+                if included.contains(&peeled) {
+                    objects.push((sha1, String::new()));
+                }
+            }
+        }
+    }
+    let options = PackOptions {
+        ofs_delta: request.ofs_delta,
+        ..Default::default()
+    };
+    let deltas = search_deltas(&objects, options.window, options.depth)?;
+    let pack = write_pack(&objects, &deltas, &options)?;
+    return Ok((pack, deltas.iter().flatten().count()));
+}
+
+/// Writes the pack of a fetch over side-band, with a progress line when
+/// the client wants one
+fn write_upload_pack(out: &mut Vec<u8>, request: &UploadRequest, common: &[String]) -> Result<()> {
+    let (pack, deltas) = build_upload_pack(request, common)?;
+    if request.progress {
+        let count = u32::from_be_bytes(pack[8..12].try_into().unwrap());
+        let message = format!("Total {} (delta {}), reused 0 (delta 0)\n", count, deltas);
+        write_sideband(out, 2, message.as_bytes())?;
+    }
+    write_sideband(out, 1, &pack)?;
+    write_flush(out)?;
+    return Ok(());
+}
+
+/// Answers a protocol v2 `ls-refs` or `fetch` command
+fn upload_pack_v2(body: &[u8], out: &mut Vec<u8>) -> Result<()> {
+    let mut reader = PktReader::new(body);
+    let header = reader.read_lines_until_flush()?;
+    let arguments = reader.read_lines_until_flush()?;
+    let command = header.iter().find_map(|l| l.strip_prefix("command="));
+    return match command {
+        Some("ls-refs") => ls_refs(&arguments, out),
+        Some("fetch") => fetch(&arguments, out),
+        Some(command) => bail!("unknown command '{}'", command),
+        None => bail!("no command requested"),
+    };
+}
+
+fn ls_refs(arguments: &[String], out: &mut Vec<u8>) -> Result<()> {
+    let mut symrefs = false;
+    let mut peel = false;
+    let mut prefixes = Vec::new();
+    for argument in arguments {
+        match argument.as_str() {
+            "symrefs" => symrefs = true,
+            "peel" => peel = true,
+            argument => {
+                if let Some(prefix) = argument.strip_prefix("ref-prefix ") {
+                    prefixes.push(prefix);
+                }
+            }
+        }
+    }
+    let head = head_target()?;
+    for (name, sha1) in served_refs(true)? {
+        if !prefixes.is_empty() && !prefixes.iter().any(|p| name.starts_with(p)) {
+            continue;
+        }
+        let mut line = format!("{} {}", sha1, name);
+        if let Some(target) = head.as_ref().filter(|_| symrefs && name == "HEAD") {
+            line.push_str(&format!(" symref-target:{}", target));
+        }
+        if let Some(peeled) = peel_tag(&sha1)?.filter(|_| peel) {
+            line.push_str(&format!(" peeled:{}", peeled));
+        }
+        write_line(out, &line)?;
+    }
+    write_flush(out)?;
+    return Ok(());
+}
+
+fn fetch(arguments: &[String], out: &mut Vec<u8>) -> Result<()> {
+    let mut request = UploadRequest {
+        progress: true,
+        ..UploadRequest::new()?
+    };
+    for argument in arguments {
+        request.parse_line(argument)?;
+    }
+    if request.wants.is_empty() {
+        bail!("upload-pack: no wants");
+    }
+    let common = request.common()?;
+    if !request.done {
+        // we are ready as soon as something is common, the client won't
+        // send anything better than that
+        write_line(out, "acknowledgments")?;
+        if common.is_empty() {
+            write_line(out, "NAK")?;
+        }
+        for sha1 in common.iter() {
+            write_line(out, &format!("ACK {}", sha1))?;
+        }
+        if common.is_empty() {
+            write_flush(out)?;
+            return Ok(());
+        }
+        write_line(out, "ready")?;
+        write_delim(out)?;
+    }
+    write_line(out, "packfile")?;
+    return write_upload_pack(out, &request, &common);
+}
+
+/// Answers a v0 upload-pack request: the wants, then the haves of the
+/// client, which end with `done` once it is ready for the pack
+fn upload_pack_v0(body: &[u8], out: &mut Vec<u8>) -> Result<()> {
+    let mut reader = PktReader::new(body);
+    let mut request = UploadRequest::new()?;
+    let mut capabilities = Vec::new();
+    for (i, line) in reader.read_lines_until_flush()?.iter().enumerate() {
+        let mut line = line.as_str();
+        if i == 0 {
+            // the capabilities follow the first want
+            let mut parts = line.splitn(3, ' ');
+            if let (Some(want), Some(sha1), Some(rest)) = (parts.next(), parts.next(), parts.next())
+            {
+                capabilities = rest.split(' ').map(|c| c.to_string()).collect();
+                line = &line[..want.len() + 1 + sha1.len()];
+            }
+        }
+        request.parse_line(line)?;
+    }
+    if request.wants.is_empty() {
+        bail!("upload-pack: no wants");
+    }
+    while let Some(packet) = reader.read_packet()? {
+        match packet {
+            Packet::Data(_) => request.parse_line(&packet.as_text().unwrap())?,
+            _ => continue,
+        }
+    }
+    let has = |c: &str| capabilities.iter().any(|capability| capability == c);
+    request.progress = !has("no-progress");
+    request.include_tag = has("include-tag");
+    request.ofs_delta = has("ofs-delta");
+
+    let common = request.common()?;
+    if !request.done {
+        if has("multi_ack_detailed") {
+            for sha1 in common.iter() {
+                write_line(out, &format!("ACK {} common", sha1))?;
+            }
+            if let Some(last) = common.last() {
+                write_line(out, &format!("ACK {} ready", last))?;
+            }
+        } else if let Some(first) = common.first() {
+            write_line(out, &format!("ACK {}", first))?;
+        }
+        write_line(out, "NAK")?;
+        return Ok(());
+    }
+    match common.last() {
+        Some(last) => write_line(out, &format!("ACK {}", last))?,
+        None => write_line(out, "NAK")?,
+    }
+    if has("side-band-64k") {
+        return write_upload_pack(out, &request, &common);
+    }
+    out.extend(build_upload_pack(&request, &common)?.0);
+    return Ok(());
+}
+
+/// Applies a ref update sent to receive-pack, returns why it was refused
+fn update_ref(config: &Config, old: &str, new: &str, name: &str) -> Result<Option<&'static str>> {
+    if !name.starts_with("refs/")
+        || check_ref_format(name).is_err()
+        || !is_sha1(old)
+        || !is_sha1(new)
+    {
+        return Ok(Some("funny refname"));
+    }
+    let current = read_ref(name)?.unwrap_or_else(|| ZERO_ID.to_string());
+    if current != old {
+        return Ok(Some("failed to update ref"));
+    }
+    // the working tree of the checked out branch isn't updated, bare
+    // repositories have none
+    let bare = config.get_bool("core.bare")?.unwrap_or(false);
+    let checked_out = !bare && head_target()?.as_deref() == Some(name);
+    if new == ZERO_ID {
+        if config.get_bool("receive.denyDeletes")?.unwrap_or(false) {
+            return Ok(Some("deletion prohibited"));
+        }
+        if checked_out && config.get("receive.denyDeleteCurrent").unwrap_or("refuse") == "refuse" {
+            return Ok(Some("deletion of the current branch prohibited"));
+        }
+        delete_ref(name)?;
+        return Ok(None);
+    }
+    if !object_exists(&new.to_string())? {
+        return Ok(Some("missing necessary objects"));
+    }
+    let deny_current = config.get("receive.denyCurrentBranch").unwrap_or("refuse");
+    if checked_out && !["ignore", "warn", "false"].contains(&deny_current) {
+        return Ok(Some("branch is currently checked out"));
+    }
+    if old != ZERO_ID
+        && config
+            .get_bool("receive.denyNonFastForwards")?
+            .unwrap_or(false)
+        && !is_ancestor(&old.to_string(), &new.to_string())?
+    {
+        return Ok(Some("non-fast-forward"));
+    }
+    write_ref(name, new)?;
+    return Ok(None);
+}
+
+/// Answers a receive-pack request: ref update commands followed by the pack
+/// with the objects they need
+fn receive_pack(body: &[u8], out: &mut Vec<u8>) -> Result<()> {
+    let mut reader = PktReader::new(body);
+    let mut commands = Vec::new();
+    let mut capabilities = Vec::new();
+    for (i, line) in reader.read_lines_until_flush()?.iter().enumerate() {
+        let line = match line.split_once('\0') {
+            Some((line, caps)) if i == 0 => {
+                capabilities = caps.split(' ').map(|c| c.to_string()).collect();
+                line
+            }
+            _ => line.as_str(),
+        };
+        match line.splitn(3, ' ').collect::<Vec<&str>>()[..] {
+            [old, new, name] => commands.push((old.to_string(), new.to_string(), name.to_string())),
+            _ => bail!("malformed ref update '{}'", line),
+        }
+    }
+    let pack = reader.into_inner();
+    let has = |c: &str| capabilities.iter().any(|capability| capability == c);
+
+    let unpack_error = match commands.iter().all(|(_, new, _)| new == ZERO_ID) {
+        true => None,
+        false => store_pack(pack).err().map(|e| e.to_string()),
+    };
+    let config = Config::load()?;
+    let mut report = Vec::new();
+    match unpack_error.as_ref() {
+        Some(error) => write_line(&mut report, &format!("unpack {}", error))?,
+        None => write_line(&mut report, "unpack ok")?,
+    }
+    for (old, new, name) in commands.iter() {
+        let refused = match unpack_error {
+            Some(_) => Some("unpacker error"),
+            None => update_ref(&config, old, new, name)?,
+        };
+        match refused {
+            Some(reason) => write_line(&mut report, &format!("ng {} {}", name, reason))?,
+            None => write_line(&mut report, &format!("ok {}", name))?,
+        }
+    }
+    write_flush(&mut report)?;
+
+    if !has("report-status") {
+        return Ok(());
+    }
+    if has("side-band-64k") {
+        write_sideband(out, 1, &report)?;
+        write_flush(out)?;
+    } else {
+        out.extend(report);
+    }
+    return Ok(());
+}
+
+/// Answers the connections accepted by `listener` with the repositories
+/// under `root`, until the listener fails
+async fn run(listener: TcpListener, root: PathBuf) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let root = root.clone();
+        tokio::spawn(async move {
+            // a failing client only loses its own connection
+            let _ = handle_connection(stream, root).await;
+        });
+    }
+}
+
+/// Serves the repositories under `dir` over smart HTTP, like
+/// `git http-backend` behind a web server does. A repository at
+/// `<dir>/<path>` is cloned from `http://<host>:<port>/<path>`.
+pub fn serve(dir: &String, options: &ServeOptions) -> Result<()> {
+    let root = match fs::canonicalize(dir) {
+        Ok(root) => root,
+        Err(e) => bail!("cannot serve '{}': {}", dir, e),
+    };
+    let runtime = tokio::runtime::Runtime::new()?;
+    return runtime.block_on(async {
+        let listener = TcpListener::bind((options.listen.as_str(), options.port)).await?;
+        eprintln!(
+            "Serving '{}' on http://{}",
+            root.display(),
+            listener.local_addr()?
+        );
+        return run(listener, root).await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::{current_dir, set_current_dir};
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpStream};
+    use std::path::Path;
+    use tokio::net::TcpListener;
+
+    use crate::clone::{clone, CloneOptions};
+    use crate::fetch::{fetch, FetchOptions};
+    use crate::pktline::{write_flush, write_line};
+    use crate::protocol::ZERO_ID;
+    use crate::push::{push, PushOptions};
+    use crate::serve::{run, write_upload_pack, UploadRequest};
+    use crate::testutils::{self, git_in};
+
+    /// Starts the server in a background thread, returns its base URL
+    fn start_server(root: &Path) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        listener.set_nonblocking(true).unwrap();
+        let root = root.to_path_buf();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                let listener = TcpListener::from_std(listener).unwrap();
+                run(listener, root).await.unwrap();
+            });
+        });
+        return url;
+    }
+
+    #[test]
+    fn test_serve() {
+        testutils::in_tmp_dir(|| {
+            let first = testutils::create_repo("remote", &[("file.txt", "first\n")]);
+            testutils::tag_in("remote", "v1");
+            let url = start_server(&current_dir().unwrap());
+            let options = CloneOptions {
+                quiet: true,
+                ..Default::default()
+            };
+
+            clone(&format!("{}/remote", url), &"local".to_string(), &options).unwrap();
+            assert_eq!(fs::read_to_string("local/file.txt").unwrap(), "first\n");
+            assert_eq!(git_in("local", &["rev-parse", "v1^{commit}"]), first);
+            assert_eq!(git_in("local", &["fsck", "--no-dangling"]), "");
+            for (dir, version) in [("real-v2", "2"), ("real-v0", "0")] {
+                let protocol = format!("protocol.version={}", version);
+                let remote = format!("{}/remote.git", url);
+                git_in(".", &["-c", &protocol, "clone", "-q", &remote, dir]);
+                assert_eq!(git_in(dir, &["rev-parse", "HEAD"]), first);
+                assert_eq!(git_in(dir, &["cat-file", "-t", "v1"]), "tag");
+            }
+
+            // fetches negotiate with what the clients already have
+            let second = testutils::commit_files("remote", &[("file.txt", "second\n")], "second");
+            testutils::tag_in("remote", "v2");
+            set_current_dir("local").unwrap();
+            let fetch_options = FetchOptions {
+                quiet: true,
+                ..Default::default()
+            };
+            fetch("origin", &[], &fetch_options).unwrap();
+            set_current_dir("..").unwrap();
+            assert_eq!(git_in("local", &["rev-parse", "origin/master"]), second);
+            assert_eq!(git_in("local", &["rev-parse", "v2^{commit}"]), second);
+            git_in("real-v0", &["-c", "protocol.version=0", "fetch", "-q"]);
+            assert_eq!(git_in("real-v0", &["rev-parse", "origin/master"]), second);
+            assert_eq!(git_in("real-v0", &["fsck", "--no-dangling"]), "");
+
+            // pushes need to be enabled and can't touch the checked out branch
+            set_current_dir("local").unwrap();
+            let push_options = PushOptions {
+                quiet: true,
+                ..Default::default()
+            };
+            let feature = ["master:feature".to_string()];
+            assert!(push("origin", &feature, &push_options).is_err());
+            git_in("../remote", &["config", "http.receivepack", "true"]);
+            testutils::commit_files(".", &[("other.txt", "other\n")], "third");
+            testutils::git_as_user_in(".", &["merge", "-q", "origin/master"]);
+            let merge = git_in(".", &["rev-parse", "HEAD"]);
+            assert_eq!(git_in(".", &["rev-parse", "HEAD^2"]), second);
+            push("origin", &feature, &push_options).unwrap();
+            assert!(push("origin", &["master".to_string()], &push_options).is_err());
+            set_current_dir("..").unwrap();
+            assert_eq!(git_in("remote", &["rev-parse", "feature"]), merge);
+            assert_eq!(git_in("remote", &["rev-parse", "master"]), second);
+            assert_eq!(git_in("remote", &["fsck", "--no-dangling"]), "");
+
+            git_in("real-v2", &["push", "-q", "origin", "HEAD:refs/heads/real"]);
+            git_in("real-v2", &["push", "-q", "origin", ":feature"]);
+            assert_eq!(git_in("remote", &["rev-parse", "real"]), first);
+            assert_eq!(git_in("remote", &["branch", "--list", "feature"]), "");
+
+            let missing = clone(&format!("{}/missing", url), &"none".to_string(), &options);
+            assert!(missing.unwrap_err().to_string().contains("404"));
+        });
+    }
+
+    /// Sends a request to a service, returns the body of the response
+    fn post(url: &str, service: &str, lines: &[String]) -> String {
+        let mut body = Vec::new();
+        for line in lines {
+            match line.as_str() {
+                "" => write_flush(&mut body).unwrap(),
+                line => write_line(&mut body, line).unwrap(),
+            }
+        }
+        let response = reqwest::blocking::Client::new()
+            .post(format!("{}/{}", url, service))
+            .header("Content-Type", format!("application/x-{}-request", service))
+            .body(body)
+            .send()
+            .unwrap();
+        return String::from_utf8_lossy(&response.bytes().unwrap()).to_string();
+    }
+
+    #[test]
+    fn test_serve_bare_and_refusals() {
+        testutils::in_tmp_dir(|| {
+            let first = testutils::create_repo("source", &[("file.txt", "first\n")]);
+            git_in(".", &["clone", "-q", "--bare", "source", "bare.git"]);
+            git_in("bare.git", &["config", "http.receivepack", "true"]);
+            let url = start_server(&current_dir().unwrap());
+            let options = CloneOptions {
+                quiet: true,
+                ..Default::default()
+            };
+            clone(&format!("{}/bare", url), &"local".to_string(), &options).unwrap();
+            assert_eq!(git_in("local", &["rev-parse", "HEAD"]), first);
+
+            // the branch HEAD points to in a bare repository can be pushed
+            let second = testutils::commit_files("local", &[("file.txt", "second\n")], "second");
+            set_current_dir("local").unwrap();
+            let push_options = PushOptions {
+                quiet: true,
+                ..Default::default()
+            };
+            push("origin", &["master".to_string()], &push_options).unwrap();
+            set_current_dir("..").unwrap();
+            assert_eq!(git_in("bare.git", &["rev-parse", "master"]), second);
+
+            // ref names escaping refs/ never reach the filesystem
+            let repo = format!("{}/bare.git", url);
+            for name in [
+                "refs/heads/../../config",
+                "refs/heads/a..b",
+                "refs/heads/x.lock",
+            ] {
+                let command = format!("{} {} {}\0report-status", ZERO_ID, ZERO_ID, name);
+                let report = post(&repo, "git-receive-pack", &[command, String::new()]);
+                assert!(report.contains(&format!("ng {} funny refname", name)));
+            }
+            assert!(Path::new("bare.git/config").exists());
+
+            // only the advertised objects can be wanted unless allowed
+            let blob = git_in("bare.git", &["rev-parse", "master:file.txt"]);
+            let request = [format!("want {}", blob), String::new(), "done".to_string()];
+            let answer = post(&repo, "git-upload-pack", &request);
+            assert!(answer.contains(&format!("ERR upload-pack: not our ref {}", blob)));
+            git_in(
+                "bare.git",
+                &["config", "uploadpack.allowReachableSHA1InWant", "true"],
+            );
+            let answer = post(&repo, "git-upload-pack", &request);
+            assert!(answer.starts_with("0008NAK\n") && answer.contains("PACK"));
+            let unreachable = git_in("bare.git", &["hash-object", "-w", "--stdin"]);
+            let request = [
+                format!("want {}", unreachable),
+                String::new(),
+                "done".to_string(),
+            ];
+            let answer = post(&repo, "git-upload-pack", &request);
+            assert!(answer.contains("not our ref"));
+
+            // bodies are read as they arrive, up to the limit of the service
+            let send = |path: &str, length: usize| {
+                let mut stream = TcpStream::connect(url.trim_start_matches("http://")).unwrap();
+                let head = format!(
+                    "POST {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+                    path, length
+                );
+                stream.write_all(head.as_bytes()).unwrap();
+                stream.shutdown(Shutdown::Write).unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                return response;
+            };
+            let response = send("/bare.git/git-upload-pack", 1 << 25);
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+            assert!(response.contains("invalid Content-Length"));
+            let response = send("/bare.git/git-receive-pack", 1 << 29);
+            assert!(response.contains("request body shorter than its Content-Length"));
+        });
+    }
+
+    #[test]
+    fn test_upload_pack_progress() {
+        testutils::in_tmp_dir(|| {
+            let content: String = (0..200).map(|i| format!("line {}\n", i)).collect();
+            let head = testutils::create_repo(
+                ".",
+                &[("a.txt", &content), ("b.txt", &format!("{}end\n", content))],
+            );
+            let request = UploadRequest {
+                wants: vec![head],
+                progress: true,
+                ofs_delta: true,
+                ..Default::default()
+            };
+            let mut out = Vec::new();
+            write_upload_pack(&mut out, &request, &[]).unwrap();
+            // commit, tree and two blobs, one stored as a delta of the other
+            let progress = b"Total 4 (delta 1), reused 0 (delta 0)\n";
+            assert!(out.windows(progress.len()).any(|w| w == progress));
+        });
+    }
+}
@@ -0,0 +1,120 @@
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Write};
+
+use crate::refs::is_sha1;
+use crate::repo_path;
+
+/// Lists the commits whose parents are missing from a shallow repository
+const SHALLOW_FILE: &str = ".git/shallow";
+
+/// Returns the commits the local history is cut at, empty for complete
+/// repositories
+pub fn read_shallow() -> Result<HashSet<String>> {
+    let content = match fs::read_to_string(repo_path(SHALLOW_FILE)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => bail!(e),
+    };
+    let mut shallow = HashSet::new();
+    for line in content.lines() {
+        if !is_sha1(line) {
+            bail!("invalid line '{}' in {}", line, SHALLOW_FILE);
+        }
+        shallow.insert(line.to_string());
+    }
+    return Ok(shallow);
+}
+
+/// Parses the date given to `--shallow-since`: a unix timestamp, a
+/// `YYYY-MM-DD` day or a RFC 3339 date
+pub fn parse_since(date: &str) -> Result<i64> {
+    if let Ok(timestamp) = date.parse::<i64>() {
+        return Ok(timestamp);
+    }
+    if let Ok(date_time) = chrono::DateTime::parse_from_rfc3339(date) {
+        return Ok(date_time.timestamp());
+    }
+    if let Ok(day) = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        return Ok(day.and_hms_opt(0, 0, 0).unwrap().timestamp());
+    }
+    bail!("invalid date '{}'", date);
+}
+
+/// Records the new shallow commits sent by a server and forgets the ones it
+/// sent the parents of. The file goes away once the history is complete.
+pub fn update_shallow(added: &[String], removed: &[String]) -> Result<()> {
+    let mut shallow = read_shallow()?;
+    let before = shallow.clone();
+    shallow.extend(added.iter().cloned());
+    for sha1 in removed {
+        shallow.remove(sha1);
+    }
+    if shallow == before {
+        return Ok(());
+    }
+    if shallow.is_empty() {
+        fs::remove_file(repo_path(SHALLOW_FILE))?;
+        return Ok(());
+    }
+
+    let mut lines: Vec<String> = shallow.into_iter().collect();
+    lines.sort();
+    let lock = &repo_path(".git/shallow.lock");
+    let mut file = match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(lock)
+    {
+        Ok(file) => file,
+        Err(e) => bail!("unable to lock {}: {}", SHALLOW_FILE, e),
+    };
+    // the lock must not outlive a failed update, it would block every
+    // later fetch
+    let result = file
+        .write_all((lines.join("\n") + "\n").as_bytes())
+        .and_then(|_| fs::rename(lock, repo_path(SHALLOW_FILE)));
+    if let Err(e) = result {
+        let _ = fs::remove_file(lock);
+        bail!(e);
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::slice::from_ref;
+
+    use crate::shallow::{read_shallow, update_shallow};
+    use crate::testutils;
+
+    #[test]
+    fn test_update_shallow() {
+        testutils::in_tmp_git(|| {
+            let (a, b) = ("a".repeat(40), "b".repeat(40));
+            update_shallow(&[b.clone(), a.clone()], &[]).unwrap();
+            assert_eq!(
+                fs::read_to_string(".git/shallow").unwrap(),
+                format!("{}\n{}\n", a, b)
+            );
+            update_shallow(&[], from_ref(&a)).unwrap();
+            assert_eq!(
+                read_shallow().unwrap().into_iter().collect::<Vec<_>>(),
+                from_ref(&b)
+            );
+
+            // a leftover lock makes the update fail
+            fs::write(".git/shallow.lock", "").unwrap();
+            assert!(update_shallow(from_ref(&a), &[]).is_err());
+            fs::remove_file(".git/shallow.lock").unwrap();
+            update_shallow(from_ref(&a), &[]).unwrap();
+            assert!(!Path::new(".git/shallow.lock").exists());
+
+            update_shallow(&[], &[a, b]).unwrap();
+            assert!(!Path::new(".git/shallow").exists());
+        });
+    }
+}
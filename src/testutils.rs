@@ -0,0 +1,422 @@
+use std::env::{current_dir, set_current_dir};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use tempdir::TempDir;
+
+use crate::init;
+use crate::pktline;
+
+static MUTEX: Mutex<u8> = Mutex::new(0u8);
+
+pub fn get_git_output(args: &[&str]) -> String {
+    let stdout = Command::new("git")
+        .args(args)
+        .output()
+        .expect("error running git")
+        .stdout;
+    return String::from_utf8(stdout).unwrap();
+}
+
+pub fn get_git_output_with_stdin(args: &[&str], input: &str) -> Vec<u8> {
+    let mut child = Command::new("git")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("error running git");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    return child.wait_with_output().unwrap().stdout;
+}
+
+/// Commits every file in the cwd using the real git and returns the commit hash
+pub fn git_commit_all(message: &str) -> String {
+    get_git_output(&["add", "-A"]);
+    git_as_user_in(".", &["commit", "-q", "-m", message]);
+    return git_in(".", &["rev-parse", "HEAD"]);
+}
+
+/// Runs the real git inside `dir` and returns its output
+pub fn get_git_output_in(dir: &str, args: &[&str]) -> String {
+    let mut full_args = vec!["-C", dir];
+    full_args.extend(args);
+    return get_git_output(&full_args);
+}
+
+/// Runs the real git inside `dir` and returns its output, trimmed
+pub fn git_in(dir: &str, args: &[&str]) -> String {
+    return get_git_output_in(dir, args).trim().to_string();
+}
+
+/// Runs the real git inside `dir` with a committer identity, for commands
+/// creating commits or tags, and returns its output, trimmed
+pub fn git_as_user_in(dir: &str, args: &[&str]) -> String {
+    let identity = ["-c", "user.name=test", "-c", "user.email=test@example.com"];
+    return git_in(dir, &[&identity[..], args].concat());
+}
+
+/// Tags HEAD of the repository at `dir` with an annotated tag, with the
+/// real git
+pub fn tag_in(dir: &str, name: &str) {
+    git_as_user_in(dir, &["tag", "-a", "-m", name, name]);
+}
+
+/// Creates a repository at `dir` with the real git, committing the given
+/// files, and returns the hash of the commit
+pub fn create_repo(dir: &str, files: &[(&str, &str)]) -> String {
+    fs::create_dir_all(dir).unwrap();
+    get_git_output(&["init", "-q", "-b", "master", dir]);
+    return commit_files(dir, files, "commit");
+}
+
+/// Writes the given files in the repository at `dir` and commits them with
+/// the real git, returning the hash of the commit
+pub fn commit_files(dir: &str, files: &[(&str, &str)], message: &str) -> String {
+    for (path, content) in files {
+        let path = Path::new(dir).join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+    get_git_output_in(dir, &["add", "-A"]);
+    git_as_user_in(dir, &["commit", "-q", "-m", message]);
+    return git_in(dir, &["rev-parse", "HEAD"]);
+}
+
+fn tempdir() -> (PathBuf, TempDir) {
+    let cwd = current_dir().unwrap();
+    let tempdir = TempDir::new("gittest").unwrap();
+    assert!(tempdir.path().is_dir());
+    return (cwd, tempdir);
+}
+
+pub fn in_tmp_dir<F>(func: F)
+where
+    F: FnOnce(),
+{
+    let _lock = match MUTEX.lock() {
+        Ok(guard) => guard,
+        Err(poison) => poison.into_inner(),
+    };
+    let (cwd, dir) = tempdir();
+    set_current_dir(&dir).unwrap();
+    let result = panic::catch_unwind(AssertUnwindSafe(func));
+    // restore the cwd even on failure so other tests don't run in a deleted dir
+    set_current_dir(cwd).unwrap();
+    if let Err(e) = result {
+        panic::resume_unwind(e);
+    }
+}
+
+pub fn in_tmp_git<F>(func: F)
+where
+    F: FnOnce(),
+{
+    in_tmp_dir(|| {
+        init().unwrap();
+        func();
+    });
+}
+
+/// Reads an HTTP request from the stream: method, path, headers and body
+fn read_http_request(
+    stream: &mut BufReader<TcpStream>,
+) -> (String, String, Vec<(String, String)>, Vec<u8>) {
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).unwrap();
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let path = parts.next().unwrap_or("").to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+        }
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.clone())
+    };
+
+    let mut body = Vec::new();
+    if let Some(len) = header("content-length") {
+        body.resize(len.parse().unwrap(), 0);
+        stream.read_exact(&mut body).unwrap();
+    } else if header("transfer-encoding").as_deref() == Some("chunked") {
+        loop {
+            let mut size = String::new();
+            stream.read_line(&mut size).unwrap();
+            let size = usize::from_str_radix(size.trim(), 16).unwrap();
+            let mut chunk = vec![0u8; size + 2];
+            stream.read_exact(&mut chunk).unwrap();
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    }
+    return (method, path, headers, body);
+}
+
+/// Runs a git service in stateless mode the way `git http-backend` does
+fn run_git_service(
+    service: &str,
+    repo: &Path,
+    protocol: Option<&str>,
+    args: &[&str],
+    input: &[u8],
+) -> Vec<u8> {
+    let mut command = Command::new("git");
+    command
+        .arg(service)
+        .arg("--stateless-rpc")
+        .args(args)
+        .arg(repo)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    if let Some(protocol) = protocol {
+        command.env("GIT_PROTOCOL", protocol);
+    }
+    let mut child = command.spawn().expect("error running git");
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    return child.wait_with_output().unwrap().stdout;
+}
+
+/// Answers a single smart HTTP request for a repository under `root`. With
+/// `authorizations` requests must carry one of these `Authorization` headers.
+fn handle_http_request(
+    mut stream: TcpStream,
+    root: &Path,
+    allow_v2: bool,
+    authorizations: &[String],
+) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let (method, path, headers, body) = read_http_request(&mut reader);
+    let authorization = headers
+        .iter()
+        .find(|(n, _)| n == "authorization")
+        .map(|(_, v)| v.clone());
+    if !authorizations.is_empty()
+        && !authorizations
+            .iter()
+            .any(|a| Some(a) == authorization.as_ref())
+    {
+        write!(
+            stream,
+            "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic realm=\"git\"\r\n\
+             Content-Length: 0\r\nConnection: close\r\n\r\n"
+        )
+        .unwrap();
+        return;
+    }
+    // requests sent through a proxy name the whole URL
+    let path = match path.split_once("://") {
+        Some((_, rest)) => rest[rest.find('/').unwrap_or(rest.len())..].to_string(),
+        None => path,
+    };
+    let protocol = headers
+        .iter()
+        .find(|(n, _)| n == "git-protocol")
+        .map(|(_, v)| v.as_str())
+        .filter(|_| allow_v2);
+
+    let (path, query) = path.split_once('?').unwrap_or((path.as_str(), ""));
+    let repo_exists = |repo: &str| root.join(repo.trim_start_matches('/')).is_dir();
+    let (status, content_type, response) = if let Some(repo) = path
+        .strip_suffix("/info/refs")
+        .filter(|repo| repo_exists(repo))
+    {
+        let service = query.strip_prefix("service=").unwrap_or("");
+        let repo = root.join(repo.trim_start_matches('/'));
+        let mut response = Vec::new();
+        if protocol != Some("version=2") {
+            pktline::write_line(&mut response, &format!("# service={}", service)).unwrap();
+            pktline::write_flush(&mut response).unwrap();
+        }
+        let service = service.strip_prefix("git-").unwrap_or(service);
+        response.extend(run_git_service(
+            service,
+            &repo,
+            protocol,
+            &["--advertise-refs"],
+            &[],
+        ));
+        (
+            "200 OK",
+            format!("application/x-git-{}-advertisement", service),
+            response,
+        )
+    } else if let (Some((repo, service)), "POST") = (
+        path.rsplit_once("/git-")
+            .filter(|(repo, _)| repo_exists(repo)),
+        method.as_str(),
+    ) {
+        let repo = root.join(repo.trim_start_matches('/'));
+        let response = run_git_service(service, &repo, protocol, &[], &body);
+        (
+            "200 OK",
+            format!("application/x-git-{}-result", service),
+            response,
+        )
+    } else {
+        (
+            "404 Not Found",
+            "text/plain".to_string(),
+            b"not found".to_vec(),
+        )
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        response.len()
+    )
+    .unwrap();
+    stream.write_all(&response).unwrap();
+}
+
+/// Starts an HTTP server in a background thread answering each request with
+/// the content type and body `respond` gives for its method and path,
+/// returns its base URL
+pub fn start_canned_http_server(respond: fn(&str, &str) -> (String, Vec<u8>)) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let (method, path, _, _) = read_http_request(&mut reader);
+            let (content_type, response) = respond(&method, &path);
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                content_type,
+                response.len()
+            )
+            .unwrap();
+            stream.write_all(&response).unwrap();
+        }
+    });
+    return url;
+}
+
+/// Starts a stand-in smart HTTP server in a background thread serving the
+/// repositories under `root` with the real git, returns its base URL
+pub fn start_http_server(root: &Path, allow_v2: bool) -> String {
+    return start_auth_http_server(root, allow_v2, &[]);
+}
+
+/// Starts a stand-in smart HTTP server like `start_http_server` answering
+/// 401 to requests without one of the given `Authorization` headers. It
+/// also works as a proxy for any host.
+pub fn start_auth_http_server(root: &Path, allow_v2: bool, authorizations: &[&str]) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let root = root.to_path_buf();
+    let authorizations: Vec<String> = authorizations.iter().map(|a| a.to_string()).collect();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            handle_http_request(stream.unwrap(), &root, allow_v2, &authorizations);
+        }
+    });
+    return url;
+}
+
+/// Starts a server of the plain files under `root` in a background thread,
+/// as used by the dumb HTTP protocol, returns its base URL
+pub fn start_static_http_server(root: &Path) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let root = root.to_path_buf();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let (_, path, _, _) = read_http_request(&mut reader);
+            let path = root.join(path.split('?').next().unwrap().trim_start_matches('/'));
+            let (status, body) = match fs::read(&path) {
+                Ok(body) if !path.components().any(|c| c.as_os_str() == "..") => ("200 OK", body),
+                _ => ("404 Not Found", b"not found".to_vec()),
+            };
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n",
+                status,
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(&body).unwrap();
+        }
+    });
+    return url;
+}
+
+/// A `git daemon` exporting every repository under a directory, killed
+/// when dropped
+pub struct GitDaemon {
+    child: Child,
+    pub url: String,
+}
+
+impl Drop for GitDaemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Starts the real `git daemon` on a free local port serving the
+/// repositories under `root`, returns once it accepts connections
+pub fn start_git_daemon(root: &Path) -> GitDaemon {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    // `git daemon` would run git-daemon as a child that outlives a kill
+    let exec_path = get_git_output(&["--exec-path"]);
+    let child = Command::new(Path::new(exec_path.trim()).join("git-daemon"))
+        .arg("--reuseaddr")
+        .arg("--export-all")
+        .arg("--listen=127.0.0.1")
+        .arg(format!("--port={}", port))
+        .arg(format!("--base-path={}", root.display()))
+        .arg(root)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("error running git daemon");
+    let daemon = GitDaemon {
+        child,
+        url: format!("git://127.0.0.1:{}", port),
+    };
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return daemon;
+        }
+        thread::sleep(std::time::Duration::from_millis(50));
+    }
+    panic!("git daemon did not start");
+}
@@ -0,0 +1,875 @@
+use anyhow::{bail, Result};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use reqwest::StatusCode;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::config::Config;
+use crate::credential::{self, Credential};
+use crate::pktline::{write_flush, write_packet, Packet, PktReader};
+
+/// Services a git server exposes to clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    UploadPack,
+    ReceivePack,
+}
+
+impl Display for Service {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Service::UploadPack => "git-upload-pack",
+            Service::ReceivePack => "git-receive-pack",
+        };
+        return write!(f, "{}", name);
+    }
+}
+
+/// A way of reaching a remote repository.
+///
+/// Transports only move bytes around, the pkt-line conversation on top of
+/// them is handled by the `protocol` module.
+pub trait Transport {
+    /// Starts a service, asking for the given protocol version, and returns
+    /// the stream holding its initial advertisement
+    fn advertise(&mut self, service: Service, version: u8) -> Result<Box<dyn Read + '_>>;
+
+    /// Sends a request to a service and returns the stream with its response,
+    /// which doesn't borrow the transport so it can outlive the negotiation
+    /// rounds made before it
+    fn request(&mut self, service: Service, body: Vec<u8>) -> Result<Box<dyn Read>>;
+
+    /// Whether every request reaches a new instance of the service, which
+    /// doesn't remember the previous requests
+    fn stateless(&self) -> bool {
+        return true;
+    }
+}
+
+/// Returns the path of the repository a URL refers to when it's local: a
+/// `file://` URL or a plain path
+pub fn local_path(url: &str) -> Option<PathBuf> {
+    if let Some(path) = url.strip_prefix("file://") {
+        return Some(PathBuf::from(path));
+    }
+    if url.contains("://") || parse_ssh_url(url).is_some() {
+        return None;
+    }
+    return Some(PathBuf::from(url));
+}
+
+/// Location of a repository reached through ssh
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshUrl {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+    /// Path on the remote host, relative to the home of the user unless it
+    /// starts with a slash
+    pub path: String,
+}
+
+/// Splits the `[user@]host` part of an ssh URL, `[]` around the host allow
+/// IPv6 addresses and colons in it
+fn split_user_host(user_host: &str) -> Option<(Option<String>, String)> {
+    let (user, host) = match user_host.rsplit_once('@') {
+        Some((user, host)) => (Some(user.to_string()), host),
+        None => (None, user_host),
+    };
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    // a leading dash would make ssh take the destination for an option
+    if host.is_empty()
+        || host.starts_with('-')
+        || user.as_deref().is_some_and(|u| u.starts_with('-'))
+    {
+        return None;
+    }
+    return Some((user, host.to_string()));
+}
+
+/// Parses `ssh://[user@]host[:port]/path` URLs and the scp-like
+/// `[user@]host:path` syntax, None for other URLs
+pub fn parse_ssh_url(url: &str) -> Option<SshUrl> {
+    for scheme in ["ssh://", "git+ssh://", "ssh+git://"] {
+        let rest = match url.strip_prefix(scheme) {
+            Some(rest) => rest,
+            None => continue,
+        };
+        let (authority, path) = rest.split_at(rest.find('/')?);
+        let (user_host, port) = match authority.rsplit_once(':') {
+            Some((user_host, port)) if !port.contains(']') => (user_host, Some(port.parse().ok()?)),
+            _ => (authority, None),
+        };
+        let (user, host) = split_user_host(user_host)?;
+        // `/~user/path` is relative to the home of that user
+        let path = match path.strip_prefix("/~") {
+            Some(home) => format!("~{}", home),
+            None => path.to_string(),
+        };
+        return Some(SshUrl {
+            user,
+            host,
+            port,
+            path,
+        });
+    }
+    if url.contains("://") {
+        return None;
+    }
+    // `host:path`, unless a slash comes before the colon as in `./a:b`
+    let colon = match url.strip_prefix('[') {
+        Some(rest) => {
+            let end = rest.find(']')?;
+            end + 1 + rest[end..].find(':')?
+        }
+        None => url.find(':')?,
+    };
+    if url[..colon].contains('/') || colon + 1 == url.len() {
+        return None;
+    }
+    let (user, host) = split_user_host(&url[..colon])?;
+    return Some(SshUrl {
+        user,
+        host,
+        port: None,
+        path: url[colon + 1..].to_string(),
+    });
+}
+
+/// Port `git daemon` listens on by default
+pub const DEFAULT_DAEMON_PORT: u16 = 9418;
+
+/// Location of a repository served by `git daemon`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DaemonUrl {
+    pub host: String,
+    pub port: Option<u16>,
+    pub path: String,
+}
+
+/// Parses `git://host[:port]/path` URLs, None for other URLs
+pub fn parse_daemon_url(url: &str) -> Option<DaemonUrl> {
+    let rest = url.strip_prefix("git://")?;
+    let (authority, path) = rest.split_at(rest.find('/')?);
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, Some(port.parse().ok()?)),
+        _ => (authority, None),
+    };
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() || host.contains('@') {
+        return None;
+    }
+    // `/~user/path` is relative to the home of that user
+    let path = match path.strip_prefix("/~") {
+        Some(home) => format!("~{}", home),
+        None => path.to_string(),
+    };
+    return Some(DaemonUrl {
+        host: host.to_string(),
+        port,
+        path,
+    });
+}
+
+/// Returns whether a URL is reached over HTTP
+pub fn is_http_url(url: &str) -> bool {
+    return url.starts_with("http://") || url.starts_with("https://");
+}
+
+/// Opens an HTTP transport to a repository, its URL gets the `.git` suffix
+/// when it doesn't have it
+pub fn http_transport(url: &str) -> Result<HttpTransport> {
+    let mut url = url.to_string();
+    if !url.ends_with(".git") {
+        url.push_str(".git");
+    }
+    return HttpTransport::new(&url);
+}
+
+/// Returns a transport able to reach the repository at `url`
+pub fn transport_for_url(url: &str) -> Result<Box<dyn Transport>> {
+    if is_http_url(url) {
+        return Ok(Box::new(http_transport(url)?));
+    }
+    if let Some(daemon_url) = parse_daemon_url(url) {
+        return Ok(Box::new(DaemonTransport::new(daemon_url)));
+    }
+    if let Some(ssh_url) = parse_ssh_url(url) {
+        return Ok(Box::new(SshTransport::new(ssh_url)));
+    }
+    if let Some(path) = local_path(url) {
+        return Ok(Box::new(LocalTransport::new(&path)?));
+    }
+    bail!("unsupported URL '{}'", url);
+}
+
+/// Output of a spawned service, the process is waited for at the end of
+/// the output and a failed exit becomes a read error
+struct ChildReader {
+    child: Child,
+    stdout: ChildStdout,
+    name: String,
+}
+
+impl ChildReader {
+    /// Spawns a command, feeding it `input` from another thread so that it
+    /// can't block writing its output while we write its input
+    fn spawn(mut command: Command, name: &str, input: Vec<u8>) -> Result<ChildReader> {
+        let mut child = match command.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn() {
+            Ok(child) => child,
+            Err(e) => bail!("unable to run {}: {}", name, e),
+        };
+        let mut stdin = child.stdin.take().unwrap();
+        thread::spawn(move || {
+            // the process reports its own errors if its input is cut short
+            let _ = stdin.write_all(&input);
+        });
+        let stdout = child.stdout.take().unwrap();
+        return Ok(ChildReader {
+            child,
+            stdout,
+            name: name.to_string(),
+        });
+    }
+}
+
+impl Read for ChildReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "{} failed ({})",
+                    self.name, status
+                )));
+            }
+        }
+        return Ok(n);
+    }
+}
+
+/// Transport to repositories on the local filesystem, which runs the
+/// services of the real git in stateless mode, as `git http-backend` does
+pub struct LocalTransport {
+    path: PathBuf,
+    version: u8,
+}
+
+impl LocalTransport {
+    pub fn new(path: &Path) -> Result<LocalTransport> {
+        if !path.is_dir() {
+            bail!(
+                "'{}' does not appear to be a git repository",
+                path.display()
+            );
+        }
+        return Ok(LocalTransport {
+            path: path.to_path_buf(),
+            version: 0,
+        });
+    }
+
+    fn run(&self, service: Service, advertise: bool, input: Vec<u8>) -> Result<ChildReader> {
+        let name = service.to_string();
+        let mut command = Command::new("git");
+        command
+            .arg(name.strip_prefix("git-").unwrap())
+            .arg("--stateless-rpc");
+        if advertise {
+            command.arg("--advertise-refs");
+        }
+        command.arg(&self.path);
+        if self.version > 0 {
+            command.env("GIT_PROTOCOL", format!("version={}", self.version));
+        }
+        return ChildReader::spawn(command, &name, input);
+    }
+}
+
+impl Transport for LocalTransport {
+    fn advertise(&mut self, service: Service, version: u8) -> Result<Box<dyn Read + '_>> {
+        self.version = version;
+        return Ok(Box::new(self.run(service, true, Vec::new())?));
+    }
+
+    fn request(&mut self, service: Service, body: Vec<u8>) -> Result<Box<dyn Read>> {
+        return Ok(Box::new(self.run(service, false, body)?));
+    }
+}
+
+/// Error of HTTP servers only serving the files of the repository, which
+/// are reached with the dumb protocol instead
+#[derive(Debug, thiserror::Error)]
+#[error("'{0}' is not a smart git HTTP server")]
+pub struct NotSmartServer(pub String);
+
+/// Smart HTTP transport as described in gitprotocol-http(5)
+pub struct HttpTransport {
+    /// URL of the repository, without the credentials it may embed
+    url: String,
+    client: reqwest::blocking::Client,
+    version: u8,
+    config: Config,
+    credential: Credential,
+    /// Whether the credential has been checked by the server and approved
+    approved: bool,
+}
+
+/// Parses the `http.extraHeader` values into headers sent with every
+/// request, an empty value clears the headers listed before it
+fn extra_headers(config: &Config) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    for header in config.get_all("http.extraHeader") {
+        if header.is_empty() {
+            headers.clear();
+            continue;
+        }
+        let (name, value) = match header.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => bail!("invalid http.extraHeader '{}'", header),
+        };
+        match (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            (Ok(name), Ok(value)) => headers.append(name, value),
+            _ => bail!("invalid http.extraHeader '{}'", header),
+        };
+    }
+    return Ok(headers);
+}
+
+impl HttpTransport {
+    pub fn new(url: &str) -> Result<HttpTransport> {
+        let mut parsed = match reqwest::Url::parse(url) {
+            Ok(parsed) => parsed,
+            Err(e) => bail!("invalid URL '{}': {}", url, e),
+        };
+        let credential = Credential::from_url(&parsed)?;
+        // the credentials never appear in messages
+        let _ = parsed.set_username("");
+        let _ = parsed.set_password(None);
+
+        let config = Config::load()?;
+        let mut builder = reqwest::blocking::Client::builder()
+            .user_agent(concat!("git/git-rust-", env!("CARGO_PKG_VERSION")))
+            .default_headers(extra_headers(&config)?);
+        // without http.proxy the usual proxy environment variables apply
+        if let Some(proxy) = config.get("http.proxy").filter(|p| !p.is_empty()) {
+            let proxy = match proxy.contains("://") {
+                true => proxy.to_string(),
+                false => format!("http://{}", proxy),
+            };
+            match reqwest::Proxy::all(&proxy) {
+                Ok(proxy) => builder = builder.proxy(proxy),
+                Err(e) => bail!("invalid http.proxy '{}': {}", proxy, e),
+            }
+        }
+        let ca_info = std::env::var("GIT_SSL_CAINFO")
+            .ok()
+            .or_else(|| config.get("http.sslCAInfo").map(|c| c.to_string()));
+        if let Some(ca_info) = ca_info {
+            let certificate = std::fs::read(&ca_info)
+                .map_err(anyhow::Error::from)
+                .and_then(|pem| Ok(reqwest::Certificate::from_pem(&pem)?));
+            match certificate {
+                Ok(certificate) => builder = builder.add_root_certificate(certificate),
+                Err(e) => bail!("unable to load CA bundle '{}': {}", ca_info, e),
+            }
+        }
+        let verify = match std::env::var("GIT_SSL_NO_VERIFY") {
+            Ok(_) => false,
+            Err(_) => config.get_bool("http.sslVerify")?.unwrap_or(true),
+        };
+        let client = builder.danger_accept_invalid_certs(!verify).build()?;
+        return Ok(HttpTransport {
+            url: parsed.as_str().trim_end_matches('/').to_string(),
+            client,
+            version: 0,
+            config,
+            credential,
+            approved: false,
+        });
+    }
+
+    /// Sends a request built by `build`, asking for credentials and sending
+    /// it again when the server requires authentication. Other unsuccessful
+    /// responses are returned as they are.
+    fn send<F>(&mut self, url: &str, build: F) -> Result<reqwest::blocking::Response>
+    where
+        F: Fn(&reqwest::blocking::Client) -> reqwest::blocking::RequestBuilder,
+    {
+        let mut filled = false;
+        loop {
+            let mut request = build(&self.client);
+            if let (Some(authtype), Some(value)) =
+                (&self.credential.authtype, &self.credential.credential)
+            {
+                request = request.header(AUTHORIZATION, format!("{} {}", authtype, value));
+            } else if let (Some(username), Some(password)) =
+                (&self.credential.username, &self.credential.password)
+            {
+                request = request.basic_auth(username, Some(password));
+            }
+            let response = match request.send() {
+                Ok(response) => response,
+                Err(e) => bail!("unable to access '{}': {}", url, e),
+            };
+
+            if response.status() != StatusCode::UNAUTHORIZED {
+                if self.credential.is_complete() && !self.approved && response.status().is_success()
+                {
+                    credential::approve(&self.credential, &self.config)?;
+                    self.approved = true;
+                }
+                return Ok(response);
+            }
+            if filled || self.approved || self.credential.is_complete() {
+                if filled {
+                    credential::reject(&self.credential, &self.config)?;
+                }
+                bail!("Authentication failed for '{}'", self.url);
+            }
+            self.credential.wwwauth = response
+                .headers()
+                .get_all(WWW_AUTHENTICATE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+                .collect();
+            self.credential = credential::fill(&self.credential, &self.config)?;
+            filled = true;
+        }
+    }
+
+    /// Downloads a file of the repository, None if the server doesn't have
+    /// it. This is all the dumb protocol asks from servers.
+    pub fn get_file(&mut self, path: &str) -> Result<Option<Vec<u8>>> {
+        let url = format!("{}/{}", self.url, path);
+        let response = self.send(&url, |client| client.get(&url))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let mut response = HttpTransport::check_response(&url, response)?;
+        let mut data = Vec::new();
+        response.read_to_end(&mut data)?;
+        return Ok(Some(data));
+    }
+
+    /// Fails with a descriptive error for unsuccessful responses
+    fn check_response(
+        url: &str,
+        response: reqwest::blocking::Response,
+    ) -> Result<reqwest::blocking::Response> {
+        let status = response.status();
+        if !status.is_success() {
+            bail!("unable to access '{}': the server returned {}", url, status);
+        }
+        return Ok(response);
+    }
+}
+
+impl Transport for HttpTransport {
+    fn advertise(&mut self, service: Service, version: u8) -> Result<Box<dyn Read + '_>> {
+        self.version = version;
+        let url = format!("{}/info/refs?service={}", self.url, service);
+        let response = self.send(&url, |client| {
+            let request = client.get(&url);
+            return match version {
+                0 => request,
+                _ => request.header("Git-Protocol", format!("version={}", version)),
+            };
+        })?;
+        let response = HttpTransport::check_response(&url, response)?;
+        let content_type = response
+            .headers()
+            .get("Content-Type")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+        if content_type != format!("application/x-{}-advertisement", service) {
+            return Err(NotSmartServer(self.url.clone()).into());
+        }
+
+        // servers answering in v0 prefix the advertisement with the service name
+        let mut reader = PktReader::new(response);
+        let first = reader.read_expected()?;
+        if let Some(line) = first.as_text() {
+            if line.starts_with("# service=") {
+                if reader.read_expected()? != Packet::Flush {
+                    bail!("expected flush after service announcement");
+                }
+                return Ok(Box::new(reader.into_inner()));
+            }
+        }
+        let mut replay = Vec::new();
+        first.write_to(&mut replay)?;
+        return Ok(Box::new(Cursor::new(replay).chain(reader.into_inner())));
+    }
+
+    fn request(&mut self, service: Service, body: Vec<u8>) -> Result<Box<dyn Read>> {
+        let url = format!("{}/{}", self.url, service);
+        let version = self.version;
+        let response = self.send(&url, |client| {
+            let request = client
+                .post(&url)
+                .header("Content-Type", format!("application/x-{}-request", service))
+                .header("Accept", format!("application/x-{}-result", service))
+                .body(body.clone());
+            return match version {
+                0 => request,
+                _ => request.header("Git-Protocol", format!("version={}", version)),
+            };
+        })?;
+        let response = HttpTransport::check_response(&url, response)?;
+        return Ok(Box::new(response));
+    }
+}
+
+/// Quotes an argument for the shell running the command on the remote host
+fn shell_quote(arg: &str) -> String {
+    return format!("'{}'", arg.replace('\'', "'\\''"));
+}
+
+/// Kinds of ssh programs, which take their options differently
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SshVariant {
+    /// OpenSSH, asked to forward the protocol version, `-p <port>`
+    OpenSsh,
+    /// PuTTY's plink, `-P <port>`
+    Plink,
+    /// TortoisePlink, like plink and told not to prompt with `-batch`
+    TortoisePlink,
+    /// Other programs, which only get the host and the command
+    Simple,
+}
+
+/// Returns the variant of an ssh program: `GIT_SSH_VARIANT` or `ssh.variant`
+/// when set to something else than `auto`, else guessed from its name
+fn ssh_variant(program: &str, config: &Config) -> SshVariant {
+    let variant = match std::env::var("GIT_SSH_VARIANT") {
+        Ok(variant) => Some(variant),
+        Err(_) => config.get("ssh.variant").map(|v| v.to_string()),
+    };
+    let name = match variant.as_deref() {
+        Some("auto") | None => Path::new(program)
+            .file_stem()
+            .and_then(|n| n.to_str())
+            .unwrap_or("")
+            .to_lowercase(),
+        Some(variant) => variant.to_string(),
+    };
+    return match name.as_str() {
+        "ssh" => SshVariant::OpenSsh,
+        "plink" | "putty" => SshVariant::Plink,
+        "tortoiseplink" => SshVariant::TortoisePlink,
+        _ => SshVariant::Simple,
+    };
+}
+
+/// Returns the command used to run ssh: `GIT_SSH_COMMAND` or `core.sshCommand`
+/// (run by the shell), `GIT_SSH` (a program) or `ssh`, with its variant
+fn ssh_command() -> Result<(Command, SshVariant)> {
+    let config = Config::load()?;
+    let shell_command = match std::env::var("GIT_SSH_COMMAND") {
+        Ok(command) => Some(command),
+        Err(_) => config.get("core.sshCommand").map(|c| c.to_string()),
+    };
+    if let Some(shell_command) = shell_command {
+        let program = shell_command.split_whitespace().next().unwrap_or("");
+        let variant = ssh_variant(program, &config);
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("{} \"$@\"", shell_command))
+            .arg(&shell_command);
+        return Ok((command, variant));
+    }
+    let program = std::env::var("GIT_SSH").unwrap_or_else(|_| "ssh".to_string());
+    let variant = ssh_variant(&program, &config);
+    return Ok((Command::new(program), variant));
+}
+
+/// Runs the services on a remote host through ssh. Unlike the other
+/// transports the service runs for the whole conversation, every request
+/// continues it on the same connection.
+pub struct SshTransport {
+    url: SshUrl,
+    process: Option<(Arc<Mutex<Child>>, ChildStdin, ChildStdout)>,
+}
+
+impl SshTransport {
+    pub fn new(url: SshUrl) -> SshTransport {
+        return SshTransport { url, process: None };
+    }
+
+    /// Output of the service, the ssh process failing becomes a read error
+    fn output(&mut self) -> Result<Box<dyn Read>> {
+        return match self.process.as_ref() {
+            Some((child, _, stdout)) => Ok(Box::new(SshOutput {
+                child: child.clone(),
+                stdout: File::from(stdout.as_fd().try_clone_to_owned()?),
+            })),
+            None => bail!("the ssh connection is not open"),
+        };
+    }
+}
+
+/// Output of a service run through ssh, checking the ssh exit status once
+/// it ends
+struct SshOutput {
+    child: Arc<Mutex<Child>>,
+    stdout: File,
+}
+
+impl Read for SshOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            let mut child = match self.child.lock() {
+                Ok(guard) => guard,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let status = child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "the remote end hung up: ssh failed ({})",
+                    status
+                )));
+            }
+        }
+        return Ok(n);
+    }
+}
+
+impl Transport for SshTransport {
+    fn advertise(&mut self, service: Service, version: u8) -> Result<Box<dyn Read + '_>> {
+        if self.process.is_some() {
+            bail!("the ssh connection is already open");
+        }
+        let (mut command, variant) = ssh_command()?;
+        match variant {
+            SshVariant::OpenSsh => {
+                command.arg("-o").arg("SendEnv=GIT_PROTOCOL");
+            }
+            SshVariant::TortoisePlink => {
+                command.arg("-batch");
+            }
+            _ => {}
+        }
+        if let Some(port) = self.url.port {
+            match variant {
+                SshVariant::OpenSsh => command.arg("-p"),
+                SshVariant::Plink | SshVariant::TortoisePlink => command.arg("-P"),
+                SshVariant::Simple => bail!("ssh variant 'simple' does not support setting a port"),
+            };
+            command.arg(port.to_string());
+        }
+        let destination = match self.url.user.as_ref() {
+            Some(user) => format!("{}@{}", user, self.url.host),
+            None => self.url.host.clone(),
+        };
+        if destination.starts_with('-') {
+            bail!("strange ssh destination '{}'", destination);
+        }
+        command.arg(destination);
+        command.arg(format!("{} {}", service, shell_quote(&self.url.path)));
+        if version > 0 {
+            command.env("GIT_PROTOCOL", format!("version={}", version));
+        }
+        let mut child = match command.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn() {
+            Ok(child) => child,
+            Err(e) => bail!("unable to run ssh: {}", e),
+        };
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        self.process = Some((Arc::new(Mutex::new(child)), stdin, stdout));
+        return self.output();
+    }
+
+    fn request(&mut self, _service: Service, body: Vec<u8>) -> Result<Box<dyn Read>> {
+        match self.process.as_mut() {
+            Some((_, stdin, _)) => {
+                stdin.write_all(&body)?;
+                stdin.flush()?;
+            }
+            None => bail!("the ssh connection is not open"),
+        }
+        return self.output();
+    }
+
+    fn stateless(&self) -> bool {
+        return false;
+    }
+}
+
+impl Drop for SshTransport {
+    fn drop(&mut self) {
+        if let Some((child, mut stdin, _)) = self.process.take() {
+            // a flush ends the conversation for services still waiting for
+            // a request, the others already exited
+            let _ = write_flush(&mut stdin);
+            drop(stdin);
+            let mut child = match child.lock() {
+                Ok(guard) => guard,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Talks to `git daemon` over a plain TCP connection. The first packet
+/// names the service, repository and virtual host, then the conversation
+/// continues on the same connection as with ssh.
+pub struct DaemonTransport {
+    url: DaemonUrl,
+    stream: Option<TcpStream>,
+}
+
+impl DaemonTransport {
+    pub fn new(url: DaemonUrl) -> DaemonTransport {
+        return DaemonTransport { url, stream: None };
+    }
+
+    /// Value of the `host=` parameter, which picks the virtual host
+    fn host_header(&self) -> String {
+        let host = match self.url.host.contains(':') {
+            true => format!("[{}]", self.url.host),
+            false => self.url.host.clone(),
+        };
+        return match self.url.port {
+            Some(port) => format!("{}:{}", host, port),
+            None => host,
+        };
+    }
+
+    fn stream(&mut self) -> Result<&mut TcpStream> {
+        return match self.stream.as_mut() {
+            Some(stream) => Ok(stream),
+            None => bail!("the connection to the git daemon is not open"),
+        };
+    }
+}
+
+impl Transport for DaemonTransport {
+    fn advertise(&mut self, service: Service, version: u8) -> Result<Box<dyn Read + '_>> {
+        if self.stream.is_some() {
+            bail!("the connection to the git daemon is already open");
+        }
+        let port = self.url.port.unwrap_or(DEFAULT_DAEMON_PORT);
+        let mut stream = match TcpStream::connect((self.url.host.as_str(), port)) {
+            Ok(stream) => stream,
+            Err(e) => bail!("unable to connect to {}:{}: {}", self.url.host, port, e),
+        };
+        // the version goes after an empty parameter, which older daemons
+        // ignore
+        let mut request = format!(
+            "{} {}\0host={}\0",
+            service,
+            self.url.path,
+            self.host_header()
+        );
+        if version > 0 {
+            request.push_str(&format!("\0version={}\0", version));
+        }
+        write_packet(&mut stream, request.as_bytes())?;
+        self.stream = Some(stream);
+        return Ok(Box::new(self.stream()?));
+    }
+
+    fn request(&mut self, _service: Service, body: Vec<u8>) -> Result<Box<dyn Read>> {
+        let stream = self.stream()?;
+        stream.write_all(&body)?;
+        stream.flush()?;
+        return Ok(Box::new(stream.try_clone()?));
+    }
+
+    fn stateless(&self) -> bool {
+        return false;
+    }
+}
+
+impl Drop for DaemonTransport {
+    fn drop(&mut self) {
+        if let Some(mut stream) = self.stream.take() {
+            let _ = write_flush(&mut stream);
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transport::{local_path, parse_daemon_url, parse_ssh_url, DaemonUrl, SshUrl};
+
+    #[test]
+    fn test_parse_urls() {
+        let url = |user: Option<&str>, host: &str, port: Option<u16>, path: &str| SshUrl {
+            user: user.map(|u| u.to_string()),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        };
+        assert_eq!(
+            parse_ssh_url("git@github.com:org/repo.git"),
+            Some(url(Some("git"), "github.com", None, "org/repo.git"))
+        );
+        assert_eq!(
+            parse_ssh_url("ssh://me@host:2222/srv/repo.git"),
+            Some(url(Some("me"), "host", Some(2222), "/srv/repo.git"))
+        );
+        assert_eq!(
+            parse_ssh_url("git+ssh://host/~me/repo"),
+            Some(url(None, "host", None, "~me/repo"))
+        );
+        assert_eq!(
+            parse_ssh_url("ssh://[::1]:22/repo"),
+            Some(url(None, "::1", Some(22), "/repo"))
+        );
+        assert_eq!(
+            parse_ssh_url("[::1]:repo"),
+            Some(url(None, "::1", None, "repo"))
+        );
+        assert_eq!(parse_ssh_url("ssh://host:port/repo"), None);
+        assert_eq!(parse_ssh_url("ssh://-oProxyCommand=x/repo"), None);
+        assert_eq!(parse_ssh_url("ssh://-oProxyCommand=x@host/repo"), None);
+        assert_eq!(parse_ssh_url("-oProxyCommand=x@host:repo"), None);
+        assert_eq!(parse_ssh_url("https://host/repo"), None);
+        assert_eq!(parse_ssh_url("./dir:with/colon"), None);
+        assert_eq!(parse_ssh_url("/srv/repo.git"), None);
+
+        assert_eq!(
+            parse_daemon_url("git://[::1]:9419/~me/repo"),
+            Some(DaemonUrl {
+                host: "::1".to_string(),
+                port: Some(9419),
+                path: "~me/repo".to_string(),
+            })
+        );
+        assert_eq!(parse_daemon_url("git://host"), None);
+        assert_eq!(parse_daemon_url("ssh://host/repo"), None);
+
+        assert_eq!(local_path("host:repo"), None);
+        assert_eq!(local_path("https://host/repo"), None);
+        assert_eq!(
+            local_path("file:///srv/repo").unwrap().to_str(),
+            Some("/srv/repo")
+        );
+        assert_eq!(local_path("../repo").unwrap().to_str(), Some("../repo"));
+    }
+}
@@ -1,10 +1,13 @@
+use crate::config::Config;
 use crate::files::hashobject;
-use crate::objects::{load_object, store_object, GitObject, GitObjectType};
+use crate::objects::{read_object, store_object, GitObjectType, ObjectReader};
+use crate::refs::resolve_object;
+use crate::repo_path;
 use anyhow::{bail, Result};
 use chrono::Utc;
+use std::collections::BTreeMap;
 use std::fs::{self, DirEntry};
 use std::io::{self, Write};
-use std::path::Path;
 use std::slice::Iter;
 
 #[derive(Debug)]
@@ -14,19 +17,50 @@ pub struct TreeNode {
     pub hash: String,
 }
 
+impl TreeNode {
+    /// Returns the type of the object the node points to, based on its mode
+    pub fn object_type(&self) -> GitObjectType {
+        return match self.permissions.as_str() {
+            "40000" | "040000" => GitObjectType::Tree,
+            "160000" => GitObjectType::Commit,
+            _ => GitObjectType::Blob,
+        };
+    }
+}
+
 #[derive(Debug)]
 pub struct Tree {
     pub nodes: Vec<TreeNode>,
 }
 
 impl Tree {
-    fn new(tree: &GitObject) -> Tree {
-        return Tree {
-            nodes: parse_tree(&tree),
-        };
+    /// Parses the binary contents of a tree object
+    pub fn parse(data: &[u8]) -> Result<Tree> {
+        let mut nodes = Vec::new();
+        let mut ix = 0;
+        while ix < data.len() {
+            let space = match data[ix..].iter().position(|b| *b == b' ') {
+                Some(pos) => ix + pos,
+                None => bail!("malformed tree entry: missing mode"),
+            };
+            let nul = match data[space..].iter().position(|b| *b == 0) {
+                Some(pos) => space + pos,
+                None => bail!("malformed tree entry: missing filename"),
+            };
+            if nul + 21 > data.len() {
+                bail!("malformed tree entry: truncated hash");
+            }
+            nodes.push(TreeNode {
+                permissions: String::from_utf8_lossy(&data[ix..space]).to_string(),
+                filename: String::from_utf8_lossy(&data[space + 1..nul]).to_string(),
+                hash: hex::encode(&data[nul + 1..nul + 21]),
+            });
+            ix = nul + 21;
+        }
+        return Ok(Tree { nodes });
     }
 
-    pub fn iter(&self) -> Iter<TreeNode> {
+    pub fn iter(&self) -> Iter<'_, TreeNode> {
         return self.nodes.iter();
     }
 
@@ -45,36 +79,247 @@ impl Tree {
     }
 }
 
-fn parse_tree(tree: &GitObject) -> Vec<TreeNode> {
-    if let GitObjectType::Tree = tree.type_ {
-        let mut vec: Vec<TreeNode> = Vec::new();
-        for line in tree
-            .data
-            .strip_suffix('\n')
-            .unwrap_or(tree.data.as_str())
-            .split('\n')
-        {
-            let mut iter = line.split('\t');
-            vec.push(TreeNode {
-                permissions: iter.next().unwrap().to_string(),
-                filename: iter.next().unwrap().to_string(),
-                hash: iter.next().unwrap().to_string(),
-            })
+pub fn lstree(treeid: &String) -> Result<Tree> {
+    let (type_, data) = read_object(treeid)?;
+    if type_ != GitObjectType::Tree {
+        bail!("object {} is a {}, not a tree", treeid, type_);
+    }
+    return Tree::parse(&data);
+}
+
+/// Looks up the entry at `path` (separated by slashes) in a tree, returns
+/// its mode and hash
+pub fn find_path(tree: &String, path: &str) -> Result<Option<(String, String)>> {
+    let mut entry = ("40000".to_string(), tree.clone());
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        if entry.0 != "40000" && entry.0 != "040000" {
+            return Ok(None);
         }
-        return vec;
+        let node = lstree(&entry.1)?
+            .nodes
+            .into_iter()
+            .find(|n| n.filename == name);
+        entry = match node {
+            Some(node) => (node.permissions, node.hash),
+            None => return Ok(None),
+        };
     }
-    panic!("object not a tree")
+    return Ok(Some(entry));
 }
 
-pub fn lstree(treeid: &String) -> Result<Tree> {
-    let obj = load_object(treeid)?;
-    let tree = Tree::new(&obj);
-    return Ok(tree);
+/// Lists the paths of the files (and submodules) that differ between two
+/// trees, recursively, `None` standing for the empty tree. A file replaced
+/// by a directory is listed along with the files of the directory.
+pub fn diff_tree_paths(
+    old: Option<&String>,
+    new: Option<&String>,
+    base: &str,
+) -> Result<Vec<String>> {
+    let mut nodes: BTreeMap<String, (Option<TreeNode>, Option<TreeNode>)> = BTreeMap::new();
+    if let Some(old) = old {
+        for node in lstree(old)?.nodes {
+            let name = node.filename.clone();
+            nodes.entry(name).or_default().0 = Some(node);
+        }
+    }
+    if let Some(new) = new {
+        for node in lstree(new)?.nodes {
+            let name = node.filename.clone();
+            nodes.entry(name).or_default().1 = Some(node);
+        }
+    }
+    let mut paths = Vec::new();
+    for (name, (old_node, new_node)) in nodes {
+        let path = format!("{}{}", base, name);
+        let same = match (&old_node, &new_node) {
+            (Some(a), Some(b)) => a.hash == b.hash && a.permissions == b.permissions,
+            _ => false,
+        };
+        if same {
+            continue;
+        }
+        let subtree = |node: &Option<TreeNode>| match node {
+            Some(node) if node.object_type() == GitObjectType::Tree => Some(node.hash.clone()),
+            _ => None,
+        };
+        let (old_tree, new_tree) = (subtree(&old_node), subtree(&new_node));
+        let old_file = old_node.is_some() && old_tree.is_none();
+        let new_file = new_node.is_some() && new_tree.is_none();
+        if old_file || new_file {
+            paths.push(path.clone());
+        }
+        if old_tree.is_some() || new_tree.is_some() {
+            let dir = format!("{}/", path);
+            paths.extend(diff_tree_paths(old_tree.as_ref(), new_tree.as_ref(), &dir)?);
+        }
+    }
+    return Ok(paths);
+}
+
+/// Resolves a tree-ish (a tree, commit, tag or ref) to the hash of its tree
+pub fn peel_to_tree(treeish: &str) -> Result<String> {
+    let mut sha1 = resolve_object(treeish)?;
+    loop {
+        let (type_, data) = read_object(&sha1)?;
+        let field = match type_ {
+            GitObjectType::Tree => return Ok(sha1),
+            GitObjectType::Commit => "tree ",
+            GitObjectType::Tag => "object ",
+            GitObjectType::Blob => bail!("{} is not a tree object", treeish),
+        };
+        let content = String::from_utf8_lossy(&data);
+        sha1 = match content.lines().find_map(|l| l.strip_prefix(field)) {
+            Some(next) => next.trim().to_string(),
+            None => bail!("{} {} is malformed", type_, sha1),
+        };
+    }
+}
+
+/// Options of the `ls-tree` command
+#[derive(Debug, Default)]
+pub struct LsTreeOptions {
+    pub recursive: bool,
+    pub show_trees: bool,
+    pub only_trees: bool,
+    pub long: bool,
+    pub name_only: bool,
+    pub abbrev: Option<usize>,
+    pub null_terminated: bool,
+    pub format: Option<String>,
+    pub paths: Vec<String>,
+}
+
+/// A tree entry listed by `ls-tree`, with its full path
+#[derive(Debug)]
+pub struct LsTreeEntry {
+    pub mode: String,
+    pub type_: GitObjectType,
+    pub hash: String,
+    pub path: String,
+}
+
+/// Collects the entries of `treeid` under `base` matching the options
+fn collect_ls_tree(
+    treeid: &String,
+    base: &str,
+    inside_match: bool,
+    options: &LsTreeOptions,
+    entries: &mut Vec<LsTreeEntry>,
+) -> Result<()> {
+    for node in lstree(treeid)?.iter() {
+        let path = format!("{}{}", base, node.filename);
+        let dir_prefix = format!("{}/", path);
+        let is_tree = node.object_type() == GitObjectType::Tree;
+        let matched = inside_match || options.paths.is_empty() || options.paths.contains(&path);
+        let leads_to_match = is_tree && options.paths.iter().any(|p| p.starts_with(&dir_prefix));
+
+        let recurse = is_tree && ((matched && options.recursive) || leads_to_match);
+        let show = if !matched {
+            recurse && options.show_trees
+        } else if is_tree {
+            !recurse || options.show_trees || options.only_trees
+        } else {
+            !options.only_trees
+        };
+
+        if show {
+            entries.push(LsTreeEntry {
+                mode: format!("{:0>6}", node.permissions),
+                type_: node.object_type(),
+                hash: node.hash.clone(),
+                path: path.clone(),
+            });
+        }
+        if recurse {
+            let children_match = matched || options.paths.contains(&dir_prefix);
+            collect_ls_tree(&node.hash, &dir_prefix, children_match, options, entries)?;
+        }
+    }
+    return Ok(());
+}
+
+/// Lists the entries of a tree-ish as `ls-tree` does
+pub fn ls_tree(treeish: &str, options: &LsTreeOptions) -> Result<Vec<LsTreeEntry>> {
+    let treeid = peel_to_tree(treeish)?;
+    let mut entries = Vec::new();
+    collect_ls_tree(&treeid, "", false, options, &mut entries)?;
+    return Ok(entries);
+}
+
+/// Quotes a path the way git does when it contains special characters
+fn quote_path(path: &str) -> String {
+    if !path
+        .chars()
+        .any(|c| c == '"' || c == '\\' || c.is_control() || !c.is_ascii())
+    {
+        return path.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for byte in path.bytes() {
+        match byte {
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            b'\t' => quoted.push_str("\\t"),
+            b'\n' => quoted.push_str("\\n"),
+            0x20..=0x7e => quoted.push(byte as char),
+            _ => quoted.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    quoted.push('"');
+    return quoted;
+}
+
+/// Formats an entry in `ls-tree` output format, without line terminator
+pub fn format_ls_tree_entry(entry: &LsTreeEntry, options: &LsTreeOptions) -> Result<String> {
+    let path = match options.null_terminated {
+        true => entry.path.clone(),
+        false => quote_path(&entry.path),
+    };
+    if options.name_only {
+        return Ok(path);
+    }
+    let hash = match options.abbrev {
+        Some(n) => &entry.hash[..n.clamp(4, 40)],
+        None => &entry.hash[..],
+    };
+    // blobs are only opened when their size is shown, they may be missing
+    // from partial clones
+    let size = || -> Result<String> {
+        return match entry.type_ {
+            GitObjectType::Blob => Ok(ObjectReader::open(&entry.hash)?.size.to_string()),
+            _ => Ok("-".to_string()),
+        };
+    };
+    if let Some(format) = &options.format {
+        let mut format = format.clone();
+        if format.contains("%(objectsize") {
+            let size = size()?;
+            format = format
+                .replace("%(objectsize:padded)", &format!("{:>7}", size))
+                .replace("%(objectsize)", &size);
+        }
+        return Ok(format
+            .replace("%(objectmode)", &entry.mode)
+            .replace("%(objecttype)", &entry.type_.to_string())
+            .replace("%(objectname)", hash)
+            .replace("%(path)", &path));
+    }
+    if options.long {
+        return Ok(format!(
+            "{} {} {} {:>7}\t{}",
+            entry.mode,
+            entry.type_,
+            hash,
+            size()?,
+            path
+        ));
+    }
+    return Ok(format!("{} {} {}\t{}", entry.mode, entry.type_, hash, path));
 }
 
 pub fn writetree() -> Result<String> {
     let hash = hash_dir(&"./".to_string())?;
-    return Ok(format!("{}", hash));
+    return Ok(hash.to_string());
 }
 
 fn hash_dir(path: &String) -> Result<String> {
@@ -109,7 +354,7 @@ fn hash_dir(path: &String) -> Result<String> {
 #[allow(dead_code)]
 // TODO: Use for actual `git commit`
 fn get_commit_parent() -> String {
-    let path = Path::new(".git/refs/heads/master");
+    let path = &repo_path(".git/refs/heads/master");
     if path.exists() {
         return fs::read_to_string(path).unwrap();
     }
@@ -126,15 +371,26 @@ fn store_commit(content: &Vec<u8>) -> Result<String> {
 }
 
 fn update_master_ref(digest: &String) -> io::Result<()> {
-    let path = Path::new(".git/refs/heads/master");
+    let path = &repo_path(".git/refs/heads/master");
     fs::create_dir_all(path.parent().unwrap()).unwrap();
     let mut file = fs::File::create(path)?;
-    file.write(digest.as_bytes())?;
+    file.write_all(digest.as_bytes())?;
     return Ok(());
 }
 
+/// Returns the `Name <email>` identity recorded in new commits, from
+/// `user.name` and `user.email`
+pub fn commit_identity() -> Result<String> {
+    let config = Config::load()?;
+    let name = config.get("user.name").unwrap_or("manuel");
+    let email = config.get("user.email").unwrap_or("manuel@manuel.com");
+    return Ok(format!("{} <{}>", name, email));
+}
+
+/// Stores a commit of the tree by `identity` (`Name <email>`), used as both
+/// author and committer, and points master to it
 pub fn committree(
-    author: &String,
+    identity: &String,
     treeid: &String,
     parent_commitid: &String,
     message: &String,
@@ -142,15 +398,15 @@ pub fn committree(
     let timestamp = current_time();
     let mut content = Vec::new();
 
-    content.write(format!("tree {}\n", treeid).as_bytes())?;
-    if *parent_commitid != "".to_string() {
-        content.write(format!("parent {}\n", parent_commitid).as_bytes())?;
+    content.write_all(format!("tree {}\n", treeid).as_bytes())?;
+    if !parent_commitid.is_empty() {
+        content.write_all(format!("parent {}\n", parent_commitid).as_bytes())?;
     }
-    content.write(format!("author {} {}\n", author, timestamp).as_bytes())?;
-    content.write(format!("commiter {} {}\n", author, timestamp).as_bytes())?;
-    content.write("\n".as_bytes())?;
-    content.write(message.as_bytes())?;
-    content.write("\n".as_bytes())?;
+    content.write_all(format!("author {} {}\n", identity, timestamp).as_bytes())?;
+    content.write_all(format!("committer {} {}\n", identity, timestamp).as_bytes())?;
+    content.write_all("\n".as_bytes())?;
+    content.write_all(message.as_bytes())?;
+    content.write_all("\n".as_bytes())?;
 
     let digest = store_commit(&content)?;
     update_master_ref(&digest)?;
@@ -162,21 +418,20 @@ pub fn committree(
 pub fn checkout_tree(sha1: &String, base: &String) -> Result<()> {
     let tree = lstree(sha1)?;
     for node in tree.iter() {
-        let new_base = format!("{}/{}", base, node.filename);
-        let new_base = new_base
-            .strip_prefix("/")
-            .unwrap_or(new_base.as_str())
-            .to_string();
+        let new_base = match base.is_empty() {
+            true => node.filename.clone(),
+            false => format!("{}/{}", base, node.filename),
+        };
         if node.permissions == "40000" {
             fs::create_dir(&new_base)?;
             if let Err(e) = checkout_tree(&node.hash, &new_base) {
                 bail!(e);
             };
         } else {
-            let blob = load_object(&node.hash)?;
+            let mut blob = ObjectReader::open(&node.hash)?;
             if let GitObjectType::Blob = blob.type_ {
                 let mut f = fs::File::create(new_base)?;
-                f.write(blob.data.as_bytes())?;
+                io::copy(&mut blob, &mut f)?;
             } else {
                 bail!("treating {} as file", node.hash)
             }
@@ -189,6 +444,7 @@ pub fn checkout_tree(sha1: &String, base: &String) -> Result<()> {
 mod tests {
     use std::fs;
     use std::path;
+    use std::process::Command;
 
     use anyhow::Result;
 
@@ -196,9 +452,11 @@ mod tests {
     use crate::testutils;
     use crate::tree::checkout_tree;
     use crate::tree::lstree;
+    use crate::tree::{format_ls_tree_entry, ls_tree, peel_to_tree, LsTreeEntry, LsTreeOptions};
 
-    use super::writetree;
     use super::Tree;
+    use super::{commit_identity, committree, writetree};
+    use crate::objects::{read_object, validate_object, GitObjectType};
 
     fn assert_tree_objects_exist(tree: &Tree, base: &String) {
         for node in &tree.nodes {
@@ -262,4 +520,118 @@ mod tests {
             assert_tree_exist(&tree, &String::new()).unwrap();
         });
     }
+
+    #[test]
+    fn test_committree() {
+        testutils::in_tmp_git(|| {
+            fs::write("a.txt", "a\n").unwrap();
+            testutils::get_git_output(&["config", "user.name", "Some One"]);
+            testutils::get_git_output(&["config", "user.email", "one@example.com"]);
+            let identity = commit_identity().unwrap();
+            assert_eq!(identity, "Some One <one@example.com>");
+
+            let tree = writetree().unwrap();
+            let first = committree(&identity, &tree, &String::new(), &"first".to_string());
+            let first = first.unwrap();
+            let second = committree(&identity, &tree, &first, &"second".to_string()).unwrap();
+
+            // the commits pass the checks of --strict and of git itself
+            for sha1 in [&first, &second] {
+                let (type_, data) = read_object(sha1).unwrap();
+                assert!(matches!(type_, GitObjectType::Commit));
+                validate_object(type_, &data).unwrap();
+            }
+            let fsck = Command::new("git").args(["fsck", "--strict"]).output();
+            assert!(fsck.unwrap().status.success());
+            let log = testutils::get_git_output(&["log", "--format=%an <%ae>|%cn <%ce>|%s"]);
+            assert_eq!(
+                log,
+                "Some One <one@example.com>|Some One <one@example.com>|second\n\
+                 Some One <one@example.com>|Some One <one@example.com>|first\n"
+            );
+        });
+    }
+
+    #[test]
+    fn test_ls_tree_matches_git() {
+        testutils::in_tmp_git(|| {
+            fs::create_dir_all("src/nested").unwrap();
+            fs::create_dir_all("docs").unwrap();
+            fs::write("README.md", "readme\n").unwrap();
+            fs::write("with space.txt", "space\n").unwrap();
+            fs::write("src/main.rs", "fn main() {}\n").unwrap();
+            fs::write("src/nested/mod.rs", "// nested\n").unwrap();
+            fs::write("docs/guide.md", "guide\n").unwrap();
+            let commit = testutils::git_commit_all("initial");
+
+            let cases: Vec<Vec<&str>> = vec![
+                vec![],
+                vec!["-r"],
+                vec!["-r", "-t"],
+                vec!["-d"],
+                vec!["-r", "-d"],
+                vec!["-l"],
+                vec!["-r", "-l", "--abbrev=10"],
+                vec!["--name-only", "-r"],
+                vec!["src"],
+                vec!["src/"],
+                vec!["-r", "src"],
+                vec!["src/nested/mod.rs"],
+                vec!["-t", "src/nested/mod.rs"],
+                vec!["--format=%(objecttype) %(objectsize:padded) %(path)", "-r"],
+            ];
+            for case in cases {
+                let mut options = LsTreeOptions::default();
+                let mut paths = Vec::new();
+                for arg in case.iter() {
+                    match *arg {
+                        "-r" => options.recursive = true,
+                        "-t" => options.show_trees = true,
+                        "-d" => options.only_trees = true,
+                        "-l" => options.long = true,
+                        "--name-only" => options.name_only = true,
+                        "--abbrev=10" => options.abbrev = Some(10),
+                        f if f.starts_with("--format=") => {
+                            options.format = Some(f["--format=".len()..].to_string())
+                        }
+                        path => paths.push(path.to_string()),
+                    }
+                }
+                options.paths = paths;
+
+                let mut output = String::new();
+                for entry in ls_tree("HEAD", &options).unwrap() {
+                    output.push_str(&format_ls_tree_entry(&entry, &options).unwrap());
+                    output.push('\n');
+                }
+                let mut args = vec!["ls-tree", commit.as_str()];
+                args.extend(case.iter());
+                assert_eq!(output, testutils::get_git_output(&args), "case {:?}", case);
+            }
+
+            let tree = peel_to_tree("master").unwrap();
+            assert_eq!(
+                tree,
+                testutils::get_git_output(&["rev-parse", "HEAD^{tree}"]).trim()
+            );
+            assert!(peel_to_tree(&lstree(&tree).unwrap().nodes[0].hash).is_err());
+
+            // a missing blob is only read for its size
+            let entry = LsTreeEntry {
+                mode: "100644".to_string(),
+                type_: GitObjectType::Blob,
+                hash: "1".repeat(40),
+                path: "missing.txt".to_string(),
+            };
+            let mut options = LsTreeOptions::default();
+            assert!(format_ls_tree_entry(&entry, &options).is_ok());
+            options.format = Some("%(objectname) %(path)".to_string());
+            assert!(format_ls_tree_entry(&entry, &options).is_ok());
+            options.format = Some("%(objectsize) %(path)".to_string());
+            assert!(format_ls_tree_entry(&entry, &options).is_err());
+            options.format = None;
+            options.long = true;
+            assert!(format_ls_tree_entry(&entry, &options).is_err());
+        });
+    }
 }